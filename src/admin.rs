@@ -0,0 +1,406 @@
+use futures::future;
+use futures_util::{SinkExt, Stream, StreamExt};
+use log::{error, info, warn};
+use std::{net::SocketAddr, path::Path, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    tungstenite::{handshake::server::Request, protocol::Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    client,
+    errors::SwapError,
+    messages::{AdminRequest, AdminResponse},
+    peer_queue::{self, OverflowPolicy, PeerReceiver},
+    peer_registry::{PeerRegistryHandle, Subscriber},
+    quotes::{QuoteRequest, SwapQuote},
+    router::{Router, SwapRoute, DEFAULT_MAX_HOPS},
+    server::Server,
+    transactions::{SwapTransaction, SwapTransactionRequest, TransactionBuilder},
+};
+
+/// Accept admin connections and forward their requests to the peer registry,
+/// pushes to subscribers are queued like the messages to the peers
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve(
+    listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    auth_token: Arc<str>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    peer_registry: PeerRegistryHandle,
+    router: Arc<Router>,
+    transactions: Arc<TransactionBuilder>,
+) {
+    // Connections end with this task when the server shuts down
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(_) = connections.join_next() => continue,
+        };
+        match accepted {
+            Ok((stream, addr)) => {
+                connections.spawn(accept_connection(
+                    tls_acceptor.clone(),
+                    stream,
+                    addr,
+                    auth_token.clone(),
+                    queue_capacity,
+                    overflow_policy,
+                    peer_registry.clone(),
+                    router.clone(),
+                    transactions.clone(),
+                ));
+            }
+            Err(e) => {
+                error!("Error accepting admin connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Do the TLS handshake if the server has `server.tls` before handling the
+/// connection
+#[allow(clippy::too_many_arguments)]
+async fn accept_connection(
+    tls_acceptor: Option<TlsAcceptor>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    auth_token: Arc<str>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    peer_registry: PeerRegistryHandle,
+    router: Arc<Router>,
+    transactions: Arc<TransactionBuilder>,
+) {
+    match tls_acceptor {
+        Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+            Ok(tls_stream) => {
+                handle_connection(
+                    tls_stream,
+                    addr,
+                    auth_token,
+                    queue_capacity,
+                    overflow_policy,
+                    peer_registry,
+                    router,
+                    transactions,
+                )
+                .await
+            }
+            Err(tls_error) => warn!("TLS handshake with admin {} failed: {}", addr, tls_error),
+        },
+        None => {
+            handle_connection(
+                stream,
+                addr,
+                auth_token,
+                queue_capacity,
+                overflow_policy,
+                peer_registry,
+                router,
+                transactions,
+            )
+            .await
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    auth_token: Arc<str>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    peer_registry: PeerRegistryHandle,
+    router: Arc<Router>,
+    transactions: Arc<TransactionBuilder>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    #[allow(clippy::result_large_err)]
+    let check_auth =
+        |request: &Request, response| Server::check_auth(Some(&auth_token), request, response);
+    let mut ws_stream = match tokio_tungstenite::accept_hdr_async(stream, check_auth).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!("Rejected admin connection from {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Admin connection from {}", addr);
+
+    // Pushes for this connection once it subscribes, until then the branch
+    // below never fires
+    let mut push_rx: Option<PeerReceiver> = None;
+    loop {
+        let pushed = async {
+            match &push_rx {
+                Some(push_rx) => push_rx.recv().await,
+                None => future::pending().await,
+            }
+        };
+        let msg = tokio::select! {
+            msg = ws_stream.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            pushed = pushed => match pushed {
+                Some(pushed) => {
+                    if ws_stream.send(pushed).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // Closed by the `disconnect` overflow policy
+                None => {
+                    warn!("Admin subscriber {} too slow, disconnecting", addr);
+                    break;
+                }
+            },
+        };
+        let response = match msg {
+            Message::Text(text) => match serde_json::from_str::<AdminRequest>(&text) {
+                Ok(AdminRequest::Subscribe {
+                    token,
+                    resolution,
+                    portfolio,
+                    prices,
+                }) => {
+                    info!("Admin subscription from {}", addr);
+                    // Replace the previous subscription, the registry drops it once
+                    // its sender fails
+                    let (tx, rx) = peer_queue::channel(queue_capacity, overflow_policy);
+                    push_rx = Some(rx);
+                    peer_registry.subscribe(Subscriber {
+                        addr,
+                        tx,
+                        token,
+                        resolution,
+                        portfolio,
+                        prices,
+                    });
+                    AdminResponse::Done
+                }
+                Ok(AdminRequest::Quote {
+                    from_token,
+                    to_token,
+                    amount,
+                    slippage_bps,
+                }) => {
+                    info!(
+                        "Admin quote from {}: {} {} to {}",
+                        addr, amount, from_token, to_token
+                    );
+                    let request = QuoteRequest::new(&from_token, &to_token, amount, slippage_bps);
+                    match quote(&peer_registry, &router, request).await {
+                        Ok(quote) => AdminResponse::Quote { quote },
+                        Err(quote_error) => AdminResponse::Error {
+                            message: quote_error.to_string(),
+                        },
+                    }
+                }
+                Ok(AdminRequest::Route {
+                    from_token,
+                    to_token,
+                    amount,
+                    slippage_bps,
+                    max_hops,
+                }) => {
+                    info!(
+                        "Admin route from {}: {} {} to {}",
+                        addr, amount, from_token, to_token
+                    );
+                    let request = QuoteRequest::new(&from_token, &to_token, amount, slippage_bps);
+                    let max_hops = max_hops.unwrap_or(DEFAULT_MAX_HOPS);
+                    match route(&peer_registry, &router, request, max_hops).await {
+                        Ok(route) => AdminResponse::Route { route },
+                        Err(route_error) => AdminResponse::Error {
+                            message: route_error.to_string(),
+                        },
+                    }
+                }
+                Ok(AdminRequest::BuildSwap {
+                    from_token,
+                    to_token,
+                    amount,
+                    slippage_bps,
+                    sender,
+                    dry_run,
+                }) => {
+                    info!(
+                        "Admin swap transaction from {}: {} {} to {} for {}",
+                        addr, amount, from_token, to_token, sender
+                    );
+                    let request = QuoteRequest::new(&from_token, &to_token, amount, slippage_bps);
+                    let result = build_swap(
+                        &peer_registry,
+                        &router,
+                        &transactions,
+                        request,
+                        &sender,
+                        dry_run,
+                    )
+                    .await;
+                    match result {
+                        Ok(transaction) => AdminResponse::SwapTransaction { transaction },
+                        Err(build_error) => AdminResponse::Error {
+                            message: build_error.to_string(),
+                        },
+                    }
+                }
+                Ok(request) => {
+                    info!("Admin request from {}: {:?}", addr, request);
+                    peer_registry.admin(request).await
+                }
+                Err(e) => AdminResponse::Error {
+                    message: format!("Invalid request: {}", e),
+                },
+            },
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let text = serde_json::to_string(&response).expect("Impossible serializing error");
+        if ws_stream.send(Message::text(text)).await.is_err() {
+            break;
+        }
+    }
+    info!("Admin connection from {} closed", addr);
+}
+
+/// Quote a swap through the pool between both tokens
+async fn quote(
+    peer_registry: &PeerRegistryHandle,
+    router: &Router,
+    request: QuoteRequest<'_>,
+) -> Result<SwapQuote, SwapError> {
+    // Don't ask for prices that won't be used
+    if !router
+        .quoter()
+        .has_pool(request.from_token, request.to_token)
+    {
+        return Err(SwapError::NoPool(
+            request.from_token.to_string(),
+            request.to_token.to_string(),
+        ));
+    }
+    let request = with_decimals(peer_registry, request).await?;
+    router.quoter().quote(request).await
+}
+
+async fn route(
+    peer_registry: &PeerRegistryHandle,
+    router: &Router,
+    request: QuoteRequest<'_>,
+    max_hops: usize,
+) -> Result<SwapRoute, SwapError> {
+    let request = with_decimals(peer_registry, request).await?;
+    router.route(request, max_hops).await
+}
+
+/// Quote a swap and build its transaction with the coin types of the tokens file
+async fn build_swap(
+    peer_registry: &PeerRegistryHandle,
+    router: &Router,
+    transactions: &TransactionBuilder,
+    request: QuoteRequest<'_>,
+    sender: &str,
+    dry_run: bool,
+) -> Result<SwapTransaction, SwapError> {
+    let pool = router
+        .quoter()
+        .pool(request.from_token, request.to_token)
+        .ok_or_else(|| {
+            SwapError::NoPool(request.from_token.to_string(), request.to_token.to_string())
+        })?;
+    let coin_type_a = &router.tokens().get(&pool.token_a)?.coin_type;
+    let coin_type_b = &router.tokens().get(&pool.token_b)?.coin_type;
+    let quote = quote(peer_registry, router, request).await?;
+    transactions
+        .build(SwapTransactionRequest {
+            quote,
+            pool,
+            coin_type_a,
+            coin_type_b,
+            sender,
+            dry_run,
+        })
+        .await
+}
+
+/// Set the decimals the price sources report for both tokens
+async fn with_decimals<'a>(
+    peer_registry: &PeerRegistryHandle,
+    request: QuoteRequest<'a>,
+) -> Result<QuoteRequest<'a>, SwapError> {
+    let from = peer_registry.usd_quote(request.from_token).await?;
+    let to = peer_registry.usd_quote(request.to_token).await?;
+    Ok(QuoteRequest {
+        from_decimals: from.decimals,
+        to_decimals: to.decimals,
+        ..request
+    })
+}
+
+/// Send a single request to a running server admin channel and wait for the answer
+pub async fn send_request(
+    url: &str,
+    auth_token: Option<&str>,
+    ca_cert: Option<&Path>,
+    request: &AdminRequest,
+) -> Result<AdminResponse, SwapError> {
+    let mut ws_stream = connect(url, auth_token, ca_cert, request).await?;
+    while let Some(msg) = ws_stream.next().await {
+        if let Message::Text(text) = msg.map_err(|e| SwapError::WsError(Box::new(e)))? {
+            // Closing is best effort, we already have the answer
+            let _ = ws_stream.close(None).await;
+            return serde_json::from_str(&text).map_err(SwapError::ParseAdminResponseError);
+        }
+    }
+    Err(SwapError::SendRequestError(url.to_string()))
+}
+
+/// Send a request and yield every message the server sends back, e.g. the
+/// candles pushed after an [`AdminRequest::Subscribe`]
+pub async fn subscribe(
+    url: &str,
+    auth_token: Option<&str>,
+    ca_cert: Option<&Path>,
+    request: &AdminRequest,
+) -> Result<impl Stream<Item = Result<AdminResponse, SwapError>>, SwapError> {
+    let ws_stream = connect(url, auth_token, ca_cert, request).await?;
+    Ok(ws_stream.filter_map(|msg| async move {
+        match msg {
+            Ok(Message::Text(text)) => {
+                Some(serde_json::from_str(&text).map_err(SwapError::ParseAdminResponseError))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(SwapError::WsError(Box::new(e)))),
+        }
+    }))
+}
+
+/// Open an admin connection and send `request` on it, trusting `ca_cert`
+/// if the URL is wss://
+async fn connect(
+    url: &str,
+    auth_token: Option<&str>,
+    ca_cert: Option<&Path>,
+    request: &AdminRequest,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, SwapError> {
+    let mut ws_stream = client::connect_hub(url, auth_token, "admin.token", ca_cert).await?;
+    let text = serde_json::to_string(request).expect("Impossible serializing error");
+    ws_stream
+        .send(Message::text(text))
+        .await
+        .map_err(|e| SwapError::WsError(Box::new(e)))?;
+    Ok(ws_stream)
+}
@@ -0,0 +1,282 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, sync::Arc};
+use tokio::time::{Duration, Instant};
+
+use crate::{clock::Clock, decimal::Decimal};
+
+/// Alert rule from the `[[alerts]]` config sections
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Sent in the notification, must be unique
+    pub name: String,
+    /// Token name as in the tokens file
+    pub token: String,
+    pub condition: AlertCondition,
+    /// URL the alert is POSTed to as JSON
+    pub webhook: String,
+    /// Minimum time between two notifications of the rule
+    #[serde(default)]
+    pub cooldown_secs: u64,
+}
+
+/// What makes a rule fire. It fires when the condition starts to hold, not
+/// again until it stops holding first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AlertCondition {
+    /// The price goes from below `level` to `level` or above
+    CrossesAbove { level: Decimal },
+    /// The price goes from above `level` to `level` or below
+    CrossesBelow { level: Decimal },
+    /// The price moves at least `pct` percent (up or down) within `window_secs`
+    ChangePct { pct: Decimal, window_secs: u64 },
+    /// No price received for longer than `secs`
+    Stale { secs: u64 },
+    /// The source reports a confidence lower than `min`
+    ConfidenceBelow { min: Decimal },
+}
+
+impl AlertCondition {
+    /// Reason the config can't be used, if any
+    pub fn check(&self) -> Option<&'static str> {
+        match self {
+            AlertCondition::ChangePct { pct, .. } if pct.is_zero() => {
+                Some("pct must be greater than 0")
+            }
+            AlertCondition::ChangePct { window_secs: 0, .. } => {
+                Some("window_secs must be greater than 0")
+            }
+            AlertCondition::Stale { secs: 0 } => Some("secs must be greater than 0"),
+            _ => None,
+        }
+    }
+}
+
+/// Notification body POSTed to the webhook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub token: String,
+    pub message: String,
+    /// Last price of the token, if any was received
+    pub price: Option<Decimal>,
+    /// Unix time the rule fired
+    pub timestamp: i64,
+}
+
+struct RuleState {
+    rule: AlertRule,
+    /// Whether the condition held on the last evaluation, None before the first
+    active: Option<bool>,
+    last_fired: Option<Instant>,
+    last_price: Option<Decimal>,
+    last_sample: Instant,
+    /// Samples within the window of a `ChangePct` rule
+    window: VecDeque<(Instant, Decimal)>,
+}
+
+/// Evaluates the alert rules against the prices the server receives
+pub struct AlertEngine {
+    rules: Vec<RuleState>,
+    clock: Arc<dyn Clock>,
+    http: reqwest::Client,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        let rules = rules
+            .into_iter()
+            .map(|rule| RuleState {
+                rule,
+                active: None,
+                last_fired: None,
+                last_price: None,
+                // Staleness counts from the server start for tokens never received
+                last_sample: now,
+                window: VecDeque::new(),
+            })
+            .collect();
+        Self {
+            rules,
+            clock,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Evaluate the rules of `token` against a new sample, sending the alerts
+    /// only if `notify`, when no other node of the cluster does
+    pub fn on_price(&mut self, token: &str, price: Decimal, confidence: Decimal, notify: bool) {
+        let now = self.clock.now();
+        let mut fired = Vec::new();
+        for state in self
+            .rules
+            .iter_mut()
+            .filter(|state| state.rule.token == token)
+        {
+            let previous = state.last_price.replace(price);
+            state.last_sample = now;
+            let (active, message) = match &state.rule.condition {
+                AlertCondition::CrossesAbove { level } => {
+                    // A first sample above the level is not a crossing
+                    if previous.is_none() {
+                        state.active = Some(price >= *level);
+                        continue;
+                    }
+                    (
+                        price >= *level,
+                        format!("{} crossed above {}", token, level),
+                    )
+                }
+                AlertCondition::CrossesBelow { level } => {
+                    if previous.is_none() {
+                        state.active = Some(price <= *level);
+                        continue;
+                    }
+                    (
+                        price <= *level,
+                        format!("{} crossed below {}", token, level),
+                    )
+                }
+                AlertCondition::ChangePct { pct, window_secs } => {
+                    let window = Duration::from_secs(*window_secs);
+                    while state
+                        .window
+                        .front()
+                        .is_some_and(|(at, _)| now.duration_since(*at) > window)
+                    {
+                        state.window.pop_front();
+                    }
+                    state.window.push_back((now, price));
+                    let oldest = state
+                        .window
+                        .front()
+                        .map(|(_, price)| *price)
+                        .unwrap_or(price);
+                    let (moved, sign) = match price.checked_sub(oldest) {
+                        Some(moved) => (moved, ""),
+                        None => (oldest.saturating_sub(price), "-"),
+                    };
+                    // None from a zero price, which moves no percentage
+                    let change = moved.checked_mul_div(Decimal::from(100), oldest);
+                    (
+                        change.is_some_and(|change| change >= *pct),
+                        format!(
+                            "{} moved {}{:.2}% in {}s",
+                            token,
+                            sign,
+                            change.unwrap_or_default().to_f64(),
+                            window_secs
+                        ),
+                    )
+                }
+                AlertCondition::Stale { secs } => (false, format!("{} stale for {}s", token, secs)),
+                AlertCondition::ConfidenceBelow { min } => (
+                    confidence < *min,
+                    format!("{} confidence {} below {}", token, confidence, min),
+                ),
+            };
+            if let Some(alert) = Self::update(state, active, message, now, &*self.clock) {
+                fired.push(alert);
+            }
+        }
+        if notify {
+            self.send(fired);
+        } else {
+            Self::skip(fired);
+        }
+    }
+
+    /// Evaluate the rules that don't need a new sample, sending the alerts of
+    /// the tokens `notify` accepts
+    pub fn on_tick(&mut self, notify: impl Fn(&str) -> bool) {
+        let now = self.clock.now();
+        let mut fired = Vec::new();
+        for state in &mut self.rules {
+            let AlertCondition::Stale { secs } = state.rule.condition else {
+                continue;
+            };
+            let silent = now.duration_since(state.last_sample);
+            let active = silent > Duration::from_secs(secs);
+            let message = format!("No {} price for {}s", state.rule.token, silent.as_secs());
+            if let Some(alert) = Self::update(state, active, message, now, &*self.clock) {
+                fired.push(alert);
+            }
+        }
+        let (sent, skipped) = fired
+            .into_iter()
+            .partition(|alert: &Alert| notify(&alert.token));
+        self.send(sent);
+        Self::skip(skipped);
+    }
+
+    /// Record the new condition state, returns the alert if the rule fires
+    fn update(
+        state: &mut RuleState,
+        active: bool,
+        message: String,
+        now: Instant,
+        clock: &dyn Clock,
+    ) -> Option<Alert> {
+        let was_active = state.active.replace(active).unwrap_or(false);
+        if !active || was_active {
+            return None;
+        }
+        let cooldown = Duration::from_secs(state.rule.cooldown_secs);
+        if state
+            .last_fired
+            .is_some_and(|last_fired| now.duration_since(last_fired) < cooldown)
+        {
+            info!("Alert {} in cooldown: {}", state.rule.name, message);
+            return None;
+        }
+        state.last_fired = Some(now);
+        Some(Alert {
+            rule: state.rule.name.clone(),
+            token: state.rule.token.clone(),
+            message,
+            price: state.last_price,
+            timestamp: clock.wall_now().timestamp(),
+        })
+    }
+
+    /// Alerts another node of the cluster sends
+    fn skip(fired: Vec<Alert>) {
+        for alert in fired {
+            info!(
+                "Alert {} left to the node serving {}",
+                alert.rule, alert.token
+            );
+        }
+    }
+
+    /// POST the alerts to their webhooks without waiting for the answers
+    fn send(&self, fired: Vec<Alert>) {
+        for alert in fired {
+            let Some(state) = self
+                .rules
+                .iter()
+                .find(|state| state.rule.name == alert.rule)
+            else {
+                continue;
+            };
+            info!("Alert {}: {}", alert.rule, alert.message);
+            let request = self.http.post(&state.rule.webhook).json(&alert);
+            let webhook = state.rule.webhook.clone();
+            tokio::spawn(async move {
+                match request.send().await {
+                    Ok(response) if !response.status().is_success() => warn!(
+                        "Webhook {} answered {} to alert {}",
+                        webhook,
+                        response.status(),
+                        alert.rule
+                    ),
+                    Ok(_) => {}
+                    Err(e) => warn!("Error sending alert {} to {}: {}", alert.rule, webhook, e),
+                }
+            });
+        }
+    }
+}
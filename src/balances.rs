@@ -0,0 +1,270 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::time::Duration;
+
+use crate::{
+    clock::Clock,
+    coin_type::CoinType,
+    decimal::Decimal,
+    errors::SwapError,
+    peer_registry::PeerRegistryHandle,
+    rates::RateBook,
+    sui_rpc::{self, SuiRpc},
+    tokens::TokenRegistry,
+};
+
+/// Balances by address, as read from the chain
+pub type Balances = BTreeMap<String, Vec<CoinBalance>>;
+
+/// Total balance of a coin type held by an address, in on-chain units
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoinBalance {
+    /// As the chain returns it, generic coins like LP tokens included
+    pub coin_type: String,
+    #[serde(with = "u128_string")]
+    pub raw_balance: u128,
+}
+
+/// Balance of a token of the tokens file, valued with its last USD price
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenBalance {
+    pub token: String,
+    pub coin_type: CoinType,
+    #[serde(with = "u128_string")]
+    pub raw_balance: u128,
+    /// From the tokens file, or from the price source if it doesn't set them
+    pub decimals: Option<u64>,
+    /// Whole tokens, unknown until the decimals are
+    pub amount: Option<f64>,
+    pub price_usd: Option<f64>,
+    pub value_usd: Option<f64>,
+}
+
+/// Holdings of one address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressPortfolio {
+    pub address: String,
+    pub tokens: Vec<TokenBalance>,
+    /// Value of the tokens with a price
+    pub total_usd: f64,
+    /// Coins not listed in the tokens file
+    pub other_coins: Vec<CoinBalance>,
+}
+
+/// Holdings of the tracked addresses and their totals by token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Portfolio {
+    /// Unix time the balances were read
+    pub updated_at: i64,
+    pub addresses: Vec<AddressPortfolio>,
+    /// Sum of every address by token
+    pub tokens: Vec<TokenBalance>,
+    pub total_usd: f64,
+    /// Tokens held without a price yet, left out of the totals
+    pub unpriced: Vec<String>,
+}
+
+/// u128 as a string like the JSON-RPC API does, the tagged admin messages
+/// can't hold u128 numbers
+mod u128_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Last balances read for the tracked addresses
+#[derive(Default)]
+pub struct BalanceBook {
+    updated_at: Option<i64>,
+    balances: Balances,
+}
+
+impl BalanceBook {
+    /// Replace the balances of the addresses read, the others keep theirs
+    pub fn update(&mut self, timestamp: i64, balances: Balances) {
+        self.updated_at = Some(timestamp);
+        self.balances.extend(balances);
+    }
+
+    /// Value the balances of every address, or only `address`, with the
+    /// latest prices
+    pub fn portfolio(
+        &self,
+        address: Option<&str>,
+        tokens: &TokenRegistry,
+        rates: &RateBook,
+    ) -> Result<Portfolio, SwapError> {
+        let updated_at = self.updated_at.ok_or(SwapError::NoBalances)?;
+        let selected: Vec<(&String, &Vec<CoinBalance>)> = match address {
+            Some(address) => {
+                let address = sui_rpc::normalize_address(address)?;
+                let entry = self
+                    .balances
+                    .get_key_value(&address)
+                    .ok_or(SwapError::UnknownAddress(address))?;
+                vec![entry]
+            }
+            None => self.balances.iter().collect(),
+        };
+
+        let mut totals: BTreeMap<&str, u128> = BTreeMap::new();
+        let addresses: Vec<AddressPortfolio> = selected
+            .into_iter()
+            .map(|(address, balances)| {
+                let mut held = Vec::new();
+                let mut values = Vec::new();
+                let mut other_coins = Vec::new();
+                for balance in balances {
+                    let listed = balance
+                        .coin_type
+                        .parse::<CoinType>()
+                        .ok()
+                        .and_then(|coin_type| tokens.by_coin_type(&coin_type));
+                    match listed {
+                        Some((token, _)) => {
+                            *totals.entry(token).or_default() += balance.raw_balance;
+                            let (token_balance, value_usd) =
+                                value(token, balance.raw_balance, tokens, rates);
+                            held.push(token_balance);
+                            values.push(value_usd);
+                        }
+                        None => other_coins.push(balance.clone()),
+                    }
+                }
+                AddressPortfolio {
+                    address: address.clone(),
+                    total_usd: total_usd(values),
+                    tokens: held,
+                    other_coins,
+                }
+            })
+            .collect();
+        let (tokens, values): (Vec<TokenBalance>, Vec<Option<Decimal>>) = totals
+            .into_iter()
+            .map(|(token, raw_balance)| value(token, raw_balance, tokens, rates))
+            .unzip();
+        Ok(Portfolio {
+            updated_at,
+            addresses,
+            total_usd: total_usd(values),
+            unpriced: tokens
+                .iter()
+                .filter(|held| held.value_usd.is_none())
+                .map(|held| held.token.clone())
+                .collect(),
+            tokens,
+        })
+    }
+}
+
+/// Balance of `token` and its exact USD value, if it has a price
+fn value(
+    token: &str,
+    raw_balance: u128,
+    tokens: &TokenRegistry,
+    rates: &RateBook,
+) -> (TokenBalance, Option<Decimal>) {
+    let config = tokens.get(token).expect("Found by coin type");
+    let quote = rates.usd(token);
+    let decimals = config.decimals.or(quote.map(|quote| quote.decimals));
+    let amount = decimals.and_then(|decimals| Decimal::from_raw(raw_balance, decimals));
+    let price_usd = quote.map(|quote| quote.price);
+    let value_usd = amount
+        .zip(price_usd)
+        .and_then(|(amount, price)| amount.checked_mul(price));
+    let balance = TokenBalance {
+        token: token.to_string(),
+        coin_type: config.coin_type.clone(),
+        raw_balance,
+        decimals,
+        amount: amount.map(Decimal::to_f64),
+        price_usd: price_usd.map(Decimal::to_f64),
+        value_usd: value_usd.map(Decimal::to_f64),
+    };
+    (balance, value_usd)
+}
+
+/// Sum of the values known, added as decimals
+fn total_usd(values: Vec<Option<Decimal>>) -> f64 {
+    values
+        .into_iter()
+        .flatten()
+        .try_fold(Decimal::ZERO, Decimal::checked_add)
+        .map_or(f64::MAX, Decimal::to_f64)
+}
+
+/// Reads the balances of an address from the Sui JSON-RPC endpoint
+pub struct BalanceReader {
+    rpc: SuiRpc,
+}
+
+impl BalanceReader {
+    pub fn new(rpc_url: &str) -> Self {
+        Self {
+            rpc: SuiRpc::new(rpc_url),
+        }
+    }
+
+    pub async fn read(&self, address: &str) -> Result<Vec<CoinBalance>, SwapError> {
+        const METHOD: &str = "suix_getAllBalances";
+        let result = self.rpc.call(METHOD, json!([address])).await?;
+        let invalid = || SwapError::RpcError(METHOD.to_string(), "unexpected answer".to_string());
+        result
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|balance| {
+                let coin_type = balance["coinType"].as_str().ok_or_else(invalid)?;
+                // Balances are u128 rendered as strings
+                let raw_balance = match &balance["totalBalance"] {
+                    Value::String(total) => total.parse().ok(),
+                    total => total.as_u64().map(u128::from),
+                };
+                Ok(CoinBalance {
+                    coin_type: coin_type.to_string(),
+                    raw_balance: raw_balance.ok_or_else(invalid)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read the balances of `addresses` every `interval` and hand them to the
+/// registry. Addresses that fail keep their previous balances.
+pub(crate) async fn track(
+    reader: BalanceReader,
+    addresses: Vec<String>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    peer_registry: PeerRegistryHandle,
+) {
+    loop {
+        let next = clock.now() + interval;
+        let mut balances = Balances::new();
+        for address in &addresses {
+            match reader.read(address).await {
+                Ok(read) => {
+                    balances.insert(address.clone(), read);
+                }
+                Err(balance_error) => {
+                    warn!("Failed to read balances of {}: {}", address, balance_error)
+                }
+            }
+        }
+        if !balances.is_empty() {
+            info!("Read balances of {} addresses", balances.len());
+            peer_registry.balances(balances);
+        }
+        clock.sleep_until(next).await;
+    }
+}
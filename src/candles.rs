@@ -0,0 +1,326 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::decimal::Decimal;
+
+/// Closed bars kept in memory per token and resolution
+const MAX_CANDLES: usize = 1000;
+
+/// Width of a candle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 4] = [
+        Resolution::OneMinute,
+        Resolution::FiveMinutes,
+        Resolution::OneHour,
+        Resolution::OneDay,
+    ];
+
+    pub fn secs(self) -> i64 {
+        match self {
+            Resolution::OneMinute => 60,
+            Resolution::FiveMinutes => 5 * 60,
+            Resolution::OneHour => 60 * 60,
+            Resolution::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the bar `at` falls in
+    fn bar_start(self, at: i64) -> i64 {
+        at - at.rem_euclid(self.secs())
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Resolution::OneMinute => "1m",
+            Resolution::FiveMinutes => "5m",
+            Resolution::OneHour => "1h",
+            Resolution::OneDay => "1d",
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Resolution::ALL
+            .into_iter()
+            .find(|resolution| resolution.as_str() == s)
+            .ok_or_else(|| format!("unknown resolution {}, expected 1m, 5m, 1h or 1d", s))
+    }
+}
+
+/// OHLC bar of the price samples received for a token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Candle {
+    /// Unix time the bar starts at
+    pub start: i64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    /// Samples aggregated in the bar
+    pub samples: u64,
+    pub avg_confidence: Decimal,
+}
+
+impl Candle {
+    fn new(start: i64, price: Decimal, confidence: Decimal) -> Self {
+        Self {
+            start,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            samples: 1,
+            avg_confidence: confidence,
+        }
+    }
+
+    fn add(&mut self, price: Decimal, confidence: Decimal) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.samples += 1;
+        // Mean of the samples from the mean of the previous ones, rounded
+        // only in the division
+        if let Some(average) = self
+            .avg_confidence
+            .checked_mul(Decimal::from(self.samples - 1))
+            .and_then(|sum| sum.checked_add(confidence))
+            .and_then(|sum| sum.checked_div(Decimal::from(self.samples)))
+        {
+            self.avg_confidence = average;
+        }
+    }
+}
+
+/// Bar that has just closed
+#[derive(Debug, Clone)]
+pub struct ClosedCandle {
+    pub token: String,
+    pub resolution: Resolution,
+    pub candle: Candle,
+}
+
+#[derive(Default)]
+struct Series {
+    closed: VecDeque<Candle>,
+    current: Option<Candle>,
+}
+
+/// Candles of every token and resolution, updated one sample at a time. Closed
+/// bars are appended to `<dir>/<token>-<resolution>.jsonl` when `dir` is set.
+pub struct CandleStore {
+    series: HashMap<(String, Resolution), Series>,
+    dir: Option<PathBuf>,
+}
+
+impl CandleStore {
+    /// Store kept only in memory
+    pub fn new() -> Self {
+        Self {
+            series: HashMap::new(),
+            dir: None,
+        }
+    }
+
+    /// Store persisted in `dir`, loading the bars saved there before
+    pub fn load(dir: &Path) -> Self {
+        let mut store = Self {
+            series: HashMap::new(),
+            dir: Some(dir.to_path_buf()),
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            // Nothing saved yet
+            return store;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some((token, resolution)) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.rsplit_once('-'))
+                .and_then(|(token, resolution)| Some((token, resolution.parse().ok()?)))
+            else {
+                continue;
+            };
+            let Ok(data) = fs::read_to_string(&path) else {
+                warn!("Error reading candles file {}", path.display());
+                continue;
+            };
+            let series = store
+                .series
+                .entry((token.to_string(), resolution))
+                .or_default();
+            for line in data.lines() {
+                match serde_json::from_str::<Candle>(line) {
+                    Ok(candle) => push_bounded(&mut series.closed, candle),
+                    Err(e) => warn!("Skipping bad candle in {}: {}", path.display(), e),
+                }
+            }
+            info!(
+                "Loaded {} {} candles for {}",
+                series.closed.len(),
+                resolution,
+                token
+            );
+        }
+        store
+    }
+
+    /// Aggregate a price sample received at unix time `at`, returns the bars
+    /// it closed
+    pub fn add_sample(
+        &mut self,
+        token: &str,
+        at: i64,
+        price: Decimal,
+        confidence: Decimal,
+    ) -> Vec<ClosedCandle> {
+        let mut closed = Vec::new();
+        for resolution in Resolution::ALL {
+            let start = resolution.bar_start(at);
+            let series = self
+                .series
+                .entry((token.to_string(), resolution))
+                .or_default();
+            // Late sample for a bar already closed
+            if series.closed.back().is_some_and(|last| start <= last.start) {
+                continue;
+            }
+            match &mut series.current {
+                Some(current) if current.start == start => current.add(price, confidence),
+                Some(current) if current.start > start => {}
+                current => {
+                    if let Some(candle) = current.replace(Candle::new(start, price, confidence)) {
+                        closed.push(ClosedCandle {
+                            token: token.to_string(),
+                            resolution,
+                            candle,
+                        });
+                    }
+                }
+            }
+        }
+        self.save(&closed);
+        closed
+    }
+
+    /// Close the bars whose period is over at unix time `now`
+    pub fn close_due(&mut self, now: i64) -> Vec<ClosedCandle> {
+        let mut closed = Vec::new();
+        for ((token, resolution), series) in &mut self.series {
+            if series
+                .current
+                .as_ref()
+                .is_some_and(|current| now >= current.start + resolution.secs())
+            {
+                let candle = series.current.take().expect("Checked above");
+                closed.push(ClosedCandle {
+                    token: token.clone(),
+                    resolution: *resolution,
+                    candle,
+                });
+            }
+        }
+        self.save(&closed);
+        closed
+    }
+
+    /// Last `limit` bars, oldest first, the one still open included
+    pub fn query(&self, token: &str, resolution: Resolution, limit: usize) -> Vec<Candle> {
+        let Some(series) = self.series.get(&(token.to_string(), resolution)) else {
+            return Vec::new();
+        };
+        let candles: Vec<Candle> = series
+            .closed
+            .iter()
+            .chain(series.current.as_ref())
+            .cloned()
+            .collect();
+        candles[candles.len().saturating_sub(limit)..].to_vec()
+    }
+
+    /// Close of the last bar starting at or before unix time `at`, from the
+    /// finest resolution that has one
+    pub fn price_at(&self, token: &str, at: i64) -> Option<Decimal> {
+        Resolution::ALL.into_iter().find_map(|resolution| {
+            let series = self.series.get(&(token.to_string(), resolution))?;
+            series
+                .closed
+                .iter()
+                .chain(series.current.as_ref())
+                .rev()
+                .find(|candle| candle.start <= at)
+                .map(|candle| candle.close)
+        })
+    }
+
+    fn save(&mut self, closed: &[ClosedCandle]) {
+        for closed_candle in closed {
+            let key = (closed_candle.token.clone(), closed_candle.resolution);
+            let series = self.series.entry(key).or_default();
+            push_bounded(&mut series.closed, closed_candle.candle.clone());
+            let Some(dir) = &self.dir else {
+                continue;
+            };
+            let path = dir.join(format!(
+                "{}-{}.jsonl",
+                closed_candle.token, closed_candle.resolution
+            ));
+            let mut line =
+                serde_json::to_string(&closed_candle.candle).expect("Impossible serializing error");
+            line.push('\n');
+            let result = fs::create_dir_all(dir).and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?
+                    .write_all(line.as_bytes())
+            });
+            if let Err(e) = result {
+                warn!("Error saving candle to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Default for CandleStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn push_bounded(candles: &mut VecDeque<Candle>, candle: Candle) {
+    if candles.len() == MAX_CANDLES {
+        candles.pop_front();
+    }
+    candles.push_back(candle);
+}
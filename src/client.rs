@@ -1,31 +1,95 @@
+use chrono::{DateTime, Utc};
 use futures::TryStreamExt;
 use futures_util::{future, pin_mut, StreamExt};
-use log::{debug, error, info};
-use serde_json::Value;
-use std::io::Read;
-use std::process;
-use std::{error::Error, fs::File};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-
-use crate::server::Tx;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, Duration},
+};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+        protocol::Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
 use crate::{
+    clock::{Clock, SystemClock},
+    config::{Config, PriceSourceConfig},
+    decimal::Decimal,
     errors::SwapError,
     messages::{SwapRequest, SwapResponse},
     models::TokenInfoResponse,
+    prices::{HttpPriceSource, PriceFetcher, PriceSource},
+    replay::{Recorder, ReplayPriceSource},
+    tls,
+    tokens::{TokenConfig, TokenRegistry},
 };
 
+type Tx = futures_channel::mpsc::UnboundedSender<Message>;
+
+/// Configured price sources by name
+type PriceSources = HashMap<String, Arc<dyn PriceSource>>;
+
+/// Client serving the price of a token to the server
 pub struct Client {
-    url: String,
     token: String,
-    token_balance_url: String,
+    config: Config,
+    /// Tokens given by the embedding application, the tokens file is read if not set
+    tokens: Option<Arc<TokenRegistry>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Builder for [`Client`], settings not given keep their `Config` defaults
+pub struct ClientBuilder {
+    token: String,
+    config: Config,
+    tokens: Option<TokenRegistry>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+/// Token the client is currently serving, it can be changed by the server
+struct ServedToken {
+    name: String,
+    config: TokenConfig,
+    source: Arc<dyn PriceSource>,
+    /// Last price sent to the server, used to check deviation between samples
+    last_price: Option<Decimal>,
+    /// Samples with an older upstream timestamp are discarded
+    max_age: Option<Duration>,
 }
 
 impl Client {
-    pub fn new(url: String, token: String, token_balance_url: String) -> Self {
+    pub fn new(token: String, config: Config) -> Self {
+        Self::with_clock(token, config, Arc::new(SystemClock))
+    }
+
+    fn with_clock(token: String, config: Config, clock: Arc<dyn Clock>) -> Self {
         Self {
-            url,
             token,
-            token_balance_url,
+            config,
+            tokens: None,
+            clock,
+        }
+    }
+
+    pub fn builder(token: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            token: token.into(),
+            config: Config::default(),
+            tokens: None,
+            clock: None,
         }
     }
 
@@ -34,8 +98,7 @@ impl Client {
         message: SwapResponse,
         ws_sender: Tx,
     ) -> Result<(), SwapError> {
-        let serialized_message =
-            bincode::serialize(&message).map_err(|e| SwapError::SerializeError(e))?;
+        let serialized_message = bincode::serialize(&message).map_err(SwapError::SerializeError)?;
         match ws_sender
             .unbounded_send(Message::binary(serialized_message))
             .map_err(|e| SwapError::SendRequestError(e.to_string()))
@@ -43,28 +106,83 @@ impl Client {
             Ok(_) => Ok(()),
             Err(error) => {
                 error!("Error sending token price to server: {}", error);
-                return Err(error);
+                Err(error)
             }
         }
     }
 
-    /// Main function for the client
-    pub async fn start(self) {
-        let token_address = self.get_token_address();
-        debug!("Token address: {}", token_address);
+    /// Main function for the client, returns when the server closes the
+    /// connection or doesn't accept our token. A lost hub is replaced by the
+    /// next one of `client.server_url` and `client.fallback_urls`.
+    pub async fn start(self) -> Result<(), SwapError> {
+        let sources = self.price_sources()?;
+        let served_token = Arc::new(Mutex::new(self.load_token(&sources, &self.token)?));
+        let urls: Vec<&str> = std::iter::once(&self.config.client.server_url)
+            .chain(&self.config.client.fallback_urls)
+            .map(String::as_str)
+            .collect();
+        let reconnect = Duration::from_secs(self.config.client.reconnect_secs);
 
-        // Websocket connection with server
-        let (ws_stream, _) = connect_async(&self.url)
-            .await
-            .expect("Failed to connect to server");
-        info!("WebSocket handshake has been successfully completed");
-        //
+        let mut failed = 0;
+        let mut failing_over = false;
+        for url in urls.iter().cycle() {
+            let registered = AtomicBool::new(false);
+            let result = match self.connect(url).await {
+                Ok(ws_stream) => {
+                    info!("Connected to hub {}", url);
+                    failed = 0;
+                    self.serve_hub(ws_stream, &sources, &served_token, &registered)
+                        .await
+                }
+                Err(connect_error) => {
+                    warn!("Can't reach hub {}: {}", url, connect_error);
+                    failed += 1;
+                    if failed < urls.len() {
+                        continue;
+                    }
+                    // No hub answered in a whole round
+                    if reconnect.is_zero() {
+                        return Err(connect_error);
+                    }
+                    failed = 0;
+                    sleep(reconnect).await;
+                    continue;
+                }
+            };
+            match result {
+                Err(SwapError::WsError(ws_error)) => {
+                    warn!("Connection with hub {} lost: {}", url, ws_error);
+                    failing_over = true;
+                }
+                // The hub taking over may not know yet the old one is gone
+                Err(SwapError::TokenTaken(token))
+                    if failing_over && !registered.load(Ordering::Relaxed) =>
+                {
+                    warn!("{} still registered through hub {}, retrying", token, url);
+                    sleep(reconnect).await;
+                }
+                result => return result,
+            }
+        }
+        unreachable!("There is always a server_url")
+    }
+
+    /// Serve the token on the connection with a hub until it ends, setting
+    /// `registered` once the hub accepts it
+    async fn serve_hub(
+        &self,
+        ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+        sources: &PriceSources,
+        served_token: &Arc<Mutex<ServedToken>>,
+        registered: &AtomicBool,
+    ) -> Result<(), SwapError> {
         let (tx, rx) = futures_channel::mpsc::unbounded();
 
         let (outgoing, incoming) = ws_stream.split();
         // Send messages to Server
         let in_to_ws = rx.map(Ok).forward(outgoing);
         // Receive messages from Server
+        let incoming = incoming.map_err(|e| SwapError::WsError(Box::new(e)));
         let ws_to_server = incoming.try_for_each(|msg| {
             info!("Received a message from server");
             match msg {
@@ -75,26 +193,89 @@ impl Client {
                             // Send token price to server
                             SwapRequest::TokenPrice => {
                                 tokio::spawn(Client::get_token_price(
-                                    self.token_balance_url.clone(),
-                                    token_address.clone(),
+                                    served_token.clone(),
+                                    self.clock.clone(),
                                     tx.clone(),
                                 ));
                             }
                             // Send Token Name to Server
                             SwapRequest::WhichToken => {
-                                let response = SwapResponse::WhichToken(self.token.clone());
+                                let name = served_token
+                                    .lock()
+                                    .expect("Served token mutex poisoned")
+                                    .name
+                                    .clone();
+                                let secret = self
+                                    .config
+                                    .client
+                                    .reclaim_file
+                                    .as_deref()
+                                    .and_then(|path| saved_secret(path, &name));
+                                let response = match secret {
+                                    Some(secret) => SwapResponse::ReclaimToken {
+                                        token: name,
+                                        secret,
+                                    },
+                                    None => SwapResponse::WhichToken(name),
+                                };
                                 Client::send_swap_response_message(response, tx.clone())
                                     .expect("Error sending WhichToken message to server");
                             }
+                            // Secret to get the token back after a server restart
+                            SwapRequest::ReclaimSecret { token, secret } => {
+                                if let Some(path) = &self.config.client.reclaim_file {
+                                    if let Err(save_error) = save_secret(path, &token, &secret) {
+                                        warn!(
+                                            "Error saving reclaim secret to {}: {}",
+                                            path.display(),
+                                            save_error
+                                        );
+                                    }
+                                }
+                            }
                             // Server responded our token is valid
                             SwapRequest::ValidToken => {
                                 info!("Received ValidToken message from server");
+                                registered.store(true, Ordering::Relaxed);
                             }
                             // Server responded our token is invalid
                             SwapRequest::RepeatedToken => {
                                 error!("Received RepeatedToken message from server");
                                 // Finish the connection
-                                process::exit(0);
+                                let name = served_token
+                                    .lock()
+                                    .expect("Served token mutex poisoned")
+                                    .name
+                                    .clone();
+                                return future::err(SwapError::TokenTaken(name));
+                            }
+                            // Server doesn't know our token or has it disabled
+                            SwapRequest::UnknownToken => {
+                                error!("Received UnknownToken message from server");
+                                let name = served_token
+                                    .lock()
+                                    .expect("Served token mutex poisoned")
+                                    .name
+                                    .clone();
+                                return future::err(SwapError::TokenRejected(name));
+                            }
+                            // Only sent to relaying hubs
+                            SwapRequest::RelayedPrice(_) => {
+                                warn!("Ignoring relayed price, this client doesn't relay");
+                            }
+                            // Server moved us to another token
+                            SwapRequest::AssignToken(name) => {
+                                match self.load_token(sources, &name) {
+                                    Ok(new_token) => {
+                                        info!("Serving token {} from now on", name);
+                                        *served_token
+                                            .lock()
+                                            .expect("Served token mutex poisoned") = new_token;
+                                    }
+                                    Err(error) => {
+                                        error!("Error loading assigned token {}: {}", name, error);
+                                    }
+                                }
                             }
                         }
                     }
@@ -106,6 +287,11 @@ impl Client {
                         return future::ok(());
                     }
                 },
+                Message::Close(_) => {
+                    info!("Server closed the connection");
+                }
+                // Keepalive, tungstenite answers pings on its own
+                Message::Ping(_) | Message::Pong(_) => {}
                 _ => {
                     error!("Received a non-binary message from server");
                 }
@@ -115,54 +301,324 @@ impl Client {
 
         // Listen in both futures, outcoming and incoming messages
         pin_mut!(in_to_ws, ws_to_server);
-        future::select(in_to_ws, ws_to_server).await;
+        match future::select(in_to_ws, ws_to_server).await {
+            future::Either::Left((result, _)) => {
+                result.map_err(|e| SwapError::WsError(Box::new(e)))
+            }
+            future::Either::Right((result, _)) => result,
+        }
+    }
+
+    /// Open the WS connection to `url`, presenting the auth token if configured
+    async fn connect(
+        &self,
+        url: &str,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, SwapError> {
+        connect_hub(
+            url,
+            self.config.auth.token.as_deref(),
+            "auth.token",
+            self.config.client.ca_cert.as_deref(),
+        )
+        .await
     }
 
-    /// Get token address from tokens.json file
-    fn get_token_address(&self) -> String {
-        let mut file = File::open("tokens.json").expect("Unable to open tokens file");
-        let mut data = String::new();
-        file.read_to_string(&mut data)
-            .expect("Unable to read  tokens file");
-        let v: Value = serde_json::from_str(&data).expect("JSON was not well-formatted");
-        let token_address = v[&self.token]
-            .as_str()
-            .expect("Token Selected is not listed in tokens.json");
-        token_address.to_string()
+    /// Build every configured price source, sharing one fetcher between the HTTP ones
+    fn price_sources(&self) -> Result<PriceSources, SwapError> {
+        let mut fetcher = PriceFetcher::new(
+            Duration::from_secs(self.config.client.price_cache_ttl_secs),
+            Duration::from_secs(self.config.client.default_retry_after_secs),
+            self.clock.clone(),
+        );
+        if let Some(record_file) = &self.config.client.record_file {
+            info!("Recording prices to {}", record_file.display());
+            fetcher = fetcher.record_to(Recorder::create(record_file)?);
+        }
+        let fetcher = Arc::new(fetcher);
+        let mut sources = PriceSources::new();
+        for (name, source_config) in &self.config.price_sources {
+            let source: Arc<dyn PriceSource> = match (&source_config.url, &source_config.replay) {
+                (Some(url), _) => Arc::new(HttpPriceSource::new(url.clone(), fetcher.clone())),
+                (None, Some(replay)) => {
+                    info!("Replaying {} for source {}", replay.display(), name);
+                    Arc::new(ReplayPriceSource::load(
+                        replay,
+                        source_config.speed,
+                        self.clock.clone(),
+                    )?)
+                }
+                (None, None) => {
+                    return Err(SwapError::InvalidConfig(format!(
+                        "price_sources.{} needs either url or replay",
+                        name
+                    )))
+                }
+            };
+            sources.insert(name.clone(), source);
+        }
+        Ok(sources)
     }
 
-    /// Get token price from token_balance_url
+    /// Get a token entry from the tokens file and the price source to use for it
+    fn load_token(&self, sources: &PriceSources, name: &str) -> Result<ServedToken, SwapError> {
+        let config = match &self.tokens {
+            Some(registry) => registry.get(name)?.clone(),
+            // Read the file every time so edits are picked up on reassignment
+            None => TokenRegistry::load(&self.config.tokens_file)?
+                .get(name)?
+                .clone(),
+        };
+        debug!("Token address: {}", config.coin_type);
+        let source_name = self.config.price_source(&config.price_sources)?;
+        let source = sources[source_name].clone();
+        Ok(ServedToken {
+            name: name.to_string(),
+            config,
+            source,
+            last_price: None,
+            max_age: Some(Duration::from_secs(self.config.client.max_price_age_secs))
+                .filter(|max_age| !max_age.is_zero()),
+        })
+    }
+
+    /// Get token price from the token price source
     async fn get_token_price(
-        token_balance_url: String,
-        token_address: String,
-        tx: futures_channel::mpsc::UnboundedSender<Message>,
+        served_token: Arc<Mutex<ServedToken>>,
+        clock: Arc<dyn Clock>,
+        tx: Tx,
     ) -> Result<(), SwapError> {
-        let full_url = format!("{}{}", token_balance_url, token_address);
-        info!("Getting token price from: {}", full_url);
-        let response = match reqwest::get(&full_url)
-            .await
-            // .map_err(|e| SwapError::SendRequestError(e.to_string()))
-        {
-            Ok(response) => response,
-            Err(error) => {
-                error!("Error getting token price: {:?}", error);
-                error!("ERROR SOURCE: {:?}", error.source());
-                return Err(SwapError::SendRequestError(error.to_string()));
-            }
+        let (source, coin_type) = {
+            let served_token = served_token.lock().expect("Served token mutex poisoned");
+            (
+                served_token.source.clone(),
+                served_token.config.coin_type.clone(),
+            )
         };
-        let token_price_result = response
-            .json::<TokenInfoResponse>()
-            .await
-            .map_err(|e| SwapError::ParseResponseError(e));
-        let token_price = match token_price_result {
+        let token_price = match source.fetch(&coin_type).await {
             Ok(token_price) => token_price,
             Err(error) => {
-                error!("Error parsing token price: {}", error);
+                error!("Error getting token price: {}", error);
+                if let Some(source) = error.source() {
+                    error!("ERROR SOURCE: {:?}", source);
+                }
                 return Err(error);
             }
         };
         info!("Token price: {}", token_price);
+        let mut served_token = served_token.lock().expect("Served token mutex poisoned");
+        if !Client::check_token_price(&mut served_token, &token_price, clock.wall_now()) {
+            return Ok(());
+        }
         let message = SwapResponse::TokenPrice(token_price);
         Client::send_swap_response_message(message, tx)
     }
+
+    /// Check upstream data against the token entry, false if the sample must be discarded
+    fn check_token_price(
+        served_token: &mut ServedToken,
+        token_price: &TokenInfoResponse,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let token_config = &served_token.config;
+        let Some(info) = token_price.get(&token_config.coin_type) else {
+            warn!("No price returned for {}", token_config.coin_type);
+            return false;
+        };
+        if let Some(max_age) = served_token.max_age {
+            let age = now.timestamp() - info.timestamp.0 as i64;
+            if age > max_age.as_secs() as i64 {
+                warn!(
+                    "Discarding {} price {}: sampled {}s ago at {}",
+                    token_config.symbol, info.price, age, info.timestamp
+                );
+                return false;
+            }
+        }
+        if let Some(decimals) = token_config.decimals {
+            if decimals != info.decimals {
+                warn!(
+                    "Decimals mismatch for {}: expected {}, upstream reports {}",
+                    token_config.symbol, decimals, info.decimals
+                );
+            }
+        }
+        if let Some(previous) = served_token.last_price {
+            if !previous.is_zero() {
+                let moved = match info.price.checked_sub(previous) {
+                    Some(moved) => moved,
+                    None => previous.saturating_sub(info.price),
+                };
+                // Percent of the last price, too large to fit is past any limit
+                let deviation = moved
+                    .checked_mul_div(Decimal::from(100), previous)
+                    .unwrap_or(Decimal::MAX);
+                if let Some(reject_pct) = token_config.deviation.reject_pct {
+                    if deviation > reject_pct {
+                        warn!(
+                            "Discarding {} price {}: {:.2}% away from last price {}",
+                            token_config.symbol,
+                            info.price,
+                            deviation.to_f64(),
+                            previous
+                        );
+                        return false;
+                    }
+                }
+                if let Some(warn_pct) = token_config.deviation.warn_pct {
+                    if deviation > warn_pct {
+                        warn!(
+                            "{} price moved {:.2}% since last sample",
+                            token_config.symbol,
+                            deviation.to_f64()
+                        );
+                    }
+                }
+            }
+        }
+        served_token.last_price = Some(info.price);
+        true
+    }
+}
+
+/// Reclaim secrets saved in `path`, by token
+fn saved_secrets(path: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn saved_secret(path: &Path, token: &str) -> Option<String> {
+    saved_secrets(path).remove(token)
+}
+
+/// Keep the reclaim secret of `token` along the saved ones of other tokens
+fn save_secret(path: &Path, token: &str, secret: &str) -> std::io::Result<()> {
+    let mut secrets = saved_secrets(path);
+    secrets.insert(token.to_string(), secret.to_string());
+    let data = serde_json::to_string_pretty(&secrets).expect("Impossible serializing error");
+    fs::write(path, data)
+}
+
+/// Open a WS connection to the hub at `url` like a client does, presenting
+/// `auth_token`, read from the `token_setting` config key, if given
+pub(crate) async fn connect_hub(
+    url: &str,
+    auth_token: Option<&str>,
+    token_setting: &str,
+    ca_cert: Option<&Path>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, SwapError> {
+    let mut request = url
+        .into_client_request()
+        .map_err(|e| SwapError::WsError(Box::new(e)))?;
+    if let Some(auth_token) = auth_token {
+        let value = HeaderValue::from_str(&format!("Bearer {}", auth_token)).map_err(|_| {
+            SwapError::InvalidConfig(format!("{} is not a valid header value", token_setting))
+        })?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    let connector = match ca_cert {
+        Some(ca_cert) => Some(tls::client_connector(ca_cert)?),
+        None => None,
+    };
+    let (ws_stream, _) = connect_async_tls_with_config(request, None, false, connector)
+        .await
+        .map_err(|e| SwapError::WsError(Box::new(e)))?;
+    Ok(ws_stream)
+}
+
+impl ClientBuilder {
+    /// Start from a whole config instead of the defaults
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn server_url(mut self, url: impl Into<String>) -> Self {
+        self.config.client.server_url = url.into();
+        self
+    }
+
+    /// Hub to switch to when the connection with the others is lost, tried
+    /// in the order they're added
+    pub fn fallback_url(mut self, url: impl Into<String>) -> Self {
+        self.config.client.fallback_urls.push(url.into());
+        self
+    }
+
+    /// Wait before another round over the hubs once none of them answers,
+    /// zero gives up instead
+    pub fn reconnect(mut self, wait: Duration) -> Self {
+        self.config.client.reconnect_secs = wait.as_secs();
+        self
+    }
+
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.config.auth.token = Some(token.into());
+        self
+    }
+
+    /// Add or replace a price source, tokens reference it by `name`
+    pub fn price_source(mut self, name: impl Into<String>, url: impl Into<String>) -> Self {
+        self.config
+            .price_sources
+            .insert(name.into(), PriceSourceConfig::http(url));
+        self
+    }
+
+    /// Add or replace a price source playing back a recording
+    pub fn replay_source(
+        mut self,
+        name: impl Into<String>,
+        path: impl Into<PathBuf>,
+        speed: f64,
+    ) -> Self {
+        self.config
+            .price_sources
+            .insert(name.into(), PriceSourceConfig::replay(path, speed));
+        self
+    }
+
+    /// Append every response fetched from price sources to a JSONL file
+    pub fn record_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.client.record_file = Some(path.into());
+        self
+    }
+
+    /// Keep the reclaim secrets handed out by the server in this file
+    pub fn reclaim_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.client.reclaim_file = Some(path.into());
+        self
+    }
+
+    pub fn price_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.config.client.price_cache_ttl_secs = ttl.as_secs();
+        self
+    }
+
+    /// Discard prices sampled upstream longer than `max_age` ago, zero disables it
+    pub fn max_price_age(mut self, max_age: Duration) -> Self {
+        self.config.client.max_price_age_secs = max_age.as_secs();
+        self
+    }
+
+    /// Time source for the price cache and staleness checks
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Use these tokens instead of reading the tokens file
+    pub fn tokens(mut self, tokens: TokenRegistry) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    pub fn build(self) -> Result<Client, SwapError> {
+        self.config.validate()?;
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let mut client = Client::with_clock(self.token, self.config, clock);
+        client.tokens = self.tokens.map(Arc::new);
+        Ok(client)
+    }
 }
@@ -0,0 +1,91 @@
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::sync::Arc;
+use tokio::{
+    sync::watch,
+    time::{Duration, Instant},
+};
+
+/// Source of time for scheduling (polls, keepalives, caches) and for checking
+/// how old upstream timestamps are.
+pub trait Clock: Send + Sync {
+    /// Monotonic time used for schedules and timeouts
+    fn now(&self) -> Instant;
+    /// Wall clock time, compared with the timestamps reported by price sources
+    fn wall_now(&self) -> DateTime<Utc>;
+    /// Completes once `now()` reaches `deadline`
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// Real time. Schedules go through tokio, so they also follow tokio paused
+/// time (`#[tokio::test(start_paused = true)]`), but the wall clock doesn't.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline).boxed()
+    }
+}
+
+/// Clock that only moves when [`ManualClock::advance`] is called, so tests
+/// can go through hours of polling while network I/O runs in real time.
+pub struct ManualClock {
+    start: Instant,
+    wall_start: DateTime<Utc>,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl ManualClock {
+    /// Clock whose wall time starts at `wall_start`
+    pub fn new(wall_start: DateTime<Utc>) -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            wall_start,
+            elapsed: watch::Sender::new(Duration::ZERO),
+        })
+    }
+
+    /// Move the clock forward, waking whoever sleeps until a time now reached
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    /// Time advanced since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.borrow()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn wall_now(&self) -> DateTime<Utc> {
+        // Durations from `advance` always fit in chrono's range in practice
+        self.wall_start + chrono::Duration::from_std(self.elapsed()).unwrap_or_default()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let start = self.start;
+        let mut elapsed = self.elapsed.subscribe();
+        async move {
+            while start + *elapsed.borrow_and_update() < deadline {
+                if elapsed.changed().await.is_err() {
+                    // The clock is gone, time won't move anymore
+                    future::pending::<()>().await;
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,422 @@
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+    time::{sleep, Duration, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{handshake::server::Request, protocol::Message};
+
+use crate::{
+    client,
+    config::ClusterConfig,
+    models::TokenInfoInnerResponse,
+    peer_queue::{self, OverflowPolicy, PeerReceiver, PeerSender},
+    peer_registry::PeerRegistryHandle,
+    server::Server,
+};
+
+/// Wait before dialing a node again after its link failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Messages kept per link while the other node is unreachable
+const LINK_CAPACITY: usize = 256;
+
+/// Message sent by a node to every other node of the cluster, bincode encoded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClusterMessage {
+    /// Tokens registered on the node, sent on every change and as heartbeat
+    State {
+        node: String,
+        /// Start time of the node, the state of a restarted node replaces the
+        /// one it had before
+        incarnation: i64,
+        registrations: Vec<Registration>,
+    },
+    /// Price received by the node from the peer serving `token`
+    Price {
+        node: String,
+        token: String,
+        /// Unix time the node received it
+        at: i64,
+        info: TokenInfoInnerResponse,
+    },
+}
+
+impl ClusterMessage {
+    /// Key used to coalesce queued messages while a node is unreachable
+    fn coalesce_key(&self) -> String {
+        match self {
+            // Only the last state matters
+            ClusterMessage::State { .. } => "state".to_string(),
+            ClusterMessage::Price { token, .. } => format!("price:{}", token),
+        }
+    }
+}
+
+/// Token served by a client connected to a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub token: String,
+    pub peer: SocketAddr,
+}
+
+/// Cluster as seen by a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterStatus {
+    pub node: String,
+    /// Other nodes heard from, sorted by id
+    pub nodes: Vec<NodeInfo>,
+    /// Tokens registered on any node, sorted by token
+    pub registrations: Vec<ClusterRegistration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub node: String,
+    /// Heard from within `cluster.node_timeout_secs`
+    pub up: bool,
+    /// Seconds since its last message
+    pub last_seen_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterRegistration {
+    pub token: String,
+    pub node: String,
+    pub peer: SocketAddr,
+    /// False once the node is lost, the token is kept until it's registered
+    /// on another node
+    pub node_up: bool,
+}
+
+/// Last state received from another node
+struct RemoteNode {
+    incarnation: i64,
+    registrations: Vec<Registration>,
+    last_seen: Instant,
+    up: bool,
+}
+
+/// This node's side of the cluster, owned by the peer registry: the links
+/// sending its messages to the other nodes and the state they sent back
+pub(crate) struct ClusterNode {
+    node: String,
+    incarnation: i64,
+    heartbeat: Duration,
+    node_timeout: Duration,
+    links: Vec<PeerSender>,
+    remote: HashMap<String, RemoteNode>,
+}
+
+impl ClusterNode {
+    /// Start dialing the configured peers, they get every message broadcast
+    /// from now on
+    pub(crate) fn connect(config: &ClusterConfig, node: String, incarnation: i64) -> Self {
+        let token: Arc<str> = Arc::from(config.token.as_deref().unwrap_or_default());
+        let links = config
+            .peers
+            .iter()
+            .map(|url| {
+                let (tx, rx) = peer_queue::channel(LINK_CAPACITY, OverflowPolicy::CoalesceLatest);
+                tokio::spawn(dial(url.clone(), token.clone(), config.ca_cert.clone(), rx));
+                tx
+            })
+            .collect();
+        info!("Cluster node {} with {} peers", node, config.peers.len());
+        Self {
+            node,
+            incarnation,
+            heartbeat: Duration::from_secs(config.heartbeat_secs),
+            node_timeout: Duration::from_secs(config.node_timeout_secs),
+            links,
+            remote: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn node(&self) -> &str {
+        &self.node
+    }
+
+    pub(crate) fn heartbeat(&self) -> Duration {
+        self.heartbeat
+    }
+
+    /// Send the tokens registered on this node to the others
+    pub(crate) fn send_state(&self, registrations: Vec<Registration>) {
+        self.broadcast(ClusterMessage::State {
+            node: self.node.clone(),
+            incarnation: self.incarnation,
+            registrations,
+        });
+    }
+
+    pub(crate) fn send_price(&self, token: &str, at: i64, info: &TokenInfoInnerResponse) {
+        self.broadcast(ClusterMessage::Price {
+            node: self.node.clone(),
+            token: token.to_string(),
+            at,
+            info: info.clone(),
+        });
+    }
+
+    fn broadcast(&self, message: ClusterMessage) {
+        let bytes = bincode::serialize(&message).expect("Impossible serializing error");
+        let key = message.coalesce_key();
+        for link in &self.links {
+            link.send(Message::Binary(bytes.clone()), Some(&key));
+        }
+    }
+
+    /// Keep the state sent by another node, false if it's ours or older than
+    /// the one we have
+    pub(crate) fn update(
+        &mut self,
+        node: String,
+        incarnation: i64,
+        registrations: Vec<Registration>,
+        now: Instant,
+    ) -> bool {
+        if node == self.node {
+            warn!(
+                "Cluster state with our own node id {}, check cluster.peers",
+                node
+            );
+            return false;
+        }
+        if let Some(remote) = self.remote.get_mut(&node) {
+            if incarnation < remote.incarnation {
+                return false;
+            }
+            if !remote.up {
+                info!("Cluster node {} is back", node);
+            }
+            *remote = RemoteNode {
+                incarnation,
+                registrations,
+                last_seen: now,
+                up: true,
+            };
+        } else {
+            info!("Cluster node {} joined", node);
+            let remote = RemoteNode {
+                incarnation,
+                registrations,
+                last_seen: now,
+                up: true,
+            };
+            self.remote.insert(node, remote);
+        }
+        true
+    }
+
+    /// A node sent something, it's still up
+    pub(crate) fn seen(&mut self, node: &str, now: Instant) {
+        if let Some(remote) = self.remote.get_mut(node) {
+            remote.last_seen = now;
+        }
+    }
+
+    /// Mark the nodes silent for longer than the timeout as lost
+    pub(crate) fn expire(&mut self, now: Instant) {
+        for (node, remote) in &mut self.remote {
+            if remote.up && now.duration_since(remote.last_seen) > self.node_timeout {
+                warn!(
+                    "Cluster node {} lost, {} tokens can be registered again",
+                    node,
+                    remote.registrations.len()
+                );
+                remote.up = false;
+            }
+        }
+    }
+
+    /// Up node other than this one where `token` is registered
+    pub(crate) fn owner(&self, token: &str) -> Option<&str> {
+        self.remote
+            .iter()
+            .filter(|(_, remote)| remote.up)
+            .find(|(_, remote)| remote.registrations.iter().any(|r| r.token == token))
+            .map(|(node, _)| node.as_str())
+    }
+
+    /// Whether this node sorts first among the up nodes, the one acting for
+    /// the cluster when no node serves a token
+    pub(crate) fn leads(&self) -> bool {
+        self.remote
+            .iter()
+            .filter(|(_, remote)| remote.up)
+            .all(|(node, _)| *node > self.node)
+    }
+
+    /// Status with `local` as the registrations of this node. Tokens of lost
+    /// nodes are listed until they're registered on an up node.
+    pub(crate) fn status(&self, local: Vec<Registration>, now: Instant) -> ClusterStatus {
+        let mut nodes: Vec<NodeInfo> = self
+            .remote
+            .iter()
+            .map(|(node, remote)| NodeInfo {
+                node: node.clone(),
+                up: remote.up,
+                last_seen_secs: now.duration_since(remote.last_seen).as_secs(),
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.node.cmp(&b.node));
+
+        let local = local
+            .into_iter()
+            .map(|registration| (&self.node, true, registration));
+        let remote = self.remote.iter().flat_map(|(node, remote)| {
+            remote
+                .registrations
+                .iter()
+                .map(move |registration| (node, remote.up, registration.clone()))
+        });
+        let mut by_token: HashMap<String, ClusterRegistration> = HashMap::new();
+        for (node, node_up, registration) in local.chain(remote) {
+            let entry = ClusterRegistration {
+                token: registration.token,
+                node: node.clone(),
+                peer: registration.peer,
+                node_up,
+            };
+            match by_token.get(&entry.token) {
+                // An up node wins over a lost one
+                Some(existing) if existing.node_up || !node_up => {}
+                _ => {
+                    by_token.insert(entry.token.clone(), entry);
+                }
+            }
+        }
+        let mut registrations: Vec<ClusterRegistration> = by_token.into_values().collect();
+        registrations.sort_by(|a, b| a.token.cmp(&b.token));
+        ClusterStatus {
+            node: self.node.clone(),
+            nodes,
+            registrations,
+        }
+    }
+}
+
+impl Drop for ClusterNode {
+    fn drop(&mut self) {
+        // Ends the dial tasks, closing the links
+        for link in &self.links {
+            link.close();
+        }
+    }
+}
+
+/// Send the messages queued for the node at `url`, reconnecting until the
+/// queue is closed
+async fn dial(url: String, token: Arc<str>, ca_cert: Option<PathBuf>, rx: PeerReceiver) {
+    loop {
+        let connected =
+            client::connect_hub(&url, Some(&token), "cluster.token", ca_cert.as_deref()).await;
+        match connected {
+            Ok(mut ws_stream) => {
+                info!("Connected to cluster node {}", url);
+                loop {
+                    let Some(message) = rx.recv().await else {
+                        let _ = ws_stream.close(None).await;
+                        return;
+                    };
+                    if let Err(send_error) = ws_stream.send(message).await {
+                        warn!("Cluster link to {} failed: {}", url, send_error);
+                        break;
+                    }
+                }
+            }
+            Err(connect_error) => {
+                info!("Can't reach cluster node {}: {}", url, connect_error);
+            }
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Accept the links of the other nodes, passing their messages to the
+/// registry, over TLS with the acceptor of the server if it has one
+pub(crate) async fn serve(
+    listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    token: Arc<str>,
+    peer_registry: PeerRegistryHandle,
+) {
+    // Links end with this task, like a lost node
+    let mut links = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(_) = links.join_next() => continue,
+        };
+        match accepted {
+            Ok((stream, addr)) => {
+                links.spawn(accept_link(
+                    tls_acceptor.clone(),
+                    stream,
+                    addr,
+                    token.clone(),
+                    peer_registry.clone(),
+                ));
+            }
+            Err(e) => {
+                warn!("Error accepting cluster connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn accept_link(
+    tls_acceptor: Option<TlsAcceptor>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    token: Arc<str>,
+    peer_registry: PeerRegistryHandle,
+) {
+    match tls_acceptor {
+        Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+            Ok(tls_stream) => handle_link(tls_stream, addr, token, peer_registry).await,
+            Err(tls_error) => warn!("TLS handshake with node {} failed: {}", addr, tls_error),
+        },
+        None => handle_link(stream, addr, token, peer_registry).await,
+    }
+}
+
+async fn handle_link<S>(
+    stream: S,
+    addr: SocketAddr,
+    token: Arc<str>,
+    peer_registry: PeerRegistryHandle,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    #[allow(clippy::result_large_err)]
+    let check_auth =
+        |request: &Request, response| Server::check_auth(Some(&token), request, response);
+    let mut ws_stream = match tokio_tungstenite::accept_hdr_async(stream, check_auth).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!("Rejected cluster connection from {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Cluster link from {}", addr);
+    while let Some(Ok(msg)) = ws_stream.next().await {
+        if let Message::Binary(bytes) = msg {
+            match bincode::deserialize::<ClusterMessage>(&bytes) {
+                Ok(message) => peer_registry.cluster(message),
+                Err(deserialize_error) => {
+                    warn!(
+                        "Error deserializing cluster message from {}: {}",
+                        addr, deserialize_error
+                    );
+                }
+            }
+        }
+    }
+    info!("Cluster link from {} closed", addr);
+}
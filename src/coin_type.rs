@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::{errors::SwapError, sui_rpc};
+
+/// Sui coin type, `address::module::Name`, with the address normalized to 0x
+/// and 64 hex digits so `0x2::sui::SUI` and its long form are the same type
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct CoinType {
+    address: String,
+    module: String,
+    name: String,
+}
+
+impl CoinType {
+    /// Package address, 0x and 64 lowercase hex digits
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether a key of a price response is this coin type, with or without
+    /// a chain prefix like `sui:`
+    pub fn matches_key(&self, key: &str) -> bool {
+        let coin_type = key.strip_prefix("sui:").unwrap_or(key);
+        coin_type
+            .parse::<CoinType>()
+            .is_ok_and(|parsed| parsed == *self)
+    }
+}
+
+impl FromStr for CoinType {
+    type Err = SwapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SwapError::InvalidCoinType(s.to_string());
+        let mut parts = s.split("::");
+        let (Some(address), Some(module), Some(name), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let is_identifier = |s: &str| {
+            s.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !is_identifier(module) || !is_identifier(name) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            address: sui_rpc::normalize_address(address).map_err(|_| invalid())?,
+            module: module.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for CoinType {
+    type Error = SwapError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CoinType> for String {
+    fn from(coin_type: CoinType) -> Self {
+        coin_type.to_string()
+    }
+}
+
+impl fmt::Display for CoinType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}::{}::{}", self.address, self.module, self.name)
+    }
+}
@@ -0,0 +1,725 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{alerts::AlertRule, errors::SwapError, peer_queue::OverflowPolicy, sui_rpc};
+
+pub const DEFAULT_CONFIG_FILE: &str = "sui-swap.toml";
+pub const DEFAULT_PRICE_SOURCE: &str = "defillama";
+const DEFAULT_PRICE_SOURCE_URL: &str = "https://coins.llama.fi/prices/current/sui:";
+
+// Env vars overriding values from the config file
+const LISTEN_ENV: &str = "SUI_SWAP_LISTEN";
+const POLL_INTERVAL_ENV: &str = "SUI_SWAP_POLL_INTERVAL_SECS";
+const SERVER_URL_ENV: &str = "SUI_SWAP_SERVER_URL";
+const AUTH_TOKEN_ENV: &str = "SUI_SWAP_AUTH_TOKEN";
+const STORAGE_PATH_ENV: &str = "SUI_SWAP_STORAGE_PATH";
+const TOKENS_FILE_ENV: &str = "SUI_SWAP_TOKENS_FILE";
+const ADMIN_TOKEN_ENV: &str = "SUI_SWAP_ADMIN_TOKEN";
+const CLUSTER_TOKEN_ENV: &str = "SUI_SWAP_CLUSTER_TOKEN";
+const RELAY_TOKEN_ENV: &str = "SUI_SWAP_RELAY_TOKEN";
+const KEYSTORE_PATH_ENV: &str = "SUI_SWAP_KEYSTORE_PATH";
+/// Kept from the first versions, overrides the default price source URL
+const TOKEN_BALANCE_ENV: &str = "TOKEN_BALANCE_URL";
+
+/// Whole application configuration, read from a TOML file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path of the tokens registry file
+    pub tokens_file: PathBuf,
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+    pub auth: AuthConfig,
+    pub admin: AdminConfig,
+    pub storage: StorageConfig,
+    /// Price sources by name, referenced from `price_sources` in the tokens file
+    pub price_sources: BTreeMap<String, PriceSourceConfig>,
+    /// Rules evaluated by the server on every price it receives
+    pub alerts: Vec<AlertRule>,
+    pub quotes: QuoteConfig,
+    pub keystore: KeystoreConfig,
+    pub balances: BalanceConfig,
+    pub discovery: DiscoveryConfig,
+    pub cluster: ClusterConfig,
+    pub relay: RelayConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// Default interval between price polls
+    pub poll_interval_secs: u64,
+    /// Messages queued per peer or admin subscriber before `overflow_policy`
+    /// applies
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// Peers not answering pings for this long are disconnected, 0 disables it
+    pub peer_timeout_secs: u64,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server_url: String,
+    /// Other hubs of the cluster, tried in order when `server_url` can't be
+    /// reached or the connection to the current hub is lost
+    pub fallback_urls: Vec<String>,
+    /// Wait before another round over the hubs once none of them answers, 0
+    /// ends the client instead
+    pub reconnect_secs: u64,
+    /// PEM file with the root certificates to trust on `wss://` connections,
+    /// the bundled web PKI roots are used if not set
+    pub ca_cert: Option<PathBuf>,
+    /// How long a fetched price is reused before asking the source again
+    pub price_cache_ttl_secs: u64,
+    /// Wait after a `429` from a price source without `Retry-After` header
+    pub default_retry_after_secs: u64,
+    /// Prices whose upstream timestamp is older than this are not sent to
+    /// the server, 0 disables the check
+    pub max_price_age_secs: u64,
+    /// JSONL file where every response fetched from a price source is appended
+    pub record_file: Option<PathBuf>,
+    /// File keeping the reclaim secrets the server hands out, so a restarted
+    /// client gets its token back first after a server restart
+    pub reclaim_file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Shared secret clients must present as a bearer token, no auth if not set
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address of the admin channel, disabled if not set
+    pub listen: Option<String>,
+    /// URL used by the `admin` subcommand to reach the server, wss:// when
+    /// the server has `server.tls`
+    pub url: String,
+    /// PEM file with the root certificates to trust when `url` is wss://
+    pub ca_cert: Option<PathBuf>,
+    /// Bearer token required on the admin channel
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory where the server keeps its data
+    pub path: PathBuf,
+    /// Interval between snapshots of the registered tokens and last prices,
+    /// 0 only saves them on shutdown
+    pub snapshot_secs: u64,
+    /// After a restart, tokens registered before are kept for the clients
+    /// holding their reclaim secret for this long
+    pub reclaim_secs: u64,
+}
+
+/// Addresses whose balances the server tracks and values with its prices
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalanceConfig {
+    pub addresses: Vec<String>,
+    /// Sui JSON-RPC endpoint the balances are read from, `quotes.rpc_url` if not set
+    pub rpc_url: Option<String>,
+    pub refresh_secs: u64,
+}
+
+/// Where `sui-swap discover` reads coin metadata from
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Sui JSON-RPC endpoint, `quotes.rpc_url` if not set
+    pub rpc_url: Option<String>,
+}
+
+/// Other server instances sharing their registered tokens and prices with
+/// this one, every node lists all the others in `peers`. `listen` serves
+/// wss:// too when `server.tls` is set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Address the other nodes connect to, clustering is disabled if not set
+    pub listen: Option<String>,
+    /// Name of this node in the cluster, its `listen` address if not set
+    pub node_id: Option<String>,
+    /// `ws://` or `wss://` URLs of the cluster address of the other nodes
+    pub peers: Vec<String>,
+    /// Shared secret the nodes present to each other, required with `listen`
+    pub token: Option<String>,
+    /// Root certificates to trust when the peers are wss://
+    pub ca_cert: Option<PathBuf>,
+    /// Interval between the state messages sent to the other nodes
+    pub heartbeat_secs: u64,
+    /// Nodes not heard from for this long are lost, their tokens can be
+    /// registered on another node
+    pub node_timeout_secs: u64,
+}
+
+/// Upstream hub this one connects to like a client, sending it the prices of
+/// the local tokens and receiving the ones of every other hub
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// `ws://` or `wss://` URL of the upstream hub, relaying is disabled if not set
+    pub upstream: Option<String>,
+    /// Name of this hub in the relayed prices, the cluster node id or the
+    /// listen address if not set
+    pub node_id: Option<String>,
+    /// `auth.token` of the upstream hub
+    pub token: Option<String>,
+    /// Root certificates to trust when the upstream is wss://
+    pub ca_cert: Option<PathBuf>,
+    /// Wait before connecting again after losing the upstream
+    pub reconnect_secs: u64,
+}
+
+/// Keys used to sign transactions, the password of an encrypted keystore is
+/// read from the `SUI_SWAP_KEYSTORE_PASSWORD` env var
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeystoreConfig {
+    /// Sui CLI keystore, plain or encrypted with `sui-swap keystore encrypt`
+    pub path: Option<PathBuf>,
+}
+
+/// Either `url` or `replay` must be set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriceSourceConfig {
+    /// Base URL, the coin type is appended to it
+    pub url: Option<String>,
+    /// File recorded with `client.record_file`, played back as if it were live
+    pub replay: Option<PathBuf>,
+    /// Replay speed, 2.0 plays the recording twice as fast
+    pub speed: f64,
+}
+
+/// Swap quotes computed from constant-product pool reserves
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuoteConfig {
+    /// Sui JSON-RPC endpoint the pool objects are read from
+    pub rpc_url: String,
+    /// JSON file with fixed reserves by pool id, used instead of `rpc_url`
+    pub fixture: Option<PathBuf>,
+    /// Slippage tolerance for the minimum received when a quote doesn't set it
+    pub slippage_bps: u32,
+    /// Gas budget of swap transactions in MIST, and the cap when estimating it
+    pub gas_budget: u64,
+    pub pools: Vec<PoolConfig>,
+}
+
+/// Constant-product pool between two tokens of the tokens file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Object id of the pool
+    pub id: String,
+    pub token_a: String,
+    pub token_b: String,
+    /// Swap fee in basis points, taken from the input amount
+    pub fee_bps: u32,
+    /// Field of the pool object with the `token_a` reserve, `reserve_x` if not set
+    #[serde(default)]
+    pub reserve_a_field: Option<String>,
+    /// Field of the pool object with the `token_b` reserve, `reserve_y` if not set
+    #[serde(default)]
+    pub reserve_b_field: Option<String>,
+    /// Move functions to execute swaps, needed to build swap transactions
+    #[serde(default)]
+    pub swap: Option<SwapCallConfig>,
+}
+
+/// Move functions of a pool package called as
+/// `<package>::<module>::<function><A, B>(pool, coin_in, min_out)`, where `A`
+/// and `B` are the coin types of `token_a` and `token_b` and the output coin is
+/// returned
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SwapCallConfig {
+    pub package: String,
+    pub module: String,
+    /// Function swapping `token_a` for `token_b`
+    pub a_to_b: String,
+    /// Function swapping `token_b` for `token_a`
+    pub b_to_a: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut price_sources = BTreeMap::new();
+        price_sources.insert(
+            DEFAULT_PRICE_SOURCE.to_string(),
+            PriceSourceConfig::http(DEFAULT_PRICE_SOURCE_URL),
+        );
+        Self {
+            tokens_file: PathBuf::from(crate::tokens::DEFAULT_TOKENS_FILE),
+            server: ServerConfig::default(),
+            client: ClientConfig::default(),
+            auth: AuthConfig::default(),
+            admin: AdminConfig::default(),
+            storage: StorageConfig::default(),
+            price_sources,
+            alerts: Vec::new(),
+            quotes: QuoteConfig::default(),
+            keystore: KeystoreConfig::default(),
+            balances: BalanceConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            cluster: ClusterConfig::default(),
+            relay: RelayConfig::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".to_string(),
+            poll_interval_secs: 10,
+            queue_capacity: 64,
+            overflow_policy: OverflowPolicy::default(),
+            peer_timeout_secs: 60,
+            tls: None,
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            server_url: "ws://127.0.0.1:8080".to_string(),
+            fallback_urls: Vec::new(),
+            reconnect_secs: 5,
+            ca_cert: None,
+            price_cache_ttl_secs: 5,
+            default_retry_after_secs: 30,
+            max_price_age_secs: 0,
+            record_file: None,
+            reclaim_file: None,
+        }
+    }
+}
+
+impl Default for PriceSourceConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            replay: None,
+            speed: 1.0,
+        }
+    }
+}
+
+impl PriceSourceConfig {
+    pub fn http(url: impl Into<String>) -> Self {
+        Self {
+            url: Some(url.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn replay(path: impl Into<PathBuf>, speed: f64) -> Self {
+        Self {
+            replay: Some(path.into()),
+            speed,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for QuoteConfig {
+    fn default() -> Self {
+        Self {
+            rpc_url: "https://fullnode.mainnet.sui.io:443".to_string(),
+            fixture: None,
+            slippage_bps: 50,
+            gas_budget: 50_000_000,
+            pools: Vec::new(),
+        }
+    }
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        Self {
+            addresses: Vec::new(),
+            rpc_url: None,
+            refresh_secs: 60,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            url: "ws://127.0.0.1:8081".to_string(),
+            ca_cert: None,
+            token: None,
+        }
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            node_id: None,
+            peers: Vec::new(),
+            token: None,
+            ca_cert: None,
+            heartbeat_secs: 2,
+            node_timeout_secs: 6,
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            upstream: None,
+            node_id: None,
+            token: None,
+            ca_cert: None,
+            reconnect_secs: 5,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("data"),
+            snapshot_secs: 30,
+            reclaim_secs: 60,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file if given (or the default one if it exists),
+    /// then apply env var overrides and validate the result
+    pub fn load(path: Option<&Path>) -> Result<Self, SwapError> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, SwapError> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| SwapError::ReadConfigFileError(path.display().to_string(), e))?;
+        Ok(toml::from_str(&data)?)
+    }
+
+    fn apply_env(&mut self) -> Result<(), SwapError> {
+        if let Ok(listen) = env::var(LISTEN_ENV) {
+            self.server.listen = listen;
+        }
+        if let Ok(poll_interval) = env::var(POLL_INTERVAL_ENV) {
+            self.server.poll_interval_secs = poll_interval.parse().map_err(|_| {
+                SwapError::InvalidConfig(format!(
+                    "{} must be a number of seconds",
+                    POLL_INTERVAL_ENV
+                ))
+            })?;
+        }
+        if let Ok(server_url) = env::var(SERVER_URL_ENV) {
+            self.client.server_url = server_url;
+        }
+        if let Ok(auth_token) = env::var(AUTH_TOKEN_ENV) {
+            self.auth.token = Some(auth_token);
+        }
+        if let Ok(admin_token) = env::var(ADMIN_TOKEN_ENV) {
+            self.admin.token = Some(admin_token);
+        }
+        if let Ok(cluster_token) = env::var(CLUSTER_TOKEN_ENV) {
+            self.cluster.token = Some(cluster_token);
+        }
+        if let Ok(relay_token) = env::var(RELAY_TOKEN_ENV) {
+            self.relay.token = Some(relay_token);
+        }
+        if let Ok(storage_path) = env::var(STORAGE_PATH_ENV) {
+            self.storage.path = PathBuf::from(storage_path);
+        }
+        if let Ok(keystore_path) = env::var(KEYSTORE_PATH_ENV) {
+            self.keystore.path = Some(PathBuf::from(keystore_path));
+        }
+        if let Ok(tokens_file) = env::var(TOKENS_FILE_ENV) {
+            self.tokens_file = PathBuf::from(tokens_file);
+        }
+        if let Ok(url) = env::var(TOKEN_BALANCE_ENV) {
+            self.price_sources.insert(
+                DEFAULT_PRICE_SOURCE.to_string(),
+                PriceSourceConfig::http(url),
+            );
+        }
+        Ok(())
+    }
+
+    /// Check values that can't be expressed by the TOML types
+    pub fn validate(&self) -> Result<(), SwapError> {
+        let invalid = |reason: String| Err(SwapError::InvalidConfig(reason));
+        if self.server.listen.parse::<SocketAddr>().is_err() {
+            return invalid(format!(
+                "server.listen must be an ip:port address, got {}",
+                self.server.listen
+            ));
+        }
+        if self.server.poll_interval_secs == 0 {
+            return invalid("server.poll_interval_secs must be greater than 0".to_string());
+        }
+        if self.server.queue_capacity == 0 {
+            return invalid("server.queue_capacity must be greater than 0".to_string());
+        }
+        for url in std::iter::once(&self.client.server_url).chain(&self.client.fallback_urls) {
+            if !url.starts_with("ws://") && !url.starts_with("wss://") {
+                return invalid(format!(
+                    "client.server_url and client.fallback_urls must be ws:// or wss:// URLs, got {}",
+                    url
+                ));
+            }
+        }
+        if let Some(tls) = &self.server.tls {
+            for file in [&tls.cert, &tls.key] {
+                if !file.is_file() {
+                    return invalid(format!("TLS file {} does not exist", file.display()));
+                }
+            }
+        }
+        if let Some(ca_cert) = &self.client.ca_cert {
+            if !ca_cert.is_file() {
+                return invalid(format!("CA file {} does not exist", ca_cert.display()));
+            }
+        }
+        if let Some(admin_listen) = &self.admin.listen {
+            if admin_listen.parse::<SocketAddr>().is_err() {
+                return invalid(format!(
+                    "admin.listen must be an ip:port address, got {}",
+                    admin_listen
+                ));
+            }
+            if self.admin.token.is_none() {
+                return invalid("admin.token is required when admin.listen is set".to_string());
+            }
+        }
+        if matches!(&self.admin.token, Some(token) if token.is_empty()) {
+            return invalid("admin.token can't be empty".to_string());
+        }
+        if !self.admin.url.starts_with("ws://") && !self.admin.url.starts_with("wss://") {
+            return invalid(format!(
+                "admin.url must be a ws:// or wss:// URL, got {}",
+                self.admin.url
+            ));
+        }
+        if let Some(ca_cert) = &self.admin.ca_cert {
+            if !ca_cert.is_file() {
+                return invalid(format!("CA file {} does not exist", ca_cert.display()));
+            }
+        }
+        if self.storage.path.is_file() {
+            return invalid(format!(
+                "storage.path {} is a file, not a directory",
+                self.storage.path.display()
+            ));
+        }
+        if matches!(&self.auth.token, Some(token) if token.is_empty()) {
+            return invalid("auth.token can't be empty".to_string());
+        }
+        for (index, rule) in self.alerts.iter().enumerate() {
+            if rule.name.is_empty() {
+                return invalid(format!("alerts[{}].name can't be empty", index));
+            }
+            if self.alerts[..index]
+                .iter()
+                .any(|other| other.name == rule.name)
+            {
+                return invalid(format!("alert name {} is repeated", rule.name));
+            }
+            if !rule.webhook.starts_with("http://") && !rule.webhook.starts_with("https://") {
+                return invalid(format!("alert {} webhook must be an HTTP URL", rule.name));
+            }
+            if let Some(reason) = rule.condition.check() {
+                return invalid(format!("alert {}: {}", rule.name, reason));
+            }
+        }
+        if !self.quotes.rpc_url.starts_with("http://")
+            && !self.quotes.rpc_url.starts_with("https://")
+        {
+            return invalid("quotes.rpc_url must be an HTTP URL".to_string());
+        }
+        if let Some(fixture) = &self.quotes.fixture {
+            if !fixture.is_file() {
+                return invalid(format!(
+                    "quotes.fixture file {} does not exist",
+                    fixture.display()
+                ));
+            }
+        }
+        if self.quotes.gas_budget == 0 {
+            return invalid("quotes.gas_budget must be greater than 0".to_string());
+        }
+        if self.quotes.slippage_bps > 10_000 {
+            return invalid("quotes.slippage_bps can't be over 10000".to_string());
+        }
+        for pool in &self.quotes.pools {
+            if pool.token_a == pool.token_b {
+                return invalid(format!("pool {} needs two different tokens", pool.id));
+            }
+            if pool.fee_bps >= 10_000 {
+                return invalid(format!("pool {} fee_bps must be under 10000", pool.id));
+            }
+        }
+        for address in &self.balances.addresses {
+            if sui_rpc::normalize_address(address).is_err() {
+                return invalid(format!("balances.addresses: invalid address {}", address));
+            }
+        }
+        if matches!(&self.balances.rpc_url, Some(url) if !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return invalid("balances.rpc_url must be an HTTP URL".to_string());
+        }
+        if self.balances.refresh_secs == 0 {
+            return invalid("balances.refresh_secs must be greater than 0".to_string());
+        }
+        if matches!(&self.discovery.rpc_url, Some(url) if !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return invalid("discovery.rpc_url must be an HTTP URL".to_string());
+        }
+        if let Some(cluster_listen) = &self.cluster.listen {
+            if cluster_listen.parse::<SocketAddr>().is_err() {
+                return invalid(format!(
+                    "cluster.listen must be an ip:port address, got {}",
+                    cluster_listen
+                ));
+            }
+            if self
+                .cluster
+                .token
+                .as_ref()
+                .is_none_or(|token| token.is_empty())
+            {
+                return invalid("cluster.token is required when cluster.listen is set".to_string());
+            }
+        }
+        if matches!(&self.cluster.node_id, Some(node_id) if node_id.is_empty()) {
+            return invalid("cluster.node_id can't be empty".to_string());
+        }
+        for peer in &self.cluster.peers {
+            if !peer.starts_with("ws://") && !peer.starts_with("wss://") {
+                return invalid(format!(
+                    "cluster.peers must be ws:// or wss:// URLs, got {}",
+                    peer
+                ));
+            }
+        }
+        if let Some(ca_cert) = &self.cluster.ca_cert {
+            if !ca_cert.is_file() {
+                return invalid(format!("CA file {} does not exist", ca_cert.display()));
+            }
+        }
+        if self.cluster.heartbeat_secs == 0 {
+            return invalid("cluster.heartbeat_secs must be greater than 0".to_string());
+        }
+        if self.cluster.node_timeout_secs <= self.cluster.heartbeat_secs {
+            return invalid(
+                "cluster.node_timeout_secs must be longer than cluster.heartbeat_secs".to_string(),
+            );
+        }
+        if matches!(&self.relay.upstream, Some(upstream) if !upstream.starts_with("ws://") && !upstream.starts_with("wss://"))
+        {
+            return invalid("relay.upstream must be a ws:// or wss:// URL".to_string());
+        }
+        if matches!(&self.relay.node_id, Some(node_id) if node_id.is_empty()) {
+            return invalid("relay.node_id can't be empty".to_string());
+        }
+        if let Some(ca_cert) = &self.relay.ca_cert {
+            if !ca_cert.is_file() {
+                return invalid(format!("CA file {} does not exist", ca_cert.display()));
+            }
+        }
+        if self.relay.reconnect_secs == 0 {
+            return invalid("relay.reconnect_secs must be greater than 0".to_string());
+        }
+        if let Some(keystore) = &self.keystore.path {
+            if !keystore.is_file() {
+                return invalid(format!(
+                    "keystore.path file {} does not exist",
+                    keystore.display()
+                ));
+            }
+        }
+        for (name, source) in &self.price_sources {
+            match (&source.url, &source.replay) {
+                (Some(url), None) => {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        return invalid(format!("price_sources.{}.url must be an HTTP URL", name));
+                    }
+                }
+                (None, Some(replay)) => {
+                    if !replay.is_file() {
+                        return invalid(format!(
+                            "price_sources.{}.replay file {} does not exist",
+                            name,
+                            replay.display()
+                        ));
+                    }
+                    if !(source.speed.is_finite() && source.speed > 0.0) {
+                        return invalid(format!(
+                            "price_sources.{}.speed must be greater than 0",
+                            name
+                        ));
+                    }
+                }
+                _ => return invalid(format!("price_sources.{} needs either url or replay", name)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the first preferred source that is configured
+    pub fn price_source(&self, preferred: &[String]) -> Result<&str, SwapError> {
+        let default = [DEFAULT_PRICE_SOURCE.to_string()];
+        let preferred = if preferred.is_empty() {
+            &default[..]
+        } else {
+            preferred
+        };
+        preferred
+            .iter()
+            .find_map(|name| self.price_sources.get_key_value(name))
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| {
+                SwapError::InvalidConfig(format!(
+                    "none of the price sources {:?} is configured",
+                    preferred
+                ))
+            })
+    }
+}
@@ -0,0 +1,268 @@
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, str::FromStr};
+
+use crate::errors::SwapError;
+
+/// Decimal places kept by [`Decimal`]
+pub const DECIMALS: u32 = 18;
+const SCALE: u128 = 10u128.pow(DECIMALS);
+
+/// Non-negative fixed-point number with 18 decimal places, used for prices,
+/// confidences and amounts so they compare and multiply exactly.
+///
+/// Serialized as a string in JSON and TOML, accepting numbers too, and as the
+/// raw scaled integer in bincode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Decimal(u128);
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal(0);
+    pub const ONE: Decimal = Decimal(SCALE);
+    pub const MAX: Decimal = Decimal(u128::MAX);
+
+    /// Amount of `raw` on-chain units of a coin with `decimals` decimals,
+    /// rounded to 18 decimal places
+    pub fn from_raw(raw: u128, decimals: u64) -> Option<Self> {
+        match decimals.checked_sub(DECIMALS as u64) {
+            None => raw
+                .checked_mul(pow10(DECIMALS as u64 - decimals)?)
+                .map(Self),
+            Some(extra) => Some(Self(div_round(raw, pow10(extra)?))),
+        }
+    }
+
+    /// On-chain units of this amount for a coin with `decimals` decimals,
+    /// rounded to the nearest unit
+    pub fn to_raw(self, decimals: u64) -> Option<u128> {
+        match decimals.checked_sub(DECIMALS as u64) {
+            None => Some(div_round(self.0, pow10(DECIMALS as u64 - decimals)?)),
+            Some(extra) => self.0.checked_mul(pow10(extra)?),
+        }
+    }
+
+    /// Closest decimal to the shortest representation of `value`, so 0.1
+    /// becomes exactly 0.1. None for negative or non finite values.
+    pub fn from_f64(value: f64) -> Option<Self> {
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        value.to_string().parse().ok()
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.to_string()
+            .parse()
+            .expect("Decimals always print as valid floats")
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    /// Product truncated to 18 decimal places
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        mul_div(self.0, other.0, SCALE).map(Self)
+    }
+
+    /// Quotient truncated to 18 decimal places, None when dividing by zero
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        mul_div(self.0, SCALE, other.0).map(Self)
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    /// `self * mul / div` truncated to 18 decimal places once, at the end
+    pub fn checked_mul_div(self, mul: Self, div: Self) -> Option<Self> {
+        mul_div(self.0, mul.0, div.0).map(Self)
+    }
+}
+
+impl From<u64> for Decimal {
+    fn from(value: u64) -> Self {
+        Self(value as u128 * SCALE)
+    }
+}
+
+fn pow10(exponent: u64) -> Option<u128> {
+    10u128.checked_pow(exponent.try_into().ok()?)
+}
+
+/// `a / b` rounding halves up
+fn div_round(a: u128, b: u128) -> u128 {
+    let quotient = a / b;
+    if a % b >= b - b / 2 {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
+
+/// `a * b / c` rounded down without overflowing in the product, None when the
+/// result doesn't fit or `c` is zero
+fn mul_div(a: u128, b: u128, c: u128) -> Option<u128> {
+    if c == 0 {
+        return None;
+    }
+    if let Some(product) = a.checked_mul(b) {
+        return Some(product / c);
+    }
+    // 256 bit product as high and low halves
+    const MASK: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & MASK);
+    let (b_high, b_low) = (b >> 64, b & MASK);
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let middle = (low_low >> 64) + (high_low & MASK) + (low_high & MASK);
+    let low = (low_low & MASK) | (middle << 64);
+    let high = a_high * b_high + (high_low >> 64) + (low_high >> 64) + (middle >> 64);
+    if high >= c {
+        return None;
+    }
+    // Long division of the low half, bit by bit
+    let (mut remainder, mut quotient) = (high, 0u128);
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    Some(quotient)
+}
+
+impl FromStr for Decimal {
+    type Err = SwapError;
+
+    /// Plain or scientific notation, rounded to 18 decimal places
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SwapError::InvalidDecimal(s.to_string());
+        let (mantissa, exponent) = match s.find(['e', 'E']) {
+            Some(index) => (
+                &s[..index],
+                s[index + 1..].parse::<i64>().map_err(|_| invalid())?,
+            ),
+            None => (s, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (integer.is_empty() && fraction.is_empty())
+            || !is_digits(integer)
+            || !is_digits(fraction)
+        {
+            return Err(invalid());
+        }
+        // Value is digits * 10^shift / 10^18
+        let shift = exponent
+            .checked_sub(fraction.len() as i64)
+            .and_then(|shift| shift.checked_add(DECIMALS as i64))
+            .ok_or_else(invalid)?;
+        let digits = format!("{}{}", integer, fraction);
+        let digits = digits.trim_start_matches('0');
+        if digits.is_empty() {
+            return Ok(Self::ZERO);
+        }
+        if shift >= 0 {
+            let value: u128 = digits.parse().map_err(|_| invalid())?;
+            return pow10(shift as u64)
+                .and_then(|scale| value.checked_mul(scale))
+                .map(Self)
+                .ok_or_else(invalid);
+        }
+        // Drop the digits past 18 decimal places, rounding on the first one
+        let dropped = shift.unsigned_abs();
+        let kept = match usize::try_from(dropped) {
+            Ok(dropped) if dropped < digits.len() => digits.len() - dropped,
+            Ok(dropped) if dropped == digits.len() => 0,
+            _ => return Ok(Self::ZERO),
+        };
+        let value: u128 = match kept {
+            0 => 0,
+            _ => digits[..kept].parse().map_err(|_| invalid())?,
+        };
+        let round_up = digits.as_bytes()[kept] >= b'5';
+        value
+            .checked_add(round_up as u128)
+            .map(Self)
+            .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (integer, fraction) = (self.0 / SCALE, self.0 % SCALE);
+        if fraction == 0 {
+            return write!(f, "{}", integer);
+        }
+        let fraction = format!("{:018}", fraction);
+        write!(f, "{}.{}", integer, fraction.trim_end_matches('0'))
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_u128(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(DecimalVisitor)
+        } else {
+            u128::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+struct DecimalVisitor;
+
+impl Visitor<'_> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a non-negative decimal number or string")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        u64::try_from(value)
+            .map(Decimal::from)
+            .map_err(|_| E::custom(SwapError::InvalidDecimal(value.to_string())))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        Decimal::from_f64(value)
+            .ok_or_else(|| E::custom(SwapError::InvalidDecimal(value.to_string())))
+    }
+}
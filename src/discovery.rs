@@ -0,0 +1,116 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    coin_type::CoinType, errors::SwapError, prices::PriceSource, sui_rpc::SuiRpc,
+    tokens::TokenConfig,
+};
+
+/// Coin metadata as returned by `suix_getCoinMetadata`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CoinMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u64,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub icon_url: Option<String>,
+    /// Id of the metadata object
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+/// Tokens file entry built from the chain metadata of a coin type
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiscoveredToken {
+    /// Key of the entry in the tokens file
+    pub name: String,
+    pub entry: TokenConfig,
+    pub metadata: CoinMetadata,
+    /// Decimals the price source reports, unknown if it has no price for the coin
+    pub upstream_decimals: Option<u64>,
+    /// The price source disagrees with the chain about the decimals
+    pub decimals_mismatch: bool,
+}
+
+/// Looks up coin types on the Sui chain, checking them against a price source
+pub struct TokenDiscovery {
+    rpc: SuiRpc,
+    price_source: Option<Arc<dyn PriceSource>>,
+}
+
+impl TokenDiscovery {
+    pub fn new(rpc_url: &str) -> Self {
+        Self {
+            rpc: SuiRpc::new(rpc_url),
+            price_source: None,
+        }
+    }
+
+    /// Compare the on-chain decimals with the ones this source reports
+    pub fn price_source(mut self, price_source: Arc<dyn PriceSource>) -> Self {
+        self.price_source = Some(price_source);
+        self
+    }
+
+    pub async fn metadata(&self, coin_type: &CoinType) -> Result<CoinMetadata, SwapError> {
+        const METHOD: &str = "suix_getCoinMetadata";
+        let result = self
+            .rpc
+            .call(METHOD, json!([coin_type.to_string()]))
+            .await?;
+        // null for coin types without metadata
+        if result.is_null() {
+            return Err(SwapError::NoCoinMetadata(coin_type.to_string()));
+        }
+        serde_json::from_value(result)
+            .map_err(|e| SwapError::RpcError(METHOD.to_string(), e.to_string()))
+    }
+
+    /// Entry for `coin_type` named `name`, or its symbol if not given
+    pub async fn discover(
+        &self,
+        coin_type: &CoinType,
+        name: Option<&str>,
+    ) -> Result<DiscoveredToken, SwapError> {
+        let metadata = self.metadata(coin_type).await?;
+        let upstream_decimals = match &self.price_source {
+            Some(price_source) => match price_source.fetch(coin_type).await {
+                Ok(response) => response.get(coin_type).map(|info| info.decimals),
+                Err(price_error) => {
+                    warn!(
+                        "Can't check the decimals of {} with the price source: {}",
+                        coin_type, price_error
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        let decimals_mismatch =
+            upstream_decimals.is_some_and(|upstream| upstream != metadata.decimals);
+        if decimals_mismatch {
+            warn!(
+                "Decimals mismatch for {}: {} on chain, the price source reports {}",
+                coin_type,
+                metadata.decimals,
+                upstream_decimals.expect("Checked above")
+            );
+        }
+        let mut entry = TokenConfig::new(coin_type.clone(), metadata.symbol.clone());
+        entry.name = Some(metadata.name.clone()).filter(|name| !name.is_empty());
+        entry.decimals = Some(metadata.decimals);
+        entry.icon_url = metadata.icon_url.clone().filter(|url| !url.is_empty());
+        Ok(DiscoveredToken {
+            name: name.unwrap_or(&metadata.symbol).to_string(),
+            entry,
+            metadata,
+            upstream_decimals,
+            decimals_mismatch,
+        })
+    }
+}
@@ -1,17 +1,108 @@
 use thiserror::Error;
 
+use crate::decimal::Decimal;
+
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum SwapError {
     #[error("Failed to read tokens file")]
     ReadTokensFileError(#[from] std::io::Error),
-    #[error("Failed to parse tokens file")]
+    #[error("Failed to parse tokens file: {0}")]
     ParseTokensFileError(#[from] serde_json::Error),
+    #[error("Invalid entry for token {0} in tokens file: {1}")]
+    InvalidTokenEntry(String, String),
+    #[error("Token {0} is not listed in tokens file")]
+    UnknownToken(String),
+    #[error("Token {0} is disabled in tokens file")]
+    TokenDisabled(String),
+    #[error("Token {0} is already served by another client")]
+    TokenTaken(String),
+    #[error("Server rejected token {0}")]
+    TokenRejected(String),
+    #[error("Failed to read config file {0}")]
+    ReadConfigFileError(String, #[source] std::io::Error),
+    #[error("Failed to parse config file: {0}")]
+    ParseConfigFileError(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Failed to bind address {0}")]
+    BindError(String, #[source] std::io::Error),
+    #[error("TLS error: {0}")]
+    TlsError(String),
     #[error("Failed to send request to: {0}")]
     SendRequestError(String),
+    #[error("Rate limited by price source, retry in {0:?}")]
+    RateLimited(std::time::Duration),
+    #[error("Price source answered with status {0}")]
+    UpstreamStatus(u16),
     #[error("Failed to parse response")]
     ParseResponseError(#[from] reqwest::Error),
+    #[error("Failed to parse price response: {0}")]
+    ParsePriceResponseError(#[source] serde_json::Error),
+    #[error("Failed to write record file {0}")]
+    WriteRecordFileError(String, #[source] std::io::Error),
+    #[error("Failed to read replay file {0}")]
+    ReadReplayFileError(String, #[source] std::io::Error),
+    #[error("Invalid replay file {0}: {1}")]
+    InvalidReplayFile(String, String),
+    #[error("No recorded price for {0} at this point of the replay")]
+    NoReplayedPrice(String),
+    #[error("No price received yet for token {0}")]
+    NoPrice(String),
+    #[error("Invalid price {1} for token {0}")]
+    InvalidPrice(String, Decimal),
+    #[error("Invalid amount {0}")]
+    InvalidAmount(Decimal),
+    #[error("Invalid decimal number {0}")]
+    InvalidDecimal(String),
+    #[error("Invalid slippage of {0} bps, the maximum is 10000")]
+    InvalidSlippage(u32),
+    #[error("No pool configured for {0}/{1}")]
+    NoPool(String, String),
+    #[error("Invalid Sui address {0}")]
+    InvalidAddress(String),
+    #[error("Invalid coin type {0}")]
+    InvalidCoinType(String),
+    #[error("Not enough {0}: {1} available, {2} needed")]
+    InsufficientBalance(String, u64, u64),
+    #[error("{0} is spread over more than {1} coins, merge them first")]
+    TooManyCoins(String, usize),
+    #[error("Pool {0} has no swap functions configured")]
+    NoSwapCall(String),
+    #[error("Sui RPC {0} failed: {1}")]
+    RpcError(String, String),
+    #[error("No route from {0} to {1}")]
+    NoRoute(String, String),
+    #[error("Invalid data for pool {0}: {1}")]
+    InvalidPoolData(String, String),
+    #[error("Failed to read pools fixture {0}")]
+    ReadFixtureError(String, #[source] std::io::Error),
+    #[error("No balances read yet")]
+    NoBalances,
+    #[error("Address {0} is not tracked")]
+    UnknownAddress(String),
+    #[error("Failed to read keystore {0}")]
+    ReadKeystoreError(String, #[source] std::io::Error),
+    #[error("Failed to write keystore {0}")]
+    WriteKeystoreError(String, #[source] std::io::Error),
+    #[error("Failed to write {0}")]
+    WriteStorageError(String, #[source] std::io::Error),
+    #[error("Invalid swap: {0}")]
+    InvalidSwap(String),
+    #[error("No coin metadata on chain for {0}")]
+    NoCoinMetadata(String),
+    #[error("Token {0} is already in the tokens file")]
+    TokenExists(String),
+    #[error("Invalid keystore: {0}")]
+    InvalidKeystore(String),
+    #[error("Wrong keystore password or corrupted keystore")]
+    KeystorePassword,
+    #[error("No key in the keystore for address {0}")]
+    UnknownKey(String),
+    #[error("Failed to parse admin response: {0}")]
+    ParseAdminResponseError(#[source] serde_json::Error),
     #[error("Failed to serialize response")]
     SerializeError(#[from] bincode::Error),
-    #[error("WS error")]
-    WsError(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("WS error: {0}")]
+    WsError(#[from] Box<tokio_tungstenite::tungstenite::Error>),
 }
@@ -0,0 +1,321 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use ed25519_dalek::Signer;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path};
+use zeroize::Zeroizing;
+
+use crate::{errors::SwapError, sui_rpc};
+
+/// Env var with the password of encrypted keystores, never read from the
+/// config file so it can't end up in a log or a dump of the config
+pub const PASSWORD_ENV: &str = "SUI_SWAP_KEYSTORE_PASSWORD";
+/// Prefix of the Bech32 private keys printed by `sui keytool export`
+const BECH32_HRP: &str = "suiprivkey";
+const ENCRYPTED_VERSION: u32 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+type Blake2b256 = Blake2b<U32>;
+
+/// Signature schemes, the flag is the first byte of keys and signatures
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureScheme {
+    Ed25519,
+    Secp256k1,
+}
+
+impl SignatureScheme {
+    pub fn flag(self) -> u8 {
+        match self {
+            SignatureScheme::Ed25519 => 0x00,
+            SignatureScheme::Secp256k1 => 0x01,
+        }
+    }
+}
+
+/// What the signed bytes are, the first byte of the intent prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntentScope {
+    TransactionData = 0,
+    PersonalMessage = 3,
+}
+
+impl IntentScope {
+    /// Scope, intent version 0 and the Sui app id
+    fn prefix(self) -> [u8; 3] {
+        [self as u8, 0, 0]
+    }
+}
+
+enum SigningKey {
+    Ed25519(ed25519_dalek::SigningKey),
+    Secp256k1(k256::ecdsa::SigningKey),
+}
+
+/// Private key of the keystore with its Sui address. Both keys wipe their
+/// memory on drop and `Debug` only shows the address.
+pub struct KeyPair {
+    key: SigningKey,
+    address: String,
+}
+
+impl KeyPair {
+    /// Key as stored by the Sui CLI: the scheme flag followed by the 32 bytes
+    /// of the private key
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (&flag, secret) = bytes.split_first().ok_or("empty key")?;
+        if secret.len() != KEY_LEN {
+            return Err(format!("key of {} bytes", secret.len()));
+        }
+        let key = match flag {
+            0x00 => {
+                let secret: &[u8; KEY_LEN] = secret.try_into().expect("Length checked");
+                SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(secret))
+            }
+            0x01 => SigningKey::Secp256k1(
+                k256::ecdsa::SigningKey::from_slice(secret).map_err(|_| "invalid secp256k1 key")?,
+            ),
+            0x02 => return Err("secp256r1 keys are not supported".to_string()),
+            flag => return Err(format!("unknown key scheme flag {}", flag)),
+        };
+        let mut key_pair = Self {
+            key,
+            address: String::new(),
+        };
+        let mut hasher = Blake2b256::new();
+        hasher.update([key_pair.scheme().flag()]);
+        hasher.update(key_pair.public_key());
+        key_pair.address = format!("0x{}", hex::encode(hasher.finalize()));
+        Ok(key_pair)
+    }
+
+    /// Key in base64 or Bech32 `suiprivkey1...`
+    fn parse(encoded: &str) -> Result<Self, String> {
+        let bytes = if encoded.starts_with(BECH32_HRP) {
+            let (hrp, bytes) = bech32::decode(encoded).map_err(|_| "invalid Bech32 key")?;
+            if hrp.as_str() != BECH32_HRP {
+                return Err("invalid Bech32 key".to_string());
+            }
+            Zeroizing::new(bytes)
+        } else {
+            Zeroizing::new(BASE64.decode(encoded).map_err(|_| "invalid base64 key")?)
+        };
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        match self.key {
+            SigningKey::Ed25519(_) => SignatureScheme::Ed25519,
+            SigningKey::Secp256k1(_) => SignatureScheme::Secp256k1,
+        }
+    }
+
+    /// Public key, compressed for secp256k1
+    pub fn public_key(&self) -> Vec<u8> {
+        match &self.key {
+            SigningKey::Ed25519(key) => key.verifying_key().to_bytes().to_vec(),
+            SigningKey::Secp256k1(key) => key
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec(),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Sign the Blake2b-256 digest of the intent prefix and `message`, and
+    /// return the signature as Sui expects it: base64 of the scheme flag, the
+    /// signature and the public key
+    pub fn sign(&self, scope: IntentScope, message: &[u8]) -> String {
+        let mut hasher = Blake2b256::new();
+        hasher.update(scope.prefix());
+        hasher.update(message);
+        let digest = hasher.finalize();
+        let signature = match &self.key {
+            SigningKey::Ed25519(key) => key.sign(&digest).to_bytes().to_vec(),
+            // Hashed again with SHA-256, s normalized to the lower half
+            SigningKey::Secp256k1(key) => {
+                let signature: k256::ecdsa::Signature = key.sign(&digest);
+                signature.to_bytes().to_vec()
+            }
+        };
+        let mut serialized = vec![self.scheme().flag()];
+        serialized.extend(signature);
+        serialized.extend(self.public_key());
+        BASE64.encode(serialized)
+    }
+
+    /// Sign BCS `TransactionData` bytes, like the `tx_bytes` of a built swap
+    pub fn sign_transaction(&self, tx_bytes: &[u8]) -> String {
+        self.sign(IntentScope::TransactionData, tx_bytes)
+    }
+
+    /// Sign an arbitrary message, BCS encoded as a byte vector like wallets do
+    pub fn sign_personal_message(&self, message: &[u8]) -> String {
+        let message = bcs::to_bytes(message).expect("Impossible serializing error");
+        self.sign(IntentScope::PersonalMessage, &message)
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("scheme", &self.scheme())
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sui CLI keystore encrypted with AES-256-GCM, with the key derived from the
+/// password with Argon2id
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncryptedKeystore {
+    version: u32,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// Base64 fields
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedKeystore {
+    fn cipher(password: &str, salt: &[u8], params: Params) -> Result<Aes256Gcm, SwapError> {
+        let mut key = Zeroizing::new([0; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), salt, key.as_mut())
+            .map_err(|e| SwapError::InvalidKeystore(e.to_string()))?;
+        Ok(Aes256Gcm::new_from_slice(key.as_ref()).expect("Key of 32 bytes"))
+    }
+
+    fn encrypt(plaintext: &[u8], password: &str) -> Result<Self, SwapError> {
+        let params = Params::default();
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Self::cipher(password, &salt, params.clone())?
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| SwapError::InvalidKeystore("encryption failed".to_string()))?;
+        Ok(Self {
+            version: ENCRYPTED_VERSION,
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: BASE64.encode(salt),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+    }
+
+    fn decrypt(&self, password: &str) -> Result<Zeroizing<Vec<u8>>, SwapError> {
+        let invalid = |reason: &str| SwapError::InvalidKeystore(reason.to_string());
+        if self.version != ENCRYPTED_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LEN))
+            .map_err(|_| invalid("invalid Argon2 parameters"))?;
+        let decode = |field: &str| BASE64.decode(field).map_err(|_| invalid("invalid base64"));
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(invalid("invalid nonce"));
+        }
+        let plaintext = Self::cipher(password, &decode(&self.salt)?, params)?
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                decode(&self.ciphertext)?.as_ref(),
+            )
+            .map_err(|_| SwapError::KeystorePassword)?;
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+/// Keys loaded from a Sui CLI keystore (`~/.sui/sui_config/sui.keystore`, a
+/// JSON array of keys) or from one encrypted with [`Keystore::encrypt`]
+#[derive(Default)]
+pub struct Keystore {
+    keys: Vec<KeyPair>,
+}
+
+impl Keystore {
+    /// Read a keystore file, `password` is only needed when it is encrypted
+    pub fn load(path: &Path, password: Option<&str>) -> Result<Self, SwapError> {
+        let data = Zeroizing::new(
+            fs::read(path)
+                .map_err(|e| SwapError::ReadKeystoreError(path.display().to_string(), e))?,
+        );
+        match serde_json::from_slice::<EncryptedKeystore>(&data) {
+            Ok(encrypted) => {
+                let password = password.ok_or_else(|| {
+                    SwapError::InvalidKeystore(format!(
+                        "{} is encrypted, set its password in {}",
+                        path.display(),
+                        PASSWORD_ENV
+                    ))
+                })?;
+                Self::from_json(&encrypted.decrypt(password)?)
+            }
+            Err(_) => Self::from_json(&data),
+        }
+    }
+
+    /// Parse the contents of a Sui CLI keystore
+    pub fn from_json(data: &[u8]) -> Result<Self, SwapError> {
+        let entries =
+            Zeroizing::new(serde_json::from_slice::<Vec<String>>(data).map_err(|_| {
+                SwapError::InvalidKeystore("expected a JSON array of keys".to_string())
+            })?);
+        let mut keys = Vec::with_capacity(entries.len());
+        // The key itself never goes in the error, only its position
+        for (index, entry) in entries.iter().enumerate() {
+            let key = KeyPair::parse(entry).map_err(|reason| {
+                SwapError::InvalidKeystore(format!("key {}: {}", index, reason))
+            })?;
+            keys.push(key);
+        }
+        Ok(Self { keys })
+    }
+
+    /// Encrypt the contents of a Sui CLI keystore with `password`, returning
+    /// the JSON to write in place of the plain file
+    pub fn encrypt(data: &[u8], password: &str) -> Result<String, SwapError> {
+        if password.is_empty() {
+            return Err(SwapError::InvalidKeystore("empty password".to_string()));
+        }
+        // Don't encrypt something that won't load
+        Self::from_json(data)?;
+        let encrypted = EncryptedKeystore::encrypt(data, password)?;
+        Ok(serde_json::to_string_pretty(&encrypted).expect("Impossible serializing error"))
+    }
+
+    pub fn keys(&self) -> &[KeyPair] {
+        &self.keys
+    }
+
+    /// Key of `address`, with or without leading zeros
+    pub fn get(&self, address: &str) -> Result<&KeyPair, SwapError> {
+        let unknown = || SwapError::UnknownKey(address.to_string());
+        let normalized = sui_rpc::normalize_address(address).map_err(|_| unknown())?;
+        self.keys
+            .iter()
+            .find(|key| key.address == normalized)
+            .ok_or_else(unknown)
+    }
+}
+
+impl fmt::Debug for Keystore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.keys).finish()
+    }
+}
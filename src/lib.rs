@@ -0,0 +1,47 @@
+//! Token price tracking hub for the SUI blockchain.
+//!
+//! A [`Server`] polls the connected [`Client`]s, each one serving the price of
+//! a token listed in the tokens file. Both can be embedded in any tokio
+//! application, the `sui-swap` binary is a thin CLI on top of them.
+
+pub mod admin;
+pub mod alerts;
+pub mod balances;
+pub mod candles;
+pub mod client;
+pub mod clock;
+pub mod cluster;
+pub mod coin_type;
+pub mod config;
+pub mod decimal;
+pub mod discovery;
+pub mod errors;
+pub mod keystore;
+pub mod messages;
+pub mod models;
+pub mod peer_queue;
+mod peer_registry;
+pub mod pnl;
+pub mod prices;
+pub mod quotes;
+pub mod rates;
+mod relay;
+pub mod replay;
+pub mod router;
+pub mod server;
+pub mod snapshot;
+pub mod sui_rpc;
+mod tls;
+pub mod tokens;
+pub mod transactions;
+
+pub use client::{Client, ClientBuilder};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use coin_type::CoinType;
+pub use config::Config;
+pub use decimal::Decimal;
+pub use errors::SwapError;
+pub use messages::{SwapRequest, SwapResponse};
+pub use models::{TokenInfoInnerResponse, TokenInfoResponse};
+pub use server::{BoundServer, Server, ServerBuilder};
+pub use tokens::{TokenConfig, TokenRegistry};
@@ -1,54 +1,637 @@
-use client::Client;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-use log::error;
-use server::Server;
-use std::env;
-mod client;
-mod errors;
-mod messages;
-mod models;
-mod server;
+use futures_util::{pin_mut, StreamExt};
+use log::{error, info};
+use std::{env, fs, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use sui_swap::{
+    admin,
+    candles::Resolution,
+    config::PriceSourceConfig,
+    discovery::TokenDiscovery,
+    keystore::{self, Keystore},
+    messages::{AdminRequest, AdminResponse},
+    prices::{HttpPriceSource, PriceFetcher},
+    Client, CoinType, Config, Decimal, Server, SwapError, SystemClock, TokenRegistry,
+};
 
-const TOKEN_BALANCE_ENV: &str = "TOKEN_BALANCE_URL";
+/// Token price tracking hub for the SUI blockchain
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML config file, sui-swap.toml is used if present
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server polling registered clients for prices
+    Server {
+        /// Address to listen on, overrides server.listen
+        #[arg(short, long)]
+        listen: Option<String>,
+        /// Seconds between price polls, overrides server.poll_interval_secs
+        #[arg(short, long)]
+        poll_interval: Option<u64>,
+    },
+    /// Run a client serving the price of a token to the server
+    Client {
+        /// Name of the token in the tokens file
+        token: String,
+        /// Server WS URL, overrides client.server_url
+        #[arg(short, long)]
+        url: Option<String>,
+        /// Append every fetched price to this JSONL file, overrides client.record_file
+        #[arg(long)]
+        record: Option<PathBuf>,
+        /// Play back a recorded file instead of asking the price sources
+        #[arg(long)]
+        replay: Option<PathBuf>,
+        /// Replay speed, 2.0 plays the recording twice as fast
+        #[arg(long, default_value_t = 1.0, requires = "replay")]
+        speed: f64,
+    },
+    /// Manage a running server through its admin channel
+    Admin {
+        /// Admin channel WS URL, overrides admin.url
+        #[arg(short, long)]
+        url: Option<String>,
+        #[command(subcommand)]
+        action: AdminAction,
+    },
+    /// Build a tokens file entry from the on-chain metadata of a coin type
+    Discover {
+        /// Coin type, e.g. 0x2::sui::SUI
+        coin_type: CoinType,
+        /// Key of the entry, the coin symbol if not set
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Sui JSON-RPC endpoint, overrides discovery.rpc_url
+        #[arg(long)]
+        rpc_url: Option<String>,
+        /// Add the entry to the tokens file instead of printing it
+        #[arg(short, long)]
+        write: bool,
+    },
+    /// Inspect a keystore and sign transactions with its keys
+    Keystore {
+        /// Keystore file, overrides keystore.path
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+        #[command(subcommand)]
+        action: KeystoreAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeystoreAction {
+    /// List the addresses of the keystore keys
+    List,
+    /// Encrypt a Sui CLI keystore with the password in SUI_SWAP_KEYSTORE_PASSWORD
+    Encrypt {
+        /// Plain keystore, e.g. ~/.sui/sui_config/sui.keystore
+        input: PathBuf,
+        /// Where to write the encrypted keystore, it must not exist
+        output: PathBuf,
+    },
+    /// Sign base64 transaction bytes, e.g. the tx_bytes printed by `admin build-swap`
+    Sign { address: String, tx_bytes: String },
+}
+
+#[derive(Subcommand)]
+enum AdminAction {
+    /// List connected peers and their tokens
+    Peers,
+    /// Close the connection of a peer
+    Disconnect { peer: SocketAddr },
+    /// Free a token so another client can register it
+    Release { token: String },
+    /// Make a connected peer serve a token
+    Reassign { token: String, peer: SocketAddr },
+    /// Poll all registered peers, or only the one serving a token, right now
+    Poll { token: Option<String> },
+    /// Change the poll interval, for all tokens or only one
+    SetInterval {
+        secs: u64,
+        #[arg(short, long)]
+        token: Option<String>,
+    },
+    /// Show the last candles of a token
+    Candles {
+        token: String,
+        /// 1m, 5m, 1h or 1d
+        #[arg(short, long, default_value = "1m")]
+        resolution: Resolution,
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+    /// Show the balances of the tracked addresses valued with the last prices
+    Portfolio {
+        /// Only this address
+        #[arg(short, long)]
+        address: Option<String>,
+    },
+    /// Record a swap of `amount_in` whole `from` tokens for `amount_out` `to` tokens
+    RecordSwap {
+        address: String,
+        from: String,
+        amount_in: Decimal,
+        to: String,
+        amount_out: Decimal,
+        /// Unix time of the swap, now if not set
+        #[arg(long)]
+        timestamp: Option<i64>,
+        /// USD value of the swap, from the price history if not set
+        #[arg(long)]
+        value_usd: Option<Decimal>,
+        #[arg(long)]
+        tx_digest: Option<String>,
+    },
+    /// Show the unrealized and realized PnL of the tracked addresses
+    Pnl {
+        #[arg(short, long)]
+        address: Option<String>,
+        #[arg(short, long)]
+        token: Option<String>,
+    },
+    /// Show the daily valuations between two unix times
+    PnlHistory {
+        #[arg(short, long)]
+        address: Option<String>,
+        #[arg(short, long)]
+        token: Option<String>,
+        #[arg(long)]
+        from: Option<i64>,
+        #[arg(long)]
+        to: Option<i64>,
+    },
+    /// Show how many `quote` tokens one `base` token is worth, e.g. `rate FUD SUI`
+    Rate { base: String, quote: String },
+    /// Quote swapping `amount` whole `from` tokens for `to` tokens
+    Quote {
+        from: String,
+        to: String,
+        amount: Decimal,
+        /// Slippage tolerance for the minimum received, quotes.slippage_bps if not set
+        #[arg(short, long)]
+        slippage_bps: Option<u32>,
+    },
+    /// Find the best path of pools to swap `amount` whole `from` tokens for `to` tokens
+    Route {
+        from: String,
+        to: String,
+        amount: Decimal,
+        #[arg(short, long)]
+        slippage_bps: Option<u32>,
+        /// Most pools in a path
+        #[arg(short, long)]
+        max_hops: Option<usize>,
+    },
+    /// Quote a swap and print the unsigned transaction executing it
+    BuildSwap {
+        from: String,
+        to: String,
+        amount: Decimal,
+        /// Address paying the input and the gas, and receiving the output
+        #[arg(long)]
+        sender: String,
+        #[arg(short, long)]
+        slippage_bps: Option<u32>,
+        /// Dry-run the transaction to check it and estimate the gas budget
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print candles as they close until interrupted
+    Watch {
+        #[arg(short, long)]
+        token: Option<String>,
+        #[arg(short, long)]
+        resolution: Option<Resolution>,
+        /// Print the portfolio after every balance refresh instead
+        #[arg(long, conflicts_with_all = ["token", "resolution"])]
+        portfolio: bool,
+        /// Print every price sample instead, of any cluster node
+        #[arg(long, conflicts_with_all = ["resolution", "portfolio"])]
+        prices: bool,
+    },
+    /// Print the nodes of the cluster and where each token is registered
+    Cluster,
+    /// Print every token with its client, last price and sample count, the
+    /// ones restored after a restart included
+    Tokens,
+}
+
+impl From<AdminAction> for AdminRequest {
+    fn from(action: AdminAction) -> Self {
+        match action {
+            AdminAction::Peers => AdminRequest::ListPeers,
+            AdminAction::Disconnect { peer } => AdminRequest::Disconnect { peer },
+            AdminAction::Release { token } => AdminRequest::ReleaseToken { token },
+            AdminAction::Reassign { token, peer } => AdminRequest::ReassignToken { token, peer },
+            AdminAction::Poll { token } => AdminRequest::PollNow { token },
+            AdminAction::SetInterval { secs, token } => {
+                AdminRequest::SetPollInterval { secs, token }
+            }
+            AdminAction::Candles {
+                token,
+                resolution,
+                limit,
+            } => AdminRequest::Candles {
+                token,
+                resolution,
+                limit,
+            },
+            AdminAction::Portfolio { address } => AdminRequest::Portfolio { address },
+            AdminAction::RecordSwap {
+                address,
+                from,
+                amount_in,
+                to,
+                amount_out,
+                timestamp,
+                value_usd,
+                tx_digest,
+            } => AdminRequest::RecordSwap {
+                address,
+                from_token: from,
+                amount_in,
+                to_token: to,
+                amount_out,
+                timestamp,
+                value_usd,
+                tx_digest,
+            },
+            AdminAction::Pnl { address, token } => AdminRequest::Pnl { address, token },
+            AdminAction::PnlHistory {
+                address,
+                token,
+                from,
+                to,
+            } => AdminRequest::PnlHistory {
+                address,
+                token,
+                from,
+                to,
+            },
+            AdminAction::Rate { base, quote } => AdminRequest::Rate { base, quote },
+            AdminAction::Quote {
+                from,
+                to,
+                amount,
+                slippage_bps,
+            } => AdminRequest::Quote {
+                from_token: from,
+                to_token: to,
+                amount,
+                slippage_bps,
+            },
+            AdminAction::Route {
+                from,
+                to,
+                amount,
+                slippage_bps,
+                max_hops,
+            } => AdminRequest::Route {
+                from_token: from,
+                to_token: to,
+                amount,
+                slippage_bps,
+                max_hops,
+            },
+            AdminAction::BuildSwap {
+                from,
+                to,
+                amount,
+                sender,
+                slippage_bps,
+                dry_run,
+            } => AdminRequest::BuildSwap {
+                from_token: from,
+                to_token: to,
+                amount,
+                slippage_bps,
+                sender,
+                dry_run,
+            },
+            AdminAction::Watch {
+                token,
+                resolution,
+                portfolio,
+                prices,
+            } => AdminRequest::Subscribe {
+                token,
+                resolution,
+                portfolio,
+                prices,
+            },
+            AdminAction::Cluster => AdminRequest::Cluster,
+            AdminAction::Tokens => AdminRequest::Tokens,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
     pretty_env_logger::init();
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse();
 
-    // Check init args to use client or server
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "-c" => run_c().await,
-            "-s" => run_s().await,
-            _ => println!("Invalid argument"),
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(config_error) => {
+            error!("Error loading config: {}", config_error);
+            std::process::exit(1);
+        }
+    };
+    // Command line args take precedence over config file and env vars
+    match &cli.command {
+        Command::Server {
+            listen,
+            poll_interval,
+        } => {
+            if let Some(listen) = listen {
+                config.server.listen = listen.clone();
+            }
+            if let Some(poll_interval) = poll_interval {
+                config.server.poll_interval_secs = *poll_interval;
+            }
+        }
+        Command::Client {
+            url,
+            record,
+            replay,
+            speed,
+            ..
+        } => {
+            if let Some(url) = url {
+                config.client.server_url = url.clone();
+            }
+            if let Some(record) = record {
+                config.client.record_file = Some(record.clone());
+            }
+            // Whatever source the token prefers, it gets the recording
+            if let Some(replay) = replay {
+                for source in config.price_sources.values_mut() {
+                    *source = PriceSourceConfig::replay(replay, *speed);
+                }
+            }
+        }
+        Command::Admin { url, .. } => {
+            if let Some(url) = url {
+                config.admin.url = url.clone();
+            }
+        }
+        Command::Keystore { path, .. } => {
+            if let Some(path) = path {
+                config.keystore.path = Some(path.clone());
+            }
+        }
+        Command::Discover { rpc_url, .. } => {
+            if let Some(rpc_url) = rpc_url {
+                config.discovery.rpc_url = Some(rpc_url.clone());
+            }
+        }
+    }
+    if let Err(config_error) = config.validate() {
+        error!("Error loading config: {}", config_error);
+        std::process::exit(1);
+    }
+
+    match cli.command {
+        Command::Server { .. } => run_s(config).await,
+        Command::Client { token, .. } => run_c(config, token).await,
+        Command::Admin { action, .. } => run_admin(config, action.into()).await,
+        Command::Keystore { action, .. } => run_keystore(config, action),
+        Command::Discover {
+            coin_type,
+            name,
+            write,
+            ..
+        } => run_discover(config, coin_type, name, write).await,
+    }
+}
+
+async fn run_discover(config: Config, coin_type: CoinType, name: Option<String>, write: bool) {
+    let rpc_url = config
+        .discovery
+        .rpc_url
+        .as_deref()
+        .unwrap_or(&config.quotes.rpc_url);
+    let mut discovery = TokenDiscovery::new(rpc_url);
+    // The source tokens use by default, a replay has nothing to check against
+    if let Some(url) = config
+        .price_source(&[])
+        .ok()
+        .and_then(|source| config.price_sources[source].url.clone())
+    {
+        let fetcher = PriceFetcher::new(
+            Duration::from_secs(config.client.price_cache_ttl_secs),
+            Duration::from_secs(config.client.default_retry_after_secs),
+            Arc::new(SystemClock),
+        );
+        discovery = discovery.price_source(Arc::new(HttpPriceSource::new(url, Arc::new(fetcher))));
+    }
+    let discovered = match discovery.discover(&coin_type, name.as_deref()).await {
+        Ok(discovered) => discovered,
+        Err(discovery_error) => {
+            error!("Error discovering {}: {}", coin_type, discovery_error);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&discovered).expect("Impossible serializing error")
+    );
+    if !write {
+        return;
+    }
+    let tokens_file = &config.tokens_file;
+    let mut registry = if tokens_file.exists() {
+        match TokenRegistry::load(tokens_file) {
+            Ok(registry) => registry,
+            Err(registry_error) => {
+                error!(
+                    "Error loading {}: {}",
+                    tokens_file.display(),
+                    registry_error
+                );
+                std::process::exit(1);
+            }
         }
     } else {
-        println!("USE: -c <URL> <TOKEN> or -s <ADDR>");
+        TokenRegistry::default()
+    };
+    let listed = registry
+        .iter()
+        .find(|(name, token)| *name == discovered.name || token.coin_type == coin_type)
+        .map(|(name, _)| name.to_string());
+    let written = match listed {
+        Some(listed) => Err(SwapError::TokenExists(listed)),
+        None => registry
+            .insert(discovered.name.clone(), discovered.entry)
+            .and_then(|_| registry.save(tokens_file)),
+    };
+    if let Err(write_error) = written {
+        error!("Error writing {}: {}", tokens_file.display(), write_error);
+        std::process::exit(1);
     }
+    println!("Added {} to {}", discovered.name, tokens_file.display());
 }
 
-async fn run_c() {
-    // Check all args/envs are present
-    let url = env::args().nth(2).expect("USE: -c <URL> <TOKEN>");
-    let token = env::args().nth(3).expect("USE: -c <URL> <TOKEN>");
-    let Ok(token_balance_url) = env::var(TOKEN_BALANCE_ENV) else {
-        panic!("{} env var is not set", TOKEN_BALANCE_ENV);
+fn run_keystore(config: Config, action: KeystoreAction) {
+    let password = env::var(keystore::PASSWORD_ENV).ok();
+    if let KeystoreAction::Encrypt { input, output } = action {
+        if output.exists() {
+            error!("{} already exists", output.display());
+            std::process::exit(1);
+        }
+        let encrypted = fs::read(&input)
+            .map_err(|e| SwapError::ReadKeystoreError(input.display().to_string(), e))
+            .and_then(|data| {
+                let password = password.ok_or_else(|| {
+                    SwapError::InvalidKeystore(format!("{} is not set", keystore::PASSWORD_ENV))
+                })?;
+                Keystore::encrypt(&data, &password)
+            })
+            .and_then(|encrypted| {
+                fs::write(&output, encrypted)
+                    .map_err(|e| SwapError::WriteKeystoreError(output.display().to_string(), e))
+            });
+        if let Err(keystore_error) = encrypted {
+            error!("Error encrypting keystore: {}", keystore_error);
+            std::process::exit(1);
+        }
+        println!("Encrypted keystore written to {}", output.display());
+        return;
+    }
+
+    let Some(path) = &config.keystore.path else {
+        error!("No keystore, set keystore.path or --path");
+        std::process::exit(1);
+    };
+    let keystore = match Keystore::load(path, password.as_deref()) {
+        Ok(keystore) => keystore,
+        Err(keystore_error) => {
+            error!("Error loading keystore: {}", keystore_error);
+            std::process::exit(1);
+        }
     };
-    println!("URL: {}", url);
+    match action {
+        KeystoreAction::List => {
+            for key in keystore.keys() {
+                println!("{} {:?}", key.address(), key.scheme());
+            }
+        }
+        KeystoreAction::Sign { address, tx_bytes } => {
+            let Ok(tx_bytes) = BASE64.decode(tx_bytes.trim()) else {
+                error!("tx_bytes must be base64");
+                std::process::exit(1);
+            };
+            match keystore.get(&address) {
+                Ok(key) => println!("{}", key.sign_transaction(&tx_bytes)),
+                Err(sign_error) => {
+                    error!("Error signing transaction: {}", sign_error);
+                    std::process::exit(1);
+                }
+            }
+        }
+        KeystoreAction::Encrypt { .. } => unreachable!("Handled above"),
+    }
+}
+
+async fn run_admin(config: Config, request: AdminRequest) {
+    if let AdminRequest::Subscribe { .. } = request {
+        return run_watch(config, request).await;
+    }
+    match admin::send_request(
+        &config.admin.url,
+        config.admin.token.as_deref(),
+        config.admin.ca_cert.as_deref(),
+        &request,
+    )
+    .await
+    {
+        Ok(response) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&response).expect("Impossible serializing error")
+            );
+            if let AdminResponse::Error { .. } = response {
+                std::process::exit(1);
+            }
+        }
+        Err(admin_error) => {
+            error!("Admin request failed: {}", admin_error);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_c(config: Config, token: String) {
+    println!("URL: {}", config.client.server_url);
     // Launch in Client mode
-    Client::new(url, token, token_balance_url).start().await;
+    if let Err(client_error) = Client::new(token, config).start().await {
+        error!("Client error: {}", client_error);
+        std::process::exit(1);
+    }
+}
+
+async fn run_s(config: Config) {
+    println!("ADDR: {}", config.server.listen);
+    let registry = match TokenRegistry::load(&config.tokens_file) {
+        Ok(registry) => registry,
+        Err(registry_error) => {
+            error!(
+                "Error loading {}: {}",
+                config.tokens_file.display(),
+                registry_error
+            );
+            std::process::exit(1);
+        }
+    };
+    // Launch in Server mode, until ctrl-c or SIGTERM
+    match Server::new(config, registry).start().await {
+        Ok(()) => info!("Server stopped"),
+        Err(server_error) => {
+            error!("Server error: {}", server_error);
+            std::process::exit(1);
+        }
+    }
 }
 
-async fn run_s() {
-    // Check all args/envs are present
-    let addr = env::args()
-        .nth(2)
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
-    println!("ADDR: {}", addr);
-    // Launch in Server mode
-    if let Err(server_error) = Server::new(addr, 10).start().await {
-        error!("Server error: {}", server_error);
+async fn run_watch(config: Config, request: AdminRequest) {
+    let stream = match admin::subscribe(
+        &config.admin.url,
+        config.admin.token.as_deref(),
+        config.admin.ca_cert.as_deref(),
+        &request,
+    )
+    .await
+    {
+        Ok(stream) => stream,
+        Err(admin_error) => {
+            error!("Admin request failed: {}", admin_error);
+            std::process::exit(1);
+        }
     };
+    pin_mut!(stream);
+    while let Some(response) = stream.next().await {
+        match response {
+            Ok(AdminResponse::Error { message }) => {
+                error!("Subscription rejected: {}", message);
+                std::process::exit(1);
+            }
+            Ok(response) => println!(
+                "{}",
+                serde_json::to_string(&response).expect("Impossible serializing error")
+            ),
+            Err(admin_error) => {
+                error!("Admin connection failed: {}", admin_error);
+                std::process::exit(1);
+            }
+        }
+    }
 }
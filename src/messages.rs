@@ -1,6 +1,18 @@
 use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, net::SocketAddr};
 
-use crate::models::TokenInfoResponse;
+use crate::{
+    balances::Portfolio,
+    candles::{Candle, Resolution},
+    cluster::ClusterStatus,
+    decimal::Decimal,
+    models::{LegacyTokenInfoResponse, TokenInfoInnerResponse, TokenInfoResponse},
+    pnl::{PnlReport, RecordedSwap},
+    quotes::SwapQuote,
+    rates::{CrossRate, UsdQuote},
+    router::SwapRoute,
+    transactions::SwapTransaction,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SwapRequest {
@@ -8,10 +20,280 @@ pub enum SwapRequest {
     ValidToken,
     RepeatedToken,
     TokenPrice,
+    UnknownToken,
+    /// Serve prices for this token from now on
+    AssignToken(String),
+    /// Price received by the hub or relayed to it, pushed to relaying peers
+    RelayedPrice(RelayedPrice),
+    /// Secret to present with `token` to claim it again first after a restart
+    /// of the hub
+    ReclaimSecret {
+        token: String,
+        secret: String,
+    },
+}
+
+impl SwapRequest {
+    /// Key used to coalesce queued messages when a peer falls behind
+    pub fn coalesce_key(&self) -> Option<Cow<'_, str>> {
+        match self {
+            // A single pending poll is enough
+            SwapRequest::TokenPrice => Some(Cow::Borrowed("token_price")),
+            // Only the last price of each token matters
+            SwapRequest::RelayedPrice(price) => Some(Cow::Owned(format!("price:{}", price.token))),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SwapResponse {
     WhichToken(String),
+    /// Prices as floats, still sent by older clients
+    LegacyTokenPrice(LegacyTokenInfoResponse),
     TokenPrice(TokenInfoResponse),
+    /// Answer to `WhichToken` of a hub relaying prices instead of serving a
+    /// token, `node` names it in the prices it relays
+    Relay {
+        node: String,
+    },
+    /// Price received by a relaying hub, pushed to the upstream one
+    RelayedPrice(RelayedPrice),
+    /// Answer to `WhichToken` of a client that registered `token` before,
+    /// with the secret it got then
+    ReclaimToken {
+        token: String,
+        secret: String,
+    },
+}
+
+/// Price of `token` received by the hub named `node` from one of its clients
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayedPrice {
+    pub node: String,
+    pub token: String,
+    /// Unix time the hub received it
+    pub at: i64,
+    pub info: TokenInfoInnerResponse,
+}
+
+/// Commands accepted on the admin channel, sent as JSON text messages
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    ListPeers,
+    Disconnect {
+        peer: SocketAddr,
+    },
+    ReleaseToken {
+        token: String,
+    },
+    ReassignToken {
+        token: String,
+        peer: SocketAddr,
+    },
+    PollNow {
+        token: Option<String>,
+    },
+    SetPollInterval {
+        secs: u64,
+        token: Option<String>,
+    },
+    /// Last `limit` candles of a token, the open one included
+    Candles {
+        token: String,
+        resolution: Resolution,
+        limit: Option<usize>,
+    },
+    /// Push every candle closing from now on, optionally only for a token or
+    /// resolution, or the portfolio after every balance refresh or every price
+    /// sample instead. A new subscription replaces the previous one.
+    Subscribe {
+        token: Option<String>,
+        resolution: Option<Resolution>,
+        #[serde(default)]
+        portfolio: bool,
+        #[serde(default)]
+        prices: bool,
+    },
+    /// Price of `base` in `quote` units from the last USD prices of both
+    Rate {
+        base: String,
+        quote: String,
+    },
+    /// Balances of the tracked addresses valued with the last prices, or only
+    /// those of `address`
+    Portfolio {
+        address: Option<String>,
+    },
+    /// Swap made by `address` to take into the cost basis, valued with the
+    /// price history at `timestamp` (now if not set) when `value_usd` isn't given
+    RecordSwap {
+        address: String,
+        from_token: String,
+        amount_in: Decimal,
+        to_token: String,
+        amount_out: Decimal,
+        timestamp: Option<i64>,
+        value_usd: Option<Decimal>,
+        tx_digest: Option<String>,
+    },
+    /// Unrealized and realized PnL now, optionally only of an address or token
+    Pnl {
+        address: Option<String>,
+        token: Option<String>,
+    },
+    /// Daily valuations between the unix times `from` and `to`
+    PnlHistory {
+        address: Option<String>,
+        token: Option<String>,
+        from: Option<i64>,
+        to: Option<i64>,
+    },
+    /// Expected output of swapping `amount` whole `from_token` for `to_token`
+    Quote {
+        from_token: String,
+        to_token: String,
+        amount: Decimal,
+        /// Tolerance for the minimum received, `quotes.slippage_bps` if not set
+        slippage_bps: Option<u32>,
+    },
+    /// Like `Quote` through any path of pools, splitting the amount between
+    /// two paths if that gives more
+    Route {
+        from_token: String,
+        to_token: String,
+        amount: Decimal,
+        slippage_bps: Option<u32>,
+        /// Most pools in a path, 3 if not set
+        max_hops: Option<usize>,
+    },
+    /// Quote a swap and build the unsigned transaction executing it for `sender`
+    BuildSwap {
+        from_token: String,
+        to_token: String,
+        amount: Decimal,
+        slippage_bps: Option<u32>,
+        sender: String,
+        /// Dry-run the transaction to check it and estimate its gas budget
+        #[serde(default)]
+        dry_run: bool,
+    },
+    /// Nodes of the cluster and the tokens registered on each
+    Cluster,
+    /// Every token with a client or a price, the ones restored on startup
+    /// included
+    Tokens,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Peers {
+        peers: Vec<PeerInfo>,
+        /// Admin connections subscribed to pushes
+        #[serde(default)]
+        subscribers: Vec<SubscriberInfo>,
+    },
+    Candles {
+        token: String,
+        resolution: Resolution,
+        candles: Vec<Candle>,
+    },
+    /// Pushed to subscribers when a candle closes
+    CandleClosed {
+        token: String,
+        resolution: Resolution,
+        candle: Candle,
+    },
+    Rate {
+        rate: CrossRate,
+    },
+    /// Pushed to price subscribers for every sample, `node` is the cluster
+    /// node that received it if not this one
+    Price {
+        token: String,
+        node: Option<String>,
+        price: Decimal,
+        confidence: Decimal,
+        timestamp: i64,
+    },
+    /// Also pushed to portfolio subscribers after every balance refresh
+    Portfolio {
+        portfolio: Portfolio,
+    },
+    SwapRecorded {
+        swap: RecordedSwap,
+    },
+    Pnl {
+        report: PnlReport,
+    },
+    PnlHistory {
+        valuations: Vec<PnlReport>,
+    },
+    Quote {
+        quote: SwapQuote,
+    },
+    Route {
+        route: SwapRoute,
+    },
+    SwapTransaction {
+        transaction: SwapTransaction,
+    },
+    Cluster {
+        cluster: ClusterStatus,
+    },
+    Tokens {
+        tokens: Vec<TokenStatus>,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub token: Option<String>,
+    /// Messages waiting to be written to the peer
+    pub queue_depth: usize,
+    /// Messages dropped or coalesced because the peer was too slow
+    pub dropped: u64,
+    /// Last price received from the peer
+    #[serde(default)]
+    pub last_price: Option<Decimal>,
+    /// When the last price was received, as a unix timestamp
+    #[serde(default)]
+    pub last_update: Option<i64>,
+    /// Name of the hub relaying its prices through this connection
+    #[serde(default)]
+    pub relay: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscriberInfo {
+    pub addr: SocketAddr,
+    /// Pushes waiting to be written to the admin connection
+    pub queue_depth: usize,
+    /// Pushes dropped or coalesced because the connection was too slow
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenStatus {
+    pub token: String,
+    /// A client serves it right now
+    pub registered: bool,
+    /// Client serving it, or the one it had before a restart while it's kept
+    /// for it to claim again
+    pub peer: Option<SocketAddr>,
+    /// Seconds left for that client to claim it again
+    pub reclaim_secs: Option<u64>,
+    /// Last USD quote received
+    pub quote: Option<UsdQuote>,
+    /// Prices received, the relayed ones included
+    pub samples: u64,
+    /// Unix time of the last price received
+    pub last_update: Option<i64>,
 }
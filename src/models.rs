@@ -1,17 +1,89 @@
 use chrono::{TimeZone, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use std::{
     collections::HashMap,
     fmt::{self, Debug},
 };
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::{coin_type::CoinType, decimal::Decimal};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TokenInfoResponse {
     pub coins: HashMap<String, TokenInfoInnerResponse>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl TokenInfoResponse {
+    /// Info of `coin_type`, whatever form of it the upstream used as key
+    pub fn get(&self, coin_type: &CoinType) -> Option<&TokenInfoInnerResponse> {
+        self.coins
+            .iter()
+            .find(|(key, _)| coin_type.matches_key(key))
+            .map(|(_, info)| info)
+    }
+
+    /// Parse a body of the price API. Prices and confidences are read from
+    /// the text of the numbers, so none loses digits going through f64.
+    pub fn from_json(body: &[u8]) -> Result<Self, serde_json::Error> {
+        let raw: RawTokenInfoResponse = serde_json::from_slice(body)?;
+        let mut coins = HashMap::with_capacity(raw.coins.len());
+        for (key, info) in raw.coins {
+            let inner = TokenInfoInnerResponse {
+                confidence: exact_decimal(info.confidence)?,
+                decimals: info.decimals,
+                price: exact_decimal(info.price)?,
+                symbol: info.symbol,
+                timestamp: info.timestamp,
+            };
+            coins.insert(key, inner);
+        }
+        Ok(Self { coins })
+    }
+}
+
+/// [`TokenInfoResponse`] as sent by the price API, numbers left unparsed
+#[derive(Deserialize)]
+struct RawTokenInfoResponse<'a> {
+    #[serde(borrow)]
+    coins: HashMap<String, RawTokenInfoInnerResponse<'a>>,
+}
+
+#[derive(Deserialize)]
+struct RawTokenInfoInnerResponse<'a> {
+    #[serde(borrow)]
+    confidence: &'a RawValue,
+    decimals: u64,
+    #[serde(borrow)]
+    price: &'a RawValue,
+    symbol: String,
+    timestamp: TimeStamp,
+}
+
+fn exact_decimal(raw: &RawValue) -> Result<Decimal, serde_json::Error> {
+    match raw.get().parse() {
+        Ok(decimal) => Ok(decimal),
+        // A string, or no number at all, left to the usual deserializer
+        Err(_) => serde_json::from_str(raw.get()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TokenInfoInnerResponse {
+    pub confidence: Decimal,
+    pub decimals: u64,
+    pub price: Decimal,
+    pub symbol: String,
+    pub timestamp: TimeStamp,
+}
+
+/// [`TokenInfoResponse`] as sent by clients from before prices were decimals
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyTokenInfoResponse {
+    pub coins: HashMap<String, LegacyTokenInfoInnerResponse>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyTokenInfoInnerResponse {
     pub confidence: f64,
     pub decimals: u64,
     pub price: f64,
@@ -19,6 +91,27 @@ pub struct TokenInfoInnerResponse {
     pub timestamp: TimeStamp,
 }
 
+impl From<LegacyTokenInfoResponse> for TokenInfoResponse {
+    /// Coins with a negative or non finite price or confidence are dropped
+    fn from(legacy: LegacyTokenInfoResponse) -> Self {
+        let coins = legacy
+            .coins
+            .into_iter()
+            .filter_map(|(key, info)| {
+                let inner = TokenInfoInnerResponse {
+                    confidence: Decimal::from_f64(info.confidence)?,
+                    decimals: info.decimals,
+                    price: Decimal::from_f64(info.price)?,
+                    symbol: info.symbol,
+                    timestamp: info.timestamp,
+                };
+                Some((key, inner))
+            })
+            .collect();
+        Self { coins }
+    }
+}
+
 impl fmt::Display for TokenInfoResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
@@ -39,14 +132,14 @@ impl fmt::Display for TokenInfoInnerResponse {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TimeStamp(pub u64);
 
 impl TimeStamp {
     pub fn to_datetime_string(&self) -> String {
         // Convertir nanosegundos a segundos
         let seconds = (self.0) as i64;
-        match Utc.timestamp_opt(seconds as i64, 0).single() {
+        match Utc.timestamp_opt(seconds, 0).single() {
             Some(datetime) => datetime.format("%d-%m-%Y %H:%M:%S").to_string(),
             None => String::from("Invalid timestamp"),
         }
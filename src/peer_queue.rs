@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// What to do when a message is sent to a peer whose queue is full. Only
+/// keyed messages, updates a later message supersedes, are ever dropped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued keyed message to make room
+    DropOldest,
+    /// Replace the queued message with the same key (e.g. the price of the
+    /// same token) in place, dropping the oldest keyed one if there is none
+    #[default]
+    CoalesceLatest,
+    /// Close the connection, the peer is too slow
+    Disconnect,
+}
+
+struct Entry {
+    key: Option<String>,
+    message: Message,
+}
+
+struct State {
+    queue: VecDeque<Entry>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    closed: bool,
+    /// Messages lost because of the overflow policy
+    dropped: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// Sending half of a bounded per-peer queue, cheap to clone
+#[derive(Clone)]
+pub struct PeerSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of a bounded per-peer queue, owned by the connection task
+pub struct PeerReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Create a queue holding up to `capacity` messages
+pub fn channel(capacity: usize, policy: OverflowPolicy) -> (PeerSender, PeerReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            closed: false,
+            dropped: 0,
+        }),
+        notify: Notify::new(),
+    });
+    (
+        PeerSender {
+            shared: shared.clone(),
+        },
+        PeerReceiver { shared },
+    )
+}
+
+impl PeerSender {
+    /// Queue a message, false if the queue is closed (or gets closed by the
+    /// `Disconnect` policy). Messages with the same `key` may be coalesced.
+    /// Messages without a key are control messages: they are never dropped,
+    /// going past the capacity when only control messages are queued.
+    pub fn send(&self, message: Message, key: Option<&str>) -> bool {
+        let mut state = self.shared.state.lock().expect("Peer queue mutex poisoned");
+        if state.closed {
+            return false;
+        }
+        if state.policy == OverflowPolicy::CoalesceLatest {
+            if let Some(key) = key {
+                if let Some(entry) = state
+                    .queue
+                    .iter_mut()
+                    .find(|entry| entry.key.as_deref() == Some(key))
+                {
+                    entry.message = message;
+                    state.dropped += 1;
+                    return true;
+                }
+            }
+        }
+        if state.queue.len() >= state.capacity {
+            match state.policy {
+                OverflowPolicy::DropOldest | OverflowPolicy::CoalesceLatest => {
+                    match state.queue.iter().position(|entry| entry.key.is_some()) {
+                        Some(oldest) => {
+                            state.queue.remove(oldest);
+                            state.dropped += 1;
+                        }
+                        // Nothing to make room with, the update is the one lost
+                        None if key.is_some() => {
+                            state.dropped += 1;
+                            return true;
+                        }
+                        None => {}
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    state.closed = true;
+                    drop(state);
+                    self.shared.notify.notify_one();
+                    return false;
+                }
+            }
+        }
+        state.queue.push_back(Entry {
+            key: key.map(str::to_string),
+            message,
+        });
+        drop(state);
+        self.shared.notify.notify_one();
+        true
+    }
+
+    /// Close the queue, the receiver stops yielding messages right away
+    pub fn close(&self) {
+        self.shared
+            .state
+            .lock()
+            .expect("Peer queue mutex poisoned")
+            .closed = true;
+        self.shared.notify.notify_one();
+    }
+
+    /// Number of messages waiting to be written to the peer
+    pub fn depth(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .expect("Peer queue mutex poisoned")
+            .queue
+            .len()
+    }
+
+    /// Number of messages dropped or replaced because the peer was too slow
+    pub fn dropped(&self) -> u64 {
+        self.shared
+            .state
+            .lock()
+            .expect("Peer queue mutex poisoned")
+            .dropped
+    }
+}
+
+impl PeerReceiver {
+    /// Wait for the next message, None once the queue is closed
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            let notified = self.shared.notify.notified();
+            {
+                let mut state = self.shared.state.lock().expect("Peer queue mutex poisoned");
+                if state.closed {
+                    return None;
+                }
+                if let Some(entry) = state.queue.pop_front() {
+                    return Some(entry.message);
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for PeerReceiver {
+    fn drop(&mut self) {
+        // Senders must see the connection is gone
+        self.shared
+            .state
+            .lock()
+            .expect("Peer queue mutex poisoned")
+            .closed = true;
+    }
+}
@@ -0,0 +1,1357 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use futures::future::{self, BoxFuture, FutureExt};
+use log::{info, warn};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Duration, Instant},
+};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::{
+    alerts::AlertEngine,
+    balances::{BalanceBook, Balances},
+    candles::{CandleStore, ClosedCandle, Resolution},
+    clock::Clock,
+    cluster::{ClusterMessage, ClusterNode, Registration},
+    config::{ServerConfig, StorageConfig},
+    decimal::Decimal,
+    errors::SwapError,
+    messages::{
+        AdminRequest, AdminResponse, PeerInfo, RelayedPrice, SubscriberInfo, SwapRequest,
+        TokenStatus,
+    },
+    models::{TokenInfoInnerResponse, TokenInfoResponse},
+    pnl::{PnlBook, RecordedSwap},
+    rates::{RateBook, UsdQuote},
+    relay::Upstream,
+    server::{Server, Tx},
+    snapshot::{hash_secret, Snapshot, TokenState},
+    sui_rpc,
+    tokens::TokenRegistry,
+};
+
+/// Connected peer, `token` is set once the server accepts the token it serves
+struct Peer {
+    tx: Tx,
+    token: Option<String>,
+    /// Last time the peer sent us anything, pongs included
+    last_seen: Instant,
+    last_price: Option<Decimal>,
+    /// Unix timestamp of `last_price`
+    last_update: Option<i64>,
+    /// Name of the hub relaying through this peer, which serves no token
+    relay: Option<String>,
+}
+
+/// Prices received for a token, restored from the snapshot on startup
+#[derive(Default)]
+struct TokenStats {
+    samples: u64,
+    /// Unix time of the last one
+    last_update: Option<i64>,
+}
+
+/// Token registered before a restart, kept for the client holding its
+/// reclaim secret
+struct Reservation {
+    /// Client that had it, for display only
+    peer: Option<SocketAddr>,
+    until: Instant,
+}
+
+/// Where the registry got a price from, it's relayed everywhere else
+#[derive(Clone, Copy, PartialEq)]
+enum PriceFrom {
+    Client,
+    Cluster,
+    Relay(SocketAddr),
+    Upstream,
+}
+
+/// Whether this node sends the alerts of `token`: the one its client is
+/// registered on or, when no node has it, the first node of the cluster.
+/// Prices reaching the others only keep their rules from going stale.
+fn notifies_alerts(
+    tokens: &HashMap<String, SocketAddr>,
+    cluster: Option<&ClusterNode>,
+    token: &str,
+) -> bool {
+    tokens.contains_key(token)
+        || cluster.is_none_or(|cluster| cluster.owner(token).is_none() && cluster.leads())
+}
+
+enum Command {
+    Connect {
+        addr: SocketAddr,
+        tx: Tx,
+    },
+    Disconnect {
+        addr: SocketAddr,
+    },
+    Seen {
+        addr: SocketAddr,
+    },
+    ClaimToken {
+        addr: SocketAddr,
+        token: String,
+        secret: Option<String>,
+    },
+    TokenPrice {
+        addr: SocketAddr,
+        token_info: TokenInfoResponse,
+    },
+    Admin {
+        request: AdminRequest,
+        reply_tx: oneshot::Sender<AdminResponse>,
+    },
+    Subscribe {
+        subscriber: Subscriber,
+    },
+    UsdQuote {
+        token: String,
+        reply_tx: oneshot::Sender<Result<UsdQuote, SwapError>>,
+    },
+    Balances {
+        balances: Balances,
+    },
+    Cluster {
+        message: ClusterMessage,
+    },
+    Relay {
+        addr: SocketAddr,
+        node: String,
+    },
+    /// Price relayed by the peer `from`, or by the upstream hub if None
+    RelayedPrice {
+        from: Option<SocketAddr>,
+        price: RelayedPrice,
+    },
+    /// Save the state and stop, answering once saved
+    Shutdown {
+        done_tx: oneshot::Sender<()>,
+    },
+}
+
+/// Admin connection waiting for closed candles, or for the portfolio or
+/// the prices
+pub struct Subscriber {
+    pub addr: SocketAddr,
+    /// Bounded like the queues of the peers, prices coalesced by token
+    pub tx: Tx,
+    pub token: Option<String>,
+    pub resolution: Option<Resolution>,
+    pub portfolio: bool,
+    pub prices: bool,
+}
+
+impl Subscriber {
+    /// Queue `response` as a JSON text message, false once the connection is
+    /// gone
+    fn push(&self, response: &AdminResponse, key: Option<&str>) -> bool {
+        let text = serde_json::to_string(response).expect("Impossible serializing error");
+        self.tx.send(Message::text(text), key)
+    }
+
+    fn wants(&self, closed: &ClosedCandle) -> bool {
+        !self.portfolio
+            && !self.prices
+            && self
+                .token
+                .as_ref()
+                .is_none_or(|token| *token == closed.token)
+            && self
+                .resolution
+                .is_none_or(|resolution| resolution == closed.resolution)
+    }
+}
+
+/// Owner of the peers and tokens state. It runs in its own task and is only
+/// reached through a [`PeerRegistryHandle`], so every change is applied at once
+/// by a single owner and no locks are shared with connection tasks.
+pub struct PeerRegistry {
+    peers: HashMap<SocketAddr, Peer>,
+    /// Index of `peers` by registered token, only updated by `assign`/`unassign_*`
+    tokens: HashMap<String, SocketAddr>,
+    token_registry: Arc<TokenRegistry>,
+    clock: Arc<dyn Clock>,
+    /// Default poll interval and when the next poll round is due
+    period: Duration,
+    next_tick: Instant,
+    /// Peers silent for this long are disconnected, None disables it
+    peer_timeout: Option<Duration>,
+    next_keepalive: Option<Instant>,
+    last_polls: HashMap<String, Instant>,
+    /// Poll intervals per token set through the admin channel
+    interval_overrides: HashMap<String, Duration>,
+    candles: CandleStore,
+    subscribers: Vec<Subscriber>,
+    alerts: AlertEngine,
+    /// Last USD quote of every token, kept after its peer leaves
+    rates: RateBook,
+    balances: BalanceBook,
+    pnl: PnlBook,
+    /// Other nodes sharing registrations and prices, None if not clustered
+    cluster: Option<ClusterNode>,
+    next_heartbeat: Option<Instant>,
+    /// Name of this hub in the prices it relays
+    node: String,
+    /// Hub this one relays its prices to, None if not relaying
+    upstream: Option<Upstream>,
+    stats: HashMap<String, TokenStats>,
+    reserved: HashMap<String, Reservation>,
+    /// Hash of the reclaim secret handed to the last client registered for
+    /// each token, the secrets themselves are never kept
+    secret_hashes: HashMap<String, String>,
+    /// Where the snapshots are saved, and how often if not only on shutdown
+    storage: PathBuf,
+    snapshot_period: Option<Duration>,
+    next_snapshot: Option<Instant>,
+}
+
+/// Cheap to clone handle used to send commands to the [`PeerRegistry`] task
+#[derive(Clone)]
+pub struct PeerRegistryHandle {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl PeerRegistry {
+    /// Start the registry task, polling registered peers every
+    /// `poll_interval_secs` and pinging them every third of `peer_timeout_secs`
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        token_registry: Arc<TokenRegistry>,
+        config: &ServerConfig,
+        storage: &StorageConfig,
+        alerts: AlertEngine,
+        cluster: Option<ClusterNode>,
+        node: String,
+        upstream: Option<Upstream>,
+        clock: Arc<dyn Clock>,
+    ) -> PeerRegistryHandle {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let now = clock.now();
+        let peer_timeout =
+            Some(Duration::from_secs(config.peer_timeout_secs)).filter(|t| !t.is_zero());
+        let snapshot_period =
+            Some(Duration::from_secs(storage.snapshot_secs)).filter(|p| !p.is_zero());
+        let mut registry = Self {
+            peers: HashMap::new(),
+            tokens: HashMap::new(),
+            token_registry,
+            clock,
+            period: Duration::from_secs(config.poll_interval_secs),
+            // First poll round right away, like a tokio interval
+            next_tick: now,
+            peer_timeout,
+            next_keepalive: peer_timeout.map(|timeout| now + timeout / 3),
+            last_polls: HashMap::new(),
+            interval_overrides: HashMap::new(),
+            candles: CandleStore::load(&storage.path.join("candles")),
+            subscribers: Vec::new(),
+            alerts,
+            rates: RateBook::default(),
+            balances: BalanceBook::default(),
+            pnl: PnlBook::load(&storage.path.join("pnl")),
+            // Announce ourselves right away
+            next_heartbeat: cluster.as_ref().map(|_| now),
+            cluster,
+            node,
+            upstream,
+            stats: HashMap::new(),
+            reserved: HashMap::new(),
+            secret_hashes: HashMap::new(),
+            storage: storage.path.clone(),
+            snapshot_period,
+            next_snapshot: snapshot_period.map(|period| now + period),
+        };
+        if let Some(snapshot) = Snapshot::load(&storage.path) {
+            registry.restore(snapshot, Duration::from_secs(storage.reclaim_secs));
+        }
+        tokio::spawn(registry.run(rx));
+        PeerRegistryHandle { tx }
+    }
+
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Command>) {
+        loop {
+            let tick = self.clock.sleep_until(self.next_tick);
+            // Tokens with their own interval are polled when due, not on the
+            // next round
+            let poll: BoxFuture<'static, ()> = match self.next_poll() {
+                Some(deadline) => self.clock.sleep_until(deadline),
+                None => future::pending().boxed(),
+            };
+            let keepalive: BoxFuture<'static, ()> = match self.next_keepalive {
+                Some(deadline) => self.clock.sleep_until(deadline),
+                None => future::pending().boxed(),
+            };
+            let heartbeat: BoxFuture<'static, ()> = match self.next_heartbeat {
+                Some(deadline) => self.clock.sleep_until(deadline),
+                None => future::pending().boxed(),
+            };
+            let snapshot: BoxFuture<'static, ()> = match self.next_snapshot {
+                Some(deadline) => self.clock.sleep_until(deadline),
+                None => future::pending().boxed(),
+            };
+            tokio::select! {
+                _ = tick => {
+                    info!("Sending Messages to all peers");
+                    self.poll_peers(None, false);
+                    self.next_tick = self.next_after(self.next_tick, self.period);
+                    let closed = self.candles.close_due(self.clock.wall_now().timestamp());
+                    self.publish(closed);
+                    let (tokens, cluster) = (&self.tokens, &self.cluster);
+                    self.alerts
+                        .on_tick(|token| notifies_alerts(tokens, cluster.as_ref(), token));
+                },
+                _ = poll => self.poll_peers(None, false),
+                _ = keepalive => self.keepalive(),
+                _ = heartbeat => self.heartbeat(),
+                _ = snapshot => {
+                    if let (Some(deadline), Some(period)) = (self.next_snapshot, self.snapshot_period) {
+                        self.next_snapshot = Some(self.next_after(deadline, period));
+                    }
+                    self.save_state();
+                },
+                command = rx.recv() => match command {
+                    Some(Command::Shutdown { done_tx }) => {
+                        self.save_state();
+                        let _ = done_tx.send(());
+                        break;
+                    }
+                    Some(command) => self.handle_command(command),
+                    // Every handle is gone, the server is shutting down
+                    None => {
+                        self.save_state();
+                        break;
+                    }
+                },
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Connect { addr, tx } => {
+                info!("Inserting peer {} into peer map", addr);
+                let peer = Peer {
+                    tx,
+                    token: None,
+                    last_seen: self.clock.now(),
+                    last_price: None,
+                    last_update: None,
+                    relay: None,
+                };
+                self.peers.insert(addr, peer);
+            }
+            Command::Disconnect { addr } => {
+                self.unassign_peer(addr);
+                self.peers.remove(&addr);
+            }
+            Command::Seen { addr } => self.seen(addr),
+            Command::ClaimToken {
+                addr,
+                token,
+                secret,
+            } => {
+                self.seen(addr);
+                self.claim_token(addr, token, secret);
+            }
+            Command::TokenPrice { addr, token_info } => {
+                self.seen(addr);
+                let wall_now = self.clock.wall_now().timestamp();
+                // Check addr is valid and token is what we expect
+                let Some(Peer {
+                    token: Some(token),
+                    last_price,
+                    last_update,
+                    ..
+                }) = self.peers.get_mut(&addr)
+                else {
+                    warn!("Not Registered yet");
+                    return;
+                };
+                info!("TokenPrice: {}", token_info);
+                // Only the coin of the token the peer serves counts
+                let info = self
+                    .token_registry
+                    .get(token)
+                    .ok()
+                    .and_then(|config| token_info.get(&config.coin_type));
+                let Some(info) = info else {
+                    warn!("No price of {} in the message of {}", token, addr);
+                    return;
+                };
+                *last_price = Some(info.price);
+                *last_update = Some(wall_now);
+                let price = RelayedPrice {
+                    node: self.node.clone(),
+                    token: token.clone(),
+                    at: wall_now,
+                    info: info.clone(),
+                };
+                self.apply_price(&price.token, wall_now, &price.info, None);
+                self.relay_price(price, PriceFrom::Client);
+            }
+            Command::Admin { request, reply_tx } => {
+                let response = self.handle_admin_request(request);
+                // The admin connection may be gone already
+                let _ = reply_tx.send(response);
+            }
+            Command::Subscribe { subscriber } => {
+                // Price subscribers start with the last known prices
+                if subscriber.prices {
+                    for (token, stats) in &self.stats {
+                        if subscriber.token.as_ref().is_some_and(|only| only != token) {
+                            continue;
+                        }
+                        let (Some(quote), Some(last_update)) =
+                            (self.rates.usd(token), stats.last_update)
+                        else {
+                            continue;
+                        };
+                        let price = AdminResponse::Price {
+                            token: token.clone(),
+                            node: None,
+                            price: quote.price,
+                            confidence: quote.confidence,
+                            timestamp: last_update,
+                        };
+                        subscriber.push(&price, Some(token));
+                    }
+                }
+                self.subscribers.push(subscriber);
+            }
+            Command::UsdQuote { token, reply_tx } => {
+                let quote = self.token_registry.get(&token).and_then(|_| {
+                    self.rates
+                        .usd(&token)
+                        .cloned()
+                        .ok_or(SwapError::NoPrice(token))
+                });
+                let _ = reply_tx.send(quote);
+            }
+            Command::Balances { balances } => {
+                let wall_now = self.clock.wall_now().timestamp();
+                self.balances.update(wall_now, balances);
+                self.publish_portfolio();
+                self.snapshot_pnl();
+            }
+            Command::Cluster { message } => self.on_cluster_message(message),
+            Command::Relay { addr, node } => {
+                self.seen(addr);
+                let Some(peer) = self.peers.get_mut(&addr) else {
+                    return;
+                };
+                info!("Peer {} relays the prices of hub {}", addr, node);
+                peer.relay = Some(node);
+                Server::send_swap_request_message(SwapRequest::ValidToken, peer.tx.clone(), addr);
+            }
+            Command::RelayedPrice { from, price } => self.on_relayed_price(from, price),
+            Command::Shutdown { .. } => unreachable!("Handled by the run loop"),
+        }
+    }
+
+    fn on_relayed_price(&mut self, from: Option<SocketAddr>, price: RelayedPrice) {
+        let from = match from {
+            Some(addr) => {
+                self.seen(addr);
+                if self
+                    .peers
+                    .get(&addr)
+                    .is_none_or(|peer| peer.relay.is_none())
+                {
+                    warn!("Relayed price from {}, which isn't a relay", addr);
+                    return;
+                }
+                PriceFrom::Relay(addr)
+            }
+            None => PriceFrom::Upstream,
+        };
+        // Back to where it started through a loop of relays
+        if price.node == self.node {
+            return;
+        }
+        if let Err(registry_error) = self.token_registry.get(&price.token) {
+            warn!("Relayed price from hub {}: {}", price.node, registry_error);
+            return;
+        }
+        // The client serving the token here wins
+        if self.tokens.contains_key(&price.token) {
+            return;
+        }
+        self.apply_price(&price.token, price.at, &price.info, Some(&price.node));
+        self.relay_price(price, from);
+    }
+
+    /// Pass a price on to the relaying peers but the one it came from, and to
+    /// the cluster and the upstream hub if it came from below
+    fn relay_price(&self, price: RelayedPrice, from: PriceFrom) {
+        if matches!(from, PriceFrom::Client | PriceFrom::Relay(_)) {
+            if let Some(cluster) = &self.cluster {
+                cluster.send_price(&price.token, price.at, &price.info);
+            }
+            if let Some(upstream) = &self.upstream {
+                upstream.send_price(&price);
+            }
+        }
+        for (addr, peer) in &self.peers {
+            if peer.relay.is_some() && from != PriceFrom::Relay(*addr) {
+                let message = SwapRequest::RelayedPrice(price.clone());
+                Server::send_swap_request_message(message, peer.tx.clone(), *addr);
+            }
+        }
+    }
+
+    /// Aggregate a price of `token` received at unix time `at`, by this node
+    /// or by the one named `node`, and push it to the subscribers
+    fn apply_price(
+        &mut self,
+        token: &str,
+        at: i64,
+        info: &TokenInfoInnerResponse,
+        node: Option<&str>,
+    ) {
+        self.rates.update(token, info.into());
+        // Every node tracks the rules, only one sends the alerts
+        let notify = notifies_alerts(&self.tokens, self.cluster.as_ref(), token);
+        self.alerts
+            .on_price(token, info.price, info.confidence, notify);
+        let stats = self.stats.entry(token.to_string()).or_default();
+        stats.samples += 1;
+        stats.last_update = Some(at);
+        let closed = self
+            .candles
+            .add_sample(token, at, info.price, info.confidence);
+        let price = AdminResponse::Price {
+            token: token.to_string(),
+            node: node.map(str::to_string),
+            price: info.price,
+            confidence: info.confidence,
+            timestamp: at,
+        };
+        self.subscribers.retain(|subscriber| {
+            !subscriber.prices
+                || subscriber.token.as_ref().is_some_and(|only| only != token)
+                || subscriber.push(&price, Some(token))
+        });
+        self.publish(closed);
+    }
+
+    fn on_cluster_message(&mut self, message: ClusterMessage) {
+        let now = self.clock.now();
+        let Some(cluster) = &mut self.cluster else {
+            warn!("Cluster message received with clustering disabled");
+            return;
+        };
+        match message {
+            ClusterMessage::State {
+                node,
+                incarnation,
+                registrations,
+            } => {
+                let our_node = cluster.node().to_string();
+                // Registered here and on a node sorting first at the same
+                // time, that node keeps it
+                let conflicts: Vec<String> = registrations
+                    .iter()
+                    .filter(|registration| {
+                        node < our_node && self.tokens.contains_key(&registration.token)
+                    })
+                    .map(|registration| registration.token.clone())
+                    .collect();
+                if !cluster.update(node.clone(), incarnation, registrations, now) {
+                    return;
+                }
+                for token in conflicts {
+                    warn!(
+                        "Token {} is also registered on node {}, releasing it",
+                        token, node
+                    );
+                    self.revoke_token(&token);
+                }
+            }
+            ClusterMessage::Price {
+                node,
+                token,
+                at,
+                info,
+            } => {
+                cluster.seen(&node, now);
+                if self.token_registry.get(&token).is_err() {
+                    warn!("Price of unknown token {} from node {}", token, node);
+                    return;
+                }
+                self.apply_price(&token, at, &info, Some(&node));
+                let price = RelayedPrice {
+                    node,
+                    token,
+                    at,
+                    info,
+                };
+                self.relay_price(price, PriceFrom::Cluster);
+            }
+        }
+    }
+
+    /// Tokens registered on this node
+    fn registrations(&self) -> Vec<Registration> {
+        let mut registrations: Vec<Registration> = self
+            .tokens
+            .iter()
+            .map(|(token, addr)| Registration {
+                token: token.clone(),
+                peer: *addr,
+            })
+            .collect();
+        registrations.sort_by(|a, b| a.token.cmp(&b.token));
+        registrations
+    }
+
+    /// Tell the other nodes which tokens are registered here
+    fn send_state(&self) {
+        if let Some(cluster) = &self.cluster {
+            cluster.send_state(self.registrations());
+        }
+    }
+
+    /// Send our state to the other nodes and check theirs is recent enough
+    fn heartbeat(&mut self) {
+        let Some(deadline) = self.next_heartbeat else {
+            return;
+        };
+        let Some(heartbeat) = self.cluster.as_ref().map(ClusterNode::heartbeat) else {
+            return;
+        };
+        self.next_heartbeat = Some(self.next_after(deadline, heartbeat));
+        self.send_state();
+        let now = self.clock.now();
+        if let Some(cluster) = &mut self.cluster {
+            cluster.expire(now);
+        }
+    }
+
+    /// Keep the PnL valued with the current balances and prices as today's
+    fn snapshot_pnl(&mut self) {
+        let wall_now = self.clock.wall_now().timestamp();
+        let portfolio = self
+            .balances
+            .portfolio(None, &self.token_registry, &self.rates)
+            .ok();
+        let report = self.pnl.report(wall_now, portfolio.as_ref(), &self.rates);
+        self.pnl.snapshot(report);
+    }
+
+    /// Swap of the admin request, valued with the price history when the
+    /// value isn't given
+    #[allow(clippy::too_many_arguments)]
+    fn recorded_swap(
+        &self,
+        address: &str,
+        from_token: String,
+        amount_in: Decimal,
+        to_token: String,
+        amount_out: Decimal,
+        timestamp: Option<i64>,
+        value_usd: Option<Decimal>,
+        tx_digest: Option<String>,
+    ) -> Result<RecordedSwap, SwapError> {
+        let address = sui_rpc::normalize_address(address)?;
+        for token in [&from_token, &to_token] {
+            self.token_registry.get(token)?;
+        }
+        if from_token == to_token {
+            return Err(SwapError::InvalidSwap(format!(
+                "{} swapped for itself",
+                from_token
+            )));
+        }
+        if amount_in.is_zero() || amount_out.is_zero() {
+            return Err(SwapError::InvalidSwap(
+                "amounts must be greater than 0".to_string(),
+            ));
+        }
+        let timestamp = timestamp.unwrap_or_else(|| self.clock.wall_now().timestamp());
+        let value_usd = match value_usd {
+            Some(value_usd) => value_usd,
+            // Either side gives the value, the sold one first
+            None => {
+                let value_of = |token: &str, amount: Decimal| {
+                    let price = self.candles.price_at(token, timestamp)?;
+                    Some(
+                        price
+                            .checked_mul(amount)
+                            .ok_or(SwapError::InvalidAmount(amount)),
+                    )
+                };
+                value_of(&from_token, amount_in)
+                    .or_else(|| value_of(&to_token, amount_out))
+                    .ok_or_else(|| SwapError::NoPrice(from_token.clone()))??
+            }
+        };
+        Ok(RecordedSwap {
+            address,
+            timestamp,
+            from_token,
+            amount_in,
+            to_token,
+            amount_out,
+            value_usd,
+            tx_digest,
+        })
+    }
+
+    /// Push the portfolio valued with the current prices to its subscribers
+    fn publish_portfolio(&mut self) {
+        let Ok(portfolio) = self
+            .balances
+            .portfolio(None, &self.token_registry, &self.rates)
+        else {
+            return;
+        };
+        let portfolio = AdminResponse::Portfolio { portfolio };
+        self.subscribers.retain(|subscriber| {
+            !subscriber.portfolio || subscriber.push(&portfolio, Some("portfolio"))
+        });
+    }
+
+    /// Push closed candles to the subscribers, dropping the ones gone
+    fn publish(&mut self, closed: Vec<ClosedCandle>) {
+        if closed.is_empty() {
+            return;
+        }
+        self.subscribers.retain(|subscriber| {
+            closed
+                .iter()
+                .filter(|closed| subscriber.wants(closed))
+                .all(|closed| {
+                    let message = AdminResponse::CandleClosed {
+                        token: closed.token.clone(),
+                        resolution: closed.resolution,
+                        candle: closed.candle.clone(),
+                    };
+                    // Keyed so a slow subscriber loses old candles, never
+                    // coalesced as no two candles share a key
+                    let key = format!(
+                        "candle:{}:{}:{}",
+                        closed.token, closed.resolution, closed.candle.start
+                    );
+                    subscriber.push(&message, Some(&key))
+                })
+        });
+    }
+
+    fn seen(&mut self, addr: SocketAddr) {
+        let now = self.clock.now();
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.last_seen = now;
+        }
+    }
+
+    /// Take back the prices, stats and poll intervals of `snapshot`, keeping
+    /// its registered tokens for their clients during `reclaim`
+    fn restore(&mut self, snapshot: Snapshot, reclaim: Duration) {
+        let until = self.clock.now() + reclaim;
+        for state in snapshot.tokens {
+            if let Err(registry_error) = self.token_registry.get(&state.token) {
+                warn!(
+                    "Not restoring the state of {}: {}",
+                    state.token, registry_error
+                );
+                continue;
+            }
+            if let Some(quote) = state.quote {
+                self.rates.update(&state.token, quote);
+            }
+            // Snapshots from before the secrets reserve nothing
+            if let Some(secret_hash) = state.secret_hash {
+                if !reclaim.is_zero() {
+                    let reservation = Reservation {
+                        peer: state.peer,
+                        until,
+                    };
+                    self.reserved.insert(state.token.clone(), reservation);
+                }
+                self.secret_hashes.insert(state.token.clone(), secret_hash);
+            }
+            let stats = TokenStats {
+                samples: state.samples,
+                last_update: state.last_update,
+            };
+            self.stats.insert(state.token, stats);
+        }
+        for (token, secs) in snapshot.poll_intervals {
+            if secs > 0 {
+                self.interval_overrides
+                    .insert(token, Duration::from_secs(secs));
+            }
+        }
+    }
+
+    /// Every token with a client, a reservation or a price, sorted
+    fn token_statuses(&self) -> Vec<TokenStatus> {
+        let now = self.clock.now();
+        let names: BTreeSet<&String> = self
+            .stats
+            .keys()
+            .chain(self.tokens.keys())
+            .chain(self.reserved.keys())
+            .collect();
+        names
+            .into_iter()
+            .map(|token| {
+                let reservation = self
+                    .reserved
+                    .get(token)
+                    .filter(|reservation| reservation.until > now);
+                let stats = self.stats.get(token);
+                TokenStatus {
+                    token: token.clone(),
+                    registered: self.tokens.contains_key(token),
+                    peer: self
+                        .tokens
+                        .get(token)
+                        .copied()
+                        .or(reservation.and_then(|reservation| reservation.peer)),
+                    reclaim_secs: reservation
+                        .map(|reservation| reservation.until.duration_since(now).as_secs()),
+                    quote: self.rates.usd(token).cloned(),
+                    samples: stats.map_or(0, |stats| stats.samples),
+                    last_update: stats.and_then(|stats| stats.last_update),
+                }
+            })
+            .collect()
+    }
+
+    /// Save the registered tokens, last prices and poll intervals
+    fn save_state(&mut self) {
+        let now = self.clock.now();
+        self.reserved
+            .retain(|_, reservation| reservation.until > now);
+        let snapshot = Snapshot {
+            taken_at: self.clock.wall_now().timestamp(),
+            tokens: self
+                .token_statuses()
+                .into_iter()
+                .map(|status| TokenState {
+                    secret_hash: self.secret_hashes.get(&status.token).cloned(),
+                    token: status.token,
+                    peer: status.peer,
+                    quote: status.quote,
+                    samples: status.samples,
+                    last_update: status.last_update,
+                })
+                .collect(),
+            poll_intervals: self
+                .interval_overrides
+                .iter()
+                .map(|(token, interval)| (token.clone(), interval.as_secs()))
+                .collect(),
+        };
+        snapshot.save(&self.storage);
+    }
+
+    /// Next deadline of a schedule, skipping the ones already missed
+    fn next_after(&self, deadline: Instant, period: Duration) -> Instant {
+        let now = self.clock.now();
+        let next = deadline + period;
+        if next <= now {
+            now + period
+        } else {
+            next
+        }
+    }
+
+    /// Ping every peer and disconnect the ones silent for longer than the timeout
+    fn keepalive(&mut self) {
+        let (Some(peer_timeout), Some(deadline)) = (self.peer_timeout, self.next_keepalive) else {
+            return;
+        };
+        self.next_keepalive = Some(self.next_after(deadline, peer_timeout / 3));
+        let now = self.clock.now();
+        let mut failed = Vec::new();
+        for (addr, peer) in &self.peers {
+            if now.duration_since(peer.last_seen) > peer_timeout {
+                warn!("Peer {} timed out, disconnecting", addr);
+                // Closing the queue ends the connection task, which unregisters the peer
+                peer.tx.close();
+            } else if !peer.tx.send(Message::Ping(Vec::new()), Some("ping")) {
+                failed.push(*addr);
+            }
+        }
+        for addr in failed {
+            self.unassign_peer(addr);
+            self.peers.remove(&addr);
+        }
+    }
+
+    /// Response to our WhichToken message
+    fn claim_token(&mut self, addr: SocketAddr, token: String, secret: Option<String>) {
+        let Some(peer) = self.peers.get(&addr) else {
+            return;
+        };
+        let tx = peer.tx.clone();
+        let response = if let Err(registry_error) = self.token_registry.get(&token) {
+            // Token not listed or disabled in tokens file
+            warn!("Rejecting token from {}: {}", addr, registry_error);
+            SwapRequest::UnknownToken
+        } else if self.tokens.contains_key(&token) {
+            info!("Token already taken");
+            SwapRequest::RepeatedToken
+        } else if self.reserved.get(&token).is_some_and(|reservation| {
+            reservation.until > self.clock.now()
+                && !secret
+                    .as_deref()
+                    .map(hash_secret)
+                    .zip(self.secret_hashes.get(&token))
+                    .is_some_and(|(presented, kept)| {
+                        presented.as_bytes().ct_eq(kept.as_bytes()).into()
+                    })
+        }) {
+            info!("Token kept for its previous client to claim it again");
+            SwapRequest::RepeatedToken
+        } else if let Some(node) = self
+            .cluster
+            .as_ref()
+            .and_then(|cluster| cluster.owner(&token))
+        {
+            info!("Token already taken on node {}", node);
+            SwapRequest::RepeatedToken
+        } else {
+            info!("Token not taken");
+            self.assign(addr, token);
+            SwapRequest::ValidToken
+        };
+        Server::send_swap_request_message(response, tx, addr);
+    }
+
+    /// Make `addr` the peer serving `token`, releasing whatever both had before
+    fn assign(&mut self, addr: SocketAddr, token: String) {
+        self.reserved.remove(&token);
+        if self.tokens.get(&token) != Some(&addr) {
+            self.revoke_token(&token);
+        }
+        self.unassign_peer(addr);
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.token = Some(token.clone());
+            let secret = new_secret();
+            let message = SwapRequest::ReclaimSecret {
+                token: token.clone(),
+                secret: secret.clone(),
+            };
+            Server::send_swap_request_message(message, peer.tx.clone(), addr);
+            self.secret_hashes
+                .insert(token.clone(), hash_secret(&secret));
+            self.tokens.insert(token, addr);
+        }
+        self.send_state();
+    }
+
+    fn unassign_token(&mut self, token: &str) -> Option<SocketAddr> {
+        let addr = self.tokens.remove(token)?;
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.token = None;
+        }
+        self.send_state();
+        Some(addr)
+    }
+
+    /// Take `token` from its client, telling it the token is taken so it stops
+    /// serving it
+    fn revoke_token(&mut self, token: &str) -> Option<SocketAddr> {
+        let addr = self.unassign_token(token)?;
+        if let Some(peer) = self.peers.get(&addr) {
+            Server::send_swap_request_message(SwapRequest::RepeatedToken, peer.tx.clone(), addr);
+        }
+        Some(addr)
+    }
+
+    fn unassign_peer(&mut self, addr: SocketAddr) {
+        if let Some(token) = self.peers.get_mut(&addr).and_then(|peer| peer.token.take()) {
+            self.tokens.remove(&token);
+            self.send_state();
+        }
+    }
+
+    /// Interval between polls of `token`: the admin override, the tokens file
+    /// or the server default
+    fn poll_interval(&self, token: &str) -> Duration {
+        self.interval_overrides
+            .get(token)
+            .copied()
+            .or_else(|| {
+                self.token_registry
+                    .get(token)
+                    .ok()
+                    .and_then(|token_config| token_config.poll_interval())
+            })
+            .unwrap_or(self.period)
+    }
+
+    /// Earliest time a registered token polled before is due again, the ones
+    /// never polled wait for the next round
+    fn next_poll(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter_map(|peer| peer.token.as_ref())
+            .filter_map(|token| {
+                let last_poll = self.last_polls.get(token)?;
+                Some(*last_poll + self.poll_interval(token))
+            })
+            .min()
+    }
+
+    /// Send TokenPrice message to the peers whose token is due, or to every
+    /// registered peer (or only the one serving `only_token`) if `force` is set
+    fn poll_peers(&mut self, only_token: Option<&str>, force: bool) {
+        let now = self.clock.now();
+        let mut failed = Vec::new();
+        for (peer_addr, peer) in &self.peers {
+            // Don't remove peers without token because can just subscribed, but would be nice to remove peers that don't message us back in x time
+            let Some(token) = &peer.token else {
+                continue;
+            };
+            if only_token.is_some_and(|only_token| only_token != token) {
+                continue;
+            }
+            let interval = self.poll_interval(token);
+            if let Some(last_poll) = self.last_polls.get(token) {
+                if !force && now.duration_since(*last_poll) < interval {
+                    continue;
+                }
+            }
+            self.last_polls.insert(token.clone(), now);
+            let message = SwapRequest::TokenPrice;
+            if !Server::send_swap_request_message(message, peer.tx.clone(), *peer_addr) {
+                failed.push(*peer_addr);
+            }
+        }
+        for addr in failed {
+            self.unassign_peer(addr);
+            self.peers.remove(&addr);
+        }
+    }
+
+    fn handle_admin_request(&mut self, request: AdminRequest) -> AdminResponse {
+        let error = |message: String| AdminResponse::Error { message };
+        match request {
+            AdminRequest::ListPeers => {
+                let mut peers: Vec<PeerInfo> = self
+                    .peers
+                    .iter()
+                    .map(|(addr, peer)| PeerInfo {
+                        addr: *addr,
+                        token: peer.token.clone(),
+                        queue_depth: peer.tx.depth(),
+                        dropped: peer.tx.dropped(),
+                        last_price: peer.last_price,
+                        last_update: peer.last_update,
+                        relay: peer.relay.clone(),
+                    })
+                    .collect();
+                peers.sort_by_key(|peer| peer.addr);
+                let mut subscribers: Vec<SubscriberInfo> = self
+                    .subscribers
+                    .iter()
+                    .map(|subscriber| SubscriberInfo {
+                        addr: subscriber.addr,
+                        queue_depth: subscriber.tx.depth(),
+                        dropped: subscriber.tx.dropped(),
+                    })
+                    .collect();
+                subscribers.sort_by_key(|subscriber| subscriber.addr);
+                AdminResponse::Peers { peers, subscribers }
+            }
+            AdminRequest::Disconnect { peer } => {
+                let Some(peer_entry) = self.peers.get(&peer) else {
+                    return error(format!("Unknown peer {}", peer));
+                };
+                info!("Disconnecting peer {} by admin request", peer);
+                // Closing the queue ends the connection task, which unregisters the peer
+                peer_entry.tx.close();
+                AdminResponse::Done
+            }
+            AdminRequest::ReleaseToken { token } => {
+                let Some(addr) = self.revoke_token(&token) else {
+                    return error(format!("Token {} is not registered", token));
+                };
+                info!("Released token {} from {} by admin request", token, addr);
+                AdminResponse::Done
+            }
+            AdminRequest::ReassignToken { token, peer } => {
+                if let Err(registry_error) = self.token_registry.get(&token) {
+                    return error(registry_error.to_string());
+                }
+                let Some(peer_entry) = self.peers.get(&peer) else {
+                    return error(format!("Unknown peer {}", peer));
+                };
+                if let Some(node) = &peer_entry.relay {
+                    return error(format!(
+                        "Peer {} relays hub {}, it serves no token",
+                        peer, node
+                    ));
+                }
+                if let Some(node) = self
+                    .cluster
+                    .as_ref()
+                    .and_then(|cluster| cluster.owner(&token))
+                {
+                    return error(format!("Token {} is registered on node {}", token, node));
+                }
+                let tx = peer_entry.tx.clone();
+                self.assign(peer, token.clone());
+                info!("Reassigned token {} to {} by admin request", token, peer);
+                let message = SwapRequest::AssignToken(token);
+                Server::send_swap_request_message(message, tx, peer);
+                AdminResponse::Done
+            }
+            AdminRequest::PollNow { token } => {
+                if let Some(token) = &token {
+                    if !self.tokens.contains_key(token) {
+                        return error(format!("Token {} is not registered", token));
+                    }
+                }
+                self.poll_peers(token.as_deref(), true);
+                AdminResponse::Done
+            }
+            AdminRequest::SetPollInterval { secs, token } => {
+                if secs == 0 {
+                    return error("Interval must be greater than 0".to_string());
+                }
+                let interval = Duration::from_secs(secs);
+                match token {
+                    Some(token) => {
+                        info!("Poll interval for {} set to {}s", token, secs);
+                        self.interval_overrides.insert(token, interval);
+                    }
+                    None => {
+                        info!("Poll interval set to {}s", secs);
+                        self.period = interval;
+                        self.next_tick = self.clock.now() + interval;
+                    }
+                }
+                AdminResponse::Done
+            }
+            AdminRequest::Candles {
+                token,
+                resolution,
+                limit,
+            } => {
+                if let Err(registry_error) = self.token_registry.get(&token) {
+                    return error(registry_error.to_string());
+                }
+                let candles = self
+                    .candles
+                    .query(&token, resolution, limit.unwrap_or(usize::MAX));
+                AdminResponse::Candles {
+                    token,
+                    resolution,
+                    candles,
+                }
+            }
+            AdminRequest::Rate { base, quote } => {
+                for token in [&base, &quote] {
+                    if let Err(registry_error) = self.token_registry.get(token) {
+                        return error(registry_error.to_string());
+                    }
+                }
+                match self.rates.cross(&base, &quote) {
+                    Ok(rate) => AdminResponse::Rate { rate },
+                    Err(rate_error) => error(rate_error.to_string()),
+                }
+            }
+            AdminRequest::Portfolio { address } => {
+                match self
+                    .balances
+                    .portfolio(address.as_deref(), &self.token_registry, &self.rates)
+                {
+                    Ok(portfolio) => AdminResponse::Portfolio { portfolio },
+                    Err(portfolio_error) => error(portfolio_error.to_string()),
+                }
+            }
+            AdminRequest::RecordSwap {
+                address,
+                from_token,
+                amount_in,
+                to_token,
+                amount_out,
+                timestamp,
+                value_usd,
+                tx_digest,
+            } => {
+                let swap = match self.recorded_swap(
+                    &address, from_token, amount_in, to_token, amount_out, timestamp, value_usd,
+                    tx_digest,
+                ) {
+                    Ok(swap) => swap,
+                    Err(swap_error) => return error(swap_error.to_string()),
+                };
+                if let Err(record_error) = self.pnl.record(swap.clone()) {
+                    return error(record_error.to_string());
+                }
+                info!(
+                    "Recorded swap of {} {} for {} {} by {}",
+                    swap.amount_in, swap.from_token, swap.amount_out, swap.to_token, swap.address
+                );
+                self.snapshot_pnl();
+                AdminResponse::SwapRecorded { swap }
+            }
+            AdminRequest::Pnl { address, token } => {
+                let address = match address.as_deref().map(sui_rpc::normalize_address) {
+                    Some(Err(address_error)) => return error(address_error.to_string()),
+                    address => address.transpose().expect("Checked above"),
+                };
+                let portfolio = self
+                    .balances
+                    .portfolio(None, &self.token_registry, &self.rates)
+                    .ok();
+                let report = self.pnl.query(
+                    self.clock.wall_now().timestamp(),
+                    portfolio.as_ref(),
+                    &self.rates,
+                    address.as_deref(),
+                    token.as_deref(),
+                );
+                AdminResponse::Pnl { report }
+            }
+            AdminRequest::PnlHistory {
+                address,
+                token,
+                from,
+                to,
+            } => {
+                let address = match address.as_deref().map(sui_rpc::normalize_address) {
+                    Some(Err(address_error)) => return error(address_error.to_string()),
+                    address => address.transpose().expect("Checked above"),
+                };
+                let valuations = self
+                    .pnl
+                    .history(address.as_deref(), token.as_deref(), from, to);
+                AdminResponse::PnlHistory { valuations }
+            }
+            AdminRequest::Cluster => match &self.cluster {
+                Some(cluster) => AdminResponse::Cluster {
+                    cluster: cluster.status(self.registrations(), self.clock.now()),
+                },
+                None => error("Clustering is not enabled, set cluster.listen".to_string()),
+            },
+            AdminRequest::Tokens => AdminResponse::Tokens {
+                tokens: self.token_statuses(),
+            },
+            // Needs the connection, see `PeerRegistryHandle::subscribe`
+            AdminRequest::Subscribe { .. } => {
+                error("Subscriptions are only available on admin connections".to_string())
+            }
+            // Reading the pools would block the registry, see `admin::quote`
+            AdminRequest::Quote { .. }
+            | AdminRequest::Route { .. }
+            | AdminRequest::BuildSwap { .. } => {
+                error("Quotes are only available on admin connections".to_string())
+            }
+        }
+    }
+}
+
+impl PeerRegistryHandle {
+    // Sending only fails when the registry task is gone, which means the server
+    // is shutting down, so there is nothing left to update
+
+    pub fn connect(&self, addr: SocketAddr, tx: Tx) {
+        let _ = self.tx.send(Command::Connect { addr, tx });
+    }
+
+    pub fn disconnect(&self, addr: SocketAddr) {
+        let _ = self.tx.send(Command::Disconnect { addr });
+    }
+
+    /// The peer answered a ping
+    pub fn seen(&self, addr: SocketAddr) {
+        let _ = self.tx.send(Command::Seen { addr });
+    }
+
+    /// `secret` is the reclaim secret of a client that had the token before
+    pub fn claim_token(&self, addr: SocketAddr, token: String, secret: Option<String>) {
+        let _ = self.tx.send(Command::ClaimToken {
+            addr,
+            token,
+            secret,
+        });
+    }
+
+    pub fn token_price(&self, addr: SocketAddr, token_info: TokenInfoResponse) {
+        let _ = self.tx.send(Command::TokenPrice { addr, token_info });
+    }
+
+    /// Get closed candles matching the filters through `tx`
+    pub fn subscribe(&self, subscriber: Subscriber) {
+        let _ = self.tx.send(Command::Subscribe { subscriber });
+    }
+
+    /// The peer is a hub relaying its prices
+    pub fn relay(&self, addr: SocketAddr, node: String) {
+        let _ = self.tx.send(Command::Relay { addr, node });
+    }
+
+    /// Price relayed by the peer at `from`, or by the upstream hub if None
+    pub fn relayed_price(&self, from: Option<SocketAddr>, price: RelayedPrice) {
+        let _ = self.tx.send(Command::RelayedPrice { from, price });
+    }
+
+    /// Stop the registry, returns once its state is saved
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(Command::Shutdown { done_tx }).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Message from another node of the cluster
+    pub fn cluster(&self, message: ClusterMessage) {
+        let _ = self.tx.send(Command::Cluster { message });
+    }
+
+    /// Balances just read, replacing the previous ones of those addresses
+    pub fn balances(&self, balances: Balances) {
+        let _ = self.tx.send(Command::Balances { balances });
+    }
+
+    /// Last USD quote received for a token of the tokens file
+    pub async fn usd_quote(&self, token: &str) -> Result<UsdQuote, SwapError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let command = Command::UsdQuote {
+            token: token.to_string(),
+            reply_tx,
+        };
+        if self.tx.send(command).is_err() {
+            return Err(SwapError::NoPrice(token.to_string()));
+        }
+        reply_rx
+            .await
+            .unwrap_or_else(|_| Err(SwapError::NoPrice(token.to_string())))
+    }
+
+    pub async fn admin(&self, request: AdminRequest) -> AdminResponse {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self.tx.send(Command::Admin { request, reply_tx }).is_err() {
+            return AdminResponse::Error {
+                message: "Server is shutting down".to_string(),
+            };
+        }
+        reply_rx.await.unwrap_or_else(|_| AdminResponse::Error {
+            message: "Server is shutting down".to_string(),
+        })
+    }
+}
+
+/// Random reclaim secret handed to a client when it registers a token
+fn new_secret() -> String {
+    let mut secret = [0u8; 16];
+    OsRng.fill_bytes(&mut secret);
+    hex::encode(secret)
+}
@@ -0,0 +1,392 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    balances::Portfolio, candles::Resolution, decimal::Decimal, errors::SwapError, rates::RateBook,
+};
+
+const SWAPS_FILE: &str = "swaps.jsonl";
+const VALUATIONS_FILE: &str = "valuations.jsonl";
+
+/// Swap made by a tracked address, amounts in whole tokens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedSwap {
+    pub address: String,
+    /// Unix time of the swap
+    pub timestamp: i64,
+    pub from_token: String,
+    pub amount_in: Decimal,
+    pub to_token: String,
+    pub amount_out: Decimal,
+    /// USD value of the swap, the cost of what was bought and the proceeds of
+    /// what was sold
+    pub value_usd: Decimal,
+    #[serde(default)]
+    pub tx_digest: Option<String>,
+}
+
+/// Holding of a token built from the recorded swaps, average cost method
+#[derive(Debug, Clone, Default)]
+struct Position {
+    amount: Decimal,
+    cost_usd: Decimal,
+    /// Proceeds and cost of what was sold, their difference is the realized PnL
+    sold_usd: Decimal,
+    sold_cost_usd: Decimal,
+}
+
+impl Position {
+    fn realized_pnl_usd(&self) -> f64 {
+        signed(self.sold_usd, self.sold_cost_usd)
+    }
+}
+
+/// `plus - minus`, which decimals can't hold when negative
+fn signed(plus: Decimal, minus: Decimal) -> f64 {
+    match plus.checked_sub(minus) {
+        Some(difference) => difference.to_f64(),
+        None => -minus.saturating_sub(plus).to_f64(),
+    }
+}
+
+/// PnL of a token held by an address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PnlEntry {
+    pub address: String,
+    pub token: String,
+    /// Held now, from the balances if the address is tracked or from the
+    /// recorded swaps if not
+    pub amount: f64,
+    pub price_usd: Option<f64>,
+    pub value_usd: Option<f64>,
+    /// Part of `amount` bought through recorded swaps
+    pub costed_amount: f64,
+    pub cost_basis_usd: f64,
+    /// Value of the costed amount minus its cost basis, unknown without a price
+    pub unrealized_pnl_usd: Option<f64>,
+    /// Proceeds minus cost of what the recorded swaps sold
+    pub realized_pnl_usd: f64,
+}
+
+/// Sums over a group of entries, the ones without a price only add their
+/// cost basis and realized PnL
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PnlTotals {
+    pub value_usd: f64,
+    pub cost_basis_usd: f64,
+    pub unrealized_pnl_usd: f64,
+    pub realized_pnl_usd: f64,
+}
+
+impl PnlTotals {
+    fn add(&mut self, entry: &PnlEntry) {
+        self.value_usd += entry.value_usd.unwrap_or_default();
+        self.cost_basis_usd += entry.cost_basis_usd;
+        self.unrealized_pnl_usd += entry.unrealized_pnl_usd.unwrap_or_default();
+        self.realized_pnl_usd += entry.realized_pnl_usd;
+    }
+}
+
+/// PnL of every address and token at a point in time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PnlReport {
+    /// Unix time of the valuation
+    pub timestamp: i64,
+    pub entries: Vec<PnlEntry>,
+    pub by_address: BTreeMap<String, PnlTotals>,
+    pub by_token: BTreeMap<String, PnlTotals>,
+    pub total: PnlTotals,
+}
+
+impl PnlReport {
+    fn new(timestamp: i64, entries: Vec<PnlEntry>) -> Self {
+        let mut report = Self {
+            timestamp,
+            entries,
+            by_address: BTreeMap::new(),
+            by_token: BTreeMap::new(),
+            total: PnlTotals::default(),
+        };
+        for entry in &report.entries {
+            report
+                .by_address
+                .entry(entry.address.clone())
+                .or_default()
+                .add(entry);
+            report
+                .by_token
+                .entry(entry.token.clone())
+                .or_default()
+                .add(entry);
+            report.total.add(entry);
+        }
+        report
+    }
+
+    /// Same report with only the entries of `address` and `token`
+    fn filter(self, address: Option<&str>, token: Option<&str>) -> Self {
+        let entries = self
+            .entries
+            .into_iter()
+            .filter(|entry| address.is_none_or(|address| entry.address == address))
+            .filter(|entry| token.is_none_or(|token| entry.token == token))
+            .collect();
+        Self::new(self.timestamp, entries)
+    }
+}
+
+/// Cost basis from the swaps recorded through the admin channel and a daily
+/// valuation of the portfolio, both saved under `dir` when set:
+/// `swaps.jsonl` is append only and `valuations.jsonl` has the last report of
+/// every day.
+pub struct PnlBook {
+    swaps: Vec<RecordedSwap>,
+    positions: HashMap<(String, String), Position>,
+    /// Last report of each day by day start
+    valuations: BTreeMap<i64, PnlReport>,
+    dir: Option<PathBuf>,
+}
+
+impl PnlBook {
+    /// Book kept only in memory
+    pub fn new() -> Self {
+        Self {
+            swaps: Vec::new(),
+            positions: HashMap::new(),
+            valuations: BTreeMap::new(),
+            dir: None,
+        }
+    }
+
+    /// Book persisted in `dir`, loading the swaps and valuations saved there
+    pub fn load(dir: &Path) -> Self {
+        let mut book = Self {
+            dir: Some(dir.to_path_buf()),
+            ..Self::new()
+        };
+        for swap in read_lines::<RecordedSwap>(&dir.join(SWAPS_FILE)) {
+            book.apply(&swap);
+            book.swaps.push(swap);
+        }
+        for report in read_lines::<PnlReport>(&dir.join(VALUATIONS_FILE)) {
+            book.valuations.insert(day(report.timestamp), report);
+        }
+        if !book.swaps.is_empty() || !book.valuations.is_empty() {
+            info!(
+                "Loaded {} swaps and {} daily valuations",
+                book.swaps.len(),
+                book.valuations.len()
+            );
+        }
+        book
+    }
+
+    /// Save a swap and update the positions of its address
+    pub fn record(&mut self, swap: RecordedSwap) -> Result<(), SwapError> {
+        if let Some(dir) = &self.dir {
+            let path = dir.join(SWAPS_FILE);
+            let mut line = serde_json::to_string(&swap).expect("Impossible serializing error");
+            line.push('\n');
+            fs::create_dir_all(dir)
+                .and_then(|_| {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)?
+                        .write_all(line.as_bytes())
+                })
+                .map_err(|e| SwapError::WriteStorageError(path.display().to_string(), e))?;
+        }
+        self.apply(&swap);
+        self.swaps.push(swap);
+        Ok(())
+    }
+
+    fn apply(&mut self, swap: &RecordedSwap) {
+        let sold = self
+            .positions
+            .entry((swap.address.clone(), swap.from_token.clone()))
+            .or_default();
+        // What was held before the recorded swaps has no known cost, selling
+        // it doesn't realize anything
+        let costed = swap.amount_in.min(sold.amount);
+        if !costed.is_zero() {
+            // Shares of the whole, never larger than it
+            let cost = sold
+                .cost_usd
+                .checked_mul_div(costed, sold.amount)
+                .unwrap_or_default();
+            let proceeds = swap
+                .value_usd
+                .checked_mul_div(costed, swap.amount_in)
+                .unwrap_or_default();
+            sold.sold_usd = sold.sold_usd.saturating_add(proceeds);
+            sold.sold_cost_usd = sold.sold_cost_usd.saturating_add(cost);
+            sold.cost_usd = sold.cost_usd.saturating_sub(cost);
+            sold.amount = sold.amount.saturating_sub(costed);
+        }
+        let bought = self
+            .positions
+            .entry((swap.address.clone(), swap.to_token.clone()))
+            .or_default();
+        bought.amount = bought.amount.saturating_add(swap.amount_out);
+        bought.cost_usd = bought.cost_usd.saturating_add(swap.value_usd);
+    }
+
+    /// PnL with the balances of `portfolio` and the current prices. Tokens
+    /// only known from recorded swaps are valued with the amount those left.
+    pub fn report(
+        &self,
+        timestamp: i64,
+        portfolio: Option<&Portfolio>,
+        rates: &RateBook,
+    ) -> PnlReport {
+        let mut held: BTreeMap<(String, String), Decimal> = BTreeMap::new();
+        let tracked = portfolio
+            .map(|portfolio| &portfolio.addresses[..])
+            .unwrap_or_default();
+        for address in tracked {
+            for token in &address.tokens {
+                let amount = token
+                    .decimals
+                    .and_then(|decimals| Decimal::from_raw(token.raw_balance, decimals));
+                let Some(amount) = amount else {
+                    continue;
+                };
+                held.insert((address.address.clone(), token.token.clone()), amount);
+            }
+        }
+        for ((address, token), position) in &self.positions {
+            let is_tracked = tracked.iter().any(|tracked| tracked.address == *address);
+            if !is_tracked {
+                held.insert((address.clone(), token.clone()), position.amount);
+            }
+        }
+
+        let no_position = Position::default();
+        let entries = held
+            .into_iter()
+            .filter_map(|((address, token), amount)| {
+                let position = self
+                    .positions
+                    .get(&(address.clone(), token.clone()))
+                    .unwrap_or(&no_position);
+                if amount.is_zero() && position.sold_usd == position.sold_cost_usd {
+                    return None;
+                }
+                let price_usd = rates.usd(&token).map(|quote| quote.price);
+                let costed_amount = amount.min(position.amount);
+                // Zero when nothing is costed
+                let cost_basis_usd = position
+                    .cost_usd
+                    .checked_mul_div(costed_amount, position.amount)
+                    .unwrap_or_default();
+                let unrealized_pnl_usd = price_usd
+                    .and_then(|price| costed_amount.checked_mul(price))
+                    .map(|value| signed(value, cost_basis_usd));
+                Some(PnlEntry {
+                    address,
+                    token,
+                    amount: amount.to_f64(),
+                    price_usd: price_usd.map(Decimal::to_f64),
+                    value_usd: price_usd
+                        .and_then(|price| amount.checked_mul(price))
+                        .map(Decimal::to_f64),
+                    costed_amount: costed_amount.to_f64(),
+                    cost_basis_usd: cost_basis_usd.to_f64(),
+                    unrealized_pnl_usd,
+                    realized_pnl_usd: position.realized_pnl_usd(),
+                })
+            })
+            .collect();
+        PnlReport::new(timestamp, entries)
+    }
+
+    /// Current report filtered by address and token
+    pub fn query(
+        &self,
+        timestamp: i64,
+        portfolio: Option<&Portfolio>,
+        rates: &RateBook,
+        address: Option<&str>,
+        token: Option<&str>,
+    ) -> PnlReport {
+        self.report(timestamp, portfolio, rates)
+            .filter(address, token)
+    }
+
+    /// Keep `report` as the valuation of its day, replacing an earlier one
+    pub fn snapshot(&mut self, report: PnlReport) {
+        self.valuations.insert(day(report.timestamp), report);
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        // Rewritten whole, it only has a line per day
+        let path = dir.join(VALUATIONS_FILE);
+        let temp = dir.join(format!("{}.tmp", VALUATIONS_FILE));
+        let mut data = String::new();
+        for report in self.valuations.values() {
+            data.push_str(&serde_json::to_string(report).expect("Impossible serializing error"));
+            data.push('\n');
+        }
+        let result = fs::create_dir_all(dir)
+            .and_then(|_| fs::write(&temp, data))
+            .and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = result {
+            warn!("Error saving valuations to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Daily valuations between the unix times `from` and `to`, oldest first
+    pub fn history(
+        &self,
+        address: Option<&str>,
+        token: Option<&str>,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Vec<PnlReport> {
+        self.valuations
+            .values()
+            .filter(|report| from.is_none_or(|from| report.timestamp >= from))
+            .filter(|report| to.is_none_or(|to| report.timestamp <= to))
+            .map(|report| report.clone().filter(address, token))
+            .collect()
+    }
+
+    pub fn swaps(&self) -> &[RecordedSwap] {
+        &self.swaps
+    }
+}
+
+impl Default for PnlBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn day(timestamp: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(Resolution::OneDay.secs())
+}
+
+/// Entries of a JSONL file, skipping the ones that don't parse
+fn read_lines<T: for<'de> Deserialize<'de>>(path: &Path) -> Vec<T> {
+    let Ok(data) = fs::read_to_string(path) else {
+        // Nothing saved yet
+        return Vec::new();
+    };
+    data.lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("Skipping bad line in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,294 @@
+use chrono::DateTime;
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, info, warn};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    clock::Clock,
+    coin_type::CoinType,
+    errors::SwapError,
+    models::TokenInfoResponse,
+    replay::{RecordedPrice, Recorder},
+};
+
+/// Where a client gets the price of its token from
+pub trait PriceSource: Send + Sync {
+    /// Latest price of `coin_type`
+    fn fetch<'a>(
+        &'a self,
+        coin_type: &'a CoinType,
+    ) -> BoxFuture<'a, Result<TokenInfoResponse, SwapError>>;
+}
+
+/// HTTP API answering like DefiLlama, the coin type is appended to `base_url`
+pub struct HttpPriceSource {
+    base_url: String,
+    fetcher: Arc<PriceFetcher>,
+}
+
+impl HttpPriceSource {
+    pub fn new(base_url: impl Into<String>, fetcher: Arc<PriceFetcher>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            fetcher,
+        }
+    }
+}
+
+impl PriceSource for HttpPriceSource {
+    fn fetch<'a>(
+        &'a self,
+        coin_type: &'a CoinType,
+    ) -> BoxFuture<'a, Result<TokenInfoResponse, SwapError>> {
+        async move {
+            let url = format!("{}{}", self.base_url, coin_type);
+            self.fetcher.fetch(&url).await
+        }
+        .boxed()
+    }
+}
+
+/// Cached responses older than this (or `ttl` if longer) are dropped, not
+/// even served while rate limited
+const MAX_STALE: Duration = Duration::from_secs(3600);
+
+struct CacheEntry {
+    fetched_at: Instant,
+    response: TokenInfoResponse,
+}
+
+/// Upstream requests for one URL, sent one at a time
+#[derive(Default)]
+struct UrlFetches {
+    /// Requests finished so far
+    done: AtomicU64,
+    /// Held while a request is in flight, with the number and error of the
+    /// last one if it failed
+    last_error: tokio::sync::Mutex<Option<(u64, SwapError)>>,
+}
+
+/// Entry of a URL taken from [`PriceFetcher::in_flight`], removed from it once
+/// nobody else waits for it
+struct InFlight<'a> {
+    in_flight: &'a Mutex<HashMap<String, Arc<UrlFetches>>>,
+    url: &'a str,
+    lock: Arc<UrlFetches>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().expect("In flight mutex poisoned");
+        // Only cloned with the map locked, so no one can be about to wait on it
+        if Arc::strong_count(&self.lock) == 2 {
+            in_flight.remove(self.url);
+        }
+    }
+}
+
+/// Fetches prices from HTTP price sources sharing one connection pool.
+///
+/// Responses are cached for `ttl` by URL, concurrent requests for the same URL
+/// wait for a single upstream call (and get its error if it fails), and after a
+/// `429` no request is sent to that host until the `Retry-After` delay is over
+/// (stale cached data is served meanwhile, up to an hour old).
+pub struct PriceFetcher {
+    http: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    ttl: Duration,
+    default_retry_after: Duration,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    /// One lock per URL so only one request per URL is in flight
+    in_flight: Mutex<HashMap<String, Arc<UrlFetches>>>,
+    /// Hosts that answered `429`, until when they are left alone
+    backoff_until: Mutex<HashMap<String, Instant>>,
+    /// Where upstream responses are recorded, if anywhere
+    recorder: Option<Recorder>,
+}
+
+impl PriceFetcher {
+    pub fn new(ttl: Duration, default_retry_after: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            clock,
+            ttl,
+            default_retry_after,
+            cache: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            backoff_until: Mutex::new(HashMap::new()),
+            recorder: None,
+        }
+    }
+
+    /// Append every response fetched from upstream (not the cached ones) to `recorder`
+    pub fn record_to(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Get the price at `url`, from cache if fresh enough
+    pub async fn fetch(&self, url: &str) -> Result<TokenInfoResponse, SwapError> {
+        if let Some(response) = self.cached(url, self.ttl) {
+            debug!("Serving cached price for {}", url);
+            return Ok(response);
+        }
+        let url_lock = InFlight {
+            in_flight: &self.in_flight,
+            url,
+            lock: self
+                .in_flight
+                .lock()
+                .expect("In flight mutex poisoned")
+                .entry(url.to_string())
+                .or_default()
+                .clone(),
+        };
+        let seen = url_lock.lock.done.load(Ordering::SeqCst);
+        let mut last_error = url_lock.lock.last_error.lock().await;
+        // Someone else may have fetched it while we waited
+        if let Some(response) = self.cached(url, self.ttl) {
+            debug!("Serving coalesced price for {}", url);
+            return Ok(response);
+        }
+        if let Some((number, error)) = last_error.as_ref() {
+            if *number > seen {
+                debug!("Coalesced request for {} failed", url);
+                return Err(shared_error(error));
+            }
+        }
+        let host = host_of(url);
+        if let Some(remaining) = self.backoff_remaining(&host) {
+            return match self.cached(url, self.max_stale()) {
+                Some(response) => {
+                    warn!("Rate limited, serving stale price for {}", url);
+                    Ok(response)
+                }
+                None => Err(SwapError::RateLimited(remaining)),
+            };
+        }
+
+        let result = self.request(url, &host).await;
+        let number = url_lock.lock.done.fetch_add(1, Ordering::SeqCst) + 1;
+        *last_error = result
+            .as_ref()
+            .err()
+            .map(|error| (number, shared_error(error)));
+        result
+    }
+
+    /// Send the request for `url` to upstream and cache the answer
+    async fn request(&self, url: &str, host: &str) -> Result<TokenInfoResponse, SwapError> {
+        info!("Getting token price from: {}", url);
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| SwapError::SendRequestError(e.to_string()))?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = self
+                .retry_after(&response)
+                .unwrap_or(self.default_retry_after);
+            warn!(
+                "Rate limited by {}, backing off for {:?}",
+                host, retry_after
+            );
+            self.backoff_until
+                .lock()
+                .expect("Backoff mutex poisoned")
+                .insert(host.to_string(), self.clock.now() + retry_after);
+            return Err(SwapError::RateLimited(retry_after));
+        }
+        if !response.status().is_success() {
+            return Err(SwapError::UpstreamStatus(response.status().as_u16()));
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(SwapError::ParseResponseError)?;
+        let token_price =
+            TokenInfoResponse::from_json(&body).map_err(SwapError::ParsePriceResponseError)?;
+        if let Some(recorder) = &self.recorder {
+            let recorded = RecordedPrice {
+                recorded_at: self.clock.wall_now().timestamp_millis(),
+                url: url.to_string(),
+                response: token_price.clone(),
+            };
+            // Losing a line of the recording must not stop the price feed
+            if let Err(record_error) = recorder.record(&recorded) {
+                warn!("Not recorded price from {}: {:?}", url, record_error);
+            }
+        }
+        let now = self.clock.now();
+        let max_stale = self.max_stale();
+        let mut cache = self.cache.lock().expect("Cache mutex poisoned");
+        cache.retain(|_, entry| now.saturating_duration_since(entry.fetched_at) < max_stale);
+        cache.insert(
+            url.to_string(),
+            CacheEntry {
+                fetched_at: now,
+                response: token_price.clone(),
+            },
+        );
+        Ok(token_price)
+    }
+
+    fn max_stale(&self) -> Duration {
+        self.ttl.max(MAX_STALE)
+    }
+
+    fn cached(&self, url: &str, max_age: Duration) -> Option<TokenInfoResponse> {
+        let cache = self.cache.lock().expect("Cache mutex poisoned");
+        let now = self.clock.now();
+        cache
+            .get(url)
+            .filter(|entry| now.saturating_duration_since(entry.fetched_at) < max_age)
+            .map(|entry| entry.response.clone())
+    }
+
+    fn backoff_remaining(&self, host: &str) -> Option<Duration> {
+        let backoff_until = self.backoff_until.lock().expect("Backoff mutex poisoned");
+        backoff_until
+            .get(host)
+            .map(|until| until.saturating_duration_since(self.clock.now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// `Retry-After` can be a number of seconds or an HTTP date
+    fn retry_after(&self, response: &reqwest::Response) -> Option<Duration> {
+        let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        let date = DateTime::parse_from_rfc2822(value).ok()?;
+        (date.to_utc() - self.clock.wall_now()).to_std().ok()
+    }
+}
+
+/// Host and port of `url`, rate limits apply to all of its URLs
+fn host_of(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => format!(
+            "{}:{}",
+            parsed.host_str().unwrap_or_default(),
+            parsed.port_or_known_default().unwrap_or_default()
+        ),
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Error of a request handed to the callers that waited for it
+fn shared_error(error: &SwapError) -> SwapError {
+    match error {
+        SwapError::RateLimited(retry_after) => SwapError::RateLimited(*retry_after),
+        SwapError::UpstreamStatus(status) => SwapError::UpstreamStatus(*status),
+        other => SwapError::SendRequestError(other.to_string()),
+    }
+}
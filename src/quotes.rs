@@ -0,0 +1,276 @@
+use futures::future::{self, BoxFuture, FutureExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use crate::{
+    config::{PoolConfig, QuoteConfig},
+    decimal::{Decimal, DECIMALS},
+    errors::SwapError,
+    sui_rpc::{self, SuiRpc},
+};
+
+/// Reserves of a pool in on-chain units, `a` and `b` as in its [`PoolConfig`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Reserves {
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+/// Where the pool reserves are read from
+pub trait PoolSource: Send + Sync {
+    fn reserves<'a>(&'a self, pool: &'a PoolConfig) -> BoxFuture<'a, Result<Reserves, SwapError>>;
+}
+
+/// Reads the pool objects with `sui_getObject`
+pub struct RpcPoolSource {
+    rpc: SuiRpc,
+}
+
+impl RpcPoolSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            rpc: SuiRpc::new(url),
+        }
+    }
+}
+
+impl PoolSource for RpcPoolSource {
+    fn reserves<'a>(&'a self, pool: &'a PoolConfig) -> BoxFuture<'a, Result<Reserves, SwapError>> {
+        async move {
+            info!("Getting pool {}", pool.id);
+            let fields = self.rpc.object_fields(&pool.id).await?;
+            let reserve = |name: &str| {
+                fields.get(name).and_then(sui_rpc::as_u64).ok_or_else(|| {
+                    SwapError::InvalidPoolData(
+                        pool.id.clone(),
+                        format!("missing reserve field {}", name),
+                    )
+                })
+            };
+            Ok(Reserves {
+                reserve_a: reserve(pool.reserve_a_field.as_deref().unwrap_or("reserve_x"))?,
+                reserve_b: reserve(pool.reserve_b_field.as_deref().unwrap_or("reserve_y"))?,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Fixed reserves by pool id, loaded from a JSON file
+pub struct FixturePoolSource {
+    pools: HashMap<String, Reserves>,
+}
+
+impl FixturePoolSource {
+    pub fn load(path: &Path) -> Result<Self, SwapError> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| SwapError::ReadFixtureError(path.display().to_string(), e))?;
+        Ok(Self {
+            pools: serde_json::from_str(&data)?,
+        })
+    }
+}
+
+impl PoolSource for FixturePoolSource {
+    fn reserves<'a>(&'a self, pool: &'a PoolConfig) -> BoxFuture<'a, Result<Reserves, SwapError>> {
+        let reserves = self.pools.get(&pool.id).copied().ok_or_else(|| {
+            SwapError::InvalidPoolData(pool.id.clone(), "not in the fixture".to_string())
+        });
+        future::ready(reserves).boxed()
+    }
+}
+
+/// Expected result of swapping `amount_in` of `from_token` for `to_token`.
+/// Amounts are in whole tokens, the `_raw` ones in on-chain units.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SwapQuote {
+    pub from_token: String,
+    pub to_token: String,
+    /// Object id of the pool used
+    pub pool: String,
+    pub amount_in: Decimal,
+    pub amount_in_raw: u64,
+    pub amount_out: Decimal,
+    pub amount_out_raw: u64,
+    /// Part of `amount_in` kept by the pool
+    pub fee: Decimal,
+    /// How much worse than the pool spot price the swap executes, fee aside
+    pub price_impact_pct: Decimal,
+    pub slippage_bps: u32,
+    /// Output after the slippage tolerance, the least the swap should accept
+    pub min_received: Decimal,
+    pub min_received_raw: u64,
+}
+
+/// Quote request with the decimals of both tokens
+pub struct QuoteRequest<'a> {
+    pub from_token: &'a str,
+    pub from_decimals: u64,
+    pub to_token: &'a str,
+    pub to_decimals: u64,
+    pub amount: Decimal,
+    /// Tolerance for `min_received`, the configured one if None
+    pub slippage_bps: Option<u32>,
+}
+
+impl<'a> QuoteRequest<'a> {
+    /// Request with the decimals still unknown
+    pub fn new(
+        from_token: &'a str,
+        to_token: &'a str,
+        amount: Decimal,
+        slippage_bps: Option<u32>,
+    ) -> Self {
+        Self {
+            from_token,
+            from_decimals: 0,
+            to_token,
+            to_decimals: 0,
+            amount,
+            slippage_bps,
+        }
+    }
+}
+
+/// Quotes swaps between tokens with a configured pool
+pub struct Quoter {
+    config: QuoteConfig,
+    source: Arc<dyn PoolSource>,
+}
+
+impl Quoter {
+    /// Read the reserves from the fixture if set, or from the RPC endpoint
+    pub fn new(config: &QuoteConfig) -> Result<Self, SwapError> {
+        let source: Arc<dyn PoolSource> = match &config.fixture {
+            Some(fixture) => Arc::new(FixturePoolSource::load(fixture)?),
+            None => Arc::new(RpcPoolSource::new(&config.rpc_url)),
+        };
+        Ok(Self::with_source(config, source))
+    }
+
+    pub fn with_source(config: &QuoteConfig, source: Arc<dyn PoolSource>) -> Self {
+        Self {
+            config: config.clone(),
+            source,
+        }
+    }
+
+    pub fn has_pool(&self, from_token: &str, to_token: &str) -> bool {
+        self.pool(from_token, to_token).is_some()
+    }
+
+    pub fn pools(&self) -> &[PoolConfig] {
+        &self.config.pools
+    }
+
+    /// Reserves of `pool` as (input, output) for a swap from `from_token`
+    pub async fn reserves(
+        &self,
+        pool: &PoolConfig,
+        from_token: &str,
+    ) -> Result<(u128, u128), SwapError> {
+        let reserves = self.source.reserves(pool).await?;
+        let (reserve_in, reserve_out) = if pool.token_a == from_token {
+            (reserves.reserve_a, reserves.reserve_b)
+        } else {
+            (reserves.reserve_b, reserves.reserve_a)
+        };
+        if reserve_in == 0 || reserve_out == 0 {
+            return Err(SwapError::InvalidPoolData(
+                pool.id.clone(),
+                "empty reserves".to_string(),
+            ));
+        }
+        Ok((reserve_in as u128, reserve_out as u128))
+    }
+
+    /// Pool between both tokens, in either direction
+    pub fn pool(&self, from_token: &str, to_token: &str) -> Option<&PoolConfig> {
+        self.config.pools.iter().find(|pool| {
+            (pool.token_a == from_token && pool.token_b == to_token)
+                || (pool.token_b == from_token && pool.token_a == to_token)
+        })
+    }
+
+    /// Input amount in on-chain units
+    pub fn amount_in_raw(&self, request: &QuoteRequest<'_>) -> Result<u128, SwapError> {
+        match request.amount.to_raw(request.from_decimals) {
+            Some(amount_in_raw) if amount_in_raw > 0 && amount_in_raw <= u64::MAX as u128 => {
+                Ok(amount_in_raw)
+            }
+            _ => Err(SwapError::InvalidAmount(request.amount)),
+        }
+    }
+
+    pub fn slippage_bps(&self, request: &QuoteRequest<'_>) -> Result<u32, SwapError> {
+        let slippage_bps = request.slippage_bps.unwrap_or(self.config.slippage_bps);
+        if slippage_bps > 10_000 {
+            return Err(SwapError::InvalidSlippage(slippage_bps));
+        }
+        Ok(slippage_bps)
+    }
+
+    pub async fn quote(&self, request: QuoteRequest<'_>) -> Result<SwapQuote, SwapError> {
+        let amount_in_raw = self.amount_in_raw(&request)?;
+        let slippage_bps = self.slippage_bps(&request)?;
+        let pool = self
+            .pool(request.from_token, request.to_token)
+            .ok_or_else(|| {
+                SwapError::NoPool(request.from_token.to_string(), request.to_token.to_string())
+            })?;
+        let (reserve_in, reserve_out) = self.reserves(pool, request.from_token).await?;
+
+        let amount_out_raw = swap_out(reserve_in, reserve_out, pool.fee_bps, amount_in_raw);
+        let in_after_fee = after_fee(amount_in_raw, pool.fee_bps);
+        let price_impact_pct = percent(in_after_fee, reserve_in + in_after_fee)?;
+        let min_received_raw = min_received(amount_out_raw, slippage_bps);
+        let amount_in = |raw| whole(raw, request.from_decimals);
+        let amount_out = |raw| whole(raw, request.to_decimals);
+
+        Ok(SwapQuote {
+            from_token: request.from_token.to_string(),
+            to_token: request.to_token.to_string(),
+            pool: pool.id.clone(),
+            amount_in: amount_in(amount_in_raw)?,
+            amount_in_raw: amount_in_raw as u64,
+            amount_out: amount_out(amount_out_raw)?,
+            amount_out_raw: amount_out_raw as u64,
+            fee: amount_in(amount_in_raw - in_after_fee)?,
+            price_impact_pct,
+            slippage_bps,
+            min_received: amount_out(min_received_raw)?,
+            min_received_raw: min_received_raw as u64,
+        })
+    }
+}
+
+/// `part` as a percent of `whole`, truncated to 18 decimal places
+pub(crate) fn percent(part: u128, whole: u128) -> Result<Decimal, SwapError> {
+    // Both taken as 18 decimal raw units, the scale cancels out
+    let raw = |units| Decimal::from_raw(units, DECIMALS as u64);
+    raw(part)
+        .zip(raw(whole))
+        .and_then(|(part, whole)| Decimal::from(100).checked_mul_div(part, whole))
+        .ok_or_else(|| SwapError::InvalidDecimal(format!("{} / {}", part, whole)))
+}
+
+/// Whole tokens in `raw` on-chain units, exact up to 18 decimals
+pub(crate) fn whole(raw: u128, decimals: u64) -> Result<Decimal, SwapError> {
+    Decimal::from_raw(raw, decimals).ok_or_else(|| SwapError::InvalidDecimal(raw.to_string()))
+}
+
+/// Part of the input that goes into the pool, the fee stays in it
+pub(crate) fn after_fee(amount_in: u128, fee_bps: u32) -> u128 {
+    amount_in * (10_000 - fee_bps as u128) / 10_000
+}
+
+/// Output of a constant-product (x * y = k) pool
+pub(crate) fn swap_out(reserve_in: u128, reserve_out: u128, fee_bps: u32, amount_in: u128) -> u128 {
+    let in_after_fee = after_fee(amount_in, fee_bps);
+    reserve_out * in_after_fee / (reserve_in + in_after_fee)
+}
+
+pub(crate) fn min_received(amount_out: u128, slippage_bps: u32) -> u128 {
+    amount_out * (10_000 - slippage_bps as u128) / 10_000
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{decimal::Decimal, errors::SwapError, models::TokenInfoInnerResponse};
+
+/// Last USD price received for a token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsdQuote {
+    pub price: Decimal,
+    pub confidence: Decimal,
+    /// Unix time reported by the price source
+    pub timestamp: i64,
+    /// Decimals of the coin, to convert amounts from and to on-chain units
+    pub decimals: u64,
+}
+
+impl From<&TokenInfoInnerResponse> for UsdQuote {
+    fn from(info: &TokenInfoInnerResponse) -> Self {
+        Self {
+            price: info.price,
+            confidence: info.confidence,
+            timestamp: info.timestamp.0 as i64,
+            decimals: info.decimals,
+        }
+    }
+}
+
+/// Price of `base` in units of `quote`, derived from their USD quotes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CrossRate {
+    pub base: String,
+    pub quote: String,
+    pub rate: Decimal,
+    /// Product of both confidences, the sources are independent
+    pub confidence: Decimal,
+    /// The older of both timestamps, the rate is as stale as its oldest leg
+    pub timestamp: i64,
+}
+
+/// Latest USD quotes of the registered tokens
+#[derive(Default)]
+pub struct RateBook {
+    quotes: HashMap<String, UsdQuote>,
+}
+
+impl RateBook {
+    pub fn update(&mut self, token: &str, quote: UsdQuote) {
+        self.quotes.insert(token.to_string(), quote);
+    }
+
+    pub fn usd(&self, token: &str) -> Option<&UsdQuote> {
+        self.quotes.get(token)
+    }
+
+    /// Rate of `base` against `quote`, e.g. FUD/SUI is how many SUI one FUD is worth
+    pub fn cross(&self, base: &str, quote: &str) -> Result<CrossRate, SwapError> {
+        let base_usd = self
+            .usd(base)
+            .ok_or_else(|| SwapError::NoPrice(base.to_string()))?;
+        let quote_usd = self
+            .usd(quote)
+            .ok_or_else(|| SwapError::NoPrice(quote.to_string()))?;
+        let invalid = || SwapError::InvalidPrice(quote.to_string(), quote_usd.price);
+        Ok(CrossRate {
+            base: base.to_string(),
+            quote: quote.to_string(),
+            // Fails on a zero quote price
+            rate: base_usd
+                .price
+                .checked_div(quote_usd.price)
+                .ok_or_else(invalid)?,
+            confidence: base_usd
+                .confidence
+                .checked_mul(quote_usd.confidence)
+                .ok_or_else(invalid)?,
+            timestamp: base_usd.timestamp.min(quote_usd.timestamp),
+        })
+    }
+}
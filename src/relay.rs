@@ -0,0 +1,126 @@
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::{
+    client,
+    config::RelayConfig,
+    messages::{RelayedPrice, SwapRequest, SwapResponse},
+    peer_queue::{self, OverflowPolicy, PeerReceiver, PeerSender},
+    peer_registry::PeerRegistryHandle,
+};
+
+/// Prices kept while the upstream hub is unreachable, the last one per token
+const UPSTREAM_CAPACITY: usize = 256;
+
+/// Prices waiting to be relayed to the upstream hub, sent by [`run`]
+pub(crate) struct Upstream {
+    tx: PeerSender,
+}
+
+impl Upstream {
+    pub(crate) fn channel() -> (Self, PeerReceiver) {
+        let (tx, rx) = peer_queue::channel(UPSTREAM_CAPACITY, OverflowPolicy::CoalesceLatest);
+        (Self { tx }, rx)
+    }
+
+    pub(crate) fn send_price(&self, price: &RelayedPrice) {
+        let message = SwapResponse::RelayedPrice(price.clone());
+        let bytes = bincode::serialize(&message).expect("Impossible serializing error");
+        self.tx.send(Message::Binary(bytes), Some(&price.token));
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        // Ends the relay task
+        self.tx.close();
+    }
+}
+
+/// Connect to `relay.upstream` as a client named `node`, relaying the queued
+/// prices and passing the ones of the other hubs to the registry. Reconnects
+/// until the queue is closed.
+pub(crate) async fn run(
+    config: RelayConfig,
+    node: String,
+    rx: PeerReceiver,
+    peer_registry: PeerRegistryHandle,
+) {
+    let Some(url) = config.upstream else {
+        return;
+    };
+    loop {
+        let connected = client::connect_hub(
+            &url,
+            config.token.as_deref(),
+            "relay.token",
+            config.ca_cert.as_deref(),
+        )
+        .await;
+        match connected {
+            Ok(ws_stream) => {
+                info!("Relaying prices to {} as {}", url, node);
+                let (mut outgoing, mut incoming) = ws_stream.split();
+                loop {
+                    tokio::select! {
+                        queued = rx.recv() => {
+                            let Some(message) = queued else {
+                                let _ = outgoing.close().await;
+                                return;
+                            };
+                            if let Err(send_error) = outgoing.send(message).await {
+                                warn!("Relay link to {} failed: {}", url, send_error);
+                                break;
+                            }
+                        }
+                        received = incoming.next() => {
+                            let Some(Ok(message)) = received else {
+                                warn!("Upstream hub {} closed the connection", url);
+                                break;
+                            };
+                            let Message::Binary(bytes) = message else {
+                                // Keepalive, tungstenite answers pings on its own
+                                continue;
+                            };
+                            let request = match bincode::deserialize::<SwapRequest>(&bytes) {
+                                Ok(request) => request,
+                                Err(deserialize_error) => {
+                                    warn!(
+                                        "Error deserializing message from {}: {}",
+                                        url, deserialize_error
+                                    );
+                                    continue;
+                                }
+                            };
+                            match request {
+                                SwapRequest::WhichToken => {
+                                    let hello = SwapResponse::Relay { node: node.clone() };
+                                    let bytes = bincode::serialize(&hello)
+                                        .expect("Impossible serializing error");
+                                    if outgoing.send(Message::Binary(bytes)).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                SwapRequest::ValidToken => {
+                                    info!("Upstream hub {} accepted the relay", url);
+                                }
+                                SwapRequest::RelayedPrice(price) => {
+                                    peer_registry.relayed_price(None, price);
+                                }
+                                other => {
+                                    warn!("Ignoring {:?} from upstream hub {}", other, url);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Err(connect_error) => {
+                warn!("Can't reach upstream hub {}: {}", url, connect_error);
+            }
+        }
+        sleep(Duration::from_secs(config.reconnect_secs)).await;
+    }
+}
@@ -0,0 +1,148 @@
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::time::Instant;
+
+use crate::{
+    clock::Clock,
+    coin_type::CoinType,
+    errors::SwapError,
+    models::{TimeStamp, TokenInfoInnerResponse, TokenInfoResponse},
+    prices::PriceSource,
+};
+
+/// One line of a recording
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedPrice {
+    /// Unix time in milliseconds when the response was fetched
+    pub recorded_at: i64,
+    pub url: String,
+    pub response: TokenInfoResponse,
+}
+
+/// Appends fetched responses to a JSONL file
+pub struct Recorder {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Open `path` for appending, creating it if needed
+    pub fn create(path: &Path) -> Result<Self, SwapError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| SwapError::WriteRecordFileError(path.display().to_string(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, recorded: &RecordedPrice) -> Result<(), SwapError> {
+        let write_error = |e| SwapError::WriteRecordFileError(self.path.display().to_string(), e);
+        let mut line = serde_json::to_string(recorded).map_err(|e| write_error(e.into()))?;
+        line.push('\n');
+        self.file
+            .lock()
+            .expect("Record file mutex poisoned")
+            .write_all(line.as_bytes())
+            .map_err(write_error)
+    }
+}
+
+/// Plays a recording back as if it were live: the recording starts when the
+/// source is created and advances `speed` times faster than the clock. Sample
+/// timestamps are moved to the present so staleness checks still apply.
+pub struct ReplayPriceSource {
+    /// Samples by coin key (e.g. `sui:0x2::sui::SUI`), sorted by time
+    samples: HashMap<String, Vec<(i64, TokenInfoInnerResponse)>>,
+    /// Time of the first line of the recording, in milliseconds
+    first_at: i64,
+    speed: f64,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+}
+
+impl ReplayPriceSource {
+    pub fn load(path: &Path, speed: f64, clock: Arc<dyn Clock>) -> Result<Self, SwapError> {
+        let display = path.display().to_string();
+        let data = fs::read_to_string(path)
+            .map_err(|e| SwapError::ReadReplayFileError(display.clone(), e))?;
+        let mut samples: HashMap<String, Vec<(i64, TokenInfoInnerResponse)>> = HashMap::new();
+        for (number, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let recorded: RecordedPrice = serde_json::from_str(line).map_err(|e| {
+                SwapError::InvalidReplayFile(display.clone(), format!("line {}: {}", number + 1, e))
+            })?;
+            for (key, info) in recorded.response.coins {
+                samples
+                    .entry(key)
+                    .or_default()
+                    .push((recorded.recorded_at, info));
+            }
+        }
+        for token_samples in samples.values_mut() {
+            token_samples.sort_by_key(|(recorded_at, _)| *recorded_at);
+        }
+        let first_at = samples
+            .values()
+            .filter_map(|token_samples| token_samples.first())
+            .map(|(recorded_at, _)| *recorded_at)
+            .min()
+            .ok_or_else(|| SwapError::InvalidReplayFile(display, "no samples".to_string()))?;
+        let started = clock.now();
+        Ok(Self {
+            samples,
+            first_at,
+            speed,
+            clock,
+            started,
+        })
+    }
+
+    /// Point of the recording being played, in milliseconds
+    fn position(&self) -> i64 {
+        let elapsed = self.clock.now().saturating_duration_since(self.started);
+        self.first_at + (elapsed.as_millis() as f64 * self.speed) as i64
+    }
+
+    fn latest(&self, coin_type: &CoinType) -> Result<TokenInfoResponse, SwapError> {
+        let no_price = || SwapError::NoReplayedPrice(coin_type.to_string());
+        let (key, token_samples) = self
+            .samples
+            .iter()
+            .find(|(key, _)| coin_type.matches_key(key))
+            .ok_or_else(no_price)?;
+        let position = self.position();
+        let played = token_samples.partition_point(|(recorded_at, _)| *recorded_at <= position);
+        let (_, info) = played
+            .checked_sub(1)
+            .and_then(|last| token_samples.get(last))
+            .ok_or_else(no_price)?;
+        let mut info = info.clone();
+        let shift = self.clock.wall_now().timestamp() - position / 1000;
+        info.timestamp = TimeStamp((info.timestamp.0 as i64 + shift).max(0) as u64);
+        Ok(TokenInfoResponse {
+            coins: HashMap::from([(key.clone(), info)]),
+        })
+    }
+}
+
+impl PriceSource for ReplayPriceSource {
+    fn fetch<'a>(
+        &'a self,
+        coin_type: &'a CoinType,
+    ) -> BoxFuture<'a, Result<TokenInfoResponse, SwapError>> {
+        future::ready(self.latest(coin_type)).boxed()
+    }
+}
@@ -0,0 +1,340 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    config::PoolConfig,
+    decimal::Decimal,
+    errors::SwapError,
+    quotes::{self, QuoteRequest, Quoter},
+    tokens::TokenRegistry,
+};
+
+/// Longest path tried when the request doesn't set one
+pub const DEFAULT_MAX_HOPS: usize = 3;
+/// Input shares tried when splitting between two paths, in percent
+const SPLIT_STEP_PCT: u32 = 10;
+
+/// Swap through one pool of a route, amounts in on-chain units
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteHop {
+    pub pool: String,
+    pub from_token: String,
+    pub to_token: String,
+    pub amount_in_raw: u64,
+    pub amount_out_raw: u64,
+}
+
+/// Part of the input swapped along one path
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteLeg {
+    /// Share of the input sent through this path, in percent
+    pub share_pct: u32,
+    /// Tokens visited, both ends included
+    pub path: Vec<String>,
+    pub hops: Vec<RouteHop>,
+}
+
+/// Output of a path with the whole input, to compare the routes found
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteCandidate {
+    pub path: Vec<String>,
+    pub pools: Vec<String>,
+    pub amount_out_raw: u64,
+}
+
+/// Best way found to swap `amount_in` of `from_token` for `to_token`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SwapRoute {
+    pub from_token: String,
+    pub to_token: String,
+    pub amount_in: Decimal,
+    pub amount_in_raw: u64,
+    pub amount_out: Decimal,
+    pub amount_out_raw: u64,
+    /// How much worse than the spot prices along the route, fees aside
+    pub price_impact_pct: Decimal,
+    pub slippage_bps: u32,
+    pub min_received: Decimal,
+    pub min_received_raw: u64,
+    /// One leg, or two when splitting the input gives more
+    pub legs: Vec<RouteLeg>,
+    /// Every path found, best first
+    pub candidates: Vec<RouteCandidate>,
+}
+
+/// Pool of a path with its reserves in the swap direction
+#[derive(Clone)]
+struct Step<'a> {
+    pool: &'a PoolConfig,
+    from_token: &'a str,
+    to_token: &'a str,
+    reserve_in: u128,
+    reserve_out: u128,
+}
+
+type Path<'a> = Vec<Step<'a>>;
+
+/// Finds the best route across the configured pools between tokens of the
+/// tokens file, direct or through other tokens
+pub struct Router {
+    quoter: Arc<Quoter>,
+    tokens: Arc<TokenRegistry>,
+}
+
+impl Router {
+    pub fn new(quoter: Arc<Quoter>, tokens: Arc<TokenRegistry>) -> Self {
+        Self { quoter, tokens }
+    }
+
+    pub fn quoter(&self) -> &Quoter {
+        &self.quoter
+    }
+
+    pub fn tokens(&self) -> &TokenRegistry {
+        &self.tokens
+    }
+
+    pub async fn route(
+        &self,
+        request: QuoteRequest<'_>,
+        max_hops: usize,
+    ) -> Result<SwapRoute, SwapError> {
+        let amount_in_raw = self.quoter.amount_in_raw(&request)?;
+        let slippage_bps = self.quoter.slippage_bps(&request)?;
+        let no_route =
+            || SwapError::NoRoute(request.from_token.to_string(), request.to_token.to_string());
+        let paths = self.paths(request.from_token, request.to_token, max_hops);
+        if paths.is_empty() || request.from_token == request.to_token {
+            return Err(no_route());
+        }
+        let paths = self.with_reserves(paths).await;
+
+        let mut candidates: Vec<(Path, u128)> = paths
+            .into_iter()
+            .map(|path| {
+                let amount_out = path_out(&path, amount_in_raw);
+                (path, amount_out)
+            })
+            .collect();
+        candidates.sort_by_key(|(_, amount_out)| std::cmp::Reverse(*amount_out));
+        let Some((best, best_out)) = candidates.first() else {
+            return Err(no_route());
+        };
+        let mut legs = vec![(best, 100)];
+        let mut amount_out_raw = *best_out;
+        // Splitting only works out the output of each part on its own when
+        // the paths don't share pools
+        for (i, (first, _)) in candidates.iter().enumerate() {
+            for (second, _) in &candidates[i + 1..] {
+                if shares_pool(first, second) {
+                    continue;
+                }
+                for share in (SPLIT_STEP_PCT..100).step_by(SPLIT_STEP_PCT as usize) {
+                    let first_in = amount_in_raw * share as u128 / 100;
+                    let out =
+                        path_out(first, first_in) + path_out(second, amount_in_raw - first_in);
+                    if out > amount_out_raw {
+                        amount_out_raw = out;
+                        legs = vec![(first, share), (second, 100 - share)];
+                    }
+                }
+            }
+        }
+
+        let mut spot_out = Some(Decimal::ZERO);
+        let mut remaining = amount_in_raw;
+        let legs: Vec<RouteLeg> = legs
+            .iter()
+            .enumerate()
+            .map(|(i, (path, share))| {
+                // The last leg takes the rounding leftovers
+                let leg_in = if i + 1 == legs.len() {
+                    remaining
+                } else {
+                    amount_in_raw * *share as u128 / 100
+                };
+                remaining -= leg_in;
+                spot_out = spot_out
+                    .zip(path_spot_out(path, leg_in))
+                    .and_then(|(total, out)| total.checked_add(out));
+                leg(path, *share, leg_in)
+            })
+            .collect();
+        let price_impact_pct = spot_out
+            .zip(Decimal::from_raw(amount_out_raw, 0))
+            .and_then(|(spot_out, amount_out)| {
+                Decimal::from(100).checked_mul_div(spot_out.saturating_sub(amount_out), spot_out)
+            })
+            .ok_or_else(|| SwapError::InvalidDecimal(amount_in_raw.to_string()))?;
+        let min_received_raw = quotes::min_received(amount_out_raw, slippage_bps);
+
+        Ok(SwapRoute {
+            from_token: request.from_token.to_string(),
+            to_token: request.to_token.to_string(),
+            amount_in: quotes::whole(amount_in_raw, request.from_decimals)?,
+            amount_in_raw: amount_in_raw as u64,
+            amount_out: quotes::whole(amount_out_raw, request.to_decimals)?,
+            amount_out_raw: amount_out_raw as u64,
+            price_impact_pct,
+            slippage_bps,
+            min_received: quotes::whole(min_received_raw, request.to_decimals)?,
+            min_received_raw: min_received_raw as u64,
+            legs,
+            candidates: candidates
+                .iter()
+                .map(|(path, amount_out)| RouteCandidate {
+                    path: tokens(path),
+                    pools: path.iter().map(|step| step.pool.id.clone()).collect(),
+                    amount_out_raw: *amount_out as u64,
+                })
+                .collect(),
+        })
+    }
+
+    /// Paths of up to `max_hops` pools not visiting a token twice, through
+    /// enabled tokens only
+    fn paths<'a>(
+        &'a self,
+        from_token: &'a str,
+        to_token: &'a str,
+        max_hops: usize,
+    ) -> Vec<Path<'a>> {
+        let pools: Vec<&PoolConfig> = self
+            .quoter
+            .pools()
+            .iter()
+            .filter(|pool| {
+                self.tokens.get(&pool.token_a).is_ok() && self.tokens.get(&pool.token_b).is_ok()
+            })
+            .collect();
+        let mut paths = Vec::new();
+        let mut stack: Vec<(Path<'a>, Vec<&'a str>)> = vec![(Vec::new(), vec![from_token])];
+        while let Some((path, visited)) = stack.pop() {
+            let current = *visited.last().expect("Starts with from_token");
+            if current == to_token {
+                paths.push(path);
+                continue;
+            }
+            if path.len() == max_hops {
+                continue;
+            }
+            for pool in &pools {
+                let next = if pool.token_a == current {
+                    pool.token_b.as_str()
+                } else if pool.token_b == current {
+                    pool.token_a.as_str()
+                } else {
+                    continue;
+                };
+                if visited.contains(&next) {
+                    continue;
+                }
+                let mut path = path.clone();
+                path.push(Step {
+                    pool,
+                    from_token: current,
+                    to_token: next,
+                    reserve_in: 0,
+                    reserve_out: 0,
+                });
+                let mut visited = visited.clone();
+                visited.push(next);
+                stack.push((path, visited));
+            }
+        }
+        paths
+    }
+
+    /// Fill in the reserves, reading each pool once. Paths through pools that
+    /// can't be read are dropped.
+    async fn with_reserves<'a>(&self, paths: Vec<Path<'a>>) -> Vec<Path<'a>> {
+        let mut reserves: HashMap<&str, Option<(u128, u128)>> = HashMap::new();
+        for step in paths.iter().flatten() {
+            if reserves.contains_key(step.pool.id.as_str()) {
+                continue;
+            }
+            // Stored oriented from token_a
+            let read = match self.quoter.reserves(step.pool, &step.pool.token_a).await {
+                Ok(read) => Some(read),
+                Err(pool_error) => {
+                    warn!("Skipping pool {} in routes: {}", step.pool.id, pool_error);
+                    None
+                }
+            };
+            reserves.insert(&step.pool.id, read);
+        }
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                path.into_iter()
+                    .map(|mut step| {
+                        let (reserve_a, reserve_b) = reserves[step.pool.id.as_str()]?;
+                        (step.reserve_in, step.reserve_out) =
+                            if step.from_token == step.pool.token_a {
+                                (reserve_a, reserve_b)
+                            } else {
+                                (reserve_b, reserve_a)
+                            };
+                        Some(step)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn path_out(path: &Path, amount_in: u128) -> u128 {
+    path.iter().fold(amount_in, |amount, step| {
+        quotes::swap_out(step.reserve_in, step.reserve_out, step.pool.fee_bps, amount)
+    })
+}
+
+/// Output at the spot prices, what the path would give without price impact,
+/// in on-chain units with 18 decimal places
+fn path_spot_out(path: &Path, amount_in: u128) -> Option<Decimal> {
+    let units = |raw| Decimal::from_raw(raw, 0);
+    path.iter().try_fold(units(amount_in)?, |amount, step| {
+        amount
+            .checked_mul_div(units((10_000 - step.pool.fee_bps) as u128)?, units(10_000)?)?
+            .checked_mul_div(units(step.reserve_out)?, units(step.reserve_in)?)
+    })
+}
+
+fn shares_pool(first: &Path, second: &Path) -> bool {
+    first
+        .iter()
+        .any(|step| second.iter().any(|other| other.pool.id == step.pool.id))
+}
+
+fn tokens(path: &Path) -> Vec<String> {
+    path.iter()
+        .map(|step| step.from_token.to_string())
+        .chain(path.last().map(|step| step.to_token.to_string()))
+        .collect()
+}
+
+fn leg(path: &Path, share_pct: u32, amount_in: u128) -> RouteLeg {
+    let mut amount = amount_in;
+    let hops = path
+        .iter()
+        .map(|step| {
+            let amount_out =
+                quotes::swap_out(step.reserve_in, step.reserve_out, step.pool.fee_bps, amount);
+            let hop = RouteHop {
+                pool: step.pool.id.clone(),
+                from_token: step.from_token.to_string(),
+                to_token: step.to_token.to_string(),
+                amount_in_raw: amount as u64,
+                amount_out_raw: amount_out as u64,
+            };
+            amount = amount_out;
+            hop
+        })
+        .collect();
+    RouteLeg {
+        share_pct,
+        path: tokens(path),
+        hops,
+    }
+}
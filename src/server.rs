@@ -1,45 +1,62 @@
-use bincode;
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
+use futures_util::{future, pin_mut, stream, stream::TryStreamExt, StreamExt};
 use log::{error, info, warn};
-use std::{
-    collections::HashMap,
-    io::Error as IoError,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-};
+use std::{future::Future, net::SocketAddr, path::PathBuf, sync::Arc};
+use subtle::ConstantTimeEq;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    time::Interval,
+    task::JoinSet,
+    time::Duration,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{header::AUTHORIZATION, StatusCode},
+    protocol::Message,
 };
-use tokio_tungstenite::tungstenite::protocol::Message;
 
 use crate::{
+    admin,
+    alerts::{AlertEngine, AlertRule},
+    balances::{self, BalanceReader},
+    clock::{Clock, SystemClock},
+    cluster::{self, ClusterNode},
+    config::{BalanceConfig, ClusterConfig, Config, QuoteConfig, RelayConfig, TlsConfig},
     errors::SwapError,
     messages::{SwapRequest, SwapResponse},
+    peer_queue::{self, OverflowPolicy, PeerSender},
+    peer_registry::{PeerRegistry, PeerRegistryHandle},
+    quotes::Quoter,
+    relay::{self, Upstream},
+    router::Router,
+    sui_rpc, tls,
+    tokens::TokenRegistry,
+    transactions::TransactionBuilder,
 };
 
-pub type Tx = UnboundedSender<Message>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
-type TokenMap = Arc<Mutex<(HashMap<SocketAddr, String>, HashMap<String, SocketAddr>)>>;
+pub type Tx = PeerSender;
 
+/// Settings shared by every connection task
+#[derive(Clone)]
+struct ConnectionSettings {
+    auth_token: Option<Arc<str>>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+}
+
+/// Hub polling the registered clients for the price of their tokens
 pub struct Server {
-    addr: String,
-    peer_map: PeerMap,
-    token_map: TokenMap,
-    timeout: Interval,
+    config: Config,
+    registry: Arc<TokenRegistry>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Server {
-    pub fn new(addr: String, timeout_secs: u64) -> Self {
-        let peer_map = PeerMap::new(Mutex::new(HashMap::new()));
-        let token_map = TokenMap::new(Mutex::new((HashMap::new(), HashMap::new())));
-        let timeout = tokio::time::interval(tokio::time::Duration::from_secs(timeout_secs));
+    pub fn new(config: Config, registry: TokenRegistry) -> Self {
         Self {
-            addr,
-            peer_map,
-            token_map,
-            timeout,
+            config,
+            registry: Arc::new(registry),
+            clock: Arc::new(SystemClock),
         }
     }
 
@@ -50,74 +67,149 @@ impl Server {
         peer_addr: SocketAddr,
     ) -> bool {
         let serialized_message = bincode::serialize(&message)
-            .map_err(|e| SwapError::SerializeError(e))
+            .map_err(SwapError::SerializeError)
             .expect("Impossible serializing error");
-        match ws_sender.unbounded_send(Message::Binary(serialized_message)) {
-            Ok(_) => {
-                info!("Sent message to {}", peer_addr);
-                true
-            }
-            Err(send_error) => {
-                info!("Error sending message to {}: {}", peer_addr, send_error);
-                info!("Removing peer {}", peer_addr);
-                false
-            }
+        let key = message.coalesce_key();
+        if ws_sender.send(Message::Binary(serialized_message), key.as_deref()) {
+            info!("Sent message to {}", peer_addr);
+            true
+        } else {
+            info!("Error sending message to {}: connection closed", peer_addr);
+            info!("Removing peer {}", peer_addr);
+            false
+        }
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+            tokens: None,
+            clock: None,
         }
     }
 
     /// Main function for the server
-    pub async fn start(mut self) -> Result<(), IoError> {
+    pub async fn start(self) -> Result<(), SwapError> {
+        self.bind().await?.run().await
+    }
+
+    /// Bind the listening sockets without accepting connections yet, useful to
+    /// learn the actual addresses when listening on port 0
+    pub async fn bind(self) -> Result<BoundServer, SwapError> {
+        let tls_acceptor = match &self.config.server.tls {
+            Some(tls_config) => Some(tls::server_acceptor(tls_config)?),
+            None => None,
+        };
         // Take the addr and listen on it
-        let listener = TcpListener::bind(&self.addr)
+        let listener = TcpListener::bind(&self.config.server.listen)
             .await
-            .expect("Failed to bind address");
-        info!("Listening on: {}", self.addr);
+            .map_err(|e| SwapError::BindError(self.config.server.listen.clone(), e))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| SwapError::BindError(self.config.server.listen.clone(), e))?;
+        info!(
+            "Listening on: {} ({})",
+            local_addr,
+            if tls_acceptor.is_some() { "wss" } else { "ws" }
+        );
 
-        // Main loop checking for new connections and sending messages every 10 secs
-        loop {
-            tokio::select! {
-                _ = self.timeout.tick() => {
-                    info!("Sending Messages to all peers");
-                    let token_map_locked = self.token_map.lock().expect("Token map mutex not poisoned");
-                    let mut peers = self.peer_map.lock().expect("Peers Mutex Poisoned");
-                    // Send TokenPrice Message to all peers
-                    peers.retain(|peer_addr, ws_sink| {
-                        if let Some(_) = token_map_locked.0.get(peer_addr) {
-                            let message = SwapRequest::TokenPrice;
-                            Self::send_swap_request_message(message, ws_sink.clone(), *peer_addr)
-                        } else {
-                            // Don't remove because can just subscribed, but would be nice to remove peers that don't message us back in x time
-                            true
-                        }
-                    });
-                },
-                // New connections from clients
-                accept_result = listener.accept() => {
-                    match accept_result {
-                        Ok((stream, addr)) => {
-                            tokio::spawn(Server::handle_connection(self.peer_map.clone(), stream, addr, self.token_map.clone()));
-                        }
-                        Err(e) => {
-                            error!("Error aceptando conexión: {}", e);
-                        }
-                    }
-                }
+        let admin_listener = match &self.config.admin.listen {
+            Some(admin_listen) => {
+                let admin_listener = TcpListener::bind(admin_listen)
+                    .await
+                    .map_err(|e| SwapError::BindError(admin_listen.clone(), e))?;
+                info!("Admin channel listening on: {}", admin_listen);
+                Some(admin_listener)
             }
-        }
+            None => None,
+        };
+
+        let cluster_listener = match &self.config.cluster.listen {
+            Some(cluster_listen) => {
+                let cluster_listener = TcpListener::bind(cluster_listen)
+                    .await
+                    .map_err(|e| SwapError::BindError(cluster_listen.clone(), e))?;
+                info!("Cluster listening on: {}", cluster_listen);
+                Some(cluster_listener)
+            }
+            None => None,
+        };
+
+        Ok(BoundServer {
+            server: self,
+            listener,
+            local_addr,
+            admin_listener,
+            cluster_listener,
+            tls_acceptor,
+        })
     }
 
-    // Handle a new connection from a client
-    async fn handle_connection(
-        peer_map: PeerMap,
+    // Do the TLS handshake if enabled before handling the connection
+    async fn accept_connection(
+        tls_acceptor: Option<TlsAcceptor>,
+        settings: ConnectionSettings,
+        peer_registry: PeerRegistryHandle,
         raw_stream: TcpStream,
         addr: SocketAddr,
-        token_map: TokenMap,
     ) {
         info!("Incoming TCP connection from: {}", addr);
+        match tls_acceptor {
+            Some(tls_acceptor) => match tls_acceptor.accept(raw_stream).await {
+                Ok(tls_stream) => {
+                    Self::handle_connection(settings, peer_registry, tls_stream, addr).await
+                }
+                Err(tls_error) => error!("TLS handshake with {} failed: {}", addr, tls_error),
+            },
+            None => Self::handle_connection(settings, peer_registry, raw_stream, addr).await,
+        }
+    }
+
+    /// Reject the WS handshake if the client doesn't present the expected bearer token
+    // The signature is the one tungstenite expects for handshake callbacks
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check_auth(
+        auth_token: Option<&str>,
+        request: &Request,
+        response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        let Some(auth_token) = auth_token else {
+            return Ok(response);
+        };
+        let presented = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // Constant time so the token can't be guessed from response times
+        if presented
+            .is_some_and(|presented| presented.as_bytes().ct_eq(auth_token.as_bytes()).into())
+        {
+            Ok(response)
+        } else {
+            let mut error_response = ErrorResponse::new(Some("Unauthorized".to_string()));
+            *error_response.status_mut() = StatusCode::UNAUTHORIZED;
+            Err(error_response)
+        }
+    }
+
+    // Handle a new connection from a client
+    async fn handle_connection<S>(
+        settings: ConnectionSettings,
+        peer_registry: PeerRegistryHandle,
+        raw_stream: S,
+        addr: SocketAddr,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         // Create a WebSocket by upgrading the connection from TCP to WS
-        let ws_stream = match tokio_tungstenite::accept_async(raw_stream)
+        #[allow(clippy::result_large_err)]
+        let check_auth = |request: &Request, response| {
+            Self::check_auth(settings.auth_token.as_deref(), request, response)
+        };
+        let ws_stream = match tokio_tungstenite::accept_hdr_async(raw_stream, check_auth)
             .await
-            .map_err(|e| SwapError::WsError(e))
+            .map_err(|e| SwapError::WsError(Box::new(e)))
         {
             Ok(ws_stream) => ws_stream,
             Err(e) => {
@@ -127,22 +219,14 @@ impl Server {
         };
         info!("WebSocket connection established: {}", addr);
 
-        // Update peers map with the new connection
-        let (tx, rx) = unbounded();
-        match peer_map.lock() {
-            Ok(mut peers) => {
-                info!("Inserting peer {} into peer map", addr);
-                peers.insert(addr, tx.clone());
-            }
-            Err(poisoned) => {
-                error!("Error locking peer map: {}", poisoned);
-                return;
-            }
-        };
+        // Register the new connection
+        let (tx, rx) = peer_queue::channel(settings.queue_capacity, settings.overflow_policy);
+        peer_registry.connect(addr, tx.clone());
 
         let (outgoing, incoming) = ws_stream.split();
-        // Send messages to Client
-        let receive_from_others = rx.map(Ok).forward(outgoing);
+        // Send messages to Client, the connection is closed when the queue is
+        let queued = stream::unfold(rx, |rx| async move { rx.recv().await.map(|msg| (msg, rx)) });
+        let receive_from_others = queued.map(Ok).forward(outgoing);
 
         // Send WichToken message to the new peer
         let request = SwapRequest::WhichToken;
@@ -157,35 +241,30 @@ impl Server {
                         // New Info about token price
                         SwapResponse::TokenPrice(token_info) => {
                             info!("Received TokenPrice message from {}", addr);
-                            // Check addr is valid and token is what we expect
-                            let token_map_locked =
-                                token_map.lock().expect("Token map mutex not poisoned");
-                            if let Some(_) = token_map_locked.0.get(&addr) {
-                                info!("TokenPrice: {}", token_info);
-                            } else {
-                                warn!("Not Registered yet");
-                            }
+                            peer_registry.token_price(addr, token_info);
+                        }
+                        SwapResponse::LegacyTokenPrice(token_info) => {
+                            info!("Received legacy TokenPrice message from {}", addr);
+                            peer_registry.token_price(addr, token_info.into());
                         }
                         // Response to our WhichToken message
                         SwapResponse::WhichToken(token) => {
                             info!("Received WhichToken message from {}", addr);
                             info!("Token: {}", token);
-                            // Check in token map if token is already taken
-                            let mut token_map_locked =
-                                token_map.lock().expect("Token map mutex not poisoned");
-                            if let Some(_) = token_map_locked.1.get(&token) {
-                                // Token already taken
-                                info!("Token already taken");
-                                let response = SwapRequest::RepeatedToken;
-                                Self::send_swap_request_message(response, tx.clone(), addr);
-                            } else {
-                                // Token not taken
-                                info!("Token not taken");
-                                token_map_locked.0.insert(addr, token.clone());
-                                token_map_locked.1.insert(token, addr);
-                                let response = SwapRequest::ValidToken;
-                                Self::send_swap_request_message(response, tx.clone(), addr);
-                            }
+                            peer_registry.claim_token(addr, token, None);
+                        }
+                        // Same, from a client that registered the token before
+                        SwapResponse::ReclaimToken { token, secret } => {
+                            info!("{} reclaims token {}", addr, token);
+                            peer_registry.claim_token(addr, token, Some(secret));
+                        }
+                        // A hub relaying its prices answered our WhichToken
+                        SwapResponse::Relay { node } => {
+                            info!("Hub {} relays through {}", node, addr);
+                            peer_registry.relay(addr, node);
+                        }
+                        SwapResponse::RelayedPrice(price) => {
+                            peer_registry.relayed_price(Some(addr), price);
                         }
                     },
                     Err(deserialize_error) => {
@@ -196,6 +275,8 @@ impl Server {
                         return future::ok(());
                     }
                 },
+                // Answer to the registry keepalive
+                Message::Pong(_) => peer_registry.seen(addr),
                 _ => {
                     error!("Received a non-binary message from {}", addr);
                 }
@@ -207,46 +288,357 @@ impl Server {
         pin_mut!(broadcast_incoming, receive_from_others);
         future::select(broadcast_incoming, receive_from_others).await;
 
-        // Client disconnected, remove from maps
+        // Client disconnected, unregister it
         info!("{} disconnected", &addr);
-        peer_map
-            .lock()
-            .expect("Peer map mutex not poisoned")
-            .remove(&addr);
-        let mut token_map_locked = token_map.lock().expect("Token map mutex not poisoned");
-        if let Some((_, token)) = token_map_locked.0.remove_entry(&addr) {
-            token_map_locked.1.remove(&token);
+        peer_registry.disconnect(addr);
+    }
+}
+
+/// Server with its sockets bound, see [`Server::bind`]
+pub struct BoundServer {
+    server: Server,
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    admin_listener: Option<TcpListener>,
+    cluster_listener: Option<TcpListener>,
+    tls_acceptor: Option<TlsAcceptor>,
+}
+
+impl BoundServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    pub fn cluster_addr(&self) -> Option<SocketAddr> {
+        self.cluster_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Accept connections until ctrl-c or SIGTERM, see [`BoundServer::run_until`]
+    pub async fn run(self) -> Result<(), SwapError> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Accept connections until `shutdown` completes, then stop everything the
+    /// server started and save the registry state. Dropping the task instead
+    /// stops it all without saving.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> Result<(), SwapError> {
+        let config = &self.server.config;
+        let settings = ConnectionSettings {
+            auth_token: config.auth.token.as_deref().map(Arc::from),
+            queue_capacity: config.server.queue_capacity,
+            overflow_policy: config.server.overflow_policy,
+        };
+
+        for rule in &config.alerts {
+            if let Err(registry_error) = self.server.registry.get(&rule.token) {
+                warn!("Alert {} will never fire: {}", rule.name, registry_error);
+            }
+        }
+        for pool in &config.quotes.pools {
+            for token in [&pool.token_a, &pool.token_b] {
+                if let Err(registry_error) = self.server.registry.get(token) {
+                    warn!("Pool {} can't be quoted: {}", pool.id, registry_error);
+                }
+            }
+        }
+
+        // Other nodes sharing the registrations and prices, named by the
+        // cluster address if no id is configured
+        let cluster_node = match &self.cluster_listener {
+            Some(cluster_listener) => {
+                let node = match &config.cluster.node_id {
+                    Some(node_id) => node_id.clone(),
+                    None => cluster_listener
+                        .local_addr()
+                        .map_err(|e| SwapError::BindError("cluster.listen".to_string(), e))?
+                        .to_string(),
+                };
+                let incarnation = self.server.clock.wall_now().timestamp_millis();
+                Some(ClusterNode::connect(&config.cluster, node, incarnation))
+            }
+            None => None,
+        };
+
+        // Name of this hub in the prices it relays, the cluster node's if not set
+        let node = match (&config.relay.node_id, &cluster_node) {
+            (Some(node_id), _) => node_id.clone(),
+            (None, Some(cluster_node)) => cluster_node.node().to_string(),
+            (None, None) => self.local_addr.to_string(),
+        };
+        let (upstream, upstream_rx) = match &config.relay.upstream {
+            Some(_) => {
+                let (upstream, upstream_rx) = Upstream::channel();
+                (Some(upstream), Some(upstream_rx))
+            }
+            None => (None, None),
+        };
+
+        // Peers and tokens state, it also sends the poll messages every interval
+        let peer_registry = PeerRegistry::spawn(
+            self.server.registry.clone(),
+            &config.server,
+            &config.storage,
+            AlertEngine::new(config.alerts.clone(), self.server.clock.clone()),
+            cluster_node,
+            node.clone(),
+            upstream,
+            self.server.clock.clone(),
+        );
+
+        // Aborted with this task, so dropping it leaves nothing running
+        let mut tasks = JoinSet::new();
+
+        if !config.balances.addresses.is_empty() {
+            let rpc_url = config
+                .balances
+                .rpc_url
+                .as_deref()
+                .unwrap_or(&config.quotes.rpc_url);
+            // Keyed as the RPC answers them, already validated by the config
+            let addresses = config
+                .balances
+                .addresses
+                .iter()
+                .map(|address| sui_rpc::normalize_address(address))
+                .collect::<Result<_, _>>()?;
+            tasks.spawn(balances::track(
+                BalanceReader::new(rpc_url),
+                addresses,
+                Duration::from_secs(config.balances.refresh_secs),
+                self.server.clock.clone(),
+                peer_registry.clone(),
+            ));
+        }
+
+        if let (Some(admin_listener), Some(admin_token)) =
+            (self.admin_listener, &config.admin.token)
+        {
+            tasks.spawn(admin::serve(
+                admin_listener,
+                self.tls_acceptor.clone(),
+                Arc::from(admin_token.as_str()),
+                config.server.queue_capacity,
+                config.server.overflow_policy,
+                peer_registry.clone(),
+                Arc::new(Router::new(
+                    Arc::new(Quoter::new(&config.quotes)?),
+                    self.server.registry.clone(),
+                )),
+                Arc::new(TransactionBuilder::new(&config.quotes)),
+            ));
         }
+
+        if let Some(upstream_rx) = upstream_rx {
+            tasks.spawn(relay::run(
+                config.relay.clone(),
+                node,
+                upstream_rx,
+                peer_registry.clone(),
+            ));
+        }
+
+        if let (Some(cluster_listener), Some(cluster_token)) =
+            (self.cluster_listener, &config.cluster.token)
+        {
+            tasks.spawn(cluster::serve(
+                cluster_listener,
+                self.tls_acceptor.clone(),
+                Arc::from(cluster_token.as_str()),
+                peer_registry.clone(),
+            ));
+        }
+
+        // Main loop checking for new connections
+        pin_mut!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                // Reap the finished connections
+                Some(_) = tasks.join_next() => continue,
+                _ = &mut shutdown => break,
+            };
+            match accepted {
+                Ok((stream, addr)) => {
+                    tasks.spawn(Server::accept_connection(
+                        self.tls_acceptor.clone(),
+                        settings.clone(),
+                        peer_registry.clone(),
+                        stream,
+                        addr,
+                    ));
+                }
+                Err(e) => {
+                    error!("Error aceptando conexión: {}", e);
+                }
+            }
+        }
+
+        info!("Shutting down");
+        tasks.shutdown().await;
+        peer_registry.shutdown().await;
+        Ok(())
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use std::env;
-
-//     use super::*;
-//     use crate::client::Client;
-
-//     #[test]
-//     fn integration_test() {
-//         pretty_env_logger::init();
-//         let rt = tokio::runtime::Runtime::new().unwrap();
-//         rt.block_on(async {
-//             let mut server = Server::new("127.0.0.1:8080".to_string(), 10);
-//             let mut client1 = Client::new(
-//                 "ws://127.0.0.1:8080".to_string(),
-//                 "SUI".to_string(),
-//                 "https://coins.llama.fi/prices/current/sui:".to_string(),
-//             );
-//             // Start server
-//             let server_handle = tokio::spawn(async move {
-//                 server.start().await.unwrap();
-//             });
-//             tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
-//             // Start client
-//             let client_handle = tokio::spawn(async move {
-//                 client1.start().await;
-//             });
-//         });
-//     }
-// }
+/// Ctrl-c, or SIGTERM on Unix. Never completes if the handlers can't be set.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(signal_error) = tokio::signal::ctrl_c().await {
+            warn!("Can't listen for ctrl-c: {}", signal_error);
+            future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(signal_error) => {
+                warn!("Can't listen for SIGTERM: {}", signal_error);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => info!("Got ctrl-c"),
+        _ = terminate => info!("Got SIGTERM"),
+    }
+}
+
+/// Builder for [`Server`], settings not given keep their `Config` defaults
+pub struct ServerBuilder {
+    config: Config,
+    tokens: Option<TokenRegistry>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl ServerBuilder {
+    /// Start from a whole config instead of the defaults
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Address to listen on, `127.0.0.1:0` picks a free port
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.config.server.listen = addr.into();
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.config.server.poll_interval_secs = interval.as_secs();
+        self
+    }
+
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.config.auth.token = Some(token.into());
+        self
+    }
+
+    pub fn admin(mut self, addr: impl Into<String>, token: impl Into<String>) -> Self {
+        self.config.admin.listen = Some(addr.into());
+        self.config.admin.token = Some(token.into());
+        self
+    }
+
+    /// Peers not answering pings for `timeout` are disconnected, zero disables it
+    pub fn peer_timeout(mut self, timeout: Duration) -> Self {
+        self.config.server.peer_timeout_secs = timeout.as_secs();
+        self
+    }
+
+    pub fn queue(mut self, capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        self.config.server.queue_capacity = capacity;
+        self.config.server.overflow_policy = overflow_policy;
+        self
+    }
+
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.config.server.tls = Some(TlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self
+    }
+
+    /// Directory where the server keeps its data, e.g. candles
+    pub fn storage(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.storage.path = path.into();
+        self
+    }
+
+    /// Save the registry state every `every`, zero only saves it when
+    /// stopping, and keep restored tokens for their clients during `reclaim`
+    pub fn snapshots(mut self, every: Duration, reclaim: Duration) -> Self {
+        self.config.storage.snapshot_secs = every.as_secs();
+        self.config.storage.reclaim_secs = reclaim.as_secs();
+        self
+    }
+
+    /// Quote swaps with these pools
+    pub fn quotes(mut self, quotes: QuoteConfig) -> Self {
+        self.config.quotes = quotes;
+        self
+    }
+
+    /// Share registrations and prices with other hubs
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.config.cluster = cluster;
+        self
+    }
+
+    /// Relay prices to and from an upstream hub
+    pub fn relay(mut self, relay: RelayConfig) -> Self {
+        self.config.relay = relay;
+        self
+    }
+
+    /// Track the balances of these addresses
+    pub fn balances(mut self, balances: BalanceConfig) -> Self {
+        self.config.balances = balances;
+        self
+    }
+
+    /// Add an alert rule to the ones in the config
+    pub fn alert(mut self, rule: AlertRule) -> Self {
+        self.config.alerts.push(rule);
+        self
+    }
+
+    /// Use these tokens instead of reading the tokens file
+    pub fn tokens(mut self, tokens: TokenRegistry) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Time source for polls and keepalives, e.g. a [`crate::ManualClock`] in tests
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn build(self) -> Result<Server, SwapError> {
+        self.config.validate()?;
+        let tokens = match self.tokens {
+            Some(tokens) => tokens,
+            None => TokenRegistry::load(&self.config.tokens_file)?,
+        };
+        let mut server = Server::new(self.config, tokens);
+        if let Some(clock) = self.clock {
+            server.clock = clock;
+        }
+        Ok(server)
+    }
+}
@@ -0,0 +1,111 @@
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, net::SocketAddr, path::Path};
+
+use crate::rates::UsdQuote;
+
+const STATE_FILE: &str = "state.json";
+
+/// What the server knew of a token when the snapshot was taken
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "StoredTokenState")]
+pub struct TokenState {
+    pub token: String,
+    /// Client registered for the token
+    pub peer: Option<SocketAddr>,
+    /// [`hash_secret`] of the reclaim secret handed to that client, only a
+    /// client presenting the secret can claim the token first after a restart
+    pub secret_hash: Option<String>,
+    /// Last USD quote received
+    pub quote: Option<UsdQuote>,
+    /// Prices received, the relayed ones included
+    pub samples: u64,
+    /// Unix time of the last price received
+    pub last_update: Option<i64>,
+}
+
+/// [`TokenState`] as read from the file, older versions saved the plain
+/// reclaim secret
+#[derive(Deserialize)]
+struct StoredTokenState {
+    token: String,
+    peer: Option<SocketAddr>,
+    #[serde(default)]
+    secret_hash: Option<String>,
+    #[serde(default)]
+    secret: Option<String>,
+    quote: Option<UsdQuote>,
+    samples: u64,
+    last_update: Option<i64>,
+}
+
+impl From<StoredTokenState> for TokenState {
+    fn from(stored: StoredTokenState) -> Self {
+        Self {
+            token: stored.token,
+            peer: stored.peer,
+            secret_hash: stored
+                .secret_hash
+                .or_else(|| stored.secret.as_deref().map(hash_secret)),
+            quote: stored.quote,
+            samples: stored.samples,
+            last_update: stored.last_update,
+        }
+    }
+}
+
+/// Hex Blake2b-256 digest of a reclaim secret, what the server keeps of it
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Blake2b::<U32>::digest(secret.as_bytes()))
+}
+
+/// State of the peer registry saved to `<storage>/state.json`, loaded back on
+/// startup
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Snapshot {
+    /// Unix time it was taken
+    pub taken_at: i64,
+    /// Sorted by token
+    pub tokens: Vec<TokenState>,
+    /// Poll intervals set through the admin channel, by token
+    pub poll_intervals: BTreeMap<String, u64>,
+}
+
+impl Snapshot {
+    /// Snapshot saved in the storage directory `dir`, None if there is none
+    /// or it can't be read
+    pub fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(STATE_FILE);
+        let data = fs::read_to_string(&path).ok()?;
+        match serde_json::from_str::<Snapshot>(&data) {
+            Ok(snapshot) => {
+                info!(
+                    "Loaded state of {} tokens saved at {}",
+                    snapshot.tokens.len(),
+                    snapshot.taken_at
+                );
+                Some(snapshot)
+            }
+            Err(e) => {
+                warn!("Ignoring bad state file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Replace the snapshot saved in `dir`
+    pub fn save(&self, dir: &Path) {
+        // Written aside and renamed, so a crash never leaves half a file
+        let path = dir.join(STATE_FILE);
+        let temp = dir.join(format!("{}.tmp", STATE_FILE));
+        let data = serde_json::to_string_pretty(self).expect("Impossible serializing error");
+        let result = fs::create_dir_all(dir)
+            .and_then(|_| fs::write(&temp, data))
+            .and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = result {
+            warn!("Error saving state to {}: {}", path.display(), e);
+        }
+    }
+}
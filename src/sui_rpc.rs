@@ -0,0 +1,85 @@
+use log::debug;
+use serde_json::{json, Value};
+
+use crate::errors::SwapError;
+
+/// Minimal client of the Sui JSON-RPC API
+pub struct SuiRpc {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl SuiRpc {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Call `method` and return its `result`
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, SwapError> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        debug!("Calling {} on {}", method, self.url);
+        let response = self
+            .http
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| SwapError::SendRequestError(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(SwapError::UpstreamStatus(response.status().as_u16()));
+        }
+        let mut body = response
+            .json::<Value>()
+            .await
+            .map_err(SwapError::ParseResponseError)?;
+        if let Some(error) = body.get("error") {
+            return Err(SwapError::RpcError(method.to_string(), error.to_string()));
+        }
+        match body.get_mut("result") {
+            Some(result) => Ok(result.take()),
+            None => Err(SwapError::RpcError(
+                method.to_string(),
+                "answer without result".to_string(),
+            )),
+        }
+    }
+
+    /// Fields of a Move object
+    pub async fn object_fields(&self, id: &str) -> Result<Value, SwapError> {
+        let mut object = self
+            .call("sui_getObject", json!([id, { "showContent": true }]))
+            .await?;
+        match object.pointer_mut("/data/content/fields") {
+            Some(fields) => Ok(fields.take()),
+            None => Err(SwapError::RpcError(
+                "sui_getObject".to_string(),
+                format!("object {} has no content fields", id),
+            )),
+        }
+    }
+}
+
+/// u64 values are rendered as strings by the JSON-RPC API
+pub fn as_u64(value: &Value) -> Option<u64> {
+    value
+        .as_str()
+        .and_then(|value| value.parse().ok())
+        .or_else(|| value.as_u64())
+}
+
+/// Address as the JSON-RPC API returns it: 0x and 64 lowercase hex digits
+pub fn normalize_address(address: &str) -> Result<String, SwapError> {
+    let digits = address.strip_prefix("0x").unwrap_or(address);
+    if digits.is_empty() || digits.len() > 64 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(SwapError::InvalidAddress(address.to_string()));
+    }
+    Ok(format!("0x{:0>64}", digits.to_ascii_lowercase()))
+}
@@ -0,0 +1,46 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{ClientConfig, RootCertStore, ServerConfig},
+    TlsAcceptor,
+};
+use tokio_tungstenite::Connector;
+
+use crate::{config::TlsConfig, errors::SwapError};
+
+/// Build the acceptor used by the server for `wss://` connections
+pub fn server_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, SwapError> {
+    let certs = rustls_pemfile::certs(&mut open_pem(&tls.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| SwapError::TlsError(format!("{}: {}", tls.cert.display(), e)))?;
+    let key = rustls_pemfile::private_key(&mut open_pem(&tls.key)?)
+        .map_err(|e| SwapError::TlsError(format!("{}: {}", tls.key.display(), e)))?
+        .ok_or_else(|| SwapError::TlsError(format!("No private key in {}", tls.key.display())))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| SwapError::TlsError(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build a connector trusting only the certificates in `ca_cert`, used by
+/// clients talking to servers with self-signed certificates
+pub fn client_connector(ca_cert: &Path) -> Result<Connector, SwapError> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut open_pem(ca_cert)?) {
+        let cert =
+            cert.map_err(|e| SwapError::TlsError(format!("{}: {}", ca_cert.display(), e)))?;
+        roots
+            .add(cert)
+            .map_err(|e| SwapError::TlsError(format!("{}: {}", ca_cert.display(), e)))?;
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, SwapError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| SwapError::TlsError(format!("{}: {}", path.display(), e)))
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
+
+use crate::{coin_type::CoinType, decimal::Decimal, errors::SwapError};
+
+pub const DEFAULT_TOKENS_FILE: &str = "tokens.json";
+
+/// Price deviation limits between two consecutive samples of the same token, in percent
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviationThresholds {
+    /// Log a warning when the price moves more than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warn_pct: Option<Decimal>,
+    /// Discard the sample when the price moves more than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reject_pct: Option<Decimal>,
+}
+
+/// Entry of the tokens file, keyed by the token name clients register with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// Sui coin type, e.g. `0x2::sui::SUI`, rejected when loading if malformed
+    pub coin_type: CoinType,
+    /// Display symbol
+    pub symbol: String,
+    /// Full name, as in the on-chain coin metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Decimals we expect upstream to report for the coin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+    /// Price sources to query, in order of preference
+    #[serde(default)]
+    pub price_sources: Vec<String>,
+    /// Poll interval for this token, the server default is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub deviation: DeviationThresholds,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl TokenConfig {
+    /// Enabled entry with default settings
+    pub fn new(coin_type: CoinType, symbol: impl Into<String>) -> Self {
+        Self {
+            coin_type,
+            symbol: symbol.into(),
+            name: None,
+            decimals: None,
+            icon_url: None,
+            price_sources: Vec::new(),
+            poll_interval_secs: None,
+            deviation: DeviationThresholds::default(),
+            enabled: true,
+        }
+    }
+
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_secs.map(Duration::from_secs)
+    }
+
+    /// Check the entry makes sense once parsed
+    fn validate(&self, name: &str) -> Result<(), SwapError> {
+        let invalid =
+            |reason: &str| SwapError::InvalidTokenEntry(name.to_string(), reason.to_string());
+        if self.symbol.trim().is_empty() {
+            return Err(invalid("symbol is empty"));
+        }
+        if self.poll_interval_secs == Some(0) {
+            return Err(invalid("poll_interval_secs must be greater than 0"));
+        }
+        for (field, value) in [
+            ("deviation.warn_pct", self.deviation.warn_pct),
+            ("deviation.reject_pct", self.deviation.reject_pct),
+        ] {
+            // Negative values are already rejected when parsing
+            if value.is_some_and(Decimal::is_zero) {
+                return Err(invalid(&format!("{} must be a positive number", field)));
+            }
+        }
+        if let (Some(warn), Some(reject)) = (self.deviation.warn_pct, self.deviation.reject_pct) {
+            if warn > reject {
+                return Err(invalid(
+                    "deviation.warn_pct is greater than deviation.reject_pct",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tokens known to the application, loaded from the tokens file
+#[derive(Debug, Clone, Default)]
+pub struct TokenRegistry {
+    tokens: BTreeMap<String, TokenConfig>,
+}
+
+impl TokenRegistry {
+    /// Read and validate a tokens file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SwapError> {
+        let data = fs::read_to_string(path)?;
+        Self::from_json(&data)
+    }
+
+    pub fn from_json(data: &str) -> Result<Self, SwapError> {
+        let tokens: BTreeMap<String, TokenConfig> = serde_json::from_str(data)?;
+        for (name, token) in &tokens {
+            token.validate(name)?;
+        }
+        Ok(Self { tokens })
+    }
+
+    /// Write the registry as a tokens file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SwapError> {
+        let path = path.as_ref();
+        let mut data =
+            serde_json::to_string_pretty(&self.tokens).expect("Impossible serializing error");
+        data.push('\n');
+        fs::write(path, data)
+            .map_err(|e| SwapError::WriteStorageError(path.display().to_string(), e))
+    }
+
+    /// Name and entry of every token, disabled ones included
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TokenConfig)> {
+        self.tokens
+            .iter()
+            .map(|(name, token)| (name.as_str(), token))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tokens.contains_key(name)
+    }
+
+    /// Add or replace a token entry
+    pub fn insert(&mut self, name: impl Into<String>, token: TokenConfig) -> Result<(), SwapError> {
+        let name = name.into();
+        token.validate(&name)?;
+        self.tokens.insert(name, token);
+        Ok(())
+    }
+
+    /// Enabled token with this coin type
+    pub fn by_coin_type(&self, coin_type: &CoinType) -> Option<(&str, &TokenConfig)> {
+        self.tokens
+            .iter()
+            .find(|(_, token)| token.enabled && token.coin_type == *coin_type)
+            .map(|(name, token)| (name.as_str(), token))
+    }
+
+    /// Get a token entry by name, failing if it is not listed or is disabled
+    pub fn get(&self, name: &str) -> Result<&TokenConfig, SwapError> {
+        match self.tokens.get(name) {
+            Some(token) if token.enabled => Ok(token),
+            Some(_) => Err(SwapError::TokenDisabled(name.to_string())),
+            None => Err(SwapError::UnknownToken(name.to_string())),
+        }
+    }
+}
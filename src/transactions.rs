@@ -0,0 +1,446 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    coin_type::CoinType,
+    config::{PoolConfig, QuoteConfig},
+    errors::SwapError,
+    quotes::SwapQuote,
+    sui_rpc::{self, SuiRpc},
+};
+
+pub const SUI_COIN_TYPE: &str = "0x2::sui::SUI";
+/// Gas units added to the dry-run computation cost, like the Sui SDKs do
+const GAS_SAFE_OVERHEAD: u64 = 1000;
+/// Coins read per `suix_getCoins` call
+const MAX_COINS: usize = 50;
+/// Coins used for one input or the gas payment, the gas payment limit of Sui
+const MAX_COIN_OBJECTS: usize = 256;
+
+// BCS layout of the Sui types needed for a swap, the field and variant order
+// must match the Sui ones
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+struct Address([u8; 32]);
+
+impl Address {
+    /// Hex address, with or without 0x and leading zeros
+    fn parse(address: &str) -> Result<Self, SwapError> {
+        let invalid = || SwapError::InvalidAddress(address.to_string());
+        let digits = address.strip_prefix("0x").unwrap_or(address);
+        if digits.is_empty() || digits.len() > 64 {
+            return Err(invalid());
+        }
+        let padded = format!("{:0>64}", digits);
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(padded, &mut bytes).map_err(|_| invalid())?;
+        Ok(Self(bytes))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct ObjectRef(Address, u64, Vec<u8>);
+
+#[derive(Serialize)]
+enum TransactionData {
+    V1(TransactionDataV1),
+}
+
+#[derive(Serialize)]
+struct TransactionDataV1 {
+    kind: TransactionKind,
+    sender: Address,
+    gas_data: GasData,
+    expiration: TransactionExpiration,
+}
+
+#[derive(Serialize)]
+enum TransactionKind {
+    ProgrammableTransaction(ProgrammableTransaction),
+}
+
+#[derive(Serialize, Clone)]
+struct ProgrammableTransaction {
+    inputs: Vec<CallArg>,
+    commands: Vec<Command>,
+}
+
+#[derive(Serialize, Clone)]
+enum CallArg {
+    Pure(Vec<u8>),
+    Object(ObjectArg),
+}
+
+#[derive(Serialize, Clone)]
+enum ObjectArg {
+    ImmOrOwnedObject(ObjectRef),
+    SharedObject {
+        id: Address,
+        initial_shared_version: u64,
+        mutable: bool,
+    },
+}
+
+#[derive(Serialize, Clone)]
+enum Command {
+    MoveCall(Box<MoveCall>),
+    TransferObjects(Vec<Argument>, Argument),
+    SplitCoins(Argument, Vec<Argument>),
+    MergeCoins(Argument, Vec<Argument>),
+}
+
+#[derive(Serialize, Clone)]
+struct MoveCall {
+    package: Address,
+    module: String,
+    function: String,
+    type_arguments: Vec<TypeTag>,
+    arguments: Vec<Argument>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+enum Argument {
+    GasCoin,
+    Input(u16),
+    Result(u16),
+}
+
+// Only coin structs are built, the other variants keep the BCS indexes
+#[allow(dead_code)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+enum TypeTag {
+    Bool,
+    U8,
+    U64,
+    U128,
+    Address,
+    Signer,
+    Vector(Box<TypeTag>),
+    Struct(Box<StructTag>),
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+struct StructTag {
+    address: Address,
+    module: String,
+    name: String,
+    type_params: Vec<TypeTag>,
+}
+
+impl StructTag {
+    fn coin(coin_type: &CoinType) -> Self {
+        Self {
+            address: Address::parse(coin_type.address()).expect("Normalized by CoinType"),
+            module: coin_type.module().to_string(),
+            name: coin_type.name().to_string(),
+            type_params: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GasData {
+    payment: Vec<ObjectRef>,
+    owner: Address,
+    price: u64,
+    budget: u64,
+}
+
+#[derive(Serialize)]
+enum TransactionExpiration {
+    None,
+}
+
+/// Coin object of the sender
+struct Coin {
+    object: ObjectRef,
+    balance: u64,
+}
+
+/// Costs reported by a dry-run, in MIST
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DryRun {
+    pub success: bool,
+    /// Why the transaction would fail
+    pub error: Option<String>,
+    pub computation_cost: u64,
+    pub storage_cost: u64,
+    pub storage_rebate: u64,
+}
+
+/// Unsigned transaction executing a quoted swap
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SwapTransaction {
+    pub quote: SwapQuote,
+    pub sender: String,
+    /// Base64 of the BCS `TransactionData`, ready to sign and execute
+    pub tx_bytes: String,
+    pub gas_price: u64,
+    /// Estimated from the dry-run if there was one, `quotes.gas_budget` if not
+    pub gas_budget: u64,
+    pub dry_run: Option<DryRun>,
+}
+
+/// What [`TransactionBuilder::build`] needs besides the quote
+pub struct SwapTransactionRequest<'a> {
+    pub quote: SwapQuote,
+    pub pool: &'a PoolConfig,
+    /// Coin types of the pool `token_a` and `token_b`
+    pub coin_type_a: &'a CoinType,
+    pub coin_type_b: &'a CoinType,
+    /// Address paying the input and the gas, and receiving the output
+    pub sender: &'a str,
+    pub dry_run: bool,
+}
+
+/// Builds programmable transaction blocks for swaps, reading the objects they
+/// use from the Sui JSON-RPC endpoint
+pub struct TransactionBuilder {
+    rpc: SuiRpc,
+    gas_budget: u64,
+}
+
+impl TransactionBuilder {
+    pub fn new(config: &QuoteConfig) -> Self {
+        Self {
+            rpc: SuiRpc::new(&config.rpc_url),
+            gas_budget: config.gas_budget,
+        }
+    }
+
+    pub async fn build(
+        &self,
+        request: SwapTransactionRequest<'_>,
+    ) -> Result<SwapTransaction, SwapError> {
+        let quote = &request.quote;
+        let pool = request.pool;
+        let call = pool
+            .swap
+            .as_ref()
+            .ok_or_else(|| SwapError::NoSwapCall(pool.id.clone()))?;
+        let sender = Address::parse(request.sender)?;
+        let a_to_b = quote.from_token == pool.token_a;
+        let (function, from_coin_type) = if a_to_b {
+            (&call.a_to_b, request.coin_type_a)
+        } else {
+            (&call.b_to_a, request.coin_type_b)
+        };
+        let type_arguments = vec![
+            TypeTag::Struct(Box::new(StructTag::coin(request.coin_type_a))),
+            TypeTag::Struct(Box::new(StructTag::coin(request.coin_type_b))),
+        ];
+
+        let sui: CoinType = SUI_COIN_TYPE.parse()?;
+        let gas_price = self.gas_price().await?;
+        // SUI swapped is split from the gas coin, so it must cover both
+        let gas_needed = if *from_coin_type == sui {
+            self.gas_budget.saturating_add(quote.amount_in_raw)
+        } else {
+            self.gas_budget
+        };
+        let gas_coins = self.coins(request.sender, &sui, gas_needed).await?;
+        if gas_coins.is_empty() {
+            return Err(SwapError::InsufficientBalance(
+                sui.to_string(),
+                0,
+                self.gas_budget,
+            ));
+        }
+
+        let mut inputs = Vec::new();
+        let mut commands = Vec::new();
+        let mut input = |arg: CallArg| {
+            inputs.push(arg);
+            Argument::Input(inputs.len() as u16 - 1)
+        };
+        let amount_in = input(CallArg::Pure(to_bcs(&quote.amount_in_raw)));
+        // Input coin split from the gas coin or from the sender coins merged
+        let source = if *from_coin_type == sui {
+            Argument::GasCoin
+        } else {
+            let coins = self
+                .coins(request.sender, from_coin_type, quote.amount_in_raw)
+                .await?;
+            let balance: u64 = coins.iter().map(|coin| coin.balance).sum();
+            if balance < quote.amount_in_raw {
+                return Err(SwapError::InsufficientBalance(
+                    from_coin_type.to_string(),
+                    balance,
+                    quote.amount_in_raw,
+                ));
+            }
+            let mut coins = coins
+                .into_iter()
+                .map(|coin| input(CallArg::Object(ObjectArg::ImmOrOwnedObject(coin.object))));
+            let first = coins.next().expect("Balance checked above");
+            let rest: Vec<Argument> = coins.collect();
+            if !rest.is_empty() {
+                commands.push(Command::MergeCoins(first, rest));
+            }
+            first
+        };
+        commands.push(Command::SplitCoins(source, vec![amount_in]));
+        let coin_in = Argument::Result(commands.len() as u16 - 1);
+        let pool_arg = input(CallArg::Object(ObjectArg::SharedObject {
+            id: Address::parse(&pool.id)?,
+            initial_shared_version: self.initial_shared_version(&pool.id).await?,
+            mutable: true,
+        }));
+        let min_out = input(CallArg::Pure(to_bcs(&quote.min_received_raw)));
+        commands.push(Command::MoveCall(Box::new(MoveCall {
+            package: Address::parse(&call.package)?,
+            module: call.module.clone(),
+            function: function.clone(),
+            type_arguments,
+            arguments: vec![pool_arg, coin_in, min_out],
+        })));
+        let coin_out = Argument::Result(commands.len() as u16 - 1);
+        let recipient = input(CallArg::Pure(to_bcs(&sender)));
+        commands.push(Command::TransferObjects(vec![coin_out], recipient));
+
+        let transaction = ProgrammableTransaction { inputs, commands };
+        let payment: Vec<ObjectRef> = gas_coins.into_iter().map(|coin| coin.object).collect();
+        let encode = |budget: u64| {
+            let data = TransactionData::V1(TransactionDataV1 {
+                kind: TransactionKind::ProgrammableTransaction(transaction.clone()),
+                sender,
+                gas_data: GasData {
+                    payment: payment.clone(),
+                    owner: sender,
+                    price: gas_price,
+                    budget,
+                },
+                expiration: TransactionExpiration::None,
+            });
+            BASE64.encode(to_bcs(&data))
+        };
+
+        let mut gas_budget = self.gas_budget;
+        let mut tx_bytes = encode(gas_budget);
+        let mut dry_run = None;
+        if request.dry_run {
+            let result = self.dry_run(&tx_bytes).await?;
+            if result.success {
+                // Same estimation as the Sui SDKs, never over the configured cap
+                let computation = result.computation_cost + GAS_SAFE_OVERHEAD * gas_price;
+                let estimated = (computation + result.storage_cost)
+                    .saturating_sub(result.storage_rebate)
+                    .max(computation);
+                gas_budget = estimated.min(self.gas_budget);
+                tx_bytes = encode(gas_budget);
+            }
+            dry_run = Some(result);
+        }
+        info!(
+            "Built swap of {} {} for {} with budget {}",
+            quote.amount_in, quote.from_token, quote.to_token, gas_budget
+        );
+        Ok(SwapTransaction {
+            quote: request.quote,
+            sender: request.sender.to_string(),
+            tx_bytes,
+            gas_price,
+            gas_budget,
+            dry_run,
+        })
+    }
+
+    async fn gas_price(&self) -> Result<u64, SwapError> {
+        let price = self
+            .rpc
+            .call("suix_getReferenceGasPrice", json!([]))
+            .await?;
+        sui_rpc::as_u64(&price).ok_or_else(|| invalid_answer("suix_getReferenceGasPrice"))
+    }
+
+    /// Coins of `owner` adding up to `needed`, or all of them if they don't,
+    /// following the pages of `suix_getCoins`
+    async fn coins(
+        &self,
+        owner: &str,
+        coin_type: &CoinType,
+        needed: u64,
+    ) -> Result<Vec<Coin>, SwapError> {
+        let invalid = || invalid_answer("suix_getCoins");
+        let mut coins = Vec::new();
+        let mut balance: u64 = 0;
+        let mut cursor = Value::Null;
+        loop {
+            let page = self
+                .rpc
+                .call(
+                    "suix_getCoins",
+                    json!([owner, coin_type.to_string(), cursor, MAX_COINS]),
+                )
+                .await?;
+            for coin in page["data"].as_array().ok_or_else(invalid)? {
+                if coins.len() == MAX_COIN_OBJECTS {
+                    return Err(SwapError::TooManyCoins(
+                        coin_type.to_string(),
+                        MAX_COIN_OBJECTS,
+                    ));
+                }
+                let id = coin["coinObjectId"].as_str().ok_or_else(invalid)?;
+                let digest = coin["digest"].as_str().ok_or_else(invalid)?;
+                let coin = Coin {
+                    object: ObjectRef(
+                        Address::parse(id)?,
+                        sui_rpc::as_u64(&coin["version"]).ok_or_else(invalid)?,
+                        bs58::decode(digest).into_vec().map_err(|_| invalid())?,
+                    ),
+                    balance: sui_rpc::as_u64(&coin["balance"]).ok_or_else(invalid)?,
+                };
+                balance = balance.saturating_add(coin.balance);
+                coins.push(coin);
+                if balance >= needed {
+                    return Ok(coins);
+                }
+            }
+            if page["hasNextPage"] != true {
+                return Ok(coins);
+            }
+            cursor = page["nextCursor"].clone();
+            if cursor.is_null() {
+                return Err(invalid());
+            }
+        }
+    }
+
+    async fn initial_shared_version(&self, id: &str) -> Result<u64, SwapError> {
+        let object = self
+            .rpc
+            .call("sui_getObject", json!([id, { "showOwner": true }]))
+            .await?;
+        object
+            .pointer("/data/owner/Shared/initial_shared_version")
+            .and_then(sui_rpc::as_u64)
+            .ok_or_else(|| SwapError::InvalidPoolData(id.to_string(), "not shared".to_string()))
+    }
+
+    async fn dry_run(&self, tx_bytes: &str) -> Result<DryRun, SwapError> {
+        let result = self
+            .rpc
+            .call("sui_dryRunTransactionBlock", json!([tx_bytes]))
+            .await?;
+        let invalid = || invalid_answer("sui_dryRunTransactionBlock");
+        let effects = &result["effects"];
+        let cost = |name: &str| sui_rpc::as_u64(&effects["gasUsed"][name]).ok_or_else(invalid);
+        Ok(DryRun {
+            success: effects["status"]["status"] == "success",
+            error: effects["status"]["error"].as_str().map(str::to_string),
+            computation_cost: cost("computationCost")?,
+            storage_cost: cost("storageCost")?,
+            storage_rebate: cost("storageRebate")?,
+        })
+    }
+}
+
+fn to_bcs<T: Serialize>(value: &T) -> Vec<u8> {
+    bcs::to_bytes(value).expect("Impossible serializing error")
+}
+
+fn invalid_answer(method: &str) -> SwapError {
+    SwapError::RpcError(method.to_string(), "unexpected answer".to_string())
+}
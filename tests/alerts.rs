@@ -0,0 +1,110 @@
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::{eventually, MockPriceServer, MockWebhook, TestHub, SUI};
+use sui_swap::{
+    alerts::{Alert, AlertCondition, AlertRule},
+    Clock, Decimal, ManualClock, Server,
+};
+use tokio::time::{sleep, Duration};
+
+const WAIT: Duration = Duration::from_secs(5);
+const INTERVAL: Duration = Duration::from_secs(10);
+
+fn rule(name: &str, token: &str, condition: AlertCondition, webhook: &MockWebhook) -> AlertRule {
+    AlertRule {
+        name: name.to_string(),
+        token: token.to_string(),
+        condition,
+        webhook: webhook.url(),
+        cooldown_secs: 0,
+    }
+}
+
+#[tokio::test]
+async fn rules_fire_once_per_transition_and_respect_cooldowns() {
+    let clock = ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+    let start = clock.wall_now().timestamp();
+    let prices = MockPriceServer::start().await;
+    let webhook = MockWebhook::start().await;
+    let builder = Server::builder()
+        .poll_interval(INTERVAL)
+        .peer_timeout(Duration::ZERO)
+        .alert(AlertRule {
+            cooldown_secs: 60,
+            ..rule(
+                "sui-above-2",
+                "SUI",
+                AlertCondition::CrossesAbove {
+                    level: Decimal::from(2),
+                },
+                &webhook,
+            )
+        })
+        .alert(rule(
+            "sui-jump",
+            "SUI",
+            AlertCondition::ChangePct {
+                pct: Decimal::from(20),
+                window_secs: 30,
+            },
+            &webhook,
+        ))
+        // No FUD client ever connects
+        .alert(rule(
+            "fud-stale",
+            "FUD",
+            AlertCondition::Stale { secs: 60 },
+            &webhook,
+        ))
+        .clock(clock.clone());
+    let hub = TestHub::start_with(builder).await;
+
+    let client = hub.client_builder("SUI", &prices).clock(clock.clone());
+    tokio::spawn(client.build().unwrap().start());
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    // One sample every 10s
+    let sample_prices = [1.5, 1.6, 2.1, 2.2, 1.9, 2.1, 1.9, 2.05, 1.9, 2.1];
+    for price in sample_prices {
+        prices.set_price(SUI, price);
+        clock.advance(INTERVAL);
+        let now = clock.wall_now().timestamp();
+        eventually(WAIT, "price update", || async {
+            hub.peer("SUI").await.and_then(|peer| peer.last_update) == Some(now)
+        })
+        .await;
+    }
+
+    // 30s: crosses 2.0 and moves 40% from 1.5
+    // 60s, 80s: crosses again but within the 60s cooldown
+    // 70s: FUD silent for more than 60s, reported once
+    // 100s: crosses again after the cooldown
+    let sui_price: Option<Decimal> = "2.1".parse().ok();
+    let expected = [
+        (30, "sui-above-2", sui_price),
+        (30, "sui-jump", sui_price),
+        (70, "fud-stale", None),
+        (100, "sui-above-2", sui_price),
+    ];
+    eventually(WAIT, "alerts delivered", || async {
+        webhook.received().len() >= expected.len()
+    })
+    .await;
+    // Nothing else on the way
+    sleep(Duration::from_millis(100)).await;
+    let mut alerts: Vec<Alert> = webhook
+        .received()
+        .into_iter()
+        .map(|body| serde_json::from_value(body).unwrap())
+        .collect();
+    alerts.sort_by_key(|alert| (alert.timestamp, alert.rule.clone()));
+    let alerts: Vec<(i64, &str, Option<Decimal>)> = alerts
+        .iter()
+        .map(|alert| (alert.timestamp - start, alert.rule.as_str(), alert.price))
+        .collect();
+    assert_eq!(alerts, expected);
+}
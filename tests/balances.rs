@@ -0,0 +1,165 @@
+mod common;
+
+use common::{eventually, MockPriceServer, MockSuiRpc, TestHub, AAA, ADMIN_TOKEN, FUD, SUI};
+use futures_util::{pin_mut, StreamExt};
+use serde_json::{json, Value};
+use sui_swap::{
+    admin,
+    balances::{CoinBalance, Portfolio},
+    config::{BalanceConfig, QuoteConfig},
+    messages::{AdminRequest, AdminResponse},
+    Server,
+};
+use tokio::time::{timeout, Duration};
+
+const ALICE: &str = "0x00000000000000000000000000000000000000000000000000000000000a11ce";
+const BOB: &str = "0x0000000000000000000000000000000000000000000000000000000000000b0b";
+const MEME: &str = "0xdead::meme::MEME";
+
+fn chain(method: &str, params: &Value) -> Option<Value> {
+    let balance = |coin_type: &str, total: u128| json!({ "coinType": coin_type, "coinObjectCount": 1, "totalBalance": total.to_string(), "lockedBalance": {} });
+    match (method, params[0].as_str()?) {
+        ("suix_getAllBalances", ALICE) => Some(json!([
+            balance(SUI, 2_500_000_000),
+            balance(FUD, 100_000_000),
+            balance(MEME, 42),
+        ])),
+        ("suix_getAllBalances", BOB) => Some(json!([
+            balance(SUI, 1_000_000_000),
+            balance(AAA, 7_000_000_000),
+        ])),
+        _ => None,
+    }
+}
+
+async fn portfolio(hub: &TestHub, address: Option<&str>) -> Result<Portfolio, String> {
+    let request = AdminRequest::Portfolio {
+        address: address.map(str::to_string),
+    };
+    match hub.admin(request).await {
+        AdminResponse::Portfolio { portfolio } => Ok(portfolio),
+        AdminResponse::Error { message } => Err(message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+#[tokio::test]
+async fn values_balances_with_server_prices() {
+    let rpc = MockSuiRpc::start(chain).await;
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 2.0);
+    prices.set_price(FUD, 0.00001);
+    prices.set_decimals(FUD, 5);
+    let builder = Server::builder()
+        .poll_interval(Duration::from_secs(3600))
+        // Balances from rpc_url, the quotes one is never used
+        .quotes(QuoteConfig {
+            rpc_url: "http://127.0.0.1:9".to_string(),
+            ..QuoteConfig::default()
+        })
+        .balances(BalanceConfig {
+            addresses: vec!["0xA11CE".to_string(), BOB.to_string()],
+            rpc_url: Some(rpc.url()),
+            refresh_secs: 1,
+        });
+    let hub = TestHub::start_with(builder).await;
+    let mut clients = Vec::new();
+    for token in ["SUI", "FUD"] {
+        clients.push(hub.spawn_client(token, &prices));
+        hub.poll(token).await;
+    }
+    eventually(Duration::from_secs(5), "valued portfolio", || async {
+        portfolio(&hub, None)
+            .await
+            .is_ok_and(|portfolio| portfolio.unpriced == ["AAA"])
+    })
+    .await;
+
+    let portfolio_all = portfolio(&hub, None).await.unwrap();
+    let [bob, alice] = &portfolio_all.addresses[..] else {
+        panic!("Two addresses: {:?}", portfolio_all.addresses);
+    };
+    assert_eq!(alice.address, ALICE);
+    let held: Vec<(&str, Option<f64>)> = alice
+        .tokens
+        .iter()
+        .map(|held| (held.token.as_str(), held.amount))
+        .collect();
+    assert_eq!(held, [("SUI", Some(2.5)), ("FUD", Some(1000.0))]);
+    assert_eq!(alice.tokens[1].decimals, Some(5));
+    assert_close(alice.total_usd, 5.01);
+    assert_eq!(
+        alice.other_coins,
+        [CoinBalance {
+            coin_type: MEME.to_string(),
+            raw_balance: 42
+        }]
+    );
+    // AAA has no price, nor decimals to convert it
+    assert_eq!(bob.address, BOB);
+    assert_close(bob.total_usd, 2.0);
+    assert_eq!(bob.tokens[1].token, "AAA");
+    assert_eq!(bob.tokens[1].amount, None);
+    assert_eq!(bob.tokens[1].value_usd, None);
+
+    let totals: Vec<(&str, u128)> = portfolio_all
+        .tokens
+        .iter()
+        .map(|held| (held.token.as_str(), held.raw_balance))
+        .collect();
+    assert_eq!(
+        totals,
+        [
+            ("AAA", 7_000_000_000),
+            ("FUD", 100_000_000),
+            ("SUI", 3_500_000_000)
+        ]
+    );
+    assert_close(portfolio_all.tokens[2].value_usd.unwrap(), 7.0);
+    assert_close(portfolio_all.total_usd, 7.01);
+
+    // Revalued with the next price
+    prices.set_price(SUI, 3.0);
+    hub.poll("SUI").await;
+    eventually(Duration::from_secs(5), "new SUI price", || async {
+        let alice = portfolio(&hub, Some("a11ce")).await.unwrap();
+        alice.addresses.len() == 1 && (alice.total_usd - 7.51).abs() < 1e-9
+    })
+    .await;
+    let untracked = portfolio(&hub, Some("0xc4201")).await.unwrap_err();
+    assert!(untracked.contains("not tracked"), "{}", untracked);
+
+    // Subscribers get it after every refresh
+    let subscribe = AdminRequest::Subscribe {
+        token: None,
+        resolution: None,
+        portfolio: true,
+        prices: false,
+    };
+    let url = hub.admin_url();
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    pin_mut!(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+    let next = timeout(Duration::from_secs(5), pushed.next())
+        .await
+        .unwrap();
+    let Some(Ok(AdminResponse::Portfolio { portfolio })) = next else {
+        panic!("Expected a portfolio, got {:?}", next);
+    };
+    assert_close(portfolio.total_usd, 10.51);
+    assert!(rpc
+        .methods()
+        .iter()
+        .all(|method| method == "suix_getAllBalances"));
+}
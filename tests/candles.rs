@@ -0,0 +1,130 @@
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::{eventually, temp_dir, MockPriceServer, TestHub, ADMIN_TOKEN, SUI};
+use futures_util::StreamExt;
+use sui_swap::{
+    admin,
+    candles::{Candle, Resolution},
+    messages::{AdminRequest, AdminResponse},
+    Clock, Decimal, ManualClock, Server,
+};
+use tokio::time::{timeout, Duration};
+
+const WAIT: Duration = Duration::from_secs(5);
+const INTERVAL: Duration = Duration::from_secs(10);
+
+async fn candles(hub: &TestHub, resolution: Resolution) -> Vec<Candle> {
+    let request = AdminRequest::Candles {
+        token: "SUI".to_string(),
+        resolution,
+        limit: None,
+    };
+    match hub.admin(request).await {
+        AdminResponse::Candles { candles, .. } => candles,
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn samples_are_aggregated_into_persisted_candles() {
+    let storage = temp_dir("storage");
+    let clock = ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+    let start = clock.wall_now().timestamp();
+    let prices = MockPriceServer::start().await;
+    let builder = Server::builder()
+        .poll_interval(INTERVAL)
+        .clock(clock.clone());
+    let hub = TestHub::start_in(builder, &storage).await;
+
+    let client = hub.client_builder("SUI", &prices).clock(clock.clone());
+    tokio::spawn(client.build().unwrap().start());
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    let url = hub.admin_url();
+    let subscribe = AdminRequest::Subscribe {
+        token: Some("SUI".to_string()),
+        resolution: Some(Resolution::OneMinute),
+        portfolio: false,
+        prices: false,
+    };
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    let mut pushed = Box::pin(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+
+    // A sample every 10s for five minutes, the price goes up and down
+    let sample_prices: Vec<f64> = (1..=30).map(|k| 1.0 + ((k * 7) % 11) as f64).collect();
+    for price in &sample_prices {
+        prices.set_price(SUI, *price);
+        clock.advance(INTERVAL);
+        let now = clock.wall_now().timestamp();
+        eventually(WAIT, "price update", || async {
+            hub.peer("SUI").await.and_then(|peer| peer.last_update) == Some(now)
+        })
+        .await;
+    }
+
+    // Bar n has the samples taken at 10s..50s into minute n, the one at the
+    // start of the minute (poll k = 6n) opens it
+    let expected = |minute: usize| {
+        let first = if minute == 0 { 0 } else { 6 * minute - 1 };
+        let bar: Vec<Decimal> = sample_prices[first..6 * minute + 5]
+            .iter()
+            .map(|price| Decimal::from_f64(*price).unwrap())
+            .collect();
+        Candle {
+            start: start + 60 * minute as i64,
+            open: bar[0],
+            high: *bar.iter().max().unwrap(),
+            low: *bar.iter().min().unwrap(),
+            close: bar[bar.len() - 1],
+            samples: bar.len() as u64,
+            avg_confidence: "0.99".parse().unwrap(),
+        }
+    };
+    let minutes = candles(&hub, Resolution::OneMinute).await;
+    // Five closed bars and the one opened by the sample at 5:00
+    assert_eq!(minutes.len(), 6);
+    for (minute, candle) in minutes.iter().take(5).enumerate() {
+        assert_eq!(*candle, expected(minute));
+    }
+
+    let five_minutes = candles(&hub, Resolution::FiveMinutes).await;
+    assert_eq!(five_minutes.len(), 2);
+    assert_eq!(five_minutes[0].samples, 29);
+    assert_eq!(five_minutes[1].samples, 1);
+
+    // Closed 1m bars were pushed as they closed
+    for minute in 0..5 {
+        let message = timeout(WAIT, pushed.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match message {
+            AdminResponse::CandleClosed {
+                token,
+                resolution,
+                candle,
+            } => {
+                assert_eq!(token, "SUI");
+                assert_eq!(resolution, Resolution::OneMinute);
+                assert_eq!(candle, expected(minute));
+            }
+            other => panic!("Unexpected push: {:?}", other),
+        }
+    }
+
+    // Closed bars survive a restart
+    drop(hub);
+    let hub = TestHub::start_in(Server::builder(), &storage).await;
+    let minutes = candles(&hub, Resolution::OneMinute).await;
+    assert_eq!(minutes.len(), 5);
+    assert_eq!(minutes[4], expected(4));
+    let _ = std::fs::remove_dir_all(&storage);
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::{eventually, MockPriceServer, TestHub, FUD, SUI};
+use std::sync::Arc;
+use sui_swap::{messages::AdminRequest, Clock, Decimal, ManualClock, Server};
+use tokio::time::{sleep, Duration};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn clock() -> Arc<ManualClock> {
+    ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+}
+
+/// Move the clock one poll interval and wait until `token` reports the price
+/// polled at the new time
+async fn step(clock: &ManualClock, hub: &TestHub, token: &str, interval: Duration) {
+    clock.advance(interval);
+    let now = clock.wall_now().timestamp();
+    eventually(WAIT, "price update", || async {
+        hub.peer(token).await.and_then(|peer| peer.last_update) == Some(now)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn hours_of_polling_follow_token_intervals() {
+    let clock = clock();
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    prices.set_price(FUD, 0.0001);
+    let interval = Duration::from_secs(60);
+    let hub = TestHub::start_with(
+        Server::builder()
+            .poll_interval(interval)
+            .clock(clock.clone()),
+    )
+    .await;
+
+    for token in ["SUI", "FUD"] {
+        let client = hub.client_builder(token, &prices).clock(clock.clone());
+        tokio::spawn(client.build().unwrap().start());
+    }
+    eventually(WAIT, "both tokens registered", || async {
+        hub.registered_tokens().await == ["FUD", "SUI"]
+    })
+    .await;
+    hub.admin(AdminRequest::SetPollInterval {
+        secs: 300,
+        token: Some("FUD".to_string()),
+    })
+    .await;
+
+    // Three hours
+    for _ in 0..180 {
+        step(&clock, &hub, "SUI", interval).await;
+    }
+
+    // FUD is polled on the first round and then every 5 minutes
+    eventually(WAIT, "FUD polls", || async { prices.hits(FUD) == 36 }).await;
+    assert_eq!(prices.hits(SUI), 180);
+    assert_eq!(clock.elapsed(), Duration::from_secs(3 * 3600));
+}
+
+#[tokio::test]
+async fn token_intervals_are_not_rounded_to_the_default() {
+    let clock = clock();
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    prices.set_price(FUD, 0.0001);
+    let hub = TestHub::start_with(
+        Server::builder()
+            .poll_interval(Duration::from_secs(60))
+            .clock(clock.clone()),
+    )
+    .await;
+
+    for token in ["SUI", "FUD"] {
+        let client = hub.client_builder(token, &prices).clock(clock.clone());
+        tokio::spawn(client.build().unwrap().start());
+    }
+    // Shorter than the default, and not a multiple of it
+    for (token, secs) in [("SUI", 15), ("FUD", 90)] {
+        hub.admin(AdminRequest::SetPollInterval {
+            secs,
+            token: Some(token.to_string()),
+        })
+        .await;
+        hub.poll(token).await;
+    }
+    let start = clock.wall_now().timestamp();
+    eventually(WAIT, "first polls", || async {
+        prices.hits(SUI) == 1 && prices.hits(FUD) == 1
+    })
+    .await;
+    for token in ["SUI", "FUD"] {
+        eventually(WAIT, "first prices", || async {
+            hub.peer(token).await.and_then(|peer| peer.last_update) == Some(start)
+        })
+        .await;
+    }
+
+    // Half an hour
+    let interval = Duration::from_secs(15);
+    for _ in 0..120 {
+        step(&clock, &hub, "SUI", interval).await;
+    }
+
+    eventually(WAIT, "FUD polls", || async { prices.hits(FUD) == 21 }).await;
+    assert_eq!(prices.hits(SUI), 121);
+}
+
+#[tokio::test]
+async fn cached_prices_are_reused_until_the_ttl_expires() {
+    let clock = clock();
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let interval = Duration::from_secs(10);
+    let hub = TestHub::start_with(
+        Server::builder()
+            .poll_interval(interval)
+            .clock(clock.clone()),
+    )
+    .await;
+
+    let client = hub
+        .client_builder("SUI", &prices)
+        .price_cache_ttl(Duration::from_secs(60))
+        .clock(clock.clone());
+    tokio::spawn(client.build().unwrap().start());
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    // Half an hour, the source is asked once a minute
+    for _ in 0..180 {
+        step(&clock, &hub, "SUI", interval).await;
+    }
+    assert_eq!(prices.hits(SUI), 30);
+}
+
+#[tokio::test]
+async fn silent_peers_are_evicted() {
+    let clock = clock();
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let interval = Duration::from_secs(10);
+    let builder = Server::builder()
+        .poll_interval(interval)
+        .peer_timeout(Duration::from_secs(60))
+        .clock(clock.clone());
+    let hub = TestHub::start_with(builder).await;
+
+    let client = hub.client_builder("SUI", &prices).clock(clock.clone());
+    tokio::spawn(client.build().unwrap().start());
+    // Connected but never reading, so pings are never answered
+    let (_silent, _) = tokio_tungstenite::connect_async(hub.url()).await.unwrap();
+    eventually(WAIT, "both peers connected", || async {
+        hub.peers().await.len() == 2 && hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    // Keepalives every 20s, the silent peer goes over the timeout on the 80s one
+    for _ in 0..7 {
+        step(&clock, &hub, "SUI", interval).await;
+    }
+    assert_eq!(hub.peers().await.len(), 2);
+    step(&clock, &hub, "SUI", interval).await;
+    eventually(WAIT, "silent peer evicted", || async {
+        hub.peers().await.len() == 1
+    })
+    .await;
+
+    // The client answering pings stays connected
+    for _ in 0..60 {
+        step(&clock, &hub, "SUI", interval).await;
+    }
+    assert_eq!(hub.registered_tokens().await, ["SUI"]);
+}
+
+#[tokio::test]
+async fn stale_prices_are_not_forwarded() {
+    let clock = clock();
+    let start = clock.wall_now().timestamp();
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    // The source keeps reporting the same sample time
+    prices.set_timestamp(Some(start));
+    let interval = Duration::from_secs(10);
+    let builder = Server::builder()
+        .poll_interval(interval)
+        .peer_timeout(Duration::ZERO)
+        .clock(clock.clone());
+    let hub = TestHub::start_with(builder).await;
+
+    let client = hub
+        .client_builder("SUI", &prices)
+        .max_price_age(Duration::from_secs(300))
+        .clock(clock.clone());
+    tokio::spawn(client.build().unwrap().start());
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    // Five minutes old at most, still fresh
+    for _ in 0..30 {
+        step(&clock, &hub, "SUI", interval).await;
+    }
+    let peer = hub.peer("SUI").await.unwrap();
+    assert_eq!(peer.last_price, Decimal::from_f64(1.5));
+
+    // Too old now, the sample is fetched but not forwarded
+    prices.set_price(SUI, 2.0);
+    let hits = prices.hits(SUI);
+    clock.advance(interval);
+    eventually(WAIT, "stale sample fetched", || async {
+        prices.hits(SUI) > hits
+    })
+    .await;
+    sleep(Duration::from_millis(100)).await;
+    let peer = hub.peer("SUI").await.unwrap();
+    assert_eq!(peer.last_price, Decimal::from_f64(1.5));
+    assert_eq!(peer.last_update, Some(start + 300));
+
+    // A new sample from the source goes through again
+    prices.set_timestamp(Some(clock.wall_now().timestamp()));
+    step(&clock, &hub, "SUI", interval).await;
+    let peer = hub.peer("SUI").await.unwrap();
+    assert_eq!(peer.last_price, Decimal::from_f64(2.0));
+}
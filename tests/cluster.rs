@@ -0,0 +1,310 @@
+mod common;
+
+use common::{certificates, eventually, MockPriceServer, MockWebhook, TestHub, ADMIN_TOKEN, SUI};
+use futures_util::StreamExt;
+use std::{net::TcpListener, path::Path};
+use sui_swap::{
+    admin,
+    alerts::{AlertCondition, AlertRule},
+    candles::Resolution,
+    cluster::{ClusterRegistration, ClusterStatus},
+    config::ClusterConfig,
+    messages::{AdminRequest, AdminResponse},
+    Decimal, Server, ServerBuilder, SwapError,
+};
+use tokio::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(10);
+const CLUSTER_TOKEN: &str = "test-cluster";
+
+/// Free local address to give each node before starting any
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Nodes named a, b, c... each listing all the others as peers
+async fn start_cluster(names: &[&str]) -> Vec<TestHub> {
+    start_nodes(names, None).await
+}
+
+/// Like [`start_cluster`] serving wss:// with the certificates in `tls`
+async fn start_nodes(names: &[&str], tls: Option<&Path>) -> Vec<TestHub> {
+    start_nodes_with(names, tls, || {
+        Server::builder().poll_interval(Duration::from_secs(3600))
+    })
+    .await
+}
+
+/// Like [`start_nodes`] with the settings of `base` on every node
+async fn start_nodes_with(
+    names: &[&str],
+    tls: Option<&Path>,
+    base: impl Fn() -> ServerBuilder,
+) -> Vec<TestHub> {
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let addrs: Vec<String> = names.iter().map(|_| free_addr()).collect();
+    let mut hubs = Vec::new();
+    for (index, name) in names.iter().enumerate() {
+        let cluster = ClusterConfig {
+            listen: Some(addrs[index].clone()),
+            node_id: Some(name.to_string()),
+            peers: addrs
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .map(|(_, addr)| format!("{}://{}", scheme, addr))
+                .collect(),
+            token: Some(CLUSTER_TOKEN.to_string()),
+            ca_cert: tls.map(|dir| dir.join("ca.pem")),
+            heartbeat_secs: 1,
+            node_timeout_secs: 3,
+        };
+        let builder = base().cluster(cluster);
+        hubs.push(match tls {
+            Some(dir) => TestHub::start_tls(builder, dir).await,
+            None => TestHub::start_with(builder).await,
+        });
+    }
+    hubs
+}
+
+async fn status(hub: &TestHub) -> ClusterStatus {
+    match hub.admin(AdminRequest::Cluster).await {
+        AdminResponse::Cluster { cluster } => cluster,
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+async fn registration(hub: &TestHub, token: &str) -> Option<ClusterRegistration> {
+    status(hub)
+        .await
+        .registrations
+        .into_iter()
+        .find(|registration| registration.token == token)
+}
+
+#[tokio::test]
+async fn nodes_share_registrations_and_prices() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let hubs = start_cluster(&["a", "b", "c"]).await;
+    let (a, b, c) = (&hubs[0], &hubs[1], &hubs[2]);
+
+    let _sui = a.spawn_client("SUI", &prices);
+    for hub in [b, c] {
+        eventually(WAIT, "SUI known on every node", || async {
+            registration(hub, "SUI")
+                .await
+                .is_some_and(|registration| registration.node == "a" && registration.node_up)
+        })
+        .await;
+    }
+
+    // Registered on a, so no other node takes it
+    let second = b.client("SUI", &prices).start().await;
+    assert!(matches!(second, Err(SwapError::TokenTaken(token)) if token == "SUI"));
+    assert!(b.registered_tokens().await.is_empty());
+
+    // Not even through the admin channel
+    let _fud = b.spawn_client("FUD", &prices);
+    eventually(WAIT, "FUD registered on b", || async {
+        b.registered_tokens().await == ["FUD"]
+    })
+    .await;
+    let reassign = AdminRequest::ReassignToken {
+        token: "SUI".to_string(),
+        peer: b.peer("FUD").await.unwrap().addr,
+    };
+    match b.admin(reassign).await {
+        AdminResponse::Error { message } => assert!(message.contains("node a"), "{}", message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+    assert_eq!(b.registered_tokens().await, ["FUD"]);
+
+    let url = c.admin_url();
+    let subscribe = AdminRequest::Subscribe {
+        token: Some("SUI".to_string()),
+        resolution: None,
+        portfolio: false,
+        prices: true,
+    };
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    let mut pushed = Box::pin(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+
+    a.poll("SUI").await;
+    let price = tokio::time::timeout(WAIT, pushed.next())
+        .await
+        .expect("Price pushed by c");
+    match price {
+        Some(Ok(AdminResponse::Price {
+            token, node, price, ..
+        })) => {
+            assert_eq!(token, "SUI");
+            assert_eq!(node.as_deref(), Some("a"));
+            assert_eq!(price, "1.5".parse::<Decimal>().unwrap());
+        }
+        other => panic!("Unexpected push: {:?}", other),
+    }
+
+    // Aggregated on c as if its own client sent it
+    let candles = AdminRequest::Candles {
+        token: "SUI".to_string(),
+        resolution: Resolution::OneMinute,
+        limit: None,
+    };
+    match c.admin(candles).await {
+        AdminResponse::Candles { candles, .. } => assert_eq!(candles.len(), 1),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn tokens_of_a_lost_node_can_register_elsewhere() {
+    let prices = MockPriceServer::start().await;
+    let mut hubs = start_cluster(&["a", "b", "c"]).await;
+
+    let _sui = hubs[0].spawn_client("SUI", &prices);
+    eventually(WAIT, "SUI known on b", || async {
+        registration(&hubs[1], "SUI").await.is_some()
+    })
+    .await;
+
+    // Stop a, its registration is kept while no other node takes it
+    drop(hubs.remove(0));
+    let (b, c) = (&hubs[0], &hubs[1]);
+    eventually(WAIT, "a lost", || async {
+        let status = status(b).await;
+        status.nodes.iter().any(|node| node.node == "a" && !node.up)
+    })
+    .await;
+    let lost = registration(b, "SUI").await.expect("SUI still listed");
+    assert_eq!(lost.node, "a");
+    assert!(!lost.node_up);
+
+    let _moved = b.spawn_client("SUI", &prices);
+    eventually(WAIT, "SUI registered on b", || async {
+        b.registered_tokens().await == ["SUI"]
+    })
+    .await;
+    eventually(WAIT, "SUI moved to b on c", || async {
+        registration(c, "SUI")
+            .await
+            .is_some_and(|registration| registration.node == "b" && registration.node_up)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn cluster_forms_over_tls() {
+    let tls = certificates();
+    let hubs = start_nodes(&["a", "b"], Some(&tls)).await;
+
+    for (hub, other) in [(&hubs[0], "b"), (&hubs[1], "a")] {
+        eventually(WAIT, "nodes linked over wss://", || async {
+            status(hub)
+                .await
+                .nodes
+                .iter()
+                .any(|node| node.node == other && node.up)
+        })
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn only_one_node_sends_each_alert() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let webhook = MockWebhook::start().await;
+    let rule = |name: &str, token: &str, condition| AlertRule {
+        name: name.to_string(),
+        token: token.to_string(),
+        condition,
+        webhook: webhook.url(),
+        cooldown_secs: 0,
+    };
+    let hubs = start_nodes_with(&["a", "b"], None, || {
+        Server::builder()
+            .poll_interval(Duration::from_secs(1))
+            .alert(rule(
+                "sui-above-2",
+                "SUI",
+                AlertCondition::CrossesAbove {
+                    level: Decimal::from(2),
+                },
+            ))
+            .alert(rule("sui-stale", "SUI", AlertCondition::Stale { secs: 5 }))
+            // No node serves FUD
+            .alert(rule("fud-stale", "FUD", AlertCondition::Stale { secs: 6 }))
+    })
+    .await;
+    let (a, b) = (&hubs[0], &hubs[1]);
+    let _sui = a.spawn_client("SUI", &prices);
+    for (hub, other) in [(a, "b"), (b, "a")] {
+        eventually(WAIT, "nodes linked", || async {
+            status(hub)
+                .await
+                .nodes
+                .iter()
+                .any(|node| node.node == other && node.up)
+        })
+        .await;
+    }
+
+    a.poll("SUI").await;
+    eventually(WAIT, "first SUI price", || async {
+        a.peer("SUI").await.and_then(|peer| peer.last_price) == "1.5".parse().ok()
+    })
+    .await;
+    prices.set_price(SUI, 2.1);
+    let fired = |name: &str| {
+        let received = webhook.received();
+        received
+            .iter()
+            .filter(|alert| alert["rule"] == name)
+            .count()
+    };
+    eventually(WAIT, "crossing and FUD staleness alerted", || async {
+        fired("sui-above-2") > 0 && fired("fud-stale") > 0
+    })
+    .await;
+    // b gets the SUI prices through the cluster, which keep its rules fresh
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(fired("sui-above-2"), 1);
+    assert_eq!(fired("fud-stale"), 1);
+    assert_eq!(fired("sui-stale"), 0);
+}
+
+#[tokio::test]
+async fn clients_fail_over_to_another_node() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let mut hubs = start_cluster(&["a", "b"]).await;
+    let (b, a) = (hubs.pop().unwrap(), hubs.pop().unwrap());
+
+    let client = a
+        .client_builder("SUI", &prices)
+        .fallback_url(b.url())
+        .reconnect(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    let client = tokio::spawn(client.start());
+    eventually(WAIT, "SUI registered on b through a", || async {
+        registration(&b, "SUI")
+            .await
+            .is_some_and(|registration| registration.node == "a")
+    })
+    .await;
+
+    // b lets the client in once it gives a up
+    a.shutdown().await;
+    eventually(WAIT, "SUI registered on b", || async {
+        b.registered_tokens().await == ["SUI"]
+    })
+    .await;
+    assert!(!client.is_finished());
+}
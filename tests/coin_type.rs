@@ -0,0 +1,105 @@
+mod common;
+
+use common::{eventually, MockPriceServer, TestHub, SUI};
+use std::collections::HashMap;
+use sui_swap::{
+    models::{TimeStamp, TokenInfoInnerResponse, TokenInfoResponse},
+    CoinType, Decimal, SwapError, TokenRegistry,
+};
+use tokio::time::Duration;
+
+const SUI_FULL: &str =
+    "0x0000000000000000000000000000000000000000000000000000000000000002::sui::SUI";
+
+#[test]
+fn normalizes_short_addresses() {
+    let short: CoinType = SUI.parse().unwrap();
+    let full: CoinType = SUI_FULL.parse().unwrap();
+    assert_eq!(short, full);
+    assert_eq!(short.to_string(), SUI_FULL);
+    assert_eq!(short.module(), "sui");
+    assert_eq!(short.name(), "SUI");
+    let upper: CoinType = "0xABC::coin::ABC".parse().unwrap();
+    assert!(upper.address().ends_with("0abc"));
+
+    for malformed in [
+        "",
+        "SUI",
+        "0x2::sui",
+        "0x2::sui::SUI::X",
+        "0xzz::sui::SUI",
+        "0x2::1sui::SUI",
+        "0x2::sui::",
+        "0x2::sui::SUI<T>",
+    ] {
+        let error = malformed.parse::<CoinType>().unwrap_err();
+        assert!(
+            matches!(error, SwapError::InvalidCoinType(ref coin_type) if coin_type == malformed),
+            "{}: {}",
+            malformed,
+            error
+        );
+    }
+}
+
+#[test]
+fn matches_response_keys_in_any_form() {
+    let sui: CoinType = SUI.parse().unwrap();
+    let info = TokenInfoInnerResponse {
+        confidence: "0.99".parse().unwrap(),
+        decimals: 9,
+        price: Decimal::from(2),
+        symbol: "SUI".to_string(),
+        timestamp: TimeStamp(0),
+    };
+    for key in [SUI, SUI_FULL, "sui:0x2::sui::SUI", "sui:0x02::sui::SUI"] {
+        let response = TokenInfoResponse {
+            coins: HashMap::from([(key.to_string(), info.clone())]),
+        };
+        assert_eq!(
+            response.get(&sui).map(|info| info.price),
+            Some(Decimal::from(2)),
+            "{}",
+            key
+        );
+    }
+    let other = TokenInfoResponse {
+        coins: HashMap::from([("sui:0x3::sui::SUI".to_string(), info)]),
+    };
+    assert!(other.get(&sui).is_none());
+}
+
+#[test]
+fn rejects_malformed_tokens_file_entries() {
+    let tokens =
+        TokenRegistry::from_json(r#"{ "SUI": { "coin_type": "0x2::sui::SUI", "symbol": "SUI" } }"#)
+            .unwrap();
+    assert_eq!(tokens.get("SUI").unwrap().coin_type.to_string(), SUI_FULL);
+
+    let error =
+        TokenRegistry::from_json(r#"{ "BAD": { "coin_type": "0x2:sui:SUI", "symbol": "BAD" } }"#)
+            .unwrap_err();
+    assert!(
+        error.to_string().contains("Invalid coin type 0x2:sui:SUI"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn asks_upstream_with_the_full_coin_type() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 2.0);
+    let hub = TestHub::start(Duration::from_secs(3600)).await;
+    let _client = hub.spawn_client("SUI", &prices);
+    hub.poll("SUI").await;
+    eventually(Duration::from_secs(5), "SUI price", || async {
+        hub.peer("SUI")
+            .await
+            .is_some_and(|peer| peer.last_price == Decimal::from_f64(2.0))
+    })
+    .await;
+    let requested = prices.requested();
+    assert!(!requested.is_empty());
+    assert!(requested.iter().all(|path| path.ends_with(SUI_FULL)));
+}
@@ -0,0 +1,570 @@
+//! Test support: an in-process server on ephemeral ports, a mock of the
+//! DefiLlama `/prices/current/` endpoint and helpers to drive clients.
+
+#![allow(dead_code)]
+
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use sui_swap::{
+    admin,
+    messages::{AdminRequest, AdminResponse, PeerInfo},
+    Client, ClientBuilder, CoinType, Server, ServerBuilder, SwapError, TokenConfig, TokenRegistry,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::oneshot,
+    task::JoinHandle,
+    time::{sleep, Duration, Instant},
+};
+
+pub const ADMIN_TOKEN: &str = "test-admin";
+pub const SUI: &str = "0x2::sui::SUI";
+pub const FUD: &str =
+    "0x76cb819b01abed502bee8a702b4c2d547532c12f25001c9dea795a5e631c26f1::fud::FUD";
+pub const AAA: &str =
+    "0xd976fda9a9786cda1a36dee360013d775a5e5f206f8e20f84fad3385e99eeb2d::aaa::AAA";
+
+/// Coin type as the hub sends it upstream, with the address in full
+pub fn full(coin_type: &str) -> String {
+    match coin_type.parse::<CoinType>() {
+        Ok(coin_type) => coin_type.to_string(),
+        // Left for the code under test to reject
+        Err(_) => coin_type.to_string(),
+    }
+}
+
+/// Same tokens as the tokens.json shipped with the project
+pub fn tokens() -> TokenRegistry {
+    let mut tokens = TokenRegistry::default();
+    for (name, coin_type) in [("SUI", SUI), ("FUD", FUD), ("AAA", AAA)] {
+        tokens
+            .insert(name, TokenConfig::new(coin_type.parse().unwrap(), name))
+            .expect("Valid token");
+    }
+    tokens
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Price by coin type, unknown coins get an empty `coins` object
+    prices: HashMap<String, f64>,
+    hits: HashMap<String, usize>,
+    /// Paths of every request, in order
+    paths: Vec<String>,
+    /// Answer this status with an empty body instead of prices
+    status: Option<u16>,
+    /// `Retry-After` header sent with `status`
+    retry_after: Option<String>,
+    /// Wait this long before answering
+    delay: Duration,
+    /// Timestamp reported for prices, the current time if not set
+    timestamp: Option<i64>,
+    /// Decimals reported by coin type, 9 if not set
+    decimals: HashMap<String, u64>,
+}
+
+/// Local HTTP server answering like `https://coins.llama.fi/prices/current/`
+pub struct MockPriceServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockPriceServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Bind mock");
+        let addr = listener.local_addr().expect("Mock address");
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let handle = tokio::spawn(Self::serve(listener, state.clone()));
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    /// Base URL to configure as price source, the coin type is appended to it
+    pub fn url(&self) -> String {
+        format!("http://{}/prices/current/sui:", self.addr)
+    }
+
+    pub fn set_price(&self, coin_type: &str, price: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.prices.insert(full(coin_type), price);
+    }
+
+    pub fn set_decimals(&self, coin_type: &str, decimals: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.decimals.insert(full(coin_type), decimals);
+    }
+
+    pub fn set_timestamp(&self, timestamp: Option<i64>) {
+        self.state.lock().unwrap().timestamp = timestamp;
+    }
+
+    pub fn set_status(&self, status: Option<u16>) {
+        self.state.lock().unwrap().status = status;
+    }
+
+    pub fn set_retry_after(&self, retry_after: Option<&str>) {
+        self.state.lock().unwrap().retry_after = retry_after.map(str::to_string);
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    /// Requests received for a coin type
+    pub fn hits(&self, coin_type: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.hits.get(&full(coin_type)).copied().unwrap_or(0)
+    }
+
+    /// Paths of the requests received, e.g. `/prices/current/sui:0x2::sui::SUI`
+    pub fn requested(&self) -> Vec<String> {
+        self.state.lock().unwrap().paths.clone()
+    }
+
+    async fn serve(listener: TcpListener, state: Arc<Mutex<MockState>>) {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let key = path.trim_start_matches("/prices/current/");
+                let coin_type = full(key.trim_start_matches("sui:"));
+                let (status, headers, body, delay) = {
+                    let mut state = state.lock().unwrap();
+                    *state.hits.entry(coin_type.clone()).or_default() += 1;
+                    state.paths.push(path.to_string());
+                    let timestamp = state
+                        .timestamp
+                        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+                    let (status, body) = match (state.status, state.prices.get(&coin_type)) {
+                        (Some(status), _) => (status, String::new()),
+                        (None, Some(price)) => {
+                            let decimals = state.decimals.get(&coin_type).copied().unwrap_or(9);
+                            let body = Self::body(key, &coin_type, *price, decimals, timestamp);
+                            (200, body)
+                        }
+                        (None, None) => (200, json!({ "coins": {} }).to_string()),
+                    };
+                    let headers = match (&state.retry_after, state.status) {
+                        (Some(retry_after), Some(_)) => format!("Retry-After: {}\r\n", retry_after),
+                        _ => String::new(),
+                    };
+                    (status, headers, body, state.delay)
+                };
+                sleep(delay).await;
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    }
+
+    fn body(key: &str, coin_type: &str, price: f64, decimals: u64, timestamp: i64) -> String {
+        let symbol = coin_type.rsplit("::").next().unwrap_or_default();
+        json!({
+            "coins": {
+                key: {
+                    "decimals": decimals,
+                    "symbol": symbol,
+                    "price": price,
+                    "timestamp": timestamp,
+                    "confidence": 0.99
+                }
+            }
+        })
+        .to_string()
+    }
+}
+
+impl Drop for MockPriceServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Local HTTP server keeping the JSON bodies POSTed to it
+pub struct MockWebhook {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<Value>>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockWebhook {
+    pub async fn start() -> Self {
+        let (addr, received, handle) = serve_json(Arc::new(|_: &Value| json!({}))).await;
+        Self {
+            addr,
+            received,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/alerts", self.addr)
+    }
+
+    pub fn received(&self) -> Vec<Value> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockWebhook {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Sui JSON-RPC endpoint answering with `handler(method, params)`, an error
+/// when it returns None
+pub struct MockSuiRpc {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<Value>>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockSuiRpc {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Option<Value> + Send + Sync + 'static,
+    {
+        let answer = move |request: &Value| {
+            let method = request["method"].as_str().unwrap_or_default();
+            match handler(method, &request["params"]) {
+                Some(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+                None => json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": { "code": -32601, "message": format!("Mock can't answer {}", method) }
+                }),
+            }
+        };
+        let (addr, received, handle) = serve_json(Arc::new(answer)).await;
+        Self {
+            addr,
+            received,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Requests received, oldest first
+    pub fn received(&self) -> Vec<Value> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Methods called, oldest first
+    pub fn methods(&self) -> Vec<String> {
+        self.received()
+            .iter()
+            .map(|request| request["method"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+}
+
+impl Drop for MockSuiRpc {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+type JsonHandler = Arc<dyn Fn(&Value) -> Value + Send + Sync>;
+
+/// HTTP server answering every JSON body POSTed to it with `answer(body)`
+async fn serve_json(answer: JsonHandler) -> (SocketAddr, Arc<Mutex<Vec<Value>>>, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Bind JSON server");
+    let addr = listener.local_addr().expect("JSON server address");
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let requests = received.clone();
+    let handle = tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let requests = requests.clone();
+            let answer = answer.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                let body_start = loop {
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                };
+                let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or(0);
+                while request.len() < body_start + length {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let Ok(body) = serde_json::from_slice(&request[body_start..body_start + length])
+                else {
+                    return;
+                };
+                let response = answer(&body).to_string();
+                requests.lock().unwrap().push(body);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    (addr, received, handle)
+}
+
+/// Server running in the test runtime, with the admin channel enabled
+pub struct TestHub {
+    pub addr: SocketAddr,
+    pub admin_addr: SocketAddr,
+    /// CA of the certificate the hub serves wss:// with, if it does
+    ca_cert: Option<PathBuf>,
+    handle: JoinHandle<Result<(), SwapError>>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+}
+
+impl TestHub {
+    pub async fn start(poll_interval: Duration) -> Self {
+        Self::start_with(Server::builder().poll_interval(poll_interval)).await
+    }
+
+    /// Start from `builder`, listen addresses, storage and tokens are set here
+    pub async fn start_with(builder: ServerBuilder) -> Self {
+        Self::start_in(builder, &temp_dir("storage")).await
+    }
+
+    /// Like [`TestHub::start_with`] serving wss:// with the certificates
+    /// made by [`certificates`] in `tls`
+    pub async fn start_tls(builder: ServerBuilder, tls: &Path) -> Self {
+        let mut hub =
+            Self::start_with(builder.tls(tls.join("cert.pem"), tls.join("key.pem"))).await;
+        hub.ca_cert = Some(tls.join("ca.pem"));
+        hub
+    }
+
+    /// Like [`TestHub::start_with`] keeping the server data in `storage`
+    pub async fn start_in(builder: ServerBuilder, storage: &Path) -> Self {
+        let server = builder
+            .storage(storage)
+            .listen("127.0.0.1:0")
+            .admin("127.0.0.1:0", ADMIN_TOKEN)
+            .tokens(tokens())
+            .build()
+            .expect("Valid server config")
+            .bind()
+            .await
+            .expect("Bind server");
+        let addr = server.local_addr();
+        let admin_addr = server.admin_addr().expect("Admin channel enabled");
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let handle = tokio::spawn(server.run_until(async {
+            let _ = shutdown_rx.await;
+        }));
+        Self {
+            addr,
+            admin_addr,
+            ca_cert: None,
+            handle,
+            shutdown_tx: Some(shutdown_tx),
+        }
+    }
+
+    /// Stop the server like a signal would, waiting for it to save its state.
+    /// Dropping the hub stops it without saving.
+    pub async fn shutdown(mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        (&mut self.handle)
+            .await
+            .expect("Server task")
+            .expect("Server stopped cleanly");
+    }
+
+    pub fn url(&self) -> String {
+        match self.ca_cert {
+            Some(_) => format!("wss://{}", self.addr),
+            None => format!("ws://{}", self.addr),
+        }
+    }
+
+    /// Client for `token` fetching prices from `prices` without caching
+    pub fn client(&self, token: &str, prices: &MockPriceServer) -> Client {
+        self.client_builder(token, prices)
+            .build()
+            .expect("Valid client config")
+    }
+
+    pub fn client_builder(&self, token: &str, prices: &MockPriceServer) -> ClientBuilder {
+        Client::builder(token)
+            .server_url(self.url())
+            .price_source("defillama", prices.url())
+            .price_cache_ttl(Duration::ZERO)
+            .tokens(tokens())
+    }
+
+    /// Start a client in the background
+    pub fn spawn_client(
+        &self,
+        token: &str,
+        prices: &MockPriceServer,
+    ) -> JoinHandle<Result<(), SwapError>> {
+        tokio::spawn(self.client(token, prices).start())
+    }
+
+    pub fn admin_url(&self) -> String {
+        match self.ca_cert {
+            Some(_) => format!("wss://{}", self.admin_addr),
+            None => format!("ws://{}", self.admin_addr),
+        }
+    }
+
+    pub fn ca_cert(&self) -> Option<&Path> {
+        self.ca_cert.as_deref()
+    }
+
+    pub async fn admin(&self, request: AdminRequest) -> AdminResponse {
+        admin::send_request(
+            &self.admin_url(),
+            Some(ADMIN_TOKEN),
+            self.ca_cert(),
+            &request,
+        )
+        .await
+        .expect("Admin request")
+    }
+
+    /// Wait for `token` to register and ask its client for a price right away
+    pub async fn poll(&self, token: &str) {
+        eventually(Duration::from_secs(5), "registration", || async {
+            self.registered_tokens().await.iter().any(|t| t == token)
+        })
+        .await;
+        let request = AdminRequest::PollNow {
+            token: Some(token.to_string()),
+        };
+        assert!(matches!(self.admin(request).await, AdminResponse::Done));
+    }
+
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        match self.admin(AdminRequest::ListPeers).await {
+            AdminResponse::Peers { peers, .. } => peers,
+            other => panic!("Unexpected admin response: {:?}", other),
+        }
+    }
+
+    pub async fn peer(&self, token: &str) -> Option<PeerInfo> {
+        self.peers()
+            .await
+            .into_iter()
+            .find(|peer| peer.token.as_deref() == Some(token))
+    }
+
+    /// Names of the registered tokens, sorted
+    pub async fn registered_tokens(&self) -> Vec<String> {
+        let mut tokens: Vec<String> = self
+            .peers()
+            .await
+            .into_iter()
+            .filter_map(|peer| peer.token)
+            .collect();
+        tokens.sort();
+        tokens
+    }
+}
+
+impl Drop for TestHub {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Path under the system temp dir not used by any other test
+pub fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("sui-swap-{}-{}-{}", std::process::id(), id, name))
+}
+
+/// Retry `check` until it holds, panicking after `timeout`
+pub async fn eventually<F, Fut>(timeout: Duration, what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + timeout;
+    while !check().await {
+        if Instant::now() > deadline {
+            panic!("Timed out waiting for {}", what);
+        }
+        sleep(Duration::from_millis(5)).await;
+    }
+}
+
+/// CA in ca.pem and the certificate it signed for 127.0.0.1 in cert.pem and
+/// key.pem
+pub fn certificates() -> PathBuf {
+    let dir = temp_dir("tls");
+    fs::create_dir_all(&dir).unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+        .unwrap()
+        .signed_by(&key, &ca, &ca_key)
+        .unwrap();
+    fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+    dir
+}
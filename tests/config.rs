@@ -0,0 +1,97 @@
+mod common;
+
+use common::temp_dir;
+use std::{env, fs};
+use sui_swap::{config::DEFAULT_PRICE_SOURCE, Config, SwapError};
+
+fn rejected(config: &Config) -> String {
+    match config.validate() {
+        Err(SwapError::InvalidConfig(reason)) => reason,
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn default_config_is_valid() {
+    Config::default().validate().unwrap();
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    let mut config = Config::default();
+    config.admin.listen = Some("127.0.0.1:8081".to_string());
+    let reason = rejected(&config);
+    assert!(reason.contains("admin.token is required"), "{}", reason);
+    config.admin.token = Some("secret".to_string());
+    config.validate().unwrap();
+
+    let mut config = Config::default();
+    config.server.queue_capacity = 0;
+    let reason = rejected(&config);
+    assert!(reason.contains("server.queue_capacity"), "{}", reason);
+
+    let mut config = Config::default();
+    config.client.server_url = "http://127.0.0.1:8080".to_string();
+    let reason = rejected(&config);
+    assert!(reason.contains("http://127.0.0.1:8080"), "{}", reason);
+
+    let mut config = Config::default();
+    config.client.fallback_urls = vec!["127.0.0.1:8080".to_string()];
+    let reason = rejected(&config);
+    assert!(reason.contains("client.fallback_urls"), "{}", reason);
+
+    let mut config = Config::default();
+    config.admin.url = "https://127.0.0.1:8081".to_string();
+    let reason = rejected(&config);
+    assert!(reason.contains("admin.url"), "{}", reason);
+}
+
+/// The only test of this file touching the environment, the others would
+/// see its variables
+#[test]
+fn env_overrides_the_config_file() {
+    let dir = temp_dir("config");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sui-swap.toml");
+    fs::write(
+        &path,
+        "[server]\npoll_interval_secs = 10\n\n[client]\nserver_url = \"ws://10.0.0.1:8080\"\n",
+    )
+    .unwrap();
+
+    let config = Config::load(Some(&path)).unwrap();
+    assert_eq!(config.server.poll_interval_secs, 10);
+    assert_eq!(config.client.server_url, "ws://10.0.0.1:8080");
+
+    env::set_var("SUI_SWAP_SERVER_URL", "wss://hub.example.com");
+    env::set_var("SUI_SWAP_POLL_INTERVAL_SECS", "3");
+    env::set_var("TOKEN_BALANCE_URL", "http://127.0.0.1:9000/prices");
+    let config = Config::load(Some(&path)).unwrap();
+    assert_eq!(config.client.server_url, "wss://hub.example.com");
+    assert_eq!(config.server.poll_interval_secs, 3);
+    assert_eq!(
+        config.price_sources[DEFAULT_PRICE_SOURCE].url.as_deref(),
+        Some("http://127.0.0.1:9000/prices")
+    );
+
+    // Overrides are validated like the file
+    env::set_var("SUI_SWAP_POLL_INTERVAL_SECS", "soon");
+    match Config::load(Some(&path)) {
+        Err(SwapError::InvalidConfig(reason)) => {
+            assert!(reason.contains("SUI_SWAP_POLL_INTERVAL_SECS"), "{}", reason)
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    env::remove_var("SUI_SWAP_POLL_INTERVAL_SECS");
+    env::set_var("SUI_SWAP_SERVER_URL", "http://hub.example.com");
+    match Config::load(Some(&path)) {
+        Err(SwapError::InvalidConfig(reason)) => {
+            assert!(reason.contains("client.server_url"), "{}", reason)
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    for var in ["SUI_SWAP_SERVER_URL", "TOKEN_BALANCE_URL"] {
+        env::remove_var(var);
+    }
+}
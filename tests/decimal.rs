@@ -0,0 +1,193 @@
+mod common;
+
+use common::{eventually, TestHub, SUI};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use sui_swap::{
+    models::{LegacyTokenInfoInnerResponse, LegacyTokenInfoResponse, TimeStamp},
+    Decimal, SwapError, SwapRequest, SwapResponse, TokenInfoResponse,
+};
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+fn dec(s: &str) -> Decimal {
+    s.parse().unwrap()
+}
+
+#[test]
+fn parses_and_prints_exactly() {
+    for (input, printed) in [
+        ("0", "0"),
+        ("0.1", "0.1"),
+        ("007.50", "7.5"),
+        (".5", "0.5"),
+        ("19743.16068", "19743.16068"),
+        ("1e-5", "0.00001"),
+        ("1.5E3", "1500"),
+        ("0.000000000000000001", "0.000000000000000001"),
+        // Past 18 decimals it rounds
+        ("1.0000000000000000005", "1.000000000000000001"),
+        ("0.0000000000000000004", "0"),
+        ("1e-300", "0"),
+    ] {
+        assert_eq!(dec(input).to_string(), printed, "{}", input);
+    }
+
+    for malformed in ["", ".", "-1", "1.2.3", "abc", "1e", "1e999", "0x10"] {
+        let error = malformed.parse::<Decimal>().unwrap_err();
+        assert!(
+            matches!(error, SwapError::InvalidDecimal(ref s) if s == malformed),
+            "{}: {}",
+            malformed,
+            error
+        );
+    }
+
+    assert_eq!(Decimal::from_f64(0.1), Some(dec("0.1")));
+    assert_eq!(Decimal::from_f64(-0.1), None);
+    assert_eq!(Decimal::from_f64(f64::NAN), None);
+    assert_eq!(dec("0.3").to_f64(), 0.3);
+}
+
+#[test]
+fn computes_without_float_error() {
+    // 0.30000000000000004 as floats
+    assert_eq!(dec("0.1").checked_add(dec("0.2")), Some(dec("0.3")));
+    assert_eq!(dec("0.1").checked_mul(Decimal::from(3)), Some(dec("0.3")));
+    assert_eq!(
+        Decimal::ONE.checked_div(Decimal::from(3)),
+        Some(dec("0.333333333333333333"))
+    );
+    assert_eq!(Decimal::ONE.checked_div(Decimal::ZERO), None);
+    assert_eq!(dec("0.1").checked_sub(dec("0.2")), None);
+    // The product overflows 128 bits before the division
+    let big = Decimal::from(u64::MAX);
+    assert_eq!(
+        big.checked_mul(dec("0.5")),
+        Some(dec("9223372036854775807.5"))
+    );
+    assert_eq!(
+        big.checked_div(dec("0.5")),
+        Some(dec("36893488147419103230"))
+    );
+    assert_eq!(big.checked_mul(big), None);
+    // Rounded once, after the division
+    assert_eq!(
+        Decimal::ONE.checked_mul_div(Decimal::from(2), Decimal::from(3)),
+        Some(dec("0.666666666666666666"))
+    );
+    assert_eq!(big.checked_mul_div(big, big), Some(big));
+    assert_eq!(dec("0.1").saturating_sub(dec("0.2")), Decimal::ZERO);
+
+    assert_eq!(
+        Decimal::from_raw(1_974_316_068, 5),
+        Some(dec("19743.16068"))
+    );
+    assert_eq!(
+        Decimal::from_raw(1_000_015_000_000, 24),
+        Some(dec("0.000000000001000015"))
+    );
+    assert_eq!(dec("10").to_raw(9), Some(10_000_000_000));
+    // Rounded to the nearest unit
+    assert_eq!(dec("0.0000000015").to_raw(9), Some(2));
+    assert_eq!(dec("0.0000000014").to_raw(9), Some(1));
+    assert_eq!(
+        dec("1.5").to_raw(24),
+        Some(1_500_000_000_000_000_000_000_000)
+    );
+}
+
+#[test]
+fn serializes_as_string_or_raw_integer() {
+    let price = dec("1.05");
+    assert_eq!(serde_json::to_string(&price).unwrap(), r#""1.05""#);
+    // Upstream numbers are accepted too
+    for json in [r#""1.05""#, "1.05", "1.05e0"] {
+        assert_eq!(serde_json::from_str::<Decimal>(json).unwrap(), price);
+    }
+    assert_eq!(
+        serde_json::from_str::<Decimal>("3").unwrap(),
+        Decimal::from(3)
+    );
+    assert!(serde_json::from_str::<Decimal>("-3").is_err());
+    assert!(serde_json::from_str::<Decimal>(r#""-1.05""#).is_err());
+
+    let bytes = bincode::serialize(&price).unwrap();
+    assert_eq!(bytes.len(), 16);
+    assert_eq!(bincode::deserialize::<Decimal>(&bytes).unwrap(), price);
+}
+
+#[test]
+fn upstream_prices_keep_every_digit() {
+    // 27 significant digits, f64 keeps about 17
+    let body = format!(
+        r#"{{"coins":{{"{}":{{"decimals":9,"symbol":"SUI","price":123456789.123456789012345678,"timestamp":1700000000,"confidence":"0.99"}}}}}}"#,
+        SUI
+    );
+    let response = TokenInfoResponse::from_json(body.as_bytes()).unwrap();
+    let info = &response.coins[SUI];
+    assert_eq!(info.price, dec("123456789.123456789012345678"));
+    let through_f64: f64 = "123456789.123456789012345678".parse().unwrap();
+    assert_ne!(info.price, Decimal::from_f64(through_f64).unwrap());
+    assert_eq!(info.confidence, dec("0.99"));
+
+    let negative = body.replace("123456789.123456789012345678", "-1.5");
+    assert!(TokenInfoResponse::from_json(negative.as_bytes()).is_err());
+}
+
+fn legacy_price(price: f64) -> SwapResponse {
+    let info = LegacyTokenInfoInnerResponse {
+        confidence: 0.99,
+        decimals: 9,
+        price,
+        symbol: "SUI".to_string(),
+        timestamp: TimeStamp(chrono::Utc::now().timestamp() as u64),
+    };
+    SwapResponse::LegacyTokenPrice(LegacyTokenInfoResponse {
+        coins: HashMap::from([(SUI.to_string(), info)]),
+    })
+}
+
+#[test]
+fn legacy_prices_convert_to_decimals() {
+    let SwapResponse::LegacyTokenPrice(legacy) = legacy_price(0.1) else {
+        unreachable!()
+    };
+    let response = TokenInfoResponse::from(legacy.clone());
+    let info = &response.coins[SUI];
+    assert_eq!(info.price, dec("0.1"));
+    assert_eq!(info.confidence, dec("0.99"));
+
+    let mut bad = legacy;
+    bad.coins.get_mut(SUI).unwrap().price = f64::NAN;
+    assert!(TokenInfoResponse::from(bad).coins.is_empty());
+}
+
+#[tokio::test]
+async fn accepts_prices_from_float_peers() {
+    let hub = TestHub::start(Duration::from_secs(3600)).await;
+    // Peer speaking the protocol from before decimal prices
+    let (mut ws, _) = tokio_tungstenite::connect_async(hub.url()).await.unwrap();
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = ws.next().await {
+            let Message::Binary(bytes) = message else {
+                continue;
+            };
+            let response = match bincode::deserialize::<SwapRequest>(&bytes).unwrap() {
+                SwapRequest::WhichToken => SwapResponse::WhichToken("SUI".to_string()),
+                SwapRequest::TokenPrice => legacy_price(1.1),
+                _ => continue,
+            };
+            let bytes = bincode::serialize(&response).unwrap();
+            ws.send(Message::Binary(bytes)).await.unwrap();
+        }
+    });
+
+    hub.poll("SUI").await;
+    eventually(Duration::from_secs(5), "legacy price", || async {
+        hub.peer("SUI")
+            .await
+            .is_some_and(|peer| peer.last_price == Some(dec("1.1")))
+    })
+    .await;
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::{full, temp_dir, MockPriceServer, MockSuiRpc, FUD, SUI};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use sui_swap::{
+    discovery::TokenDiscovery,
+    prices::{HttpPriceSource, PriceFetcher},
+    CoinType, SystemClock, TokenRegistry,
+};
+use tokio::time::Duration;
+
+fn chain(method: &str, params: &Value) -> Option<Value> {
+    let coin_type = params[0].as_str()?;
+    match method {
+        "suix_getCoinMetadata" if coin_type == full(SUI) => Some(json!({
+            "decimals": 9,
+            "name": "Sui",
+            "symbol": "SUI",
+            "description": "",
+            "iconUrl": null,
+            "id": "0x9258181f5ceac8dbffb7030890243caed69a9599d2886d957a9cb7656af3bdb3"
+        })),
+        "suix_getCoinMetadata" if coin_type == full(FUD) => Some(json!({
+            "decimals": 5,
+            "name": "Fud the Pug",
+            "symbol": "FUD",
+            "description": "The Sui community meme coin",
+            "iconUrl": "https://example.com/fud.png",
+            "id": null
+        })),
+        "suix_getCoinMetadata" => Some(Value::Null),
+        _ => None,
+    }
+}
+
+fn coin(coin_type: &str) -> CoinType {
+    coin_type.parse().unwrap()
+}
+
+fn discovery(rpc: &MockSuiRpc, prices: &MockPriceServer) -> TokenDiscovery {
+    let fetcher = PriceFetcher::new(
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        Arc::new(SystemClock),
+    );
+    let source = HttpPriceSource::new(prices.url(), Arc::new(fetcher));
+    TokenDiscovery::new(&rpc.url()).price_source(Arc::new(source))
+}
+
+#[tokio::test]
+async fn builds_entries_from_chain_metadata() {
+    let rpc = MockSuiRpc::start(chain).await;
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 2.0);
+    // 9 decimals reported for FUD, 5 on chain
+    prices.set_price(FUD, 0.001);
+    let discovery = discovery(&rpc, &prices);
+
+    let sui = discovery.discover(&coin(SUI), None).await.unwrap();
+    assert_eq!(sui.name, "SUI");
+    assert_eq!(sui.entry.coin_type, coin(SUI));
+    assert_eq!(sui.entry.name.as_deref(), Some("Sui"));
+    assert_eq!(sui.entry.decimals, Some(9));
+    assert_eq!(sui.entry.icon_url, None);
+    assert_eq!(sui.upstream_decimals, Some(9));
+    assert!(!sui.decimals_mismatch);
+
+    let fud = discovery.discover(&coin(FUD), Some("PUG")).await.unwrap();
+    assert_eq!(fud.name, "PUG");
+    assert_eq!(fud.entry.symbol, "FUD");
+    assert_eq!(fud.entry.decimals, Some(5));
+    assert_eq!(
+        fud.entry.icon_url.as_deref(),
+        Some("https://example.com/fud.png")
+    );
+    assert_eq!(fud.upstream_decimals, Some(9));
+    assert!(fud.decimals_mismatch);
+
+    // Written entries load back as they were
+    let path = temp_dir("tokens.json");
+    let mut tokens = TokenRegistry::default();
+    tokens.insert(sui.name, sui.entry.clone()).unwrap();
+    tokens.insert(fud.name, fud.entry.clone()).unwrap();
+    tokens.save(&path).unwrap();
+    let loaded = TokenRegistry::load(&path).unwrap();
+    assert_eq!(loaded.get("SUI").unwrap(), &sui.entry);
+    assert_eq!(loaded.get("PUG").unwrap(), &fud.entry);
+}
+
+#[tokio::test]
+async fn rejects_coin_types_without_metadata() {
+    let rpc = MockSuiRpc::start(chain).await;
+    let prices = MockPriceServer::start().await;
+    let discovery = discovery(&rpc, &prices);
+
+    // No price for the coin, nothing to compare with
+    let sui = discovery.discover(&coin(SUI), None).await.unwrap();
+    assert_eq!(sui.upstream_decimals, None);
+    assert!(!sui.decimals_mismatch);
+
+    let unknown = discovery.discover(&coin("0xbad::nope::NOPE"), None).await;
+    assert!(
+        unknown
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("No coin metadata"),
+        "{:?}",
+        unknown
+    );
+}
@@ -0,0 +1,267 @@
+mod common;
+
+use common::{certificates, eventually, MockPriceServer, TestHub, ADMIN_TOKEN, FUD, SUI};
+use futures_util::StreamExt;
+use sui_swap::{
+    admin,
+    messages::{AdminRequest, AdminResponse},
+    Server, SwapError,
+};
+use tokio::time::{timeout, Duration};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn clients_register_their_tokens() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let _sui = hub.spawn_client("SUI", &prices);
+    let _fud = hub.spawn_client("FUD", &prices);
+
+    eventually(WAIT, "both tokens registered", || async {
+        hub.registered_tokens().await == ["FUD", "SUI"]
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn repeated_token_is_rejected() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let _first = hub.spawn_client("SUI", &prices);
+    eventually(WAIT, "first client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    let second = hub.client("SUI", &prices).start().await;
+    assert!(matches!(second, Err(SwapError::TokenTaken(token)) if token == "SUI"));
+    assert_eq!(hub.registered_tokens().await, ["SUI"]);
+}
+
+#[tokio::test]
+async fn unknown_token_fails_before_connecting() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let result = hub.client("NOPE", &prices).start().await;
+    assert!(matches!(result, Err(SwapError::UnknownToken(token)) if token == "NOPE"));
+    assert!(hub.peers().await.is_empty());
+}
+
+#[tokio::test]
+async fn registered_clients_are_polled() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    prices.set_price(FUD, 0.0001);
+    let hub = TestHub::start(Duration::from_secs(1)).await;
+
+    let _sui = hub.spawn_client("SUI", &prices);
+    let _fud = hub.spawn_client("FUD", &prices);
+
+    eventually(WAIT, "both tokens polled twice", || async {
+        prices.hits(SUI) >= 2 && prices.hits(FUD) >= 2
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn admin_poll_reaches_only_the_requested_token() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    prices.set_price(FUD, 0.0001);
+    let hub = TestHub::start(Duration::from_secs(3600)).await;
+
+    let _sui = hub.spawn_client("SUI", &prices);
+    let _fud = hub.spawn_client("FUD", &prices);
+    eventually(WAIT, "both tokens registered", || async {
+        hub.registered_tokens().await == ["FUD", "SUI"]
+    })
+    .await;
+    let (sui_hits, fud_hits) = (prices.hits(SUI), prices.hits(FUD));
+
+    hub.admin(AdminRequest::PollNow {
+        token: Some("SUI".to_string()),
+    })
+    .await;
+
+    eventually(WAIT, "SUI polled", || async { prices.hits(SUI) > sui_hits }).await;
+    assert_eq!(prices.hits(FUD), fud_hits);
+}
+
+#[tokio::test]
+async fn subscribers_are_listed_with_their_queue() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let hub = TestHub::start(Duration::from_secs(3600)).await;
+    let _sui = hub.spawn_client("SUI", &prices);
+
+    let url = hub.admin_url();
+    let subscribe = AdminRequest::Subscribe {
+        token: Some("SUI".to_string()),
+        resolution: None,
+        portfolio: false,
+        prices: true,
+    };
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    let mut pushed = Box::pin(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+
+    hub.poll("SUI").await;
+    let price = timeout(WAIT, pushed.next()).await.unwrap();
+    assert!(matches!(price, Some(Ok(AdminResponse::Price { token, .. })) if token == "SUI"));
+
+    match hub.admin(AdminRequest::ListPeers).await {
+        AdminResponse::Peers { subscribers, .. } => {
+            assert_eq!(subscribers.len(), 1);
+            assert_eq!(subscribers[0].queue_depth, 0);
+            assert_eq!(subscribers[0].dropped, 0);
+        }
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn admin_connections_end_on_shutdown() {
+    let hub = TestHub::start(Duration::from_secs(3600)).await;
+    let url = hub.admin_url();
+    let subscribe = AdminRequest::Subscribe {
+        token: None,
+        resolution: None,
+        portfolio: false,
+        prices: true,
+    };
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    let mut pushed = Box::pin(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+
+    hub.shutdown().await;
+    let closed = timeout(WAIT, pushed.next())
+        .await
+        .expect("Connection closed");
+    assert!(!matches!(closed, Some(Ok(_))), "{:?}", closed);
+}
+
+#[tokio::test]
+async fn admin_channel_uses_tls_with_the_server() {
+    let tls = certificates();
+    let hub = TestHub::start_tls(Server::builder(), &tls).await;
+    assert!(matches!(
+        hub.admin(AdminRequest::ListPeers).await,
+        AdminResponse::Peers { .. }
+    ));
+
+    // The token is never sent in cleartext
+    let plain = format!("ws://{}", hub.admin_addr);
+    assert!(
+        admin::send_request(&plain, Some(ADMIN_TOKEN), None, &AdminRequest::ListPeers)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn disconnected_client_releases_its_token() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let first = hub.spawn_client("SUI", &prices);
+    eventually(WAIT, "first client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    first.abort();
+    eventually(WAIT, "peer removed", || async {
+        hub.peers().await.is_empty()
+    })
+    .await;
+
+    // The token is free again for a new client
+    let _second = hub.spawn_client("SUI", &prices);
+    eventually(WAIT, "second client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn admin_disconnect_ends_the_client() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let client = hub.spawn_client("SUI", &prices);
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+    let peer = hub.peers().await[0].addr;
+
+    hub.admin(AdminRequest::Disconnect { peer }).await;
+
+    let result = tokio::time::timeout(WAIT, client)
+        .await
+        .expect("Client ends")
+        .expect("Client task");
+    assert!(result.is_ok());
+    eventually(WAIT, "peer removed", || async {
+        hub.peers().await.is_empty()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn released_token_ends_its_client() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let client = hub.spawn_client("SUI", &prices);
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+
+    let release = AdminRequest::ReleaseToken {
+        token: "SUI".to_string(),
+    };
+    assert!(matches!(hub.admin(release).await, AdminResponse::Done));
+
+    let result = timeout(WAIT, client)
+        .await
+        .expect("Client ends")
+        .expect("Client task");
+    assert!(matches!(result, Err(SwapError::TokenTaken(token)) if token == "SUI"));
+}
+
+#[tokio::test]
+async fn reassigned_token_ends_its_previous_client() {
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start(Duration::from_secs(60)).await;
+
+    let sui = hub.spawn_client("SUI", &prices);
+    let _fud = hub.spawn_client("FUD", &prices);
+    eventually(WAIT, "both tokens registered", || async {
+        hub.registered_tokens().await == ["FUD", "SUI"]
+    })
+    .await;
+    let fud_peer = hub.peer("FUD").await.unwrap().addr;
+
+    let reassign = AdminRequest::ReassignToken {
+        token: "SUI".to_string(),
+        peer: fud_peer,
+    };
+    assert!(matches!(hub.admin(reassign).await, AdminResponse::Done));
+
+    let result = timeout(WAIT, sui)
+        .await
+        .expect("Client ends")
+        .expect("Client task");
+    assert!(matches!(result, Err(SwapError::TokenTaken(token)) if token == "SUI"));
+    assert_eq!(hub.peer("SUI").await.unwrap().addr, fud_peer);
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use common::temp_dir;
+use ed25519_dalek::Verifier;
+use std::fs;
+use sui_swap::{
+    keystore::{Keystore, SignatureScheme},
+    SwapError,
+};
+
+/// Key and address from the Sui SDK test vectors
+const ED25519_KEY: &str = "suiprivkey1qrwsjvr6gwaxmsvxk4cfun99ra8uwxg3c9pl0nhle7xxpe4s80y05ctazer";
+const ED25519_ADDRESS: &str = "0xa2d14fad60c56049ecf75246a481934691214ce413e6a8ae2fe6834c173a6133";
+
+fn blake2b256(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Blake2b::<U32>::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Base64 key as in `sui.keystore`: scheme flag and private key
+fn base64_key(flag: u8, secret: [u8; 32]) -> String {
+    let mut bytes = vec![flag];
+    bytes.extend(secret);
+    BASE64.encode(bytes)
+}
+
+fn sui_keystore(keys: &[&str]) -> Vec<u8> {
+    serde_json::to_vec(keys).unwrap()
+}
+
+#[test]
+fn loads_keys_and_derives_addresses() {
+    let secp256k1 = base64_key(1, [0x11; 32]);
+    let keystore = Keystore::from_json(&sui_keystore(&[ED25519_KEY, &secp256k1])).unwrap();
+
+    let ed25519 = keystore.get(ED25519_ADDRESS).unwrap();
+    assert_eq!(ed25519.scheme(), SignatureScheme::Ed25519);
+    let secp256k1 = &keystore.keys()[1];
+    assert_eq!(secp256k1.scheme(), SignatureScheme::Secp256k1);
+    assert_eq!(secp256k1.public_key().len(), 33);
+    let address = format!(
+        "0x{}",
+        hex::encode(blake2b256(&[&[1], &secp256k1.public_key()]))
+    );
+    assert_eq!(secp256k1.address(), address);
+    // Found without 0x and in upper case
+    assert!(keystore
+        .get(&address[2..].to_ascii_uppercase())
+        .is_ok_and(|key| key.address() == address));
+    assert!(matches!(keystore.get("0x2"), Err(SwapError::UnknownKey(_))));
+
+    // Same key in the base64 format
+    let (_, bytes) = bech32::decode(ED25519_KEY).unwrap();
+    let base64 = Keystore::from_json(&sui_keystore(&[&BASE64.encode(bytes)])).unwrap();
+    assert_eq!(base64.keys()[0].address(), ED25519_ADDRESS);
+}
+
+#[test]
+fn signs_transactions_with_intent() {
+    let secp256k1 = base64_key(1, [0x11; 32]);
+    let keystore = Keystore::from_json(&sui_keystore(&[ED25519_KEY, &secp256k1])).unwrap();
+    let tx_bytes = b"transaction data";
+    let digest = blake2b256(&[&[0, 0, 0], tx_bytes]);
+
+    // flag, signature, public key
+    let key = &keystore.keys()[0];
+    let signature = BASE64.decode(key.sign_transaction(tx_bytes)).unwrap();
+    assert_eq!(signature.len(), 1 + 64 + 32);
+    assert_eq!(signature[0], 0);
+    assert_eq!(signature[65..], key.public_key());
+    let public_key =
+        ed25519_dalek::VerifyingKey::from_bytes(&key.public_key()[..].try_into().unwrap()).unwrap();
+    let ed25519_signature = ed25519_dalek::Signature::from_slice(&signature[1..65]).unwrap();
+    assert!(public_key.verify(&digest, &ed25519_signature).is_ok());
+    // Personal messages use another intent, so they can't pass for transactions
+    let message = BASE64.decode(key.sign_personal_message(tx_bytes)).unwrap();
+    let ed25519_message = ed25519_dalek::Signature::from_slice(&message[1..65]).unwrap();
+    assert!(public_key.verify(&digest, &ed25519_message).is_err());
+
+    let key = &keystore.keys()[1];
+    let signature = BASE64.decode(key.sign_transaction(tx_bytes)).unwrap();
+    assert_eq!(signature.len(), 1 + 64 + 33);
+    assert_eq!(signature[0], 1);
+    assert_eq!(signature[65..], key.public_key());
+    let public_key = k256::ecdsa::VerifyingKey::from_sec1_bytes(&key.public_key()).unwrap();
+    let secp256k1_signature = k256::ecdsa::Signature::from_slice(&signature[1..65]).unwrap();
+    // Sui only accepts the lower s
+    assert!(secp256k1_signature.normalize_s().is_none());
+    assert!(
+        k256::ecdsa::signature::Verifier::verify(&public_key, &digest, &secp256k1_signature)
+            .is_ok()
+    );
+}
+
+#[test]
+fn encrypts_keystores_with_a_password() {
+    let dir = temp_dir("keystore");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("keystore.json");
+    let plain = sui_keystore(&[ED25519_KEY]);
+    fs::write(&path, Keystore::encrypt(&plain, "hunter2").unwrap()).unwrap();
+    let encrypted = fs::read_to_string(&path).unwrap();
+    assert!(!encrypted.contains(ED25519_KEY));
+
+    let keystore = Keystore::load(&path, Some("hunter2")).unwrap();
+    assert_eq!(keystore.keys()[0].address(), ED25519_ADDRESS);
+    assert!(matches!(
+        Keystore::load(&path, Some("hunter3")),
+        Err(SwapError::KeystorePassword)
+    ));
+    assert!(matches!(
+        Keystore::load(&path, None),
+        Err(SwapError::InvalidKeystore(_))
+    ));
+
+    // Plain keystores don't need one
+    fs::write(&path, &plain).unwrap();
+    assert_eq!(
+        Keystore::load(&path, None).unwrap().keys()[0].address(),
+        ED25519_ADDRESS
+    );
+    // Nothing that won't load again gets encrypted
+    assert!(Keystore::encrypt(b"[\"AAAA\"]", "hunter2").is_err());
+    assert!(Keystore::encrypt(&plain, "").is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn never_shows_private_keys() {
+    let keystore = Keystore::from_json(&sui_keystore(&[ED25519_KEY])).unwrap();
+    let (_, bytes) = bech32::decode(ED25519_KEY).unwrap();
+    let debug = format!("{:?}", keystore);
+    assert!(debug.contains(ED25519_ADDRESS));
+    assert!(!debug.contains(&hex::encode(&bytes[1..])));
+    assert!(!debug.contains(&BASE64.encode(&bytes)));
+    assert!(!debug.contains(&format!("{:?}", &bytes[1..])));
+
+    // Broken entries are reported by position
+    let secp256r1 = base64_key(2, [0x11; 32]);
+    for broken in [
+        &secp256r1,
+        "not a key",
+        &ED25519_KEY[..ED25519_KEY.len() - 1],
+    ] {
+        let keystore_error = Keystore::from_json(&sui_keystore(&[ED25519_KEY, broken]))
+            .unwrap_err()
+            .to_string();
+        assert!(keystore_error.contains("key 1"), "{}", keystore_error);
+        assert!(!keystore_error.contains(broken), "{}", keystore_error);
+    }
+}
@@ -0,0 +1,57 @@
+use sui_swap::peer_queue::{self, OverflowPolicy, PeerReceiver, PeerSender};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// Everything queued, in order
+async fn drain(tx: &PeerSender, rx: &PeerReceiver) -> Vec<String> {
+    let mut received = Vec::new();
+    while tx.depth() > 0 {
+        match rx.recv().await {
+            Some(Message::Text(text)) => received.push(text.to_string()),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+    received
+}
+
+#[tokio::test]
+async fn drop_oldest_makes_room_for_the_newest() {
+    let (tx, rx) = peer_queue::channel(2, OverflowPolicy::DropOldest);
+    for (text, key) in [("SUI 1.5", "SUI"), ("FUD 0.25", "FUD"), ("SUI 1.6", "SUI")] {
+        assert!(tx.send(Message::text(text), Some(key)));
+    }
+    assert_eq!(tx.dropped(), 1);
+    // Same key, but nothing is coalesced
+    assert_eq!(drain(&tx, &rx).await, ["FUD 0.25", "SUI 1.6"]);
+}
+
+#[tokio::test]
+async fn disconnect_closes_the_queue_of_a_slow_peer() {
+    let (tx, rx) = peer_queue::channel(2, OverflowPolicy::Disconnect);
+    assert!(tx.send(Message::text("SUI 1.5"), Some("SUI")));
+    assert!(tx.send(Message::text("FUD 0.25"), Some("FUD")));
+    assert!(!tx.send(Message::text("SUI 1.6"), Some("SUI")));
+    assert!(!tx.send(Message::text("valid"), None));
+    assert_eq!(rx.recv().await, None);
+}
+
+#[tokio::test]
+async fn control_messages_are_never_evicted() {
+    for policy in [OverflowPolicy::DropOldest, OverflowPolicy::CoalesceLatest] {
+        let (tx, rx) = peer_queue::channel(2, policy);
+        assert!(tx.send(Message::text("valid"), None));
+        assert!(tx.send(Message::text("SUI 1.5"), Some("SUI")));
+        // The price makes room, not the control message before it
+        assert!(tx.send(Message::text("assign FUD"), None));
+        // Full of control messages, the update is the one dropped
+        assert!(tx.send(Message::text("FUD 0.25"), Some("FUD")));
+        // And another control message goes past the capacity
+        assert!(tx.send(Message::text("secret"), None));
+        assert_eq!(tx.dropped(), 2, "{:?}", policy);
+        assert_eq!(
+            drain(&tx, &rx).await,
+            ["valid", "assign FUD", "secret"],
+            "{:?}",
+            policy
+        );
+    }
+}
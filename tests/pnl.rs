@@ -0,0 +1,193 @@
+mod common;
+
+use common::{eventually, temp_dir, MockPriceServer, MockSuiRpc, TestHub, FUD, SUI};
+use serde_json::{json, Value};
+use sui_swap::{
+    config::BalanceConfig,
+    messages::{AdminRequest, AdminResponse},
+    pnl::{PnlEntry, PnlReport, RecordedSwap},
+    Decimal, Server,
+};
+use tokio::time::Duration;
+
+const ALICE: &str = "0x00000000000000000000000000000000000000000000000000000000000a11ce";
+const BOB: &str = "0x0000000000000000000000000000000000000000000000000000000000000b0b";
+
+fn chain(method: &str, params: &Value) -> Option<Value> {
+    let balance = |coin_type: &str, total: u128| json!({ "coinType": coin_type, "coinObjectCount": 1, "totalBalance": total.to_string(), "lockedBalance": {} });
+    match (method, params[0].as_str()?) {
+        ("suix_getAllBalances", ALICE) => Some(json!([
+            balance(SUI, 2_500_000_000),
+            balance(FUD, 100_000_000),
+        ])),
+        _ => None,
+    }
+}
+
+async fn record(
+    hub: &TestHub,
+    address: &str,
+    (from_token, amount_in): (&str, f64),
+    (to_token, amount_out): (&str, f64),
+    value_usd: Option<f64>,
+) -> Result<RecordedSwap, String> {
+    let request = AdminRequest::RecordSwap {
+        address: address.to_string(),
+        from_token: from_token.to_string(),
+        amount_in: Decimal::from_f64(amount_in).unwrap(),
+        to_token: to_token.to_string(),
+        amount_out: Decimal::from_f64(amount_out).unwrap(),
+        timestamp: None,
+        value_usd: value_usd.and_then(Decimal::from_f64),
+        tx_digest: None,
+    };
+    match hub.admin(request).await {
+        AdminResponse::SwapRecorded { swap } => Ok(swap),
+        AdminResponse::Error { message } => Err(message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+async fn pnl(hub: &TestHub, address: Option<&str>, token: Option<&str>) -> PnlReport {
+    let request = AdminRequest::Pnl {
+        address: address.map(str::to_string),
+        token: token.map(str::to_string),
+    };
+    match hub.admin(request).await {
+        AdminResponse::Pnl { report } => report,
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+async fn history(hub: &TestHub, address: Option<&str>, from: Option<i64>) -> Vec<PnlReport> {
+    let request = AdminRequest::PnlHistory {
+        address: address.map(str::to_string),
+        token: None,
+        from,
+        to: None,
+    };
+    match hub.admin(request).await {
+        AdminResponse::PnlHistory { valuations } => valuations,
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+fn entry<'a>(report: &'a PnlReport, address: &str, token: &str) -> &'a PnlEntry {
+    report
+        .entries
+        .iter()
+        .find(|entry| entry.address == address && entry.token == token)
+        .unwrap_or_else(|| panic!("No {} entry for {}: {:?}", token, address, report))
+}
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+#[tokio::test]
+async fn tracks_cost_basis_and_daily_valuations() {
+    let rpc = MockSuiRpc::start(chain).await;
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 2.0);
+    prices.set_price(FUD, 0.001);
+    prices.set_decimals(FUD, 5);
+    let storage = temp_dir("pnl");
+    let builder = Server::builder()
+        .poll_interval(Duration::from_secs(3600))
+        .balances(BalanceConfig {
+            addresses: vec![ALICE.to_string()],
+            rpc_url: Some(rpc.url()),
+            refresh_secs: 3600,
+        });
+    let hub = TestHub::start_in(builder, &storage).await;
+    let mut clients = Vec::new();
+    for token in ["SUI", "FUD"] {
+        clients.push(hub.spawn_client(token, &prices));
+        hub.poll(token).await;
+    }
+    eventually(Duration::from_secs(5), "priced balances", || async {
+        (pnl(&hub, Some(ALICE), None).await.total.value_usd - 6.0).abs() < 1e-9
+            && !history(&hub, None, None).await.is_empty()
+    })
+    .await;
+
+    // Held before any recorded swap, no cost basis
+    let before = pnl(&hub, Some(ALICE), None).await;
+    assert_close(before.total.value_usd, 6.0);
+    assert_close(before.total.cost_basis_usd, 0.0);
+    assert_close(before.total.unrealized_pnl_usd, 0.0);
+
+    // Bought 1000 FUD for 1 SUI when it was worth $2, now worth $1
+    let swap = record(&hub, "0xa11ce", ("SUI", 1.0), ("FUD", 1000.0), Some(2.0))
+        .await
+        .unwrap();
+    assert_eq!(swap.address, ALICE);
+    let report = pnl(&hub, Some(ALICE), Some("FUD")).await;
+    assert_eq!(report.entries.len(), 1);
+    let fud = entry(&report, ALICE, "FUD");
+    assert_close(fud.amount, 1000.0);
+    assert_close(fud.cost_basis_usd, 2.0);
+    assert_close(fud.unrealized_pnl_usd.unwrap(), -1.0);
+    // Selling SUI without a cost basis realizes nothing
+    assert_close(
+        entry(&pnl(&hub, None, None).await, ALICE, "SUI").realized_pnl_usd,
+        0.0,
+    );
+
+    // Valued with the last FUD price when not given
+    let swap = record(&hub, ALICE, ("FUD", 500.0), ("SUI", 0.3), None)
+        .await
+        .unwrap();
+    assert_eq!(swap.value_usd, "0.5".parse::<Decimal>().unwrap());
+    let report = pnl(&hub, Some(ALICE), None).await;
+    let fud = entry(&report, ALICE, "FUD");
+    assert_close(fud.realized_pnl_usd, -0.5);
+    assert_close(fud.costed_amount, 500.0);
+    assert_close(fud.cost_basis_usd, 1.0);
+    let sui = entry(&report, ALICE, "SUI");
+    assert_close(sui.cost_basis_usd, 0.5);
+    assert_close(sui.unrealized_pnl_usd.unwrap(), 0.1);
+    assert_close(report.by_address[ALICE].realized_pnl_usd, -0.5);
+    assert_close(report.total.unrealized_pnl_usd, -0.4);
+
+    // Addresses without tracked balances hold what their swaps left
+    record(&hub, BOB, ("SUI", 1.0), ("FUD", 2000.0), None)
+        .await
+        .unwrap();
+    let report = pnl(&hub, None, Some("FUD")).await;
+    let bob = entry(&report, BOB, "FUD");
+    assert_close(bob.amount, 2000.0);
+    assert_close(bob.cost_basis_usd, 2.0);
+    assert_close(report.by_token["FUD"].value_usd, 3.0);
+    assert_close(report.by_token["FUD"].realized_pnl_usd, -0.5);
+
+    let zero = record(&hub, BOB, ("SUI", 0.0), ("FUD", 1.0), None)
+        .await
+        .unwrap_err();
+    assert!(zero.contains("greater than 0"), "{}", zero);
+    let unknown = record(&hub, BOB, ("SUI", 1.0), ("NOPE", 1.0), None)
+        .await
+        .unwrap_err();
+    assert!(unknown.contains("NOPE"), "{}", unknown);
+
+    // Today's valuation follows the last swap
+    let valuations = history(&hub, Some(BOB), None).await;
+    assert_eq!(valuations.len(), 1);
+    assert_eq!(valuations[0].entries, std::slice::from_ref(bob));
+    let later = valuations[0].timestamp + 1;
+    assert!(history(&hub, None, Some(later)).await.is_empty());
+
+    // Swaps and valuations survive a restart
+    drop(hub);
+    let hub = TestHub::start_in(Server::builder(), &storage).await;
+    assert_eq!(history(&hub, Some(BOB), None).await, valuations);
+    let bob = entry(&pnl(&hub, Some(BOB), None).await, BOB, "FUD").clone();
+    assert_close(bob.cost_basis_usd, 2.0);
+    // No price since the restart
+    assert_eq!(bob.unrealized_pnl_usd, None);
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::{MockPriceServer, FUD, SUI};
+use futures::future::join_all;
+use std::sync::Arc;
+use sui_swap::{
+    prices::{HttpPriceSource, PriceFetcher, PriceSource},
+    Clock, CoinType, Decimal, ManualClock, SwapError, SystemClock,
+};
+use tokio::time::Duration;
+
+const TTL: Duration = Duration::from_secs(10);
+
+fn coin(coin_type: &str) -> CoinType {
+    coin_type.parse().unwrap()
+}
+
+fn price(response: &sui_swap::TokenInfoResponse) -> Decimal {
+    response.coins.values().next().expect("Price").price
+}
+
+#[tokio::test]
+async fn rate_limits_back_off_per_host_serving_stale_prices() {
+    let clock = ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+    let fetcher = Arc::new(PriceFetcher::new(
+        TTL,
+        Duration::from_secs(30),
+        clock.clone(),
+    ));
+    let limited = MockPriceServer::start().await;
+    let other = MockPriceServer::start().await;
+    for prices in [&limited, &other] {
+        prices.set_price(SUI, 1.5);
+    }
+    let source = HttpPriceSource::new(limited.url(), fetcher.clone());
+    let other_source = HttpPriceSource::new(other.url(), fetcher);
+
+    source.fetch(&coin(SUI)).await.unwrap();
+    clock.advance(TTL);
+    limited.set_status(Some(429));
+    limited.set_retry_after(Some("60"));
+    let rate_limited = source.fetch(&coin(SUI)).await.unwrap_err();
+    assert!(matches!(rate_limited, SwapError::RateLimited(d) if d == Duration::from_secs(60)));
+
+    // The last price is served meanwhile, nothing is asked to the host
+    let stale = source.fetch(&coin(SUI)).await.unwrap();
+    assert_eq!(price(&stale), "1.5".parse().unwrap());
+    let never_fetched = source.fetch(&coin(FUD)).await.unwrap_err();
+    assert!(matches!(never_fetched, SwapError::RateLimited(_)));
+    assert_eq!((limited.hits(SUI), limited.hits(FUD)), (2, 0));
+
+    // Other hosts are still asked
+    other_source.fetch(&coin(SUI)).await.unwrap();
+    assert_eq!(other.hits(SUI), 1);
+
+    clock.advance(Duration::from_secs(60));
+    limited.set_status(None);
+    source.fetch(&coin(SUI)).await.unwrap();
+    assert_eq!(limited.hits(SUI), 3);
+}
+
+#[tokio::test]
+async fn retry_after_can_be_an_http_date() {
+    let clock = ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+    let fetcher = Arc::new(PriceFetcher::new(
+        TTL,
+        Duration::from_secs(30),
+        clock.clone(),
+    ));
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let source = HttpPriceSource::new(prices.url(), fetcher);
+
+    let until = clock.wall_now() + chrono::Duration::seconds(120);
+    let date = until.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    prices.set_status(Some(429));
+    prices.set_retry_after(Some(&date));
+    let rate_limited = source.fetch(&coin(SUI)).await.unwrap_err();
+    assert!(matches!(rate_limited, SwapError::RateLimited(d) if d == Duration::from_secs(120)));
+
+    clock.advance(Duration::from_secs(119));
+    let still = source.fetch(&coin(SUI)).await.unwrap_err();
+    assert!(matches!(still, SwapError::RateLimited(d) if d == Duration::from_secs(1)));
+    assert_eq!(prices.hits(SUI), 1);
+
+    clock.advance(Duration::from_secs(1));
+    prices.set_status(None);
+    source.fetch(&coin(SUI)).await.unwrap();
+    assert_eq!(prices.hits(SUI), 2);
+}
+
+#[tokio::test]
+async fn concurrent_fetches_share_one_request() {
+    let fetcher = Arc::new(PriceFetcher::new(
+        TTL,
+        Duration::from_secs(30),
+        Arc::new(SystemClock),
+    ));
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    prices.set_delay(Duration::from_millis(200));
+    let source = HttpPriceSource::new(prices.url(), fetcher);
+
+    let sui = coin(SUI);
+    let fetched = join_all((0..5).map(|_| source.fetch(&sui))).await;
+    assert!(fetched.iter().all(Result::is_ok));
+    assert_eq!(prices.hits(SUI), 1);
+
+    // Failures too, the waiters get the error of the request they waited for
+    prices.set_status(Some(500));
+    let fud = coin(FUD);
+    let failed = join_all((0..5).map(|_| source.fetch(&fud))).await;
+    assert!(failed
+        .iter()
+        .all(|result| matches!(result, Err(SwapError::UpstreamStatus(500)))));
+    assert_eq!(prices.hits(FUD), 1);
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use common::{eventually, temp_dir, MockPriceServer, MockSuiRpc, TestHub, FUD, SUI};
+use serde_json::json;
+use std::fs;
+use sui_swap::{
+    config::{PoolConfig, QuoteConfig},
+    messages::{AdminRequest, AdminResponse},
+    quotes::SwapQuote,
+    Decimal, Server, SwapError,
+};
+use tokio::{task::JoinHandle, time::Duration};
+
+const POOL: &str = "0x5eb2dfcdd1b15d2021328258f6d5ec081e9a0cdcfa9e13a0eaeb9b5f7505ca78";
+
+fn quote_config() -> QuoteConfig {
+    QuoteConfig {
+        slippage_bps: 50,
+        pools: vec![PoolConfig {
+            id: POOL.to_string(),
+            token_a: "SUI".to_string(),
+            token_b: "FUD".to_string(),
+            fee_bps: 30,
+            reserve_a_field: None,
+            reserve_b_field: None,
+            swap: None,
+        }],
+        ..QuoteConfig::default()
+    }
+}
+
+/// Server quoting with `quotes`, with SUI (9 decimals) and FUD (5) prices
+async fn start(quotes: QuoteConfig, prices: &MockPriceServer) -> TestHub {
+    prices.set_price(SUI, 2.0);
+    prices.set_price(FUD, 0.00001);
+    prices.set_decimals(FUD, 5);
+    let builder = Server::builder()
+        .poll_interval(Duration::from_secs(3600))
+        .quotes(quotes);
+    TestHub::start_with(builder).await
+}
+
+async fn quote(
+    hub: &TestHub,
+    from: &str,
+    to: &str,
+    amount: f64,
+    slippage_bps: Option<u32>,
+) -> Result<SwapQuote, String> {
+    let request = AdminRequest::Quote {
+        from_token: from.to_string(),
+        to_token: to.to_string(),
+        amount: Decimal::from_f64(amount).unwrap(),
+        slippage_bps,
+    };
+    match hub.admin(request).await {
+        AdminResponse::Quote { quote } => Ok(quote),
+        AdminResponse::Error { message } => Err(message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+/// Wait until the server knows the decimals of SUI and FUD
+async fn wait_prices(
+    hub: &TestHub,
+    prices: &MockPriceServer,
+) -> Vec<JoinHandle<Result<(), SwapError>>> {
+    let mut clients = Vec::new();
+    for token in ["SUI", "FUD"] {
+        clients.push(hub.spawn_client(token, prices));
+        hub.poll(token).await;
+    }
+    eventually(Duration::from_secs(5), "prices", || async {
+        quote(hub, "SUI", "FUD", 1.0, None).await.is_ok()
+    })
+    .await;
+    clients
+}
+
+#[tokio::test]
+async fn quotes_constant_product_swaps_from_fixture() {
+    let fixture = temp_dir("pools.json");
+    // 1000 SUI and 2M FUD
+    let reserves =
+        json!({ POOL: { "reserve_a": 1_000_000_000_000u64, "reserve_b": 200_000_000_000u64 } });
+    fs::write(&fixture, reserves.to_string()).unwrap();
+    let prices = MockPriceServer::start().await;
+    let hub = start(
+        QuoteConfig {
+            fixture: Some(fixture),
+            ..quote_config()
+        },
+        &prices,
+    )
+    .await;
+
+    // Decimals are not known before the first prices
+    let no_price = quote(&hub, "SUI", "FUD", 10.0, None).await.unwrap_err();
+    assert!(no_price.contains("SUI"), "{}", no_price);
+    let _clients = wait_prices(&hub, &prices).await;
+
+    let sui_to_fud = quote(&hub, "SUI", "FUD", 10.0, None).await.unwrap();
+    assert_eq!(sui_to_fud.pool, POOL);
+    assert_eq!(sui_to_fud.amount_in_raw, 10_000_000_000);
+    assert_eq!(sui_to_fud.amount_out_raw, 1_974_316_068);
+    assert_eq!(sui_to_fud.amount_out, "19743.16068".parse().unwrap());
+    assert_eq!(sui_to_fud.fee, "0.03".parse().unwrap());
+    assert!((sui_to_fud.price_impact_pct.to_f64() - 0.98716).abs() < 1e-5);
+    assert_eq!(sui_to_fud.slippage_bps, 50);
+    assert_eq!(sui_to_fud.min_received_raw, 1_964_444_487);
+
+    // The other way through the same pool, with another tolerance
+    let fud_to_sui = quote(&hub, "FUD", "SUI", 1000.0, Some(100)).await.unwrap();
+    assert_eq!(fud_to_sui.amount_in_raw, 100_000_000);
+    assert_eq!(fud_to_sui.amount_out_raw, 498_251_621);
+    assert_eq!(fud_to_sui.min_received_raw, 493_269_104);
+    assert!((fud_to_sui.price_impact_pct.to_f64() - 0.049825).abs() < 1e-5);
+
+    let no_pool = quote(&hub, "SUI", "AAA", 1.0, None).await.unwrap_err();
+    assert!(no_pool.contains("No pool"), "{}", no_pool);
+    // Less than one on-chain unit
+    let bad_amount = quote(&hub, "SUI", "FUD", 1e-10, None).await.unwrap_err();
+    assert!(bad_amount.contains("Invalid amount"), "{}", bad_amount);
+}
+
+#[tokio::test]
+async fn reads_pool_reserves_from_json_rpc() {
+    let rpc = MockSuiRpc::start(|method, params| {
+        (method == "sui_getObject" && params[0] == POOL).then(|| {
+            json!({ "data": { "objectId": POOL, "content": { "dataType": "moveObject", "fields": {
+                "id": { "id": POOL },
+                "reserve_x": "1000000000000",
+                "reserve_y": "200000000000",
+            } } } })
+        })
+    })
+    .await;
+    let prices = MockPriceServer::start().await;
+    let hub = start(
+        QuoteConfig {
+            rpc_url: rpc.url(),
+            ..quote_config()
+        },
+        &prices,
+    )
+    .await;
+    let _clients = wait_prices(&hub, &prices).await;
+
+    let sui_to_fud = quote(&hub, "SUI", "FUD", 10.0, None).await.unwrap();
+    assert_eq!(sui_to_fud.amount_out_raw, 1_974_316_068);
+    let request = rpc.received().pop().expect("RPC request");
+    assert_eq!(request["method"], "sui_getObject");
+    assert_eq!(request["params"][0], POOL);
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{eventually, MockPriceServer, TestHub, FUD, SUI};
+use sui_swap::{
+    messages::{AdminRequest, AdminResponse},
+    rates::CrossRate,
+    Decimal,
+};
+use tokio::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn rate(hub: &TestHub, base: &str, quote: &str) -> Result<CrossRate, String> {
+    let request = AdminRequest::Rate {
+        base: base.to_string(),
+        quote: quote.to_string(),
+    };
+    match hub.admin(request).await {
+        AdminResponse::Rate { rate } => Ok(rate),
+        AdminResponse::Error { message } => Err(message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn cross_rate_combines_both_usd_quotes() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 2.0);
+    prices.set_price(FUD, 0.5);
+    // Only polled on demand
+    let hub = TestHub::start(Duration::from_secs(3600)).await;
+
+    assert!(rate(&hub, "FUD", "NOPE")
+        .await
+        .unwrap_err()
+        .contains("NOPE"));
+    assert!(rate(&hub, "FUD", "SUI").await.unwrap_err().contains("FUD"));
+
+    // The quotes are taken at different times
+    prices.set_timestamp(Some(1_700_000_000));
+    let _sui = hub.spawn_client("SUI", &prices);
+    hub.poll("SUI").await;
+    eventually(WAIT, "SUI price", || async {
+        hub.peer("SUI")
+            .await
+            .is_some_and(|peer| peer.last_price.is_some())
+    })
+    .await;
+    prices.set_timestamp(Some(1_700_000_060));
+    let _fud = hub.spawn_client("FUD", &prices);
+    hub.poll("FUD").await;
+
+    let expected = CrossRate {
+        base: "FUD".to_string(),
+        quote: "SUI".to_string(),
+        rate: "0.25".parse().unwrap(),
+        confidence: "0.9801".parse().unwrap(),
+        timestamp: 1_700_000_000,
+    };
+    eventually(WAIT, "FUD/SUI rate", || async {
+        rate(&hub, "FUD", "SUI").await.as_ref() == Ok(&expected)
+    })
+    .await;
+    let inverse = rate(&hub, "SUI", "FUD").await.unwrap();
+    assert_eq!(inverse.rate, Decimal::from(4));
+    assert_eq!(inverse.timestamp, 1_700_000_000);
+
+    // A newer SUI quote moves the rate and its timestamp
+    prices.set_price(SUI, 1.0);
+    prices.set_timestamp(Some(1_700_000_120));
+    hub.poll("SUI").await;
+    eventually(WAIT, "updated FUD/SUI rate", || async {
+        rate(&hub, "FUD", "SUI").await.is_ok_and(|rate| {
+            rate.rate == "0.5".parse().unwrap() && rate.timestamp == 1_700_000_060
+        })
+    })
+    .await;
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use common::{eventually, MockPriceServer, MockWebhook, TestHub, ADMIN_TOKEN, FUD, SUI};
+use futures_util::{Stream, StreamExt};
+use std::pin::Pin;
+use sui_swap::{
+    admin,
+    alerts::{AlertCondition, AlertRule},
+    candles::Resolution,
+    config::RelayConfig,
+    messages::{AdminRequest, AdminResponse, RelayedPrice},
+    models::TimeStamp,
+    peer_queue::{self, OverflowPolicy},
+    Decimal, Server, SwapError, SwapRequest, TokenInfoInnerResponse,
+};
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+type Pushed = Pin<Box<dyn Stream<Item = Result<AdminResponse, SwapError>>>>;
+
+/// Hub relaying to `upstream` as `node`
+async fn start_relay(upstream: &TestHub, node: &str) -> TestHub {
+    start_relay_with(upstream, node, Duration::from_secs(3600)).await
+}
+
+/// Like [`start_relay`] polling its clients every `poll_interval`
+async fn start_relay_with(upstream: &TestHub, node: &str, poll_interval: Duration) -> TestHub {
+    let relay = RelayConfig {
+        upstream: Some(upstream.url()),
+        node_id: Some(node.to_string()),
+        reconnect_secs: 1,
+        ..RelayConfig::default()
+    };
+    let builder = Server::builder().poll_interval(poll_interval).relay(relay);
+    TestHub::start_with(builder).await
+}
+
+async fn watch_prices(hub: &TestHub) -> Pushed {
+    let url = hub.admin_url();
+    let subscribe = AdminRequest::Subscribe {
+        token: None,
+        resolution: None,
+        portfolio: false,
+        prices: true,
+    };
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    let mut pushed: Pushed = Box::pin(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+    pushed
+}
+
+/// Next price pushed, as token, node and price
+async fn next_price(pushed: &mut Pushed) -> (String, Option<String>, Decimal) {
+    match tokio::time::timeout(WAIT, pushed.next()).await {
+        Ok(Some(Ok(AdminResponse::Price {
+            token, node, price, ..
+        }))) => (token, node, price),
+        other => panic!("Unexpected push: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn regional_hubs_feed_a_central_one() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    prices.set_price(FUD, 0.25);
+    let central = TestHub::start(Duration::from_secs(3600)).await;
+    let eu = start_relay(&central, "eu").await;
+    let us = start_relay(&central, "us").await;
+
+    eventually(WAIT, "both relays connected", || async {
+        let mut relays: Vec<String> = central
+            .peers()
+            .await
+            .into_iter()
+            .filter_map(|peer| peer.relay)
+            .collect();
+        relays.sort();
+        relays == ["eu", "us"]
+    })
+    .await;
+
+    let _sui = eu.spawn_client("SUI", &prices);
+    let _fud = central.spawn_client("FUD", &prices);
+    let mut on_central = watch_prices(&central).await;
+    let mut on_eu = watch_prices(&eu).await;
+    let mut on_us = watch_prices(&us).await;
+
+    // A price of a regional hub reaches the central one and the other regions
+    eu.poll("SUI").await;
+    let sui = (
+        "SUI".to_string(),
+        Some("eu".to_string()),
+        "1.5".parse().unwrap(),
+    );
+    assert_eq!(next_price(&mut on_central).await, sui);
+    assert_eq!(next_price(&mut on_us).await, sui);
+    let (token, node, _) = next_price(&mut on_eu).await;
+    assert_eq!((token.as_str(), node), ("SUI", None));
+
+    // And the prices of the central hub reach every region
+    central.poll("FUD").await;
+    let fud = (
+        "FUD".to_string(),
+        Some(central.addr.to_string()),
+        "0.25".parse().unwrap(),
+    );
+    assert_eq!(next_price(&mut on_eu).await, fud);
+    assert_eq!(next_price(&mut on_us).await, fud);
+
+    // Relayed prices are aggregated like local ones
+    let candles = AdminRequest::Candles {
+        token: "SUI".to_string(),
+        resolution: Resolution::OneMinute,
+        limit: None,
+    };
+    match central.admin(candles).await {
+        AdminResponse::Candles { candles, .. } => assert_eq!(candles.len(), 1),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+
+    // A relay serves no token
+    let relay = central
+        .peers()
+        .await
+        .into_iter()
+        .find(|peer| peer.relay.is_some())
+        .unwrap();
+    let reassign = AdminRequest::ReassignToken {
+        token: "AAA".to_string(),
+        peer: relay.addr,
+    };
+    assert!(matches!(
+        central.admin(reassign).await,
+        AdminResponse::Error { .. }
+    ));
+}
+
+fn relayed(token: &str, price: &str) -> SwapRequest {
+    SwapRequest::RelayedPrice(RelayedPrice {
+        node: "eu".to_string(),
+        token: token.to_string(),
+        at: 0,
+        info: TokenInfoInnerResponse {
+            confidence: "0.99".parse().unwrap(),
+            decimals: 9,
+            price: price.parse().unwrap(),
+            symbol: token.to_string(),
+            timestamp: TimeStamp(0),
+        },
+    })
+}
+
+#[tokio::test]
+async fn slow_relays_keep_the_last_price_of_each_token() {
+    let (tx, rx) = peer_queue::channel(2, OverflowPolicy::CoalesceLatest);
+    for (token, price) in [
+        ("SUI", "1.5"),
+        ("FUD", "0.25"),
+        ("SUI", "1.6"),
+        ("SUI", "1.7"),
+    ] {
+        let message = relayed(token, price);
+        let bytes = bincode::serialize(&message).unwrap();
+        assert!(tx.send(Message::Binary(bytes), message.coalesce_key().as_deref()));
+    }
+    assert_eq!(tx.depth(), 2);
+
+    let mut received = Vec::new();
+    while tx.depth() > 0 {
+        let Some(Message::Binary(bytes)) = rx.recv().await else {
+            panic!("Queue closed");
+        };
+        match bincode::deserialize(&bytes).unwrap() {
+            SwapRequest::RelayedPrice(price) => {
+                received.push((price.token, price.info.price.to_string()))
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+    let expected = [("FUD", "0.25"), ("SUI", "1.7")];
+    let mut received: Vec<(&str, &str)> = received
+        .iter()
+        .map(|(token, price)| (token.as_str(), price.as_str()))
+        .collect();
+    received.sort();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn relayed_prices_drive_the_alerts() {
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let webhook = MockWebhook::start().await;
+    let rule = |name: &str, condition| AlertRule {
+        name: name.to_string(),
+        token: "SUI".to_string(),
+        condition,
+        webhook: webhook.url(),
+        cooldown_secs: 0,
+    };
+    let builder = Server::builder()
+        .poll_interval(Duration::from_secs(1))
+        .alert(rule(
+            "sui-above-2",
+            AlertCondition::CrossesAbove {
+                level: Decimal::from(2),
+            },
+        ))
+        .alert(rule("sui-stale", AlertCondition::Stale { secs: 3 }));
+    // SUI only reaches the central hub through the relay
+    let central = TestHub::start_with(builder).await;
+    let eu = start_relay_with(&central, "eu", Duration::from_secs(1)).await;
+    let mut on_central = watch_prices(&central).await;
+    let _sui = eu.spawn_client("SUI", &prices);
+    let (token, _, price) = next_price(&mut on_central).await;
+    assert_eq!((token.as_str(), price), ("SUI", "1.5".parse().unwrap()));
+
+    prices.set_price(SUI, 2.1);
+    let fired = |name: &str| {
+        let received = webhook.received();
+        received
+            .iter()
+            .filter(|alert| alert["rule"] == name)
+            .count()
+    };
+    eventually(WAIT, "crossing alerted", || async {
+        fired("sui-above-2") > 0
+    })
+    .await;
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert_eq!(fired("sui-above-2"), 1);
+    assert_eq!(fired("sui-stale"), 0);
+}
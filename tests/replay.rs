@@ -0,0 +1,88 @@
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::{eventually, temp_dir, MockPriceServer, TestHub, SUI};
+use std::{fs, sync::Arc};
+use sui_swap::{replay::RecordedPrice, Clock, ManualClock, Server};
+use tokio::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(5);
+const INTERVAL: Duration = Duration::from_secs(10);
+
+async fn start(clock: &Arc<ManualClock>) -> TestHub {
+    let builder = Server::builder()
+        .poll_interval(INTERVAL)
+        .clock(clock.clone());
+    TestHub::start_with(builder).await
+}
+
+/// Move the clock one poll interval and wait for the SUI price polled then
+async fn step(clock: &ManualClock, hub: &TestHub) -> f64 {
+    clock.advance(INTERVAL);
+    let now = clock.wall_now().timestamp();
+    eventually(WAIT, "price update", || async {
+        hub.peer("SUI").await.and_then(|peer| peer.last_update) == Some(now)
+    })
+    .await;
+    hub.peer("SUI").await.unwrap().last_price.unwrap().to_f64()
+}
+
+#[tokio::test]
+async fn recorded_prices_are_replayed_time_scaled() {
+    let record_file = temp_dir("prices.jsonl");
+    let recorded: Vec<f64> = (1..=20).map(|k| 1.0 + k as f64 / 10.0).collect();
+
+    // Record 20 polls
+    {
+        let clock = ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        let prices = MockPriceServer::start().await;
+        let hub = start(&clock).await;
+        let client = hub
+            .client_builder("SUI", &prices)
+            .record_file(&record_file)
+            .clock(clock.clone());
+        let client = tokio::spawn(client.build().unwrap().start());
+        eventually(WAIT, "client registered", || async {
+            hub.registered_tokens().await == ["SUI"]
+        })
+        .await;
+        for price in &recorded {
+            prices.set_price(SUI, *price);
+            prices.set_timestamp(Some((clock.wall_now() + INTERVAL).timestamp()));
+            assert_eq!(step(&clock, &hub).await, *price);
+        }
+        client.abort();
+    }
+    let lines: Vec<RecordedPrice> = fs::read_to_string(&record_file)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 20);
+
+    // Replay it a year later at double speed, no price server running
+    let clock = ManualClock::new(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+    let prices = MockPriceServer::start().await;
+    let hub = start(&clock).await;
+    let client = hub
+        .client_builder("SUI", &prices)
+        .replay_source("defillama", &record_file, 2.0)
+        // Replayed samples look fresh
+        .max_price_age(Duration::from_secs(30))
+        .clock(clock.clone());
+    tokio::spawn(client.build().unwrap().start());
+    eventually(WAIT, "client registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+    // 10s of replay cover 20s of recording, 2 polls
+    for poll in 1..=9 {
+        assert_eq!(step(&clock, &hub).await, recorded[2 * poll]);
+    }
+    // The last recorded price is kept once the recording is over
+    assert_eq!(step(&clock, &hub).await, recorded[19]);
+    assert_eq!(step(&clock, &hub).await, recorded[19]);
+    assert_eq!(prices.hits(SUI), 0);
+
+    let _ = fs::remove_file(&record_file);
+}
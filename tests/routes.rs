@@ -0,0 +1,143 @@
+mod common;
+
+use common::{eventually, temp_dir, MockPriceServer, TestHub, AAA, FUD, SUI};
+use serde_json::json;
+use std::fs;
+use sui_swap::{
+    config::{PoolConfig, QuoteConfig},
+    messages::{AdminRequest, AdminResponse},
+    router::SwapRoute,
+    Decimal, Server,
+};
+use tokio::time::Duration;
+
+const AAA_FUD: &str = "0xaf";
+const AAA_SUI: &str = "0xa5";
+const SUI_FUD: &str = "0x5f";
+/// Pool with a token missing from the tokens file
+const SUI_XYZ: &str = "0x5e";
+const UNIT: u64 = 1_000_000_000;
+
+fn pool(id: &str, token_a: &str, token_b: &str) -> PoolConfig {
+    PoolConfig {
+        id: id.to_string(),
+        token_a: token_a.to_string(),
+        token_b: token_b.to_string(),
+        fee_bps: 30,
+        reserve_a_field: None,
+        reserve_b_field: None,
+        swap: None,
+    }
+}
+
+async fn route(
+    hub: &TestHub,
+    from: &str,
+    to: &str,
+    amount: f64,
+    max_hops: Option<usize>,
+) -> Result<SwapRoute, String> {
+    let request = AdminRequest::Route {
+        from_token: from.to_string(),
+        to_token: to.to_string(),
+        amount: Decimal::from_f64(amount).unwrap(),
+        slippage_bps: None,
+        max_hops,
+    };
+    match hub.admin(request).await {
+        AdminResponse::Route { route } => Ok(route),
+        AdminResponse::Error { message } => Err(message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn routes_direct_multi_hop_or_split() {
+    // A shallow direct pool and a deep path through SUI, both about 1:1
+    let fixture = temp_dir("pools.json");
+    let reserves = json!({
+        AAA_FUD: { "reserve_a": 1_000 * UNIT, "reserve_b": 1_000 * UNIT },
+        AAA_SUI: { "reserve_a": 10_000 * UNIT, "reserve_b": 20_000 * UNIT },
+        SUI_FUD: { "reserve_a": 20_000 * UNIT, "reserve_b": 10_000 * UNIT },
+        SUI_XYZ: { "reserve_a": 1_000 * UNIT, "reserve_b": 1_000 * UNIT },
+    });
+    fs::write(&fixture, reserves.to_string()).unwrap();
+    let quotes = QuoteConfig {
+        fixture: Some(fixture),
+        pools: vec![
+            pool(AAA_FUD, "AAA", "FUD"),
+            pool(AAA_SUI, "AAA", "SUI"),
+            pool(SUI_FUD, "SUI", "FUD"),
+            pool(SUI_XYZ, "SUI", "XYZ"),
+        ],
+        ..QuoteConfig::default()
+    };
+    let prices = MockPriceServer::start().await;
+    for coin_type in [AAA, SUI, FUD] {
+        prices.set_price(coin_type, 1.0);
+    }
+    let builder = Server::builder()
+        .poll_interval(Duration::from_secs(3600))
+        .quotes(quotes);
+    let hub = TestHub::start_with(builder).await;
+    let mut clients = Vec::new();
+    for token in ["AAA", "FUD"] {
+        clients.push(hub.spawn_client(token, &prices));
+        hub.poll(token).await;
+    }
+    eventually(Duration::from_secs(5), "prices", || async {
+        route(&hub, "AAA", "FUD", 1.0, None).await.is_ok()
+    })
+    .await;
+
+    // Small amounts go direct, the second fee costs more than the impact
+    let small = route(&hub, "AAA", "FUD", 1.0, None).await.unwrap();
+    assert_eq!(small.amount_out_raw, 996_006_981);
+    assert_eq!(small.legs.len(), 1);
+    assert_eq!(small.legs[0].path, ["AAA", "FUD"]);
+    let candidates: Vec<(Vec<String>, u64)> = small
+        .candidates
+        .iter()
+        .map(|candidate| (candidate.path.clone(), candidate.amount_out_raw))
+        .collect();
+    assert_eq!(
+        candidates,
+        [
+            (vec!["AAA".into(), "FUD".into()], 996_006_981),
+            (vec!["AAA".into(), "SUI".into(), "FUD".into()], 993_811_131),
+        ]
+    );
+
+    // Large amounts are split, most of it through the deep path
+    let large = route(&hub, "AAA", "FUD", 1000.0, None).await.unwrap();
+    assert_eq!(large.candidates[0].path, ["AAA", "SUI", "FUD"]);
+    assert_eq!(large.candidates[0].amount_out_raw, 828_961_933_061);
+    assert_eq!(large.amount_out_raw, 852_198_575_390);
+    assert_eq!(large.min_received_raw, 847_937_582_513);
+    assert_eq!(large.legs.len(), 2);
+    let deep = &large.legs[0];
+    assert_eq!((deep.share_pct, deep.path.len()), (80, 3));
+    assert_eq!(deep.hops[0].pool, AAA_SUI);
+    assert_eq!(deep.hops[0].amount_in_raw, 800 * UNIT);
+    assert_eq!(deep.hops[0].amount_out_raw, deep.hops[1].amount_in_raw);
+    assert_eq!(deep.hops[1].amount_out_raw, 685_948_783_828);
+    let direct = &large.legs[1];
+    assert_eq!(
+        (direct.share_pct, direct.hops[0].pool.as_str()),
+        (20, AAA_FUD)
+    );
+    assert_eq!(direct.hops[0].amount_out_raw, 166_249_791_562);
+    assert!(
+        large.price_impact_pct > Decimal::from(10),
+        "{}",
+        large.price_impact_pct
+    );
+
+    // Only the direct pool within one hop
+    let one_hop = route(&hub, "AAA", "FUD", 1000.0, Some(1)).await.unwrap();
+    assert_eq!(one_hop.amount_out_raw, 499_248_873_309);
+    assert_eq!(one_hop.candidates.len(), 1);
+
+    let no_route = route(&hub, "FUD", "XYZ", 1.0, None).await.unwrap_err();
+    assert!(no_route.contains("XYZ"), "{}", no_route);
+}
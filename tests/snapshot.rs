@@ -0,0 +1,215 @@
+mod common;
+
+use common::{eventually, temp_dir, MockPriceServer, TestHub, ADMIN_TOKEN, SUI};
+use futures_util::StreamExt;
+use std::{collections::BTreeMap, fs};
+use sui_swap::{
+    admin,
+    messages::{AdminRequest, AdminResponse, TokenStatus},
+    rates::UsdQuote,
+    snapshot::{hash_secret, Snapshot, TokenState},
+    Decimal, Server, ServerBuilder, SwapError,
+};
+use tokio::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn builder(reclaim: Duration) -> ServerBuilder {
+    Server::builder()
+        .poll_interval(Duration::from_secs(3600))
+        .snapshots(Duration::from_secs(1), reclaim)
+}
+
+async fn token_status(hub: &TestHub, token: &str) -> Option<TokenStatus> {
+    match hub.admin(AdminRequest::Tokens).await {
+        AdminResponse::Tokens { tokens } => tokens.into_iter().find(|status| status.token == token),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn registrations_and_prices_survive_a_restart() {
+    let storage = temp_dir("storage");
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    let reclaim_file = temp_dir("reclaim.json");
+    let hub = TestHub::start_in(builder(Duration::from_secs(60)), &storage).await;
+    let client = |hub: &TestHub| {
+        hub.client_builder("SUI", &prices)
+            .reclaim_file(&reclaim_file)
+            .build()
+            .unwrap()
+    };
+    let _sui = tokio::spawn(client(&hub).start());
+    hub.poll("SUI").await;
+    let peer = hub.peer("SUI").await.unwrap().addr;
+
+    eventually(WAIT, "SUI saved", || async {
+        Snapshot::load(&storage).is_some_and(|snapshot| {
+            snapshot.tokens.iter().any(|state| {
+                state.peer == Some(peer) && state.samples == 1 && state.secret_hash.is_some()
+            })
+        })
+    })
+    .await;
+    // Only the client keeps its secret, the server saves a hash of it
+    let secrets: BTreeMap<String, String> =
+        serde_json::from_str(&fs::read_to_string(&reclaim_file).unwrap()).unwrap();
+    let secret = &secrets["SUI"];
+    assert!(!fs::read_to_string(storage.join("state.json"))
+        .unwrap()
+        .contains(secret.as_str()));
+    let saved = Snapshot::load(&storage).unwrap();
+    assert_eq!(saved.tokens[0].secret_hash, Some(hash_secret(secret)));
+    drop(hub);
+
+    let hub = TestHub::start_in(builder(Duration::from_secs(60)), &storage).await;
+    let restored = token_status(&hub, "SUI").await.expect("SUI restored");
+    assert!(!restored.registered);
+    assert_eq!(restored.peer, Some(peer));
+    assert!(restored.reclaim_secs.is_some());
+    assert_eq!(restored.samples, 1);
+    let price = restored.quote.map(|quote| quote.price);
+    assert_eq!(price, Some("1.5".parse::<Decimal>().unwrap()));
+
+    // Subscribers get the last prices right away
+    let url = hub.admin_url();
+    let subscribe = AdminRequest::Subscribe {
+        token: None,
+        resolution: None,
+        portfolio: false,
+        prices: true,
+    };
+    let pushed = admin::subscribe(&url, Some(ADMIN_TOKEN), None, &subscribe)
+        .await
+        .unwrap();
+    let mut pushed = Box::pin(pushed);
+    assert!(matches!(pushed.next().await, Some(Ok(AdminResponse::Done))));
+    match pushed.next().await {
+        Some(Ok(AdminResponse::Price { token, price, .. })) => {
+            assert_eq!(token, "SUI");
+            assert_eq!(price, "1.5".parse::<Decimal>().unwrap());
+        }
+        other => panic!("Unexpected push: {:?}", other),
+    }
+
+    // Another client on the same host can't take it
+    let other = hub.client("SUI", &prices).start().await;
+    assert!(matches!(other, Err(SwapError::TokenTaken(token)) if token == "SUI"));
+
+    // The client coming back with its reclaim secret gets its token again
+    let _sui = tokio::spawn(client(&hub).start());
+    eventually(WAIT, "SUI reclaimed", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+    let reclaimed = token_status(&hub, "SUI").await.unwrap();
+    assert!(reclaimed.registered);
+    assert_eq!(reclaimed.reclaim_secs, None);
+}
+
+#[tokio::test]
+async fn state_is_saved_on_shutdown() {
+    let storage = temp_dir("storage");
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 1.5);
+    // Only saved on shutdown
+    let builder = || {
+        Server::builder()
+            .poll_interval(Duration::from_secs(3600))
+            .snapshots(Duration::ZERO, Duration::from_secs(60))
+    };
+    let hub = TestHub::start_in(builder(), &storage).await;
+    let _sui = hub.spawn_client("SUI", &prices);
+    hub.poll("SUI").await;
+    eventually(WAIT, "SUI price", || async {
+        token_status(&hub, "SUI")
+            .await
+            .is_some_and(|status| status.samples == 1)
+    })
+    .await;
+    let peer = hub.peer("SUI").await.unwrap().addr;
+    assert_eq!(Snapshot::load(&storage), None);
+
+    hub.shutdown().await;
+    let snapshot = Snapshot::load(&storage).expect("Saved on shutdown");
+    assert_eq!(snapshot.tokens.len(), 1);
+    assert_eq!(snapshot.tokens[0].peer, Some(peer));
+
+    let hub = TestHub::start_in(builder(), &storage).await;
+    let restored = token_status(&hub, "SUI").await.expect("SUI restored");
+    assert_eq!(restored.peer, Some(peer));
+    assert_eq!(restored.samples, 1);
+    let price = restored.quote.map(|quote| quote.price);
+    assert_eq!(price, Some("1.5".parse::<Decimal>().unwrap()));
+}
+
+#[tokio::test]
+async fn restored_tokens_wait_for_their_client() {
+    let storage = temp_dir("storage");
+    let quote = UsdQuote {
+        price: Decimal::from(2),
+        confidence: "0.99".parse().unwrap(),
+        timestamp: 1_700_000_000,
+        decimals: 9,
+    };
+    let snapshot = Snapshot {
+        taken_at: 1_700_000_000,
+        tokens: vec![TokenState {
+            token: "SUI".to_string(),
+            peer: Some("10.0.0.1:5000".parse().unwrap()),
+            secret_hash: Some(hash_secret("0123456789abcdef")),
+            quote: Some(quote.clone()),
+            samples: 7,
+            last_update: Some(1_700_000_000),
+        }],
+        poll_intervals: BTreeMap::new(),
+    };
+    snapshot.save(&storage);
+
+    let prices = MockPriceServer::start().await;
+    let hub = TestHub::start_in(builder(Duration::from_secs(2)), &storage).await;
+    let restored = token_status(&hub, "SUI").await.unwrap();
+    assert_eq!(restored.quote, Some(quote));
+    assert_eq!(restored.samples, 7);
+
+    // Kept for the client with the secret until the reclaim time is over
+    let taken = hub.client("SUI", &prices).start().await;
+    assert!(matches!(taken, Err(SwapError::TokenTaken(token)) if token == "SUI"));
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let _sui = hub.spawn_client("SUI", &prices);
+    eventually(WAIT, "SUI registered", || async {
+        hub.registered_tokens().await == ["SUI"]
+    })
+    .await;
+}
+
+#[test]
+fn plain_secrets_of_older_snapshots_are_hashed_when_loaded() {
+    let storage = temp_dir("storage");
+    fs::create_dir_all(&storage).unwrap();
+    let state = r#"{
+        "taken_at": 1700000000,
+        "tokens": [{
+            "token": "SUI",
+            "peer": "10.0.0.1:5000",
+            "secret": "0123456789abcdef",
+            "quote": null,
+            "samples": 7,
+            "last_update": 1700000000
+        }]
+    }"#;
+    fs::write(storage.join("state.json"), state).unwrap();
+
+    let snapshot = Snapshot::load(&storage).unwrap();
+    assert_eq!(
+        snapshot.tokens[0].secret_hash,
+        Some(hash_secret("0123456789abcdef"))
+    );
+    // And written back without them
+    snapshot.save(&storage);
+    let saved = fs::read_to_string(storage.join("state.json")).unwrap();
+    assert!(!saved.contains("0123456789abcdef"), "{}", saved);
+    assert_eq!(Snapshot::load(&storage), Some(snapshot));
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::{temp_dir, SUI};
+use std::fs;
+use sui_swap::{Decimal, SwapError, TokenConfig, TokenRegistry};
+
+/// Tokens file with a single SUI entry having `fields` besides the required ones
+fn sui_entry(fields: &str) -> String {
+    format!(
+        r#"{{ "SUI": {{ "coin_type": "{}", "symbol": "SUI"{} }} }}"#,
+        SUI, fields
+    )
+}
+
+fn invalid_entry(result: Result<TokenRegistry, SwapError>) -> (String, String) {
+    match result {
+        Err(SwapError::InvalidTokenEntry(name, reason)) => (name, reason),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn entries_are_validated_when_loading() {
+    let dir = temp_dir("tokens");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tokens.json");
+    let empty_symbol = format!(
+        r#"{{ "SUI": {{ "coin_type": "{}", "symbol": " " }} }}"#,
+        SUI
+    );
+    fs::write(&path, empty_symbol).unwrap();
+    let (name, reason) = invalid_entry(TokenRegistry::load(&path));
+    assert_eq!(name, "SUI");
+    assert!(reason.contains("symbol"), "{}", reason);
+
+    let (_, reason) = invalid_entry(TokenRegistry::from_json(&sui_entry(
+        r#", "poll_interval_secs": 0"#,
+    )));
+    assert!(reason.contains("poll_interval_secs"), "{}", reason);
+
+    // Entries added in code are checked the same way
+    let mut registry = TokenRegistry::default();
+    let mut entry = TokenConfig::new(SUI.parse().unwrap(), "SUI");
+    entry.poll_interval_secs = Some(0);
+    assert!(matches!(
+        registry.insert("SUI", entry),
+        Err(SwapError::InvalidTokenEntry(..))
+    ));
+}
+
+#[test]
+fn deviation_thresholds_are_checked() {
+    let registry = TokenRegistry::from_json(&sui_entry(
+        r#", "deviation": { "warn_pct": 10, "reject_pct": "50.5" }"#,
+    ))
+    .unwrap();
+    let deviation = &registry.get("SUI").unwrap().deviation;
+    assert_eq!(deviation.warn_pct, Some(Decimal::from(10)));
+    assert_eq!(deviation.reject_pct, "50.5".parse().ok());
+
+    let (_, reason) = invalid_entry(TokenRegistry::from_json(&sui_entry(
+        r#", "deviation": { "warn_pct": 50, "reject_pct": 10 }"#,
+    )));
+    assert!(reason.contains("greater than"), "{}", reason);
+
+    let (_, reason) = invalid_entry(TokenRegistry::from_json(&sui_entry(
+        r#", "deviation": { "reject_pct": 0 }"#,
+    )));
+    assert!(reason.contains("deviation.reject_pct"), "{}", reason);
+
+    // Decimals can't be negative, so the file doesn't even parse
+    for negative in ["-5", r#""-5""#] {
+        let result = TokenRegistry::from_json(&sui_entry(&format!(
+            r#", "deviation": {{ "warn_pct": {} }}"#,
+            negative
+        )));
+        assert!(
+            matches!(result, Err(SwapError::ParseTokensFileError(_))),
+            "{:?}",
+            result
+        );
+    }
+}
+
+#[test]
+fn misspelled_keys_are_rejected() {
+    for fields in [
+        r#", "simbol": "SUI""#,
+        r#", "deviation": { "warn_percent": 10 }"#,
+    ] {
+        match TokenRegistry::from_json(&sui_entry(fields)) {
+            Err(SwapError::ParseTokensFileError(parse_error)) => assert!(
+                parse_error.to_string().contains("unknown field"),
+                "{}",
+                parse_error
+            ),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use common::{eventually, full, MockPriceServer, MockSuiRpc, TestHub, FUD, SUI};
+use serde_json::{json, Value};
+use sui_swap::{
+    config::{PoolConfig, QuoteConfig, SwapCallConfig},
+    messages::{AdminRequest, AdminResponse},
+    transactions::SwapTransaction,
+    Decimal, Server,
+};
+use tokio::time::Duration;
+
+const POOL: &str = "0x5eb2dfcdd1b15d2021328258f6d5ec081e9a0cdcfa9e13a0eaeb9b5f7505ca78";
+const PACKAGE: &str = "0xdee9";
+const SENDER: &str = "0xa11ce";
+const GAS_PRICE: u64 = 750;
+
+/// Address as the 32 bytes BCS writes
+fn address_bytes(address: &str) -> Vec<u8> {
+    let digits = address.trim_start_matches("0x");
+    let padded = format!("{:0>64}", digits);
+    (0..32)
+        .map(|i| u8::from_str_radix(&padded[i * 2..i * 2 + 2], 16).unwrap())
+        .collect()
+}
+
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    bytes.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Chain with a shared SUI/FUD pool, a SUI coin and 2 FUD coins of `SENDER`
+fn chain(method: &str, params: &Value) -> Option<Value> {
+    let coin = |id: &str, digest: &str, balance: &str| json!({ "coinType": "", "coinObjectId": id, "version": "42", "digest": digest, "balance": balance });
+    match method {
+        "sui_getObject" if params[0] == POOL => Some(json!({ "data": {
+            "objectId": POOL,
+            "owner": { "Shared": { "initial_shared_version": 3_000_001 } },
+            "content": { "dataType": "moveObject", "fields": {
+                "reserve_x": "1000000000000",
+                "reserve_y": "200000000000",
+            } },
+        } })),
+        "suix_getReferenceGasPrice" => Some(json!(GAS_PRICE.to_string())),
+        "suix_getCoins" if params[0] == SENDER => {
+            // FUD coins come one per page
+            let (coins, next) = if params[1] == full(SUI) {
+                (
+                    vec![coin(
+                        "0xa1",
+                        "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi",
+                        "5000000000",
+                    )],
+                    None,
+                )
+            } else if params[1] == full(FUD) && params[2].is_null() {
+                (
+                    vec![coin(
+                        "0xf1",
+                        "8qbHbw2BbbTHBW1sbeqakYXVKRQM8Ne7pLK7m6CVfeR",
+                        "60000000",
+                    )],
+                    Some("0xf1"),
+                )
+            } else if params[1] == full(FUD) && params[2] == "0xf1" {
+                (
+                    vec![coin(
+                        "0xf2",
+                        "CktRuQ2mttgRGkXJtyksdKHjUdc2C4TgDzyB98oEzy8",
+                        "50000000",
+                    )],
+                    None,
+                )
+            } else {
+                (vec![], None)
+            };
+            Some(json!({ "data": coins, "nextCursor": next, "hasNextPage": next.is_some() }))
+        }
+        "sui_dryRunTransactionBlock" => Some(json!({
+            "effects": {
+                "status": { "status": "success" },
+                "gasUsed": {
+                    "computationCost": "1000000",
+                    "storageCost": "2000000",
+                    "storageRebate": "500000",
+                    "nonRefundableStorageFee": "5000",
+                },
+            },
+            "balanceChanges": [],
+        })),
+        _ => None,
+    }
+}
+
+async fn build_swap(
+    hub: &TestHub,
+    from: &str,
+    to: &str,
+    amount: f64,
+    dry_run: bool,
+) -> Result<SwapTransaction, String> {
+    let request = AdminRequest::BuildSwap {
+        from_token: from.to_string(),
+        to_token: to.to_string(),
+        amount: Decimal::from_f64(amount).unwrap(),
+        slippage_bps: None,
+        sender: SENDER.to_string(),
+        dry_run,
+    };
+    match hub.admin(request).await {
+        AdminResponse::SwapTransaction { transaction } => Ok(transaction),
+        AdminResponse::Error { message } => Err(message),
+        other => panic!("Unexpected admin response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn builds_and_dry_runs_swap_transactions() {
+    let rpc = MockSuiRpc::start(chain).await;
+    let quotes = QuoteConfig {
+        rpc_url: rpc.url(),
+        pools: vec![PoolConfig {
+            id: POOL.to_string(),
+            token_a: "SUI".to_string(),
+            token_b: "FUD".to_string(),
+            fee_bps: 30,
+            reserve_a_field: None,
+            reserve_b_field: None,
+            swap: Some(SwapCallConfig {
+                package: PACKAGE.to_string(),
+                module: "amm".to_string(),
+                a_to_b: "swap_a_to_b".to_string(),
+                b_to_a: "swap_b_to_a".to_string(),
+            }),
+        }],
+        ..QuoteConfig::default()
+    };
+    let prices = MockPriceServer::start().await;
+    prices.set_price(SUI, 2.0);
+    prices.set_price(FUD, 0.00001);
+    prices.set_decimals(FUD, 5);
+    let builder = Server::builder()
+        .poll_interval(Duration::from_secs(3600))
+        .quotes(quotes);
+    let hub = TestHub::start_with(builder).await;
+    let mut clients = Vec::new();
+    for token in ["SUI", "FUD"] {
+        clients.push(hub.spawn_client(token, &prices));
+        hub.poll(token).await;
+    }
+    eventually(Duration::from_secs(5), "prices", || async {
+        build_swap(&hub, "SUI", "FUD", 1.0, false).await.is_ok()
+    })
+    .await;
+
+    // FUD coins are merged and split, the gas budget comes from the dry-run
+    let swap = build_swap(&hub, "FUD", "SUI", 1000.0, true).await.unwrap();
+    assert_eq!(swap.quote.amount_in_raw, 100_000_000);
+    assert_eq!(swap.gas_price, GAS_PRICE);
+    let dry_run = swap.dry_run.clone().expect("Dry-run");
+    assert!(dry_run.success);
+    // 1000000 + 1000 * 750 overhead + 2000000 - 500000
+    assert_eq!(swap.gas_budget, 3_250_000);
+
+    let dry_run_request = rpc
+        .received()
+        .into_iter()
+        .rfind(|request| request["method"] == "sui_dryRunTransactionBlock")
+        .expect("Dry-run request");
+    let dry_run_bytes = BASE64
+        .decode(dry_run_request["params"][0].as_str().unwrap())
+        .unwrap();
+    let bytes = BASE64.decode(&swap.tx_bytes).unwrap();
+    // Only the budget differs from the dry-run
+    assert_eq!(dry_run_bytes.len(), bytes.len());
+    assert_eq!(dry_run_bytes[..bytes.len() - 9], bytes[..bytes.len() - 9]);
+    assert_eq!(
+        &dry_run_bytes[bytes.len() - 9..bytes.len() - 1],
+        50_000_000u64.to_le_bytes()
+    );
+
+    // V1, programmable, 6 inputs: amount, 2 FUD coins, pool, min out, sender
+    assert_eq!(bytes[..3], [0, 0, 6]);
+    assert_eq!(bytes[3..5], [0, 8]);
+    assert_eq!(bytes[5..13], 100_000_000u64.to_le_bytes());
+    let mut shared_pool = vec![1, 1];
+    shared_pool.extend(address_bytes(POOL));
+    shared_pool.extend(3_000_001u64.to_le_bytes());
+    shared_pool.push(1);
+    assert!(contains(&bytes, &shared_pool));
+    let mut min_out = vec![0, 8];
+    min_out.extend(swap.quote.min_received_raw.to_le_bytes());
+    assert!(contains(&bytes, &min_out));
+    let mut call = address_bytes(PACKAGE);
+    call.extend(b"\x03amm\x0bswap_b_to_a\x02\x07");
+    call.extend(address_bytes("0x2"));
+    call.extend(b"\x03sui\x03SUI\x00\x07");
+    call.extend(address_bytes(FUD.split("::").next().unwrap()));
+    call.extend(b"\x03fud\x03FUD\x00");
+    assert!(contains(&bytes, &call));
+    // Sender, gas data (1 SUI coin, owner, price, budget) and no expiration
+    let tail = &bytes[bytes.len() - 49..];
+    assert_eq!(tail[..32], address_bytes(SENDER));
+    assert_eq!(tail[32..40], GAS_PRICE.to_le_bytes());
+    assert_eq!(tail[40..48], 3_250_000u64.to_le_bytes());
+    assert_eq!(tail[48], 0);
+
+    // SUI is split from the gas coin, no other coins are needed
+    let calls = rpc.received().len();
+    let swap = build_swap(&hub, "SUI", "FUD", 10.0, false).await.unwrap();
+    assert_eq!(swap.dry_run, None);
+    assert_eq!(swap.gas_budget, 50_000_000);
+    let coin_types: Vec<Value> = rpc.received()[calls..]
+        .iter()
+        .filter(|request| request["method"] == "suix_getCoins")
+        .map(|request| request["params"][1].clone())
+        .collect();
+    // Sent with the address in full
+    assert_eq!(coin_types, [full(SUI)]);
+
+    // The first page of FUD coins is enough
+    let calls = rpc.received().len();
+    build_swap(&hub, "FUD", "SUI", 500.0, false).await.unwrap();
+    let cursors: Vec<Value> = rpc.received()[calls..]
+        .iter()
+        .filter(|request| request["method"] == "suix_getCoins" && request["params"][1] == full(FUD))
+        .map(|request| request["params"][2].clone())
+        .collect();
+    assert_eq!(cursors, [Value::Null]);
+
+    let too_much = build_swap(&hub, "FUD", "SUI", 2000.0, false)
+        .await
+        .unwrap_err();
+    assert!(too_much.contains("Not enough"), "{}", too_much);
+}